@@ -140,17 +140,18 @@ fn save_buffer(
 
     output.write_header()?;
 
-    let first_pts = video_buffer
+    // The first DTS is the smallest timestamp, with B-frames it's below the first PTS
+    let first_dts = video_buffer
         .values()
         .next()
-        .map(|frame| frame.pts)
+        .map(|frame| frame.dts)
         .unwrap_or(0);
 
     // Write video
     for frame in video_buffer.values() {
         let mut packet = ffmpeg_next::codec::packet::Packet::copy(&frame.data);
-        packet.set_pts(Some(frame.pts - first_pts));
-        packet.set_dts(Some(frame.dts - first_pts));
+        packet.set_pts(Some(frame.pts - first_dts));
+        packet.set_dts(Some(frame.dts - first_dts));
 
         // 0 = Video
         // 1 = Audio
@@ -0,0 +1,29 @@
+/// Records 10 seconds of screen and system audio into out.mp4 using the built-in muxer
+use std::time::{Duration, Instant};
+
+use waycap_rs::{
+    pipeline::{builder::CaptureBuilder, muxer::Muxer},
+    types::error::Result,
+};
+
+fn main() -> Result<()> {
+    let mut capture = CaptureBuilder::new()
+        .with_audio()
+        .with_cursor_shown()
+        .build()?;
+    let video_recv = capture.get_video_receiver();
+    let audio_recv = capture.get_audio_receiver()?;
+    let mut muxer = Muxer::new("out.mp4", &capture)?;
+
+    capture.start()?;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        crossbeam::select! {
+            recv(video_recv) -> frame => muxer.push_video(frame.unwrap())?,
+            recv(audio_recv) -> frame => muxer.push_audio(frame.unwrap())?,
+            default(Duration::from_millis(100)) => {}
+        }
+    }
+    capture.close()?;
+    muxer.finish()
+}
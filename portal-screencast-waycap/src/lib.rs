@@ -95,6 +95,8 @@ pub struct ScreenCast {
     multiple: bool,
     source_types: Option<SourceType>,
     cursor_mode: Option<CursorMode>,
+    persist_mode: Option<PersistMode>,
+    restore_token: Option<String>,
 }
 
 impl ScreenCast {
@@ -133,6 +135,8 @@ impl ScreenCast {
             multiple: false,
             source_types: None,
             cursor_mode: None,
+            persist_mode: None,
+            restore_token: None,
         })
     }
 
@@ -153,6 +157,19 @@ impl ScreenCast {
         self.cursor_mode = Some(mode);
     }
 
+    /// Ask the portal to remember the selected sources. The token to restore
+    /// them is available from `ActiveScreenCast::restore_token()`.
+    pub fn set_persist_mode(&mut self, mode: PersistMode) {
+        self.persist_mode = Some(mode);
+    }
+
+    /// Restore the sources of a previous session instead of prompting the
+    /// user again. Tokens can only be used once, each session hands out a new
+    /// one. The portal prompts as usual if it doesn't know the token.
+    pub fn set_restore_token(&mut self, token: &str) {
+        self.restore_token = Some(token.to_owned());
+    }
+
     /// Enable multi-stream selection. This allows the user to choose more than
     /// one thing to share. Each will be a separate item in the
     /// `ActiveScreenCast::streams()` iterator.
@@ -188,29 +205,42 @@ impl ScreenCast {
                     None => CursorMode::HIDDEN.bits(),
                 })),
             );
+            if let Some(mode) = self.persist_mode {
+                select_args.insert("persist_mode".into(), Variant(Box::new(mode as u32)));
+            }
+            if let Some(token) = &self.restore_token {
+                select_args.insert("restore_token".into(), Variant(Box::new(token.clone())));
+            }
 
             desktop_proxy.select_sources(session, select_args)?;
             request.wait_response()?;
         }
 
-        let streams = {
+        let (streams, restore_token) = {
             let request = Request::with_handler(&self.state, |response| {
                 if response.response != 0 {
                     return Err(PortalError::Cancelled);
                 }
-                match response.results.get("streams") {
-                    Some(streams) => match streams.as_iter() {
-                        Some(streams) => streams
-                            .flat_map(|s| {
-                                s.as_iter()
-                                    .into_iter()
-                                    .flat_map(|t| t.map(|u| u.try_into()))
-                            })
-                            .collect(),
+                let restore_token = response
+                    .results
+                    .get("restore_token")
+                    .and_then(|token| token.as_str())
+                    .map(str::to_owned);
+                let streams: Result<Vec<ScreenCastStream>, PortalError> =
+                    match response.results.get("streams") {
+                        Some(streams) => match streams.as_iter() {
+                            Some(streams) => streams
+                                .flat_map(|s| {
+                                    s.as_iter()
+                                        .into_iter()
+                                        .flat_map(|t| t.map(|u| u.try_into()))
+                                })
+                                .collect(),
+                            None => Err(PortalError::Parse),
+                        },
                         None => Err(PortalError::Parse),
-                    },
-                    None => Err(PortalError::Parse),
-                }
+                    };
+                Ok((streams?, restore_token))
             })?;
             let session = dbus::Path::from(&self.session);
             let mut select_args = HashMap::<String, Variant<Box<dyn RefArg>>>::new();
@@ -230,6 +260,7 @@ impl ScreenCast {
             session_path: self.session,
             pipewire_fd,
             streams,
+            restore_token,
         })
     }
 }
@@ -241,6 +272,7 @@ pub struct ActiveScreenCast {
     session_path: String,
     pipewire_fd: OwnedFd,
     streams: Vec<ScreenCastStream>,
+    restore_token: Option<String>,
 }
 
 impl ActiveScreenCast {
@@ -254,6 +286,12 @@ impl ActiveScreenCast {
         self.streams.iter()
     }
 
+    /// Get the token to restore this session's sources with, only handed out
+    /// when a persist mode was set and the portal supports it.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.restore_token.as_deref()
+    }
+
     /// Close the ScreenCast session. This ends the cast.
     pub fn close(&self) -> Result<(), PortalError> {
         // Open a handle to the active session, and close it.
@@ -278,6 +316,7 @@ pub struct ScreenCastStream {
     pipewire_node: u32,
     width: u32,
     height: u32,
+    position: Option<(i32, i32)>,
 }
 
 impl ScreenCastStream {
@@ -297,6 +336,12 @@ impl ScreenCastStream {
     pub fn size(&self) -> (u32, u32) {
         (self.width(), self.height())
     }
+
+    /// Position of a monitor stream in the compositor's logical layout.
+    /// Not every portal reports it, and it's meaningless for windows.
+    pub fn position(&self) -> Option<(i32, i32)> {
+        self.position
+    }
 }
 
 impl std::convert::TryFrom<&dyn RefArg> for ScreenCastStream {
@@ -316,6 +361,7 @@ impl std::convert::TryFrom<&dyn RefArg> for ScreenCastStream {
 
         let mut width = 0;
         let mut height = 0;
+        let mut position = None;
 
         if let Some(mut dict_iter) = metadata.as_iter() {
             while let Some(key) = dict_iter.next() {
@@ -338,6 +384,23 @@ impl std::convert::TryFrom<&dyn RefArg> for ScreenCastStream {
                     } else {
                         return Err(PortalError::Parse);
                     }
+                } else if key.as_str() == Some("position") {
+                    if let Some(values) = dict_iter.next().ok_or(PortalError::Parse)?.as_iter() {
+                        for v in values {
+                            let mut v_iter = v.as_iter().ok_or(PortalError::Parse)?;
+                            let x = v_iter
+                                .next()
+                                .and_then(|x| x.as_i64())
+                                .ok_or(PortalError::Parse)?;
+                            let y = v_iter
+                                .next()
+                                .and_then(|y| y.as_i64())
+                                .ok_or(PortalError::Parse)?;
+                            position = Some((x as i32, y as i32));
+                        }
+                    } else {
+                        return Err(PortalError::Parse);
+                    }
                 }
             }
         }
@@ -346,6 +409,7 @@ impl std::convert::TryFrom<&dyn RefArg> for ScreenCastStream {
             pipewire_node: node_id,
             width,
             height,
+            position,
         })
     }
 }
@@ -373,6 +437,22 @@ bitflags! {
     }
 }
 
+/// Persist Mode
+///
+/// Whether the portal should remember the sources the user picked, refer to
+/// the freedesktop [docs](https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html#org-freedesktop-portal-screencast-selectsources)
+///
+/// Default: DO_NOT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistMode {
+    /// Forget the sources once the session ends
+    DoNot = 0,
+    /// Remember the sources while the application is running
+    Application = 1,
+    /// Remember the sources until the user revokes the permission
+    ExplicitlyRevoked = 2,
+}
+
 // - - - - - - - - - - - - - -  Private Implementation - - - - - - - - - - - -
 
 /// D-Bus connection state. Used to access the Desktop portal
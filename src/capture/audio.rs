@@ -1,11 +1,22 @@
-use std::{process::Command, sync::Arc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::Arc};
 
-use crate::{types::audio_frame::RawAudioFrame, CaptureControls, ReadyState};
+use crate::{
+    types::{audio_frame::RawAudioFrame, error::WaycapError},
+    CaptureControls, ReadyState,
+};
 use crossbeam::channel::Sender;
+use ffmpeg_next::{
+    self as ffmpeg,
+    format::{sample::Type as SampleType, Sample},
+    software::resampling,
+    ChannelLayout,
+};
 use pipewire::{
     self as pw,
     context::Context,
+    core::Core,
     main_loop::MainLoop,
+    metadata::{Metadata, MetadataListener},
     properties::properties,
     spa::{
         self,
@@ -15,30 +26,85 @@ use pipewire::{
     },
     stream::{StreamFlags, StreamState},
     sys::pw_stream_get_nsec,
+    types::ObjectType,
 };
 
-use super::Terminate;
+use super::{do_roundtrip, Terminate};
 
 #[derive(Clone, Copy, Default)]
 struct UserData {
     audio_format: spa::param::audio::AudioInfoRaw,
 }
 
+/// Which kind of PipeWire node an [`AudioCapture`] records from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioSource {
+    /// Monitor of the default sink, i.e. whatever is playing on the system
+    SystemAudio,
+    /// The default source, usually a microphone
+    Microphone,
+}
+
+impl AudioSource {
+    /// Start of the names of the threads recording and encoding this source
+    pub(crate) fn thread_prefix(self) -> &'static str {
+        match self {
+            AudioSource::SystemAudio => "waycap-audio",
+            AudioSource::Microphone => "waycap-mic",
+        }
+    }
+}
+
+/// An audio node which can be recorded from, see [`crate::pipeline::audio_devices`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioDevice {
+    /// PipeWire node id, pass this to
+    /// [`crate::pipeline::builder::CaptureBuilder::with_audio_device`]
+    pub node_id: u32,
+    /// Unique node name, e.g. `alsa_output.pci-0000_00_1f.3.analog-stereo`
+    pub name: String,
+    /// Human readable name, when the node has one
+    pub description: Option<String>,
+    /// Sinks play audio (recording one captures its monitor), sources are inputs like microphones
+    pub is_sink: bool,
+}
+
+/// Records raw samples from PipeWire and sends them to the audio encoding loop.
+///
+/// Shares the [`ReadyState`] and [`CaptureControls`] of the capture like
+/// [`super::video::VideoCapture`] does, so audio is only sent once video is streaming and
+/// the capture isn't paused.
 pub struct AudioCapture {
     ready_state: Arc<ReadyState>,
+    controls: Arc<CaptureControls>,
+    source: AudioSource,
+    sample_rate: u32,
+    /// Node to record from instead of the default one for `source`
+    target_node: Option<u32>,
 }
 
-// TODO: Similar approach to video capture in how the struct should look
 impl AudioCapture {
-    pub fn new(ready_state: Arc<ReadyState>) -> Self {
-        Self { ready_state }
+    pub fn new(
+        ready_state: Arc<ReadyState>,
+        controls: Arc<CaptureControls>,
+        source: AudioSource,
+        sample_rate: u32,
+        target_node: Option<u32>,
+    ) -> Self {
+        Self {
+            ready_state,
+            controls,
+            source,
+            sample_rate,
+            target_node,
+        }
     }
 
+    /// Run the PipeWire loop until a [`Terminate`] is received on `termination_recv`
     pub fn run(
         &self,
         audio_sender: Sender<RawAudioFrame>,
         termination_recv: pw::channel::Receiver<Terminate>,
-        controls: Arc<CaptureControls>,
     ) -> Result<(), pw::Error> {
         let pw_loop = MainLoop::new(None)?;
         let terminate_loop = pw_loop.clone();
@@ -51,37 +117,60 @@ impl AudioCapture {
         let pw_context = Context::new(&pw_loop)?;
         let audio_core = pw_context.connect(None)?;
 
+        let core_controls = Arc::clone(&self.controls);
+        let core_loop = pw_loop.clone();
         let _audio_core_listener = audio_core
             .add_listener_local()
             .info(|i| log::debug!("AUDIO CORE:\n{i:#?}"))
-            .error(|e, f, g, h| log::error!("{e},{f},{g},{h}"))
+            .error(move |id, seq, res, message| {
+                log::error!("Audio core error on {id} (seq {seq}): {res} {message}");
+                core_controls.fail(format!("{message} ({res})"));
+                core_loop.quit();
+            })
             .done(|d, _| log::debug!("DONE: {d}"))
             .register();
 
         let data = UserData::default();
 
+        let (stream_name, media_role) = match self.source {
+            AudioSource::SystemAudio => ("waycap-audio", "Music"),
+            AudioSource::Microphone => ("waycap-mic", "Communication"),
+        };
+
         // Audio Stream
         let audio_stream = pw::stream::Stream::new(
             &audio_core,
-            "waycap-audio",
+            stream_name,
             properties! {
             *pw::keys::MEDIA_TYPE => "Audio",
             *pw::keys::MEDIA_CATEGORY => "Capture",
-            *pw::keys::MEDIA_ROLE => "Music",
-            *pw::keys::NODE_LATENCY => "1024/48000",
+            *pw::keys::MEDIA_ROLE => media_role,
+            *pw::keys::NODE_LATENCY => format!("1024/{}", self.sample_rate),
             },
         )?;
 
+        let source = self.source;
         let ready_state_a = Arc::clone(&self.ready_state);
         let ready_state_b = Arc::clone(&self.ready_state);
+        let controls = Arc::clone(&self.controls);
+        let stop_loop = pw_loop.clone();
+        let state_controls = Arc::clone(&self.controls);
+        let state_loop = pw_loop.clone();
+        let sample_rate = self.sample_rate;
+        let mut resampler: Option<Resampler> = None;
         let _audio_stream_shared_data_listener = audio_stream
             .add_local_listener_with_user_data(data)
             .state_changed(move |_, _, old, new| {
-                log::info!("Audio Stream State Changed: {old:?} -> {new:?}");
-                ready_state_a.audio.store(
-                    new == StreamState::Streaming,
-                    std::sync::atomic::Ordering::Release,
-                );
+                log::info!("{source:?} Stream State Changed: {old:?} -> {new:?}");
+                let streaming = new == StreamState::Streaming;
+                match source {
+                    AudioSource::SystemAudio => ready_state_a.set_audio(streaming),
+                    AudioSource::Microphone => ready_state_a.set_mic(streaming),
+                }
+                if let StreamState::Error(message) = new {
+                    state_controls.fail(message);
+                    state_loop.quit();
+                }
             })
             .param_changed(|_, udata, id, param| {
                 let Some(param) = param else {
@@ -114,9 +203,14 @@ impl AudioCapture {
                     udata.audio_format.format().as_raw()
                 );
             })
-            .process(move |stream, _| match stream.dequeue_buffer() {
+            .process(move |stream, udata| match stream.dequeue_buffer() {
                 None => log::debug!("Out of audio buffers"),
                 Some(mut buffer) => {
+                    // Stopping is final, no need to wait for a Terminate
+                    if controls.is_stopped() {
+                        stop_loop.quit();
+                        return;
+                    }
                     // Wait until video is streaming before we try to process
                     if !ready_state_b.video_ready() || controls.skip_processing() {
                         return;
@@ -133,24 +227,50 @@ impl AudioCapture {
                     if let Some(samples) = data.data() {
                         let samples_f32: &[f32] = bytemuck::cast_slice(samples);
                         let audio_samples = &samples_f32[..n_samples as usize];
-                        match audio_sender.try_send(RawAudioFrame {
-                            samples: audio_samples.to_vec(),
-                            timestamp: unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64,
-                        }) {
+
+                        // PipeWire converts to the rate we ask for, this only kicks in if the
+                        // graph could not
+                        let input_rate = udata.audio_format.rate();
+                        let audio_samples = if input_rate != 0 && input_rate != sample_rate {
+                            if resampler
+                                .as_ref()
+                                .is_none_or(|r| r.input_rate != input_rate)
+                            {
+                                log::info!("Resampling audio from {input_rate} to {sample_rate}");
+                                match Resampler::new(input_rate, sample_rate) {
+                                    Ok(r) => resampler = Some(r),
+                                    Err(e) => {
+                                        log::error!("Could not create audio resampler: {e}");
+                                        return;
+                                    }
+                                }
+                            }
+                            match resampler.as_mut().unwrap().run(audio_samples) {
+                                Ok(samples) => samples,
+                                Err(e) => {
+                                    log::error!("Error resampling audio: {e}");
+                                    return;
+                                }
+                            }
+                        } else {
+                            audio_samples.to_vec()
+                        };
+
+                        let now = unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64;
+                        let timestamp = controls.without_pauses(now);
+                        match audio_sender.try_send(RawAudioFrame::new(audio_samples, timestamp)) {
                             Ok(_) => {}
                             Err(crossbeam::channel::TrySendError::Full(frame)) => {
                                 log::error!(
                                     "channel is full when trying to send frame at: {}.",
-                                    frame.timestamp
+                                    frame.timestamp_ns
                                 );
                             }
-                            Err(crossbeam::channel::TrySendError::Disconnected(frame)) => {
-                                // TODO: If we disconnected, terminate the session instead of
-                                // throwing an error it means the receiver was dropped.
-                                log::error!(
-                                    "channel is disconnected when trying to send frame at: {}.",
-                                    frame.timestamp
-                                );
+                            Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
+                                // The receiver was dropped, end the whole session
+                                log::info!("{source:?} frame receiver dropped, stopping capture");
+                                controls.stop();
+                                stop_loop.quit();
                             }
                         }
                     }
@@ -175,6 +295,16 @@ impl AudioCapture {
                 pw::spa::param::format::FormatProperties::AudioFormat,
                 Id,
                 pw::spa::param::audio::AudioFormat::F32LE
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::AudioRate,
+                Int,
+                self.sample_rate as i32
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::AudioChannels,
+                Int,
+                2
             )
         };
 
@@ -188,12 +318,20 @@ impl AudioCapture {
 
         let mut audio_params = [Pod::from_bytes(&audio_spa_values).unwrap()];
 
-        let sink_id_to_use = get_default_sink_node_id();
+        let target_id = match self.source {
+            _ if self.target_node.is_some() => self.target_node,
+            AudioSource::SystemAudio => {
+                let sink_id = get_default_sink_node_id(&pw_loop, &audio_core);
+                log::debug!("Default sink id: {sink_id:?}");
+                sink_id
+            }
+            // Input streams without a target get linked to the default source by PipeWire
+            AudioSource::Microphone => None,
+        };
 
-        log::debug!("Default sink id: {sink_id_to_use:?}");
         audio_stream.connect(
             Direction::Input,
-            sink_id_to_use,
+            target_id,
             StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS,
             &mut audio_params,
         )?;
@@ -205,17 +343,238 @@ impl AudioCapture {
     }
 }
 
-// Theres gotta be a less goofy way to do this
-fn get_default_sink_node_id() -> Option<u32> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(r#"pactl list sinks | awk -v sink="$(pactl info | grep 'Default Sink' | cut -d' ' -f3)" '$0 ~ "Name: " sink { found=1 } found && /object.id/ { print $NF; exit }'"#)
-        .output()
-        .expect("Failed to execute command");
+/// Converts interleaved stereo samples to the rate the encoder runs at
+struct Resampler {
+    context: resampling::Context,
+    input_rate: u32,
+}
+
+impl Resampler {
+    const FORMAT: Sample = Sample::F32(SampleType::Packed);
+
+    fn new(input_rate: u32, output_rate: u32) -> Result<Self, ffmpeg::Error> {
+        let context = resampling::Context::get(
+            Self::FORMAT,
+            ChannelLayout::STEREO,
+            input_rate,
+            Self::FORMAT,
+            ChannelLayout::STEREO,
+            output_rate,
+        )?;
+        Ok(Self {
+            context,
+            input_rate,
+        })
+    }
+
+    fn run(&mut self, samples: &[f32]) -> Result<Vec<f32>, ffmpeg::Error> {
+        let n_samples = samples.len() / 2;
+        let mut input = ffmpeg::frame::Audio::new(Self::FORMAT, n_samples, ChannelLayout::STEREO);
+        input.set_rate(self.input_rate);
+        let input_bytes: &[u8] = bytemuck::cast_slice(samples);
+        input.data_mut(0)[..input_bytes.len()].copy_from_slice(input_bytes);
+
+        // Leave room for the samples the resampler buffered from the previous call
+        let output_rate = self.context.output().rate;
+        let capacity = n_samples * output_rate as usize / self.input_rate as usize + 64;
+        let mut output = ffmpeg::frame::Audio::new(Self::FORMAT, capacity, ChannelLayout::STEREO);
+        self.context.run(&input, &mut output)?;
+
+        let n_bytes = output.samples() * 2 * std::mem::size_of::<f32>();
+        Ok(bytemuck::cast_slice(&output.data(0)[..n_bytes]).to_vec())
+    }
+}
+
+/// Every audio sink and source in the PipeWire registry
+pub(crate) fn list_audio_devices() -> crate::types::error::Result<Vec<AudioDevice>> {
+    let pw_loop = MainLoop::new(None)?;
+    let context = Context::new(&pw_loop)?;
+    let core = context.connect(None)?;
+    let registry = core.get_registry()?;
+
+    let devices: Rc<RefCell<Vec<AudioDevice>>> = Rc::new(RefCell::new(Vec::new()));
+    let devices_global = Rc::clone(&devices);
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            if global.type_ != ObjectType::Node {
+                return;
+            }
+            let Some(props) = global.props else {
+                return;
+            };
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+            let is_sink = match props.get("media.class") {
+                Some("Audio/Sink") => true,
+                Some("Audio/Source") => false,
+                _ => return,
+            };
+            let Some(name) = props.get("node.name") else {
+                return;
+            };
+            devices_global.borrow_mut().push(AudioDevice {
+                node_id: global.id,
+                name: name.to_string(),
+                description: props.get("node.description").map(str::to_string),
+                is_sink,
+            });
+        })
+        .register();
+
+    do_roundtrip(&pw_loop, &core);
+
+    let devices = devices.borrow().clone();
+    Ok(devices)
+}
+
+/// Node id of the audio sink or source whose `node.name`, or else `node.description`, is `name`
+pub(crate) fn find_audio_device(name: &str) -> crate::types::error::Result<u32> {
+    let devices = list_audio_devices()?;
+    devices
+        .iter()
+        .find(|device| device.name == name)
+        .or_else(|| {
+            devices
+                .iter()
+                .find(|device| device.description.as_deref() == Some(name))
+        })
+        .map(|device| device.node_id)
+        .ok_or_else(|| WaycapError::Device(format!("No audio sink or source named {name:?}")))
+}
 
-    let cleaned = stdout.replace('"', "");
+/// Looks up the node id of the default sink through the `default` metadata object.
+///
+/// Returns `None` if the metadata can't be read, in which case PipeWire picks a node itself.
+fn get_default_sink_node_id(main_loop: &MainLoop, core: &Core) -> Option<u32> {
+    let registry = match core.get_registry() {
+        Ok(registry) => Rc::new(registry),
+        Err(e) => {
+            log::warn!("Could not get the PipeWire registry: {e}");
+            return None;
+        }
+    };
 
-    cleaned.trim().parse::<u32>().ok()
+    let node_ids: Rc<RefCell<HashMap<String, u32>>> = Rc::new(RefCell::new(HashMap::new()));
+    let default_sink: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    // Keep the bound metadata objects alive so their property events reach us
+    let metadata_bindings: Rc<RefCell<Vec<(Metadata, MetadataListener)>>> =
+        Rc::new(RefCell::new(Vec::new()));
+
+    let registry_weak = Rc::downgrade(&registry);
+    let node_ids_global = Rc::clone(&node_ids);
+    let default_sink_global = Rc::clone(&default_sink);
+    let metadata_bindings_global = Rc::clone(&metadata_bindings);
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            let Some(props) = global.props else {
+                return;
+            };
+
+            match global.type_ {
+                ObjectType::Node => {
+                    if let Some(name) = props.get("node.name") {
+                        node_ids_global
+                            .borrow_mut()
+                            .insert(name.to_string(), global.id);
+                    }
+                }
+                ObjectType::Metadata => {
+                    if props.get("metadata.name") != Some("default") {
+                        return;
+                    }
+                    let Some(registry) = registry_weak.upgrade() else {
+                        return;
+                    };
+                    let metadata: Metadata = match registry.bind(global) {
+                        Ok(metadata) => metadata,
+                        Err(e) => {
+                            log::warn!("Could not bind the default metadata object: {e}");
+                            return;
+                        }
+                    };
+
+                    let default_sink = Rc::clone(&default_sink_global);
+                    let listener = metadata
+                        .add_listener_local()
+                        .property(move |_, key, _, value| {
+                            if key == Some("default.audio.sink") {
+                                *default_sink.borrow_mut() = value.and_then(parse_metadata_name);
+                            }
+                            0
+                        })
+                        .register();
+                    metadata_bindings_global
+                        .borrow_mut()
+                        .push((metadata, listener));
+                }
+                _ => {}
+            }
+        })
+        .register();
+
+    // First roundtrip collects the globals and binds the metadata,
+    // the second one waits for the metadata to send us its properties
+    do_roundtrip(main_loop, core);
+    do_roundtrip(main_loop, core);
+
+    let sink_name = default_sink.borrow().clone()?;
+    let sink_id = node_ids.borrow().get(&sink_name).copied();
+    if sink_id.is_none() {
+        log::warn!("Default sink {sink_name:?} is not a known node");
+    }
+    sink_id
+}
+
+/// Default nodes are stored as JSON in the metadata, e.g. `{ "name": "alsa_output.pci-0000" }`.
+/// `None` if the name is missing, empty or not a string.
+fn parse_metadata_name(value: &str) -> Option<String> {
+    let (_, rest) = value.split_once("\"name\"")?;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let mut chars = rest.strip_prefix('"')?.chars();
+    let mut name = String::new();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            // Node names don't need the other JSON escapes, only quotes and backslashes
+            '\\' => name.push(chars.next()?),
+            c => name.push(c),
+        }
+    }
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_name_is_parsed() {
+        assert_eq!(
+            parse_metadata_name(r#"{ "name": "alsa_output.pci-0000" }"#).as_deref(),
+            Some("alsa_output.pci-0000")
+        );
+        assert_eq!(
+            parse_metadata_name(r#"{"name":"bluez_output.00_11"}"#).as_deref(),
+            Some("bluez_output.00_11")
+        );
+    }
+
+    #[test]
+    fn missing_or_empty_metadata_name_is_none() {
+        assert_eq!(parse_metadata_name(""), None);
+        assert_eq!(parse_metadata_name("{ }"), None);
+        assert_eq!(parse_metadata_name(r#"{ "name": "" }"#), None);
+        // A name that isn't a string doesn't take the next string in the object
+        assert_eq!(parse_metadata_name(r#"{ "name": null, "id": "42" }"#), None);
+        assert_eq!(parse_metadata_name(r#"{ "name": "unterminated }"#), None);
+    }
+
+    #[test]
+    fn quoted_metadata_name_is_unescaped() {
+        assert_eq!(
+            parse_metadata_name(r#"{ "name": "sink \"a\" \\ b" }"#).as_deref(),
+            Some(r#"sink "a" \ b"#)
+        );
+    }
 }
@@ -0,0 +1,313 @@
+use std::thread::JoinHandle;
+
+use crossbeam::channel::{Receiver, Select, Sender, TrySendError};
+use drm_fourcc::DrmFourcc;
+use pipewire::spa::{param::video::VideoFormat, utils::Rectangle};
+
+use crate::{
+    types::{
+        error::{Result, WaycapError},
+        video_frame::RawVideoFrame,
+    },
+    utils::{drm_fourcc, extract_dmabuf_planes, spawn_named, video_format},
+    waycap_egl::{EglContext, ExportedTexture},
+};
+
+/// Bytes per pixel of the packed RGB formats the software encoder negotiates
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Lay out streams of the given sizes side by side from left to right, top aligned.
+///
+/// Returns the x offset of each stream and the size of the combined frame.
+pub(crate) fn side_by_side(sizes: &[crate::Resolution]) -> (Vec<u32>, (u32, u32)) {
+    let mut offsets = Vec::with_capacity(sizes.len());
+    let mut width = 0;
+    for size in sizes {
+        offsets.push(width);
+        width += size.width;
+    }
+    let height = sizes.iter().map(|size| size.height).max().unwrap_or(0);
+    (offsets, (width, height))
+}
+
+/// Copy the frames of every input into one frame of `size`, each into its slot given as x
+/// offset and width, and send the result to `output` whenever any input has a new frame.
+///
+/// Frames in CPU memory are combined in memory. DMA-BUF frames, which the hardware encoders
+/// negotiate, are combined on the GPU with GL into one of `buffers` exported textures, which
+/// has to be more than the frames `output` and the encoder can hold at once. Which of the two
+/// is used is decided by the first frame, frames of the other kind are dropped after that.
+///
+/// Frames larger than their slot, e.g. after an output changed resolution, are cut off. Exits
+/// once any input or the output disconnects.
+pub(crate) fn composite_frames(
+    inputs: Vec<(Receiver<RawVideoFrame>, (u32, u32))>,
+    size: (u32, u32),
+    buffers: usize,
+    output: Sender<RawVideoFrame>,
+) -> JoinHandle<Result<()>> {
+    spawn_named("waycap-combine", move || -> Result<()> {
+        let mut canvas: Option<Canvas> = None;
+        let mut warned_kind = false;
+
+        let mut select = Select::new();
+        for (input, _) in &inputs {
+            select.recv(input);
+        }
+
+        loop {
+            let operation = select.select();
+            let index = operation.index();
+            let (input, slot) = &inputs[index];
+            let Ok(frame) = operation.recv(input) else {
+                log::info!("Video stream {index} ended, stopping the combined capture");
+                break;
+            };
+
+            if canvas.is_none() {
+                canvas = Some(if frame.dmabuf_fd.is_some() {
+                    log::info!("Combining DMA-BUF frames on the GPU");
+                    Canvas::Gl(GlCanvas::new(size, buffers)?)
+                } else {
+                    Canvas::Memory(MemoryCanvas::new(size))
+                });
+            }
+            let canvas = canvas.as_mut().unwrap();
+            if canvas.is_dmabuf() != frame.dmabuf_fd.is_some() {
+                if !warned_kind {
+                    log::warn!(
+                        "Outputs sent both DMA-BUF and memory frames, only one kind can be \
+                         combined, dropping the others"
+                    );
+                    warned_kind = true;
+                }
+                continue;
+            }
+
+            let combined = match canvas {
+                Canvas::Memory(canvas) => {
+                    canvas.draw(&frame, *slot);
+                    canvas.frame(&frame)
+                }
+                Canvas::Gl(canvas) => {
+                    canvas.draw(&frame, *slot)?;
+                    canvas.frame(&frame)?
+                }
+            };
+            match output.try_send(combined) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Video encoder is falling behind, dropping a combined frame");
+                }
+                Err(TrySendError::Disconnected(_)) => break,
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Where the frames are combined, picked to match the frames the outputs send
+enum Canvas {
+    Memory(MemoryCanvas),
+    Gl(GlCanvas),
+}
+
+impl Canvas {
+    fn is_dmabuf(&self) -> bool {
+        matches!(self, Canvas::Gl(_))
+    }
+}
+
+/// Combined frame in CPU memory, for the software encoder
+struct MemoryCanvas {
+    data: Vec<u8>,
+    stride: usize,
+    size: (u32, u32),
+}
+
+impl MemoryCanvas {
+    fn new(size: (u32, u32)) -> Self {
+        let stride = size.0 as usize * BYTES_PER_PIXEL;
+        Self {
+            data: vec![0u8; stride * size.1 as usize],
+            stride,
+            size,
+        }
+    }
+
+    fn draw(&mut self, frame: &RawVideoFrame, slot: (u32, u32)) {
+        blit(
+            &mut self.data,
+            self.stride,
+            self.size.1 as usize,
+            frame,
+            slot,
+        );
+    }
+
+    /// The combined frame, with the timestamp and format of the newest frame `latest`
+    fn frame(&self, latest: &RawVideoFrame) -> RawVideoFrame {
+        RawVideoFrame {
+            data: self.data.clone(),
+            timestamp: latest.timestamp,
+            dmabuf_fd: None,
+            planes: Vec::new(),
+            stride: self.stride as i32,
+            offset: 0,
+            size: self.data.len() as u32,
+            modifier: 0,
+            format: latest.format,
+            dimensions: Rectangle {
+                width: self.size.0,
+                height: self.size.1,
+            },
+            cursor: None,
+        }
+    }
+}
+
+/// Combined frame on the GPU, for the hardware encoders.
+///
+/// Frames are imported into a GL context of their own and copied into a canvas texture, which
+/// is then copied to the next of several textures exported as DMA-BUFs. The encoder can still
+/// read the previous ones while the next frame is combined.
+struct GlCanvas {
+    egl: EglContext,
+    canvas: u32,
+    size: (i32, i32),
+    targets: Vec<(u32, ExportedTexture)>,
+    next: usize,
+    /// Pixel format the exported textures ended up with
+    format: VideoFormat,
+}
+
+impl GlCanvas {
+    /// Create the textures on a new GL context, which stays current on the calling thread
+    fn new(size: (u32, u32), buffers: usize) -> Result<Self> {
+        let size = (size.0 as i32, size.1 as i32);
+        let egl = EglContext::new(size.0, size.1)?;
+        let canvas = egl.create_texture(size.0, size.1)?;
+        let mut gl_canvas = Self {
+            egl,
+            canvas,
+            size,
+            targets: Vec::with_capacity(buffers),
+            next: 0,
+            format: VideoFormat::RGBA,
+        };
+        gl_canvas.egl.clear_texture(canvas)?;
+
+        for _ in 0..buffers.max(1) {
+            let texture = gl_canvas.egl.create_texture(size.0, size.1)?;
+            match gl_canvas.egl.export_texture(texture) {
+                Ok(exported) => gl_canvas.targets.push((texture, exported)),
+                Err(e) => {
+                    gl_canvas.egl.delete_texture(texture);
+                    return Err(e);
+                }
+            }
+        }
+
+        let fourcc = gl_canvas.targets[0].1.fourcc;
+        gl_canvas.format = DrmFourcc::try_from(fourcc)
+            .ok()
+            .and_then(video_format)
+            .ok_or_else(|| {
+                WaycapError::Init(format!(
+                    "Combined frames were exported with the unsupported DRM format {fourcc:#x}"
+                ))
+            })?;
+        Ok(gl_canvas)
+    }
+
+    fn draw(&mut self, frame: &RawVideoFrame, (x, width): (u32, u32)) -> Result<()> {
+        let fourcc = drm_fourcc(frame.format).ok_or_else(|| {
+            WaycapError::Encoding(format!("Can't combine {:?} DMA-BUFs", frame.format))
+        })?;
+        let planes = extract_dmabuf_planes(frame)?;
+        let image = self.egl.create_image_from_dmabuf(
+            &planes,
+            fourcc as u32,
+            frame.dimensions.width,
+            frame.dimensions.height,
+            frame.modifier,
+        )?;
+        let result = self.egl.copy_image_to_texture(
+            image,
+            self.canvas,
+            (x as i32, 0),
+            Some((width as i32, self.size.1)),
+        );
+        self.egl.destroy_image(image)?;
+        result
+    }
+
+    /// Copy the canvas to the next exported texture and return it as a DMA-BUF frame, with
+    /// the timestamp of the newest frame `latest`
+    fn frame(&mut self, latest: &RawVideoFrame) -> Result<RawVideoFrame> {
+        let (texture, exported) = &self.targets[self.next];
+        self.next = (self.next + 1) % self.targets.len();
+        self.egl
+            .copy_texture(self.canvas, *texture, (0, 0), self.size)?;
+        // The encoders import the buffer without waiting on a fence
+        unsafe { gl::Finish() };
+
+        let first = exported.planes[0];
+        Ok(RawVideoFrame {
+            data: Vec::new(),
+            timestamp: latest.timestamp,
+            dmabuf_fd: Some(first.fd),
+            planes: exported.planes.clone(),
+            stride: first.stride as i32,
+            offset: first.offset,
+            size: first.stride * self.size.1 as u32,
+            modifier: exported.modifier,
+            format: self.format,
+            dimensions: Rectangle {
+                width: self.size.0 as u32,
+                height: self.size.1 as u32,
+            },
+            cursor: None,
+        })
+    }
+}
+
+impl Drop for GlCanvas {
+    fn drop(&mut self) {
+        for (texture, exported) in self.targets.drain(..) {
+            if let Err(e) = self.egl.destroy_image(exported.image) {
+                log::error!("Failed to destroy an exported texture's image: {e}");
+            }
+            self.egl.delete_texture(texture);
+        }
+        self.egl.delete_texture(self.canvas);
+    }
+}
+
+/// Copy `frame` into `canvas` at the slot with left edge `x` and `width`, clipped to it
+fn blit(
+    canvas: &mut [u8],
+    canvas_stride: usize,
+    canvas_height: usize,
+    frame: &RawVideoFrame,
+    (x, width): (u32, u32),
+) {
+    let frame_stride = match frame.stride {
+        stride if stride > 0 => stride as usize,
+        _ => frame.dimensions.width as usize * BYTES_PER_PIXEL,
+    };
+    let start = x as usize * BYTES_PER_PIXEL;
+    let row_len = (frame.dimensions.width.min(width) as usize * BYTES_PER_PIXEL)
+        .min(canvas_stride.saturating_sub(start))
+        .min(frame_stride);
+    let rows = (frame.dimensions.height as usize).min(canvas_height);
+
+    for row in 0..rows {
+        let src = frame.offset as usize + row * frame_stride;
+        let Some(src_row) = frame.data.get(src..src + row_len) else {
+            break;
+        };
+        let dst = row * canvas_stride + start;
+        canvas[dst..dst + row_len].copy_from_slice(src_row);
+    }
+}
@@ -1,4 +1,42 @@
+use std::{cell::Cell, rc::Rc};
+
+use pipewire::{
+    core::{Core, PW_ID_CORE},
+    main_loop::MainLoop,
+};
+
 pub mod audio;
+pub mod composite;
+pub(crate) mod test_pattern;
 pub mod video;
 
 pub struct Terminate {}
+
+/// Block on the main loop until the server has processed all of our pending requests
+pub(crate) fn do_roundtrip(main_loop: &MainLoop, core: &Core) {
+    let done = Rc::new(Cell::new(false));
+    let done_clone = Rc::clone(&done);
+    let loop_clone = main_loop.clone();
+
+    let pending = match core.sync(0) {
+        Ok(seq) => seq,
+        Err(e) => {
+            log::warn!("Could not sync with the PipeWire server: {e}");
+            return;
+        }
+    };
+
+    let _listener = core
+        .add_listener_local()
+        .done(move |id, seq| {
+            if id == PW_ID_CORE && seq == pending {
+                done_clone.set(true);
+                loop_clone.quit();
+            }
+        })
+        .register();
+
+    while !done.get() {
+        main_loop.run();
+    }
+}
@@ -0,0 +1,151 @@
+//! Synthetic video source for testing without a display, PipeWire or portal.
+
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Sender, TrySendError};
+use pipewire::spa::{param::video::VideoFormat, utils::Rectangle};
+
+use crate::{
+    types::{error::Result, video_frame::RawVideoFrame},
+    utils::{monotonic_ns, spawn_named, TIME_UNIT_NS},
+    CaptureControls,
+};
+
+const BYTES_PER_PIXEL: usize = 4;
+
+/// White, yellow, cyan, green, magenta, red, blue and black in BGRx
+const BARS: [[u8; BYTES_PER_PIXEL]; 8] = [
+    [255, 255, 255, 255],
+    [0, 255, 255, 255],
+    [255, 255, 0, 255],
+    [0, 255, 0, 255],
+    [255, 0, 255, 255],
+    [0, 0, 255, 255],
+    [255, 0, 0, 255],
+    [0, 0, 0, 255],
+];
+
+/// Pixels the bars move to the left every frame
+const SCROLL_SPEED: usize = 4;
+
+/// Send color bars of `width` x `height` which move a little every frame to `output`, `fps`
+/// times per second while the capture is running.
+///
+/// Timestamps come from the same monotonic clock as PipeWire's. Exits once the capture is
+/// stopped or the output disconnects.
+pub(crate) fn spawn_test_pattern(
+    width: u32,
+    height: u32,
+    fps: u64,
+    controls: Arc<CaptureControls>,
+    output: Sender<RawVideoFrame>,
+) -> JoinHandle<Result<()>> {
+    spawn_named("waycap-pattern", move || -> Result<()> {
+        let interval = Duration::from_nanos(TIME_UNIT_NS / fps.max(1));
+        let mut next_frame = Instant::now();
+        let mut frame_number = 0;
+
+        while !controls.is_stopped() {
+            if controls.skip_processing() {
+                controls.wait_until_running();
+                next_frame = Instant::now();
+                continue;
+            }
+
+            let data = pattern(width as usize, height as usize, frame_number);
+            let frame = RawVideoFrame {
+                size: data.len() as u32,
+                data,
+                timestamp: controls.without_pauses(monotonic_ns()),
+                dmabuf_fd: None,
+                planes: Vec::new(),
+                stride: (width as usize * BYTES_PER_PIXEL) as i32,
+                offset: 0,
+                modifier: 0,
+                format: VideoFormat::BGRx,
+                dimensions: Rectangle { width, height },
+                cursor: None,
+            };
+            match output.try_send(frame) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Video encoder is falling behind, dropping a test frame");
+                }
+                Err(TrySendError::Disconnected(_)) => break,
+            }
+            frame_number += 1;
+
+            next_frame += interval;
+            match next_frame.checked_duration_since(Instant::now()) {
+                Some(wait) => std::thread::sleep(wait),
+                // Don't try to catch up after falling behind
+                None => next_frame = Instant::now(),
+            }
+        }
+        Ok(())
+    })
+}
+
+/// One BGRx frame of the color bars, moved left by `frame_number` steps
+fn pattern(width: usize, height: usize, frame_number: usize) -> Vec<u8> {
+    let shift = frame_number * SCROLL_SPEED;
+    let row: Vec<u8> = (0..width)
+        .flat_map(|x| BARS[(x + shift) % width * BARS.len() / width])
+        .collect();
+    row.repeat(height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &[u8], width: usize, x: usize, y: usize) -> &[u8] {
+        let start = (y * width + x) * BYTES_PER_PIXEL;
+        &frame[start..start + BYTES_PER_PIXEL]
+    }
+
+    #[test]
+    fn pattern_has_every_bar_in_order() {
+        let (width, height) = (16, 2);
+        let frame = pattern(width, height, 0);
+        assert_eq!(frame.len(), width * height * BYTES_PER_PIXEL);
+        for (i, bar) in BARS.iter().enumerate() {
+            let x = i * width / BARS.len();
+            assert_eq!(pixel(&frame, width, x, 0), bar);
+            assert_eq!(pixel(&frame, width, x + 1, 0), bar);
+        }
+    }
+
+    #[test]
+    fn pattern_rows_are_equal() {
+        let (width, height) = (8, 3);
+        let frame = pattern(width, height, 5);
+        let row_len = width * BYTES_PER_PIXEL;
+        for row in frame.chunks(row_len) {
+            assert_eq!(row, &frame[..row_len]);
+        }
+    }
+
+    #[test]
+    fn pattern_scrolls_left_every_frame() {
+        let (width, height) = (32, 2);
+        let first = pattern(width, height, 0);
+        let second = pattern(width, height, 1);
+        for x in 0..width {
+            assert_eq!(
+                pixel(&second, width, x, 0),
+                pixel(&first, width, (x + SCROLL_SPEED) % width, 0)
+            );
+        }
+    }
+
+    #[test]
+    fn pattern_of_smallest_size() {
+        let frame = pattern(2, 2, 3);
+        assert_eq!(frame.len(), 2 * 2 * BYTES_PER_PIXEL);
+    }
+}
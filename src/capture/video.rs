@@ -1,5 +1,9 @@
 use std::{
-    os::fd::{FromRawFd, OwnedFd, RawFd},
+    cell::RefCell,
+    collections::HashMap,
+    os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    ptr::NonNull,
+    rc::Rc,
     sync::{
         mpsc::{self},
         Arc,
@@ -14,10 +18,12 @@ use pipewire::{
     main_loop::MainLoop,
     spa::{
         buffer::{Data, DataType},
+        param::video::VideoFormat,
         utils::Direction,
     },
-    stream::{Stream, StreamFlags, StreamListener, StreamState},
+    stream::{Stream, StreamFlags, StreamListener, StreamRef, StreamState},
     sys::pw_stream_get_nsec,
+    types::ObjectType,
 };
 use pw::{properties::properties, spa};
 
@@ -26,11 +32,11 @@ use spa::pod::Pod;
 use crate::{
     types::{
         error::{Result, WaycapError},
-        video_frame::RawVideoFrame,
-    }, CaptureControls, ReadyState, Resolution
+        video_frame::{CursorImage, CursorMeta, DmaBufPlane, RawVideoFrame, Transform},
+    }, CaptureControls, ReadyState, Resolution, SourceInfo
 };
 
-use super::Terminate;
+use super::{do_roundtrip, Terminate};
 
 
 
@@ -69,8 +75,11 @@ impl VideoCapture {
         let pw_loop = MainLoop::new(None)?;
         let context = Context::new(&pw_loop)?;
         let mut core = context.connect_fd(unsafe { OwnedFd::from_raw_fd(pipewire_fd) }, None)?;
-        let core_listener = Self::setup_core_listener(&mut core)?;
+        let core_listener = Self::setup_core_listener(&mut core, &controls, pw_loop.clone())?;
+        let properties = Self::read_node_properties(&pw_loop, &core, stream_node);
+        controls.set_source_info(SourceInfo::from_properties(stream_node, properties));
         let mut stream = Self::create_stream(&core)?;
+        let video_params = Self::serialize_params(pw_obj)?;
         let stream_listener = Self::setup_stream_listener(
             &mut stream,
             UserData::default(),
@@ -78,8 +87,11 @@ impl VideoCapture {
             &controls,
             resolution_sender.clone(),
             frame_tx.clone(),
+            pw_loop.clone(),
+            stream_node,
+            video_params.clone(),
         )?;
-        Self::connect_stream(&mut stream, stream_node, pw_obj)?;
+        Self::connect_stream(&stream, stream_node, &video_params)?;
 
         Ok(Self {
             termination_recv: Some(termination_recv),
@@ -109,11 +121,59 @@ impl VideoCapture {
         }
     }
 
-    fn setup_core_listener(core: &mut Core) -> Result<Listener> {
+    /// Every property of the node `stream_node`, empty if it can't be found in the registry
+    fn read_node_properties(
+        pw_loop: &MainLoop,
+        core: &Core,
+        stream_node: u32,
+    ) -> HashMap<String, String> {
+        let registry = match core.get_registry() {
+            Ok(registry) => registry,
+            Err(e) => {
+                log::warn!("Could not get the PipeWire registry: {e}");
+                return HashMap::new();
+            }
+        };
+
+        let properties = Rc::new(RefCell::new(HashMap::new()));
+        let properties_global = Rc::clone(&properties);
+        let _registry_listener = registry
+            .add_listener_local()
+            .global(move |global| {
+                if global.type_ != ObjectType::Node || global.id != stream_node {
+                    return;
+                }
+                if let Some(props) = global.props {
+                    properties_global.borrow_mut().extend(
+                        props
+                            .iter()
+                            .map(|(key, value)| (key.to_string(), value.to_string())),
+                    );
+                }
+            })
+            .register();
+
+        do_roundtrip(pw_loop, core);
+        if properties.borrow().is_empty() {
+            log::debug!("No properties found for video node {stream_node}");
+        }
+        properties.take()
+    }
+
+    fn setup_core_listener(
+        core: &mut Core,
+        controls: &Arc<CaptureControls>,
+        pw_loop: MainLoop,
+    ) -> Result<Listener> {
+        let controls = Arc::clone(controls);
         Ok(core
             .add_listener_local()
             .info(|i| log::debug!("VIDEO CORE:\n{i:#?}"))
-            .error(|e, f, g, h| log::error!("{e},{f},{g},{h}"))
+            .error(move |id, seq, res, message| {
+                log::error!("Video core error on {id} (seq {seq}): {res} {message}");
+                controls.fail(format!("{message} ({res})"));
+                pw_loop.quit();
+            })
             .done(|d, _| log::debug!("DONE: {d}"))
             .register())
     }
@@ -126,20 +186,56 @@ impl VideoCapture {
         controls: &Arc<CaptureControls>,
         resolution_sender: mpsc::Sender<Resolution>,
         frame_tx: Sender<RawVideoFrame>,
+        pw_loop: MainLoop,
+        stream_node: u32,
+        video_params: Vec<u8>,
     ) -> Result<StreamListener<UserData>> {
         let ready_state_clone = Arc::clone(&ready_state);
         let controls_clone = Arc::clone(controls);
+        let controls_state = Arc::clone(controls);
+        let controls_format = Arc::clone(controls);
+        let controls_buffers = Arc::clone(controls);
+        let state_loop = pw_loop.clone();
 
         let stream_listener = stream
             .add_local_listener_with_user_data(data)
-            .state_changed(move |_, _, old, new| {
+            .state_changed(move |stream, _, old, new| {
                 log::info!("Video Stream State Changed: {old:?} -> {new:?}");
-                ready_state.video.store(
-                    new == StreamState::Streaming,
-                    std::sync::atomic::Ordering::Release,
-                );
+                ready_state.set_video(new == StreamState::Streaming);
+                match new {
+                    StreamState::Error(message) => {
+                        controls_state.fail(message);
+                        state_loop.quit();
+                    }
+                    // The compositor dropped the stream while we were recording, e.g. because
+                    // the screen got locked
+                    StreamState::Paused | StreamState::Unconnected
+                        if old == StreamState::Streaming && !controls_state.skip_processing() =>
+                    {
+                        if !controls_state.auto_reconnect() {
+                            controls_state
+                                .fail_stream(format!("Video stream stopped streaming ({new:?})"));
+                            state_loop.quit();
+                            return;
+                        }
+
+                        log::warn!("Video stream stopped streaming ({new:?}), reconnecting");
+                        let reconnected = if new == StreamState::Paused {
+                            stream.set_active(true).map_err(WaycapError::from)
+                        } else {
+                            Self::connect_stream(stream, stream_node, &video_params)
+                        };
+                        if let Err(e) = reconnected {
+                            controls_state.fail_stream(format!(
+                                "Could not reconnect the video stream: {e}"
+                            ));
+                            state_loop.quit();
+                        }
+                    }
+                    _ => {}
+                }
             })
-            .param_changed(move |_, user_data, id, param| {
+            .param_changed(move |stream, user_data, id, param| {
                 let Some(param) = param else {
                     return;
                 };
@@ -160,6 +256,7 @@ impl VideoCapture {
                     return;
                 }
 
+                let previous_size = user_data.video_format.size();
                 user_data
                     .video_format
                     .parse(param)
@@ -170,14 +267,47 @@ impl VideoCapture {
                     user_data.video_format.format().as_raw(),
                     user_data.video_format.format()
                 );
+                controls_format.set_negotiated_format(
+                    user_data.video_format.format(),
+                    user_data.video_format.modifier(),
+                );
+                // Variable rate streams advertise the output's refresh rate as the most they
+                // will send, fixed rate ones only their framerate
+                let framerate = match user_data.video_format.max_framerate() {
+                    max if max.num > 0 && max.denom > 0 => max,
+                    _ => user_data.video_format.framerate(),
+                };
+                if framerate.num > 0 && framerate.denom > 0 {
+                    controls_format.set_source_refresh_rate(
+                        stream_node,
+                        framerate.num as f64 / framerate.denom as f64,
+                    );
+                }
+
+                // Ask for cursor and transform metadata now that the format is known
+                if let Err(e) = Self::request_metas(stream) {
+                    log::warn!("Could not request cursor and transform metadata: {e}");
+                }
 
                 let (width, height) = (
 
                     user_data.video_format.size().width,
                     user_data.video_format.size().height,
                     );
+                // Frames carry their size, so the processing loop picks up a renegotiated
+                // resolution and reconfigures the encoder on its own
+                let renegotiated = previous_size.width != 0 && previous_size.height != 0;
+                if renegotiated && (previous_size.width, previous_size.height) != (width, height) {
+                    log::info!(
+                        "Stream resolution changed from {}x{} to {width}x{height}",
+                        previous_size.width,
+                        previous_size.height
+                    );
+                }
                 match resolution_sender.send(Resolution { width, height }) {
                     Ok(_) => {}
+                    // Only the initial negotiation is waited on
+                    Err(_) if renegotiated => {}
                     Err(e) => {
                         log::error!("Tried to send resolution update {width}x{height} but ran into an error on the channel: {e}");
                     }
@@ -194,49 +324,75 @@ impl VideoCapture {
                     user_data.video_format.framerate().denom
                 );
             })
+            .add_buffer(move |_, _, buffer| {
+                // Buffers are allocated after the format was negotiated, their type tells
+                // whether the compositor shares DMA-BUFs or copies frames into memory
+                let dmabuf = unsafe { Self::first_data_type(buffer) } == Some(DataType::DmaBuf);
+                controls_buffers.set_dmabuf(dmabuf);
+            })
             .process(move |stream, udata| {
-                match stream.dequeue_buffer() {
+                match RawBuffer::dequeue(stream) {
                     None => log::debug!("out of buffers"),
                     Some(mut buffer) => {
+                        // Stopping is final, no need to wait for a Terminate
+                        if controls_clone.is_stopped() {
+                            pw_loop.quit();
+                            return;
+                        }
+                        // Known before the capture starts, so muxers can pick it up
+                        if let Some(transform) = buffer.transform_meta() {
+                            controls_clone.set_transform(transform);
+                        }
                         // Wait until audio is streaming before we try to process
                         if !ready_state_clone.audio_ready() || controls_clone.skip_processing() {
                             return;
                         }
 
+                        let cursor = buffer.cursor_meta();
                         let datas = buffer.datas_mut();
                         if datas.is_empty() {
                             return;
                         }
 
+                        let planes = Self::get_dmabuf_planes(datas);
                         let data = &mut datas[0];
 
                         let fd = Self::get_dmabuf_fd(data);
+                        // DMA-BUF frames are read through the fd, copying the mapped
+                        // memory would only waste CPU time
+                        let frame_data = match fd {
+                            Some(_) => Vec::new(),
+                            None => data.data().unwrap_or_default().to_vec(),
+                        };
 
                         match frame_tx.try_send(RawVideoFrame {
-                            data: data.data().unwrap_or_default().to_vec(),
-                            timestamp: unsafe { pw_stream_get_nsec(stream.as_raw_ptr())} as i64,
+                            data: frame_data,
+                            timestamp: controls_clone.without_pauses(
+                                unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64,
+                            ),
                             dmabuf_fd: fd,
+                            planes,
                             stride: data.chunk().stride(),
                             offset: data.chunk().offset(),
                             size: data.chunk().size(),
                             modifier: udata.video_format.modifier(),
                             format: udata.video_format.format(),
-                            dimensions: udata.video_format.size()
+                            dimensions: udata.video_format.size(),
+                            cursor,
                         }) {
-                            Ok(_) => {}
+                            Ok(_) => controls_clone.counters().frame_captured(),
                             Err(crossbeam::channel::TrySendError::Full(frame)) => {
+                                controls_clone.counters().frame_dropped();
                                 log::error!(
                                     "Could not send video frame at: {}. Channel full.",
                                     frame.timestamp
                                 );
                             }
-                            Err(crossbeam::channel::TrySendError::Disconnected(frame)) => {
-                                // TODO: If we disconnected, terminate the session instead of
-                                // throwing an error it means the receiver was dropped.
-                                log::error!(
-                                    "Could not send video frame at: {}. Connection closed.",
-                                    frame.timestamp
-                                );
+                            Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
+                                // The receiver was dropped, end the whole session
+                                log::info!("Video frame receiver dropped, stopping capture");
+                                controls_clone.stop();
+                                pw_loop.quit();
                             }
                         }
                     }
@@ -247,20 +403,70 @@ impl VideoCapture {
         Ok(stream_listener)
     }
 
-    fn connect_stream(
-        stream: &mut Stream,
-        stream_node: u32,
-        pw_obj: spa::pod::Object,
-    ) -> Result<()> {
-        let video_spa_values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+    /// Ask for the cursor and transform metadata on every buffer
+    fn request_metas(stream: &StreamRef) -> Result<()> {
+        let cursor_size =
+            pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Int(pw::spa::utils::Choice(
+                pw::spa::utils::ChoiceFlags::empty(),
+                pw::spa::utils::ChoiceEnum::Range {
+                    default: cursor_meta_size(64, 64),
+                    min: cursor_meta_size(1, 1),
+                    max: cursor_meta_size(1024, 1024),
+                },
+            )));
+        let transform_size = std::mem::size_of::<spa::sys::spa_meta_videotransform>() as i32;
+        let cursor_values = Self::serialize_meta_param(spa::sys::SPA_META_Cursor, cursor_size)?;
+        let transform_values = Self::serialize_meta_param(
+            spa::sys::SPA_META_VideoTransform,
+            pw::spa::pod::Value::Int(transform_size),
+        )?;
+
+        let mut params = [
+            Pod::from_bytes(&cursor_values)
+                .ok_or_else(|| WaycapError::PipeWire("Invalid meta param pod".into()))?,
+            Pod::from_bytes(&transform_values)
+                .ok_or_else(|| WaycapError::PipeWire("Invalid meta param pod".into()))?,
+        ];
+        stream.update_params(&mut params)?;
+        Ok(())
+    }
+
+    fn serialize_meta_param(meta_type: u32, size: pw::spa::pod::Value) -> Result<Vec<u8>> {
+        let meta_obj = pw::spa::pod::Object {
+            type_: pw::spa::utils::SpaTypes::ObjectParamMeta.as_raw(),
+            id: pw::spa::param::ParamType::Meta.as_raw(),
+            properties: vec![
+                pw::spa::pod::Property::new(
+                    spa::sys::SPA_PARAM_META_type,
+                    pw::spa::pod::Value::Id(pw::spa::utils::Id(meta_type)),
+                ),
+                pw::spa::pod::Property::new(spa::sys::SPA_PARAM_META_size, size),
+            ],
+        };
+        Ok(pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(meta_obj),
+        )
+        .map_err(|e| WaycapError::PipeWire(format!("{e:?}")))?
+        .0
+        .into_inner())
+    }
+
+    fn serialize_params(pw_obj: spa::pod::Object) -> Result<Vec<u8>> {
+        Ok(pw::spa::pod::serialize::PodSerializer::serialize(
             std::io::Cursor::new(Vec::new()),
             &pw::spa::pod::Value::Object(pw_obj),
         )
-        .unwrap()
+        .map_err(|e| WaycapError::PipeWire(format!("{e:?}")))?
         .0
-        .into_inner();
+        .into_inner())
+    }
 
-        let mut video_params = [Pod::from_bytes(&video_spa_values).unwrap()];
+    /// Connect to `stream_node` offering the formats in the serialized `video_spa_values`.
+    /// Also used to bring back a stream the compositor disconnected.
+    fn connect_stream(stream: &StreamRef, stream_node: u32, video_spa_values: &[u8]) -> Result<()> {
+        let mut video_params = [Pod::from_bytes(video_spa_values)
+            .ok_or_else(|| WaycapError::PipeWire("Invalid video format pod".into()))?];
         stream.connect(
             Direction::Input,
             Some(stream_node),
@@ -286,6 +492,18 @@ impl VideoCapture {
         Ok(())
     }
 
+    /// Type of the first data of a buffer, `None` for a buffer without any.
+    ///
+    /// # Safety
+    /// `buffer` must be null or point to a valid PipeWire buffer
+    unsafe fn first_data_type(buffer: *mut pw::sys::pw_buffer) -> Option<DataType> {
+        let spa_buffer = buffer.as_ref()?.buffer;
+        if spa_buffer.is_null() || (*spa_buffer).n_datas == 0 || (*spa_buffer).datas.is_null() {
+            return None;
+        }
+        Some(DataType::from_raw((*(*spa_buffer).datas).type_))
+    }
+
     fn get_dmabuf_fd(data: &Data) -> Option<RawFd> {
         let raw_data = data.as_raw();
 
@@ -299,4 +517,194 @@ impl VideoCapture {
 
         None
     }
+
+    /// One plane per buffer data, stops at the first one which isn't a DMA-BUF
+    fn get_dmabuf_planes(datas: &[Data]) -> Vec<DmaBufPlane> {
+        datas
+            .iter()
+            .map_while(|data| {
+                Self::get_dmabuf_fd(data).map(|fd| DmaBufPlane {
+                    fd,
+                    offset: data.chunk().offset(),
+                    stride: data.chunk().stride() as u32,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Find which of the portal's `stream_nodes` belongs to the output (connector) called `name`.
+///
+/// The portal does not report connector names, so this looks at the PipeWire properties the
+/// compositor put on each stream node and picks the first one advertising `name`.
+pub(crate) fn find_output_node(
+    pipewire_fd: RawFd,
+    stream_nodes: &[u32],
+    name: &str,
+) -> Result<Option<u32>> {
+    // The fd is still needed for the capture itself, so connect with a copy of it
+    let fd = unsafe { BorrowedFd::borrow_raw(pipewire_fd) }.try_clone_to_owned()?;
+    let pw_loop = MainLoop::new(None)?;
+    let context = Context::new(&pw_loop)?;
+    let core = context.connect_fd(fd, None)?;
+    let registry = core.get_registry()?;
+
+    let found: Rc<RefCell<Option<u32>>> = Rc::new(RefCell::new(None));
+    let found_global = Rc::clone(&found);
+    let stream_nodes = stream_nodes.to_vec();
+    let name = name.to_string();
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            if global.type_ != ObjectType::Node || !stream_nodes.contains(&global.id) {
+                return;
+            }
+            let Some(props) = global.props else {
+                return;
+            };
+
+            let matches = ["node.name", "node.nick", "node.description", "media.name"]
+                .iter()
+                .any(|key| props.get(key) == Some(name.as_str()));
+            if matches && found_global.borrow().is_none() {
+                *found_global.borrow_mut() = Some(global.id);
+            }
+        })
+        .register();
+
+    do_roundtrip(&pw_loop, &core);
+
+    let node = *found.borrow();
+    Ok(node)
+}
+
+/// Size of a cursor meta holding a bitmap of up to `width`x`height` RGBA pixels
+fn cursor_meta_size(width: usize, height: usize) -> i32 {
+    (std::mem::size_of::<spa::sys::spa_meta_cursor>()
+        + std::mem::size_of::<spa::sys::spa_meta_bitmap>()
+        + width * height * 4) as i32
+}
+
+/// A dequeued buffer which is queued back to the stream when dropped.
+///
+/// Same as [`pw::buffer::Buffer`] but also gives access to the buffer metadata.
+struct RawBuffer<'s> {
+    buffer: NonNull<pw::sys::pw_buffer>,
+    stream: &'s StreamRef,
+}
+
+impl<'s> RawBuffer<'s> {
+    fn dequeue(stream: &'s StreamRef) -> Option<Self> {
+        NonNull::new(unsafe { stream.dequeue_raw_buffer() }).map(|buffer| Self { buffer, stream })
+    }
+
+    fn datas_mut(&mut self) -> &mut [Data] {
+        unsafe {
+            let spa_buffer = self.buffer.as_ref().buffer;
+            if spa_buffer.is_null() || (*spa_buffer).n_datas == 0 || (*spa_buffer).datas.is_null() {
+                return &mut [];
+            }
+            std::slice::from_raw_parts_mut(
+                (*spa_buffer).datas as *mut Data,
+                (*spa_buffer).n_datas as usize,
+            )
+        }
+    }
+
+    fn transform_meta(&self) -> Option<Transform> {
+        unsafe {
+            let spa_buffer = self.buffer.as_ref().buffer;
+            if spa_buffer.is_null() || (*spa_buffer).n_metas == 0 || (*spa_buffer).metas.is_null() {
+                return None;
+            }
+            let metas =
+                std::slice::from_raw_parts((*spa_buffer).metas, (*spa_buffer).n_metas as usize);
+            let meta = metas.iter().find(|meta| {
+                meta.type_ == spa::sys::SPA_META_VideoTransform
+                    && meta.size as usize
+                        >= std::mem::size_of::<spa::sys::spa_meta_videotransform>()
+            })?;
+
+            let transform = &*(meta.data as *const spa::sys::spa_meta_videotransform);
+            Some(Transform::from_raw(transform.transform))
+        }
+    }
+
+    fn cursor_meta(&self) -> Option<CursorMeta> {
+        unsafe {
+            let spa_buffer = self.buffer.as_ref().buffer;
+            if spa_buffer.is_null() || (*spa_buffer).n_metas == 0 || (*spa_buffer).metas.is_null() {
+                return None;
+            }
+            let metas =
+                std::slice::from_raw_parts((*spa_buffer).metas, (*spa_buffer).n_metas as usize);
+            let meta = metas.iter().find(|meta| {
+                meta.type_ == spa::sys::SPA_META_Cursor
+                    && meta.size as usize >= std::mem::size_of::<spa::sys::spa_meta_cursor>()
+            })?;
+
+            let cursor = &*(meta.data as *const spa::sys::spa_meta_cursor);
+            // An id of 0 means there is no valid cursor data for this frame
+            if cursor.id == 0 {
+                return None;
+            }
+            Some(CursorMeta {
+                position: (cursor.position.x, cursor.position.y),
+                hotspot: (cursor.hotspot.x, cursor.hotspot.y),
+                serial: cursor.id,
+                image: Self::cursor_image(meta, cursor),
+            })
+        }
+    }
+
+    /// Copy out the bitmap following the cursor meta, if the compositor sent a new one
+    unsafe fn cursor_image(
+        meta: &spa::sys::spa_meta,
+        cursor: &spa::sys::spa_meta_cursor,
+    ) -> Option<CursorImage> {
+        let bitmap_offset = cursor.bitmap_offset as usize;
+        if bitmap_offset < std::mem::size_of::<spa::sys::spa_meta_cursor>()
+            || bitmap_offset + std::mem::size_of::<spa::sys::spa_meta_bitmap>() > meta.size as usize
+        {
+            return None;
+        }
+
+        let bitmap_ptr = (meta.data as *const u8).add(bitmap_offset);
+        let bitmap = &*(bitmap_ptr as *const spa::sys::spa_meta_bitmap);
+        // An unknown format means the image didn't change since the last one
+        if bitmap.format == spa::sys::SPA_VIDEO_FORMAT_UNKNOWN
+            || bitmap.size.width == 0
+            || bitmap.size.height == 0
+        {
+            return None;
+        }
+
+        let row_len = bitmap.size.width as usize * 4;
+        let stride = (bitmap.stride as usize).max(row_len);
+        let pixels_start = bitmap_offset + bitmap.offset as usize;
+        let pixels_len = stride * (bitmap.size.height as usize - 1) + row_len;
+        if pixels_start + pixels_len > meta.size as usize {
+            log::warn!("Cursor bitmap does not fit in its metadata, skipping it");
+            return None;
+        }
+
+        let pixels = std::slice::from_raw_parts(bitmap_ptr.add(bitmap.offset as usize), pixels_len);
+        let data = pixels
+            .chunks(stride)
+            .flat_map(|row| &row[..row_len])
+            .copied()
+            .collect();
+        Some(CursorImage {
+            width: bitmap.size.width,
+            height: bitmap.size.height,
+            format: VideoFormat::from_raw(bitmap.format),
+            data,
+        })
+    }
+}
+
+impl Drop for RawBuffer<'_> {
+    fn drop(&mut self) {
+        unsafe { self.stream.queue_raw_buffer(self.buffer.as_ptr()) };
+    }
 }
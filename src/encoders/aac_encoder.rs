@@ -0,0 +1,176 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use ffmpeg_next::{self as ffmpeg, Rational};
+use std::collections::VecDeque;
+
+use crate::types::{
+    audio_frame::EncodedAudioFrame, config::AudioEncoderConfig, error::WaycapError,
+};
+
+use super::audio::{apply_gain, match_channels, AudioEncoder};
+
+pub struct AacEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Audio>,
+    next_pts: i64,
+    leftover_data: VecDeque<f32>,
+    encoded_samples_recv: Option<Receiver<EncodedAudioFrame>>,
+    encoded_samples_sender: Sender<EncodedAudioFrame>,
+    capture_timestamps: VecDeque<i64>,
+    config: AudioEncoderConfig,
+}
+
+impl AacEncoder {
+    fn create_encoder(
+        config: &AudioEncoderConfig,
+    ) -> crate::types::error::Result<ffmpeg::codec::encoder::Audio> {
+        let encoder_codec = ffmpeg::codec::encoder::find(ffmpeg_next::codec::Id::AAC)
+            .ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .audio()?;
+
+        encoder_ctx.set_rate(config.sample_rate as i32);
+        encoder_ctx.set_bit_rate(config.bitrate.unwrap_or(160_000) as usize);
+        // The native AAC encoder only accepts planar samples
+        encoder_ctx.set_format(ffmpeg::format::Sample::F32(
+            ffmpeg_next::format::sample::Type::Planar,
+        ));
+        encoder_ctx.set_time_base(Rational::new(1, config.sample_rate as i32));
+        encoder_ctx.set_frame_rate(Some(Rational::new(1, config.sample_rate as i32)));
+        encoder_ctx.set_channel_layout(config.channel_layout);
+
+        Ok(encoder_ctx.open()?)
+    }
+}
+
+impl AudioEncoder for AacEncoder {
+    fn new(config: AudioEncoderConfig) -> crate::types::error::Result<Self>
+    where
+        Self: Sized,
+    {
+        let encoder = Self::create_encoder(&config)?;
+        let (frame_tx, frame_rx): (Sender<EncodedAudioFrame>, Receiver<EncodedAudioFrame>) =
+            bounded(config.buffer_frames);
+        Ok(Self {
+            encoder: Some(encoder),
+            next_pts: 0,
+            leftover_data: VecDeque::with_capacity(10),
+            encoded_samples_recv: Some(frame_rx),
+            encoded_samples_sender: frame_tx,
+            capture_timestamps: VecDeque::with_capacity(10),
+            config,
+        })
+    }
+
+    fn process(
+        &mut self,
+        mut raw_frame: crate::types::audio_frame::RawAudioFrame,
+    ) -> crate::types::error::Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            let n_channels = encoder.channels() as usize;
+            // Input is always stereo, the encoder may be mono
+            raw_frame.samples = match_channels(raw_frame.samples, n_channels)?;
+
+            // AAC frames are always 1024 samples per channel
+            let frame_size = encoder.frame_size() as usize;
+            let chunk_size = frame_size * n_channels;
+
+            // By default boost the audio so that even if system audio level is low
+            // it's still audible in playback
+            apply_gain(&mut raw_frame.samples, self.config.gain)?;
+            self.leftover_data.extend(raw_frame.samples);
+
+            // Send chunked frames to encoder
+            while self.leftover_data.len() >= chunk_size {
+                let frame_samples: Vec<f32> = self.leftover_data.drain(..chunk_size).collect();
+                let mut frame = ffmpeg::frame::Audio::new(
+                    encoder.format(),
+                    frame_size,
+                    encoder.channel_layout(),
+                );
+
+                // PipeWire gives us interleaved samples, split them into one plane per channel
+                for channel in 0..n_channels {
+                    let plane = frame.plane_mut::<f32>(channel);
+                    for (i, sample) in plane.iter_mut().enumerate() {
+                        *sample = frame_samples[i * n_channels + channel];
+                    }
+                }
+                frame.set_pts(Some(self.next_pts));
+                frame.set_rate(encoder.rate());
+
+                self.capture_timestamps.push_back(raw_frame.timestamp_ns);
+                encoder.send_frame(&frame)?;
+
+                // Try and get a frame back from encoder
+                let mut packet = ffmpeg::codec::packet::Packet::empty();
+                if encoder.receive_packet(&mut packet).is_ok() {
+                    if let Some(data) = packet.data() {
+                        let pts = packet.pts().unwrap_or(0);
+                        match self.encoded_samples_sender.try_send(EncodedAudioFrame::new(
+                            data.to_vec(),
+                            pts,
+                            self.capture_timestamps.pop_front().unwrap_or(0),
+                        )) {
+                            Ok(_) => {}
+                            Err(crossbeam::channel::TrySendError::Full(_)) => {
+                                log::error!("Could not send encoded audio frame. Receiver is full");
+                            }
+                            Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
+                                return Err(WaycapError::Disconnected);
+                            }
+                        }
+                    }
+                }
+
+                self.next_pts += frame_size as i64;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg_next::codec::encoder::Audio> {
+        &self.encoder
+    }
+
+    fn drain(&mut self) -> crate::types::error::Result<()> {
+        self.drain_collecting()?; // Discard frames
+        Ok(())
+    }
+
+    fn drain_collecting(&mut self) -> crate::types::error::Result<Vec<EncodedAudioFrame>> {
+        let mut frames = Vec::new();
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.send_eof()?;
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {
+                if let Some(data) = packet.data() {
+                    frames.push(EncodedAudioFrame::new(
+                        data.to_vec(),
+                        packet.pts().unwrap_or(0),
+                        self.capture_timestamps.pop_front().unwrap_or(0),
+                    ));
+                }
+            }
+        }
+
+        Ok(frames)
+    }
+
+    fn drop_encoder(&mut self) {
+        self.encoder.take();
+    }
+
+    fn reset(&mut self) -> crate::types::error::Result<()> {
+        self.drop_encoder();
+        self.capture_timestamps.clear();
+        self.encoder = Some(Self::create_encoder(&self.config)?);
+
+        Ok(())
+    }
+
+    fn get_encoded_recv(&mut self) -> Option<Receiver<EncodedAudioFrame>> {
+        self.encoded_samples_recv.clone()
+    }
+}
@@ -0,0 +1,484 @@
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::Arc;
+
+use crate::{
+    encoders::{
+        vaapi_encoder::drm_prime_frame,
+        video::{drain_filter_graph, EncodedOutput, PipewireSPA, ProcessingThread, VideoEncoder},
+    },
+    types::{
+        config::{QualityPreset, RateControl, VideoEncoderConfig},
+        error::{Result, WaycapError},
+        stats::FrameCounters,
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+    utils::TIME_UNIT_NS,
+};
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{
+        av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_create, av_hwdevice_find_type_by_name,
+        av_hwframe_ctx_init, AVBufferRef, AVHWDeviceContext, AVHWDeviceType, AVHWFramesContext,
+        AVPixelFormat,
+    },
+    Rational,
+};
+use pipewire as pw;
+
+use super::video::{create_hw_device, create_hw_frame_ctx, with_drm_device};
+
+/// Encoder which encodes frames using AMD's Advanced Media Framework
+///
+/// Needs the AMF runtime (`libamfrt64`) of AMD's proprietary driver, which sometimes gives
+/// better quality or latency than [`crate::VaapiEncoder`] on the same GPU. Frames are imported
+/// and scaled through VAAPI like [`crate::VaapiEncoder`] does. AMF on Linux can't import VAAPI
+/// surfaces, so they are downloaded as NV12 and uploaded to the AMF device by the encoder.
+pub struct AmfEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Video>,
+    width: u32,
+    height: u32,
+    encoder_name: String,
+    config: VideoEncoderConfig,
+    output: EncodedOutput,
+    filter_graph: Option<ffmpeg::filter::Graph>,
+    /// Capture timestamp of the first frame, for zero based PTS
+    first_timestamp: Option<i64>,
+    /// VAAPI frames DMA-BUF frames are imported into
+    vaapi_frames_ctx: *mut AVBufferRef,
+}
+
+unsafe impl Send for AmfEncoder {}
+unsafe impl Sync for AmfEncoder {}
+
+impl ProcessingThread for AmfEncoder {
+    fn request_keyframe(&mut self) {
+        self.output.force_keyframe = true;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.reset()
+    }
+
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        self.output.counters = counters;
+    }
+
+    fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        if frame.dmabuf_fd.is_none() {
+            return Err(WaycapError::Encoding(
+                "AMF can only encode DMA-BUF frames, got a frame in memory".to_string(),
+            ));
+        }
+        if let Some(ref mut encoder) = self.encoder {
+            let mut drm_frame = drm_prime_frame(&frame, self.vaapi_frames_ctx)?;
+            drm_frame.set_pts(Some(
+                self.config
+                    .frame_pts(&mut self.first_timestamp, frame.timestamp),
+            ));
+            self.output.encode_filtered(
+                self.filter_graph.as_mut().unwrap(),
+                encoder,
+                &drm_frame,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VideoEncoder for AmfEncoder {
+    type Output = EncodedVideoFrame;
+    fn reset(&mut self) -> Result<()> {
+        self.drop_processor();
+        let (new_encoder, vaapi_frames_ctx) = Self::create_encoder(
+            self.width,
+            self.height,
+            &self.encoder_name,
+            &mut self.config,
+        )?;
+
+        let new_filter_graph =
+            Self::create_filter_graph(vaapi_frames_ctx, self.width, self.height, &self.config)?;
+
+        self.encoder = Some(new_encoder);
+        self.filter_graph = Some(new_filter_graph);
+        self.vaapi_frames_ctx = vaapi_frames_ctx;
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
+    fn drop_processor(&mut self) {
+        self.encoder.take();
+        self.filter_graph.take();
+        if !self.vaapi_frames_ctx.is_null() {
+            unsafe { av_buffer_unref(&mut self.vaapi_frames_ctx) };
+        }
+    }
+
+    fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
+        Some(self.output.receiver())
+    }
+
+    fn close_output(&mut self) {
+        self.output.close();
+    }
+
+    /// Drain the filter graph and encoder of any remaining frames it is processing
+    fn drain(&mut self) -> Result<()> {
+        self.drain_filter_graph()?;
+        self.output.discard(self.encoder.as_mut())
+    }
+
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedVideoFrame>> {
+        self.drain_filter_graph()?;
+        self.output.drain_frames(self.encoder.as_mut())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.encoder
+    }
+}
+
+impl PipewireSPA for AmfEncoder {
+    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        Ok(pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::Raw
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoModifier,
+                Long,
+                0
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
+                Id,
+                pw::spa::param::video::VideoFormat::NV12,
+                pw::spa::param::video::VideoFormat::I420,
+                pw::spa::param::video::VideoFormat::BGRA,
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoSize,
+                Choice,
+                Range,
+                Rectangle,
+                pw::spa::utils::Rectangle {
+                    width: 2560,
+                    height: 1440
+                }, // Default
+                pw::spa::utils::Rectangle {
+                    width: 1,
+                    height: 1
+                }, // Min
+                pw::spa::utils::Rectangle {
+                    width: 4096,
+                    height: 4096
+                } // Max
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFramerate,
+                Choice,
+                Range,
+                Fraction,
+                pw::spa::utils::Fraction { num: 240, denom: 1 }, // Default
+                pw::spa::utils::Fraction { num: 0, denom: 1 },   // Min
+                pw::spa::utils::Fraction { num: 244, denom: 1 }  // Max
+            ),
+        ))
+    }
+}
+
+impl AmfEncoder {
+    /// Create a new AMF encoder using the given ffmpeg encoder name (e.g. `h264_amf`).
+    ///
+    /// Errors if ffmpeg was built without AMF or the AMF runtime isn't installed.
+    pub(crate) fn new(
+        encoder_name: &str,
+        width: u32,
+        height: u32,
+        mut config: VideoEncoderConfig,
+    ) -> Result<Self> {
+        let (encoder, vaapi_frames_ctx) =
+            Self::create_encoder(width, height, encoder_name, &mut config)?;
+
+        let filter_graph = Some(Self::create_filter_graph(
+            vaapi_frames_ctx,
+            width,
+            height,
+            &config,
+        )?);
+
+        Ok(Self {
+            encoder: Some(encoder),
+            width,
+            height,
+            encoder_name: encoder_name.to_string(),
+            output: EncodedOutput::new(config.buffer_frames, config.bitstream_format),
+            config,
+            filter_graph,
+            first_timestamp: None,
+            vaapi_frames_ctx,
+        })
+    }
+
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.config.quality = quality;
+        self.reset()
+    }
+
+    pub(crate) fn rotate_segment(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.output.segment_pending = true;
+        self.reset()
+    }
+
+    /// Send the frames still in the filter graph to the encoder
+    fn drain_filter_graph(&mut self) -> Result<()> {
+        if let (Some(encoder), Some(graph)) = (self.encoder.as_mut(), self.filter_graph.as_mut()) {
+            drain_filter_graph(graph, encoder)?;
+        }
+        Ok(())
+    }
+
+    /// Create the encoder on the configured DRM device, or the first one it works on which
+    /// is then kept in `config` for later resets
+    fn create_encoder(
+        width: u32,
+        height: u32,
+        encoder: &str,
+        config: &mut VideoEncoderConfig,
+    ) -> Result<(ffmpeg::codec::encoder::Video, *mut AVBufferRef)> {
+        let (encoder, drm_device) = with_drm_device(config.drm_device.as_deref(), |device| {
+            Self::open_encoder(width, height, encoder, config, device)
+        })?;
+        config.drm_device = Some(drm_device);
+        Ok(encoder)
+    }
+
+    fn open_encoder(
+        width: u32,
+        height: u32,
+        encoder: &str,
+        config: &VideoEncoderConfig,
+        drm_device: &Path,
+    ) -> Result<(ffmpeg::codec::encoder::Video, *mut AVBufferRef)> {
+        let (width, height) = config.output_size(width, height);
+        let encoder_codec =
+            ffmpeg::codec::encoder::find_by_name(encoder).ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .video()?;
+
+        encoder_ctx.set_width(width);
+        encoder_ctx.set_height(height);
+        encoder_ctx.set_format(ffmpeg::format::Pixel::NV12);
+        if let Some((bitrate, max_bitrate)) = config.rate_control().bitrates() {
+            encoder_ctx.set_bit_rate(bitrate as usize);
+            encoder_ctx.set_max_bit_rate(max_bitrate as usize);
+        }
+        encoder_ctx.set_color_range(config.color_range.ffmpeg_range());
+        encoder_ctx.set_colorspace(config.color_space.ffmpeg_space());
+
+        // DMA-BUFs are imported and scaled through VAAPI, only encoding runs on AMF
+        let mut vaapi_device =
+            create_hw_device(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI, drm_device)?;
+        let mut vaapi_frames_ctx = create_hw_frame_ctx(vaapi_device)?;
+        let mut amf_device = match create_amf_device() {
+            Ok(device) => device,
+            Err(e) => {
+                unsafe {
+                    av_buffer_unref(&mut vaapi_frames_ctx);
+                    av_buffer_unref(&mut vaapi_device);
+                }
+                return Err(e);
+            }
+        };
+
+        unsafe {
+            let hw_frame_context = &mut *((*vaapi_frames_ctx).data as *mut AVHWFramesContext);
+            hw_frame_context.width = width as i32;
+            hw_frame_context.height = height as i32;
+            hw_frame_context.sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+            hw_frame_context.format = AVPixelFormat::AV_PIX_FMT_VAAPI;
+            hw_frame_context.device_ref = av_buffer_ref(vaapi_device);
+            hw_frame_context.device_ctx = (*vaapi_device).data as *mut AVHWDeviceContext;
+            hw_frame_context.initial_pool_size = 2;
+
+            let err = av_hwframe_ctx_init(vaapi_frames_ctx);
+            if err < 0 {
+                av_buffer_unref(&mut amf_device);
+                av_buffer_unref(&mut vaapi_frames_ctx);
+                av_buffer_unref(&mut vaapi_device);
+                return Err(WaycapError::Init(format!(
+                    "Error trying to initialize hw frame context: {err:?}",
+                )));
+            }
+
+            // The encoder uploads the downloaded NV12 frames to this device itself
+            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = av_buffer_ref(amf_device);
+
+            av_buffer_unref(&mut amf_device);
+            av_buffer_unref(&mut vaapi_device);
+        }
+
+        encoder_ctx.set_time_base(Rational::new(1, TIME_UNIT_NS as i32));
+
+        // Needed to insert I-Frames more frequently so we don't lose full seconds
+        // when popping frames from the front
+        encoder_ctx.set_gop(config.gop_size);
+
+        let encoder_params = ffmpeg::codec::Parameters::new();
+
+        let opts = Self::get_encoder_params(config);
+
+        encoder_ctx.set_parameters(encoder_params)?;
+        let encoder = match encoder_ctx.open_with(opts) {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                unsafe { av_buffer_unref(&mut vaapi_frames_ctx) };
+                return Err(e.into());
+            }
+        };
+        Ok((encoder, vaapi_frames_ctx))
+    }
+
+    fn get_encoder_params(config: &VideoEncoderConfig) -> ffmpeg::Dictionary<'_> {
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("vsync", "vfr");
+
+        let quality = match config.quality {
+            QualityPreset::Low => "speed",
+            QualityPreset::Medium => "balanced",
+            QualityPreset::High | QualityPreset::Ultra => "quality",
+        };
+        opts.set("quality", quality);
+
+        // The bitrates themselves are set on the encoder context
+        match config.rate_control() {
+            RateControl::ConstantQuality(qp) => {
+                opts.set("rc", "cqp");
+                opts.set("qp_i", &qp.to_string());
+                opts.set("qp_p", &qp.to_string());
+            }
+            RateControl::ConstantBitrate(_) => opts.set("rc", "cbr"),
+            RateControl::VariableBitrate { .. } => opts.set("rc", "vbr_peak"),
+        }
+        opts
+    }
+
+    fn create_filter_graph(
+        vaapi_frames_ctx: *mut AVBufferRef,
+        width: u32,
+        height: u32,
+        config: &VideoEncoderConfig,
+    ) -> Result<ffmpeg::filter::Graph> {
+        let mut graph = ffmpeg::filter::Graph::new();
+
+        let args = format!("video_size={width}x{height}:pix_fmt=bgra:time_base=1/{TIME_UNIT_NS}",);
+
+        let mut input = graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &args)?;
+
+        let mut vaapi_map = graph.add(
+            &ffmpeg::filter::find("hwmap").unwrap(),
+            "vaapi_map",
+            "mode=read+write:derive_device=vaapi",
+        )?;
+
+        // Cropping hardware frames only sets the crop fields, scale_vaapi then reads just
+        // that part of the surface
+        let (out_width, out_height) = config.output_size(width, height);
+        let (crop_width, crop_height) = config.crop_size(width, height);
+        let (x, y) = config.region.map_or((0, 0), |region| (region.x, region.y));
+        let crop_args = format!("w={crop_width}:h={crop_height}:x={x}:y={y}");
+        let mut crop = graph.add(&ffmpeg::filter::find("crop").unwrap(), "crop", &crop_args)?;
+
+        let scale_args = format!(
+            "w={out_width}:h={out_height}:format=nv12:out_range={}:out_color_matrix={}",
+            config.color_range.filter_name(),
+            config.color_space.vaapi_name()
+        );
+        let mut scale = graph.add(
+            &ffmpeg::filter::find("scale_vaapi").unwrap(),
+            "scale",
+            &scale_args,
+        )?;
+
+        let mut download =
+            graph.add(&ffmpeg::filter::find("hwdownload").unwrap(), "download", "")?;
+
+        let mut format = graph.add(
+            &ffmpeg::filter::find("format").unwrap(),
+            "format",
+            "pix_fmts=nv12",
+        )?;
+
+        let mut out = graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+        unsafe {
+            let vaapi_frames = (*vaapi_frames_ctx).data as *mut AVHWFramesContext;
+            (*vaapi_map.as_mut_ptr()).hw_device_ctx = av_buffer_ref((*vaapi_frames).device_ref);
+        }
+
+        input.link(0, &mut vaapi_map, 0);
+        vaapi_map.link(0, &mut crop, 0);
+        crop.link(0, &mut scale, 0);
+        scale.link(0, &mut download, 0);
+        download.link(0, &mut format, 0);
+        format.link(0, &mut out, 0);
+
+        graph.validate()?;
+        log::trace!("AMF Graph\n{}", graph.dump());
+
+        Ok(graph)
+    }
+}
+
+impl Drop for AmfEncoder {
+    fn drop(&mut self) {
+        if let Err(e) = self.drain() {
+            log::error!("Error while draining amf encoder during drop: {e:?}");
+        }
+        self.drop_processor();
+    }
+}
+
+/// Open the AMF device. Fails when ffmpeg was built without AMF or its runtime isn't installed
+pub(crate) fn create_amf_device() -> Result<*mut AVBufferRef> {
+    unsafe {
+        let device_type = av_hwdevice_find_type_by_name(c"amf".as_ptr());
+        if device_type == AVHWDeviceType::AV_HWDEVICE_TYPE_NONE {
+            return Err(WaycapError::Init(
+                "ffmpeg was built without AMF support".to_string(),
+            ));
+        }
+        let mut device: *mut AVBufferRef = null_mut();
+        let err = av_hwdevice_ctx_create(&mut device, device_type, null_mut(), null_mut(), 0);
+        if err < 0 {
+            return Err(WaycapError::Init(format!(
+                "Could not open AMF, is AMD's proprietary AMF runtime (libamfrt64) installed? \
+                 Error code {err}"
+            )));
+        }
+        Ok(device)
+    }
+}
@@ -2,26 +2,82 @@ use crossbeam::channel::Receiver;
 
 use crate::types::{
     audio_frame::{EncodedAudioFrame, RawAudioFrame},
+    config::{AudioEncoderConfig, AudioGain},
     error::Result,
 };
 
 const MIN_RMS: f32 = 0.01;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+/// Channels audio is captured with, encoders may downmix to fewer
+pub const CAPTURE_CHANNELS: usize = 2;
+/// Sample rates libopus can encode at
+pub const OPUS_SAMPLE_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
 
 pub trait AudioEncoder: Send {
-    fn new() -> Result<Self>
+    fn new(config: AudioEncoderConfig) -> Result<Self>
     where
         Self: Sized;
     fn process(&mut self, raw_frame: RawAudioFrame) -> Result<()>;
     fn drain(&mut self) -> Result<()>;
+    /// Like [`AudioEncoder::drain`], but return the frames still in the encoder instead of
+    /// discarding them. Encoders which can't hand them back return none
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedAudioFrame>> {
+        self.drain()?;
+        Ok(Vec::new())
+    }
     fn reset(&mut self) -> Result<()>;
     fn get_encoder(&self) -> &Option<ffmpeg_next::codec::encoder::Audio>;
     fn get_encoded_recv(&mut self) -> Option<Receiver<EncodedAudioFrame>>;
     fn drop_encoder(&mut self);
 }
 
-pub fn boost_with_rms(samples: &mut [f32]) -> Result<()> {
+/// Average interleaved stereo samples into a single channel
+pub fn downmix_to_mono(samples: &[f32]) -> Vec<f32> {
+    samples
+        .chunks_exact(2)
+        .map(|pair| (pair[0] + pair[1]) * 0.5)
+        .collect()
+}
+
+/// Bring captured samples to the channel count of the encoder. Errors if `samples` isn't
+/// made of whole frames of [`CAPTURE_CHANNELS`] samples.
+pub fn match_channels(samples: Vec<f32>, n_channels: usize) -> Result<Vec<f32>> {
+    if !samples.len().is_multiple_of(CAPTURE_CHANNELS) {
+        return Err(crate::types::error::WaycapError::FFmpeg(
+            ffmpeg_next::Error::InvalidData,
+        ));
+    }
+    Ok(match n_channels {
+        1 => downmix_to_mono(&samples),
+        _ => samples,
+    })
+}
+
+/// Root mean square of the samples, 0 for an empty slice
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
     let sum_sqrs = samples.iter().map(|&s| s * s).sum::<f32>();
-    let rms = (sum_sqrs / samples.len() as f32).sqrt();
+    (sum_sqrs / samples.len() as f32).sqrt()
+}
+
+/// Apply `gain` to the samples in place
+pub fn apply_gain(samples: &mut [f32], gain: AudioGain) -> Result<()> {
+    match gain {
+        AudioGain::None => Ok(()),
+        AudioGain::Rms => boost_with_rms(samples),
+        AudioGain::Fixed(factor) => {
+            for sample in samples.iter_mut() {
+                *sample *= factor;
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn boost_with_rms(samples: &mut [f32]) -> Result<()> {
+    let rms = rms(samples);
 
     let gain = if rms > 0.0 && rms < MIN_RMS {
         MIN_RMS / rms
@@ -35,3 +91,27 @@ pub fn boost_with_rms(samples: &mut [f32]) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downmix_averages_each_stereo_pair() {
+        assert_eq!(downmix_to_mono(&[1.0, 0.0, -0.5, -0.5]), [0.5, -0.5]);
+        assert!(downmix_to_mono(&[]).is_empty());
+    }
+
+    #[test]
+    fn downmix_drops_a_trailing_half_frame() {
+        assert_eq!(downmix_to_mono(&[1.0, 0.0, 0.25]), [0.5]);
+        assert!(downmix_to_mono(&[0.25]).is_empty());
+    }
+
+    #[test]
+    fn match_channels_rejects_odd_sample_counts() {
+        assert!(match_channels(vec![0.0; 3], 1).is_err());
+        assert!(match_channels(vec![0.0; 3], 2).is_err());
+        assert_eq!(match_channels(vec![1.0, 0.0], 1).unwrap(), [0.5]);
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
     encoders::video::{PipewireSPA, StartVideoEncoder},
+    pipeline::detect_gpu_vendor,
     types::{error::WaycapError, video_frame::RawVideoFrame},
-    waycap_egl::{EglContext, GpuVendor},
-    NvencEncoder, VaapiEncoder, VideoEncoder,
+    GpuVendor, NvencEncoder, VaapiEncoder, VideoEncoder,
 };
 use crossbeam::channel::Receiver;
 
@@ -12,6 +12,8 @@ use crate::types::error::Result;
 ///
 /// Allows for using the image directly on the GPU, which makes it far more performant when, for example, trying to display it to a user.
 /// The implementations of [`crate::NvencEncoder`] and [`crate::VaapiEncoder`] show how a [`RawVideoFrame`] can be used.
+/// Use [`RawVideoFrame::dmabuf`] to hold onto a frame past the next one being received, and
+/// [`crate::types::video_frame::VulkanDmaBufInfo`] to import it into Vulkan.
 #[derive(Default)]
 pub struct DmaBufEncoder {
     receiver: Option<Receiver<RawVideoFrame>>,
@@ -56,8 +58,7 @@ impl VideoEncoder for DmaBufEncoder {
 
 impl PipewireSPA for DmaBufEncoder {
     fn get_spa_definition() -> Result<pipewire::spa::pod::Object> {
-        let dummy_context = EglContext::new(100, 100)?;
-        match dummy_context.get_gpu_vendor() {
+        match detect_gpu_vendor()? {
             GpuVendor::NVIDIA => NvencEncoder::get_spa_definition(),
             GpuVendor::AMD | GpuVendor::INTEL => VaapiEncoder::get_spa_definition(),
             GpuVendor::UNKNOWN => Err(WaycapError::Init(
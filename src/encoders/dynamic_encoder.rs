@@ -1,24 +1,32 @@
 use crossbeam::channel::Receiver;
 use ffmpeg_next::codec::encoder;
+use std::sync::Arc;
 
 use crate::{
     encoders::{
+        amf_encoder::AmfEncoder,
         nvenc_encoder::NvencEncoder,
+        qsv_encoder::QsvEncoder,
         vaapi_encoder::VaapiEncoder,
         video::{PipewireSPA, ProcessingThread},
+        x264_encoder::X264Encoder,
     },
+    pipeline::detect_gpu_vendor,
     types::{
-        config::VideoEncoder as VideoEncoderType,
+        config::{BitDepth, QualityPreset, VideoEncoder as VideoEncoderType, VideoEncoderConfig},
         error::{Result, WaycapError},
+        stats::FrameCounters,
         video_frame::{EncodedVideoFrame, RawVideoFrame},
     },
-    waycap_egl::{EglContext, GpuVendor},
-    VideoEncoder,
+    EglContext, GpuVendor, VideoEncoder,
 };
 
 pub enum DynamicEncoder {
     Vaapi(VaapiEncoder),
     Nvenc(NvencEncoder),
+    Qsv(QsvEncoder),
+    Amf(AmfEncoder),
+    Software(X264Encoder),
 }
 
 impl DynamicEncoder {
@@ -26,16 +34,29 @@ impl DynamicEncoder {
         encoder_type: Option<VideoEncoderType>,
         width: u32,
         height: u32,
-        quality_preset: crate::types::config::QualityPreset,
+        config: VideoEncoderConfig,
     ) -> crate::types::error::Result<DynamicEncoder> {
         let encoder_type = match encoder_type {
             Some(typ) => typ,
             None => {
-                // Dummy dimensions we just use this go get GPU vendor then drop it
-                let dummy_context = EglContext::new(100, 100)?;
-                match dummy_context.get_gpu_vendor() {
+                match detect_gpu_vendor()? {
                     GpuVendor::NVIDIA => VideoEncoderType::H264Nvenc,
-                    GpuVendor::AMD | GpuVendor::INTEL => VideoEncoderType::H264Vaapi,
+                    // HEVC is the only encoder with a 10-bit profile
+                    GpuVendor::AMD | GpuVendor::INTEL if config.bit_depth == BitDepth::Ten => {
+                        VideoEncoderType::H265Vaapi
+                    }
+                    GpuVendor::INTEL => {
+                        // Prefer QSV on Intel but not every driver setup supports it
+                        match QsvEncoder::new("h264_qsv", width, height, config.clone()) {
+                            Ok(enc) => return Ok(DynamicEncoder::Qsv(enc)),
+                            Err(e) => {
+                                log::info!("QSV unavailable, falling back to VAAPI: {e}");
+                                VideoEncoderType::H264Vaapi
+                            }
+                        }
+                    }
+                    // AMF needs AMD's proprietary runtime, so it's only used when asked for
+                    GpuVendor::AMD => VideoEncoderType::H264Vaapi,
                     GpuVendor::UNKNOWN => {
                         return Err(WaycapError::Init(
                             "Unknown/Unimplemented GPU vendor".to_string(),
@@ -44,15 +65,91 @@ impl DynamicEncoder {
                 }
             }
         };
+        if config.bit_depth == BitDepth::Ten && !matches!(encoder_type, VideoEncoderType::H265Vaapi)
+        {
+            return Err(WaycapError::Init(format!(
+                "{encoder_type:?} does not support 10-bit encoding"
+            )));
+        }
         Ok(match encoder_type {
             VideoEncoderType::H264Nvenc => {
-                DynamicEncoder::Nvenc(NvencEncoder::new(width, height, quality_preset)?)
+                DynamicEncoder::Nvenc(NvencEncoder::new("h264_nvenc", width, height, config)?)
+            }
+            VideoEncoderType::Av1Nvenc => {
+                DynamicEncoder::Nvenc(NvencEncoder::new("av1_nvenc", width, height, config)?)
             }
             VideoEncoderType::H264Vaapi => {
-                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, quality_preset)?)
+                DynamicEncoder::Vaapi(VaapiEncoder::new("h264_vaapi", width, height, config)?)
+            }
+            VideoEncoderType::H265Vaapi => {
+                DynamicEncoder::Vaapi(VaapiEncoder::new("hevc_vaapi", width, height, config)?)
+            }
+            VideoEncoderType::Vp9Vaapi => {
+                DynamicEncoder::Vaapi(VaapiEncoder::new("vp9_vaapi", width, height, config)?)
+            }
+            VideoEncoderType::H264Qsv => {
+                DynamicEncoder::Qsv(QsvEncoder::new("h264_qsv", width, height, config)?)
             }
+            VideoEncoderType::H264Amf => {
+                DynamicEncoder::Amf(AmfEncoder::new("h264_amf", width, height, config)?)
+            }
+            VideoEncoderType::H264Software => {
+                DynamicEncoder::Software(X264Encoder::new(width, height, config)?)
+            }
+        })
+    }
+
+    /// VAAPI and QSV upload frames in memory when there are no DMA-BUFs, NVENC and AMF only
+    /// import DMA-BUFs. `None` is the encoder [`Self::new`] picks, which is NVENC on NVIDIA
+    /// GPUs.
+    pub(crate) fn needs_dmabuf(encoder_type: Option<VideoEncoderType>) -> Result<bool> {
+        Ok(match encoder_type {
+            Some(encoder_type) => matches!(
+                encoder_type,
+                VideoEncoderType::H264Nvenc
+                    | VideoEncoderType::Av1Nvenc
+                    | VideoEncoderType::H264Amf
+            ),
+            None => detect_gpu_vendor()? == GpuVendor::NVIDIA,
         })
     }
+
+    /// Only NVENC imports frames through EGL
+    pub(crate) fn set_egl_context(&mut self, egl_context: Arc<EglContext>) -> Result<()> {
+        match self {
+            DynamicEncoder::Nvenc(enc) => {
+                enc.set_egl_context(egl_context);
+                Ok(())
+            }
+            _ => Err(WaycapError::Config(
+                "Only the NVENC encoders use an EGL context".to_string(),
+            )),
+        }
+    }
+
+    /// Switch to a different quality preset by re-creating the encoder.
+    /// Frames still buffered in the old encoder are sent to the output first.
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_quality(quality),
+            DynamicEncoder::Nvenc(enc) => enc.set_quality(quality),
+            DynamicEncoder::Qsv(enc) => enc.set_quality(quality),
+            DynamicEncoder::Amf(enc) => enc.set_quality(quality),
+            DynamicEncoder::Software(enc) => enc.set_quality(quality),
+        }
+    }
+
+    /// Drain the encoder and re-create it so the next frame is a keyframe which starts a new
+    /// segment. Frames still buffered in the old encoder are sent to the output first.
+    pub(crate) fn rotate_segment(&mut self) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.rotate_segment(),
+            DynamicEncoder::Nvenc(enc) => enc.rotate_segment(),
+            DynamicEncoder::Qsv(enc) => enc.rotate_segment(),
+            DynamicEncoder::Amf(enc) => enc.rotate_segment(),
+            DynamicEncoder::Software(enc) => enc.rotate_segment(),
+        }
+    }
 }
 
 impl VideoEncoder for DynamicEncoder {
@@ -62,6 +159,19 @@ impl VideoEncoder for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.reset(),
             DynamicEncoder::Nvenc(enc) => enc.reset(),
+            DynamicEncoder::Qsv(enc) => enc.reset(),
+            DynamicEncoder::Amf(enc) => enc.reset(),
+            DynamicEncoder::Software(enc) => enc.reset(),
+        }
+    }
+
+    fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.reconfigure(width, height),
+            DynamicEncoder::Nvenc(enc) => enc.reconfigure(width, height),
+            DynamicEncoder::Qsv(enc) => enc.reconfigure(width, height),
+            DynamicEncoder::Amf(enc) => enc.reconfigure(width, height),
+            DynamicEncoder::Software(enc) => enc.reconfigure(width, height),
         }
     }
 
@@ -69,6 +179,19 @@ impl VideoEncoder for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.output(),
             DynamicEncoder::Nvenc(enc) => enc.output(),
+            DynamicEncoder::Qsv(enc) => enc.output(),
+            DynamicEncoder::Amf(enc) => enc.output(),
+            DynamicEncoder::Software(enc) => enc.output(),
+        }
+    }
+
+    fn close_output(&mut self) {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.close_output(),
+            DynamicEncoder::Nvenc(enc) => enc.close_output(),
+            DynamicEncoder::Qsv(enc) => enc.close_output(),
+            DynamicEncoder::Amf(enc) => enc.close_output(),
+            DynamicEncoder::Software(enc) => enc.close_output(),
         }
     }
 
@@ -76,6 +199,9 @@ impl VideoEncoder for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.drop_processor(),
             DynamicEncoder::Nvenc(enc) => enc.drop_processor(),
+            DynamicEncoder::Qsv(enc) => enc.drop_processor(),
+            DynamicEncoder::Amf(enc) => enc.drop_processor(),
+            DynamicEncoder::Software(enc) => enc.drop_processor(),
         }
     }
 
@@ -83,6 +209,19 @@ impl VideoEncoder for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.drain(),
             DynamicEncoder::Nvenc(enc) => enc.drain(),
+            DynamicEncoder::Qsv(enc) => enc.drain(),
+            DynamicEncoder::Amf(enc) => enc.drain(),
+            DynamicEncoder::Software(enc) => enc.drain(),
+        }
+    }
+
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedVideoFrame>> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.drain_collecting(),
+            DynamicEncoder::Nvenc(enc) => enc.drain_collecting(),
+            DynamicEncoder::Qsv(enc) => enc.drain_collecting(),
+            DynamicEncoder::Amf(enc) => enc.drain_collecting(),
+            DynamicEncoder::Software(enc) => enc.drain_collecting(),
         }
     }
 
@@ -90,6 +229,9 @@ impl VideoEncoder for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.get_encoder(),
             DynamicEncoder::Nvenc(enc) => enc.get_encoder(),
+            DynamicEncoder::Qsv(enc) => enc.get_encoder(),
+            DynamicEncoder::Amf(enc) => enc.get_encoder(),
+            DynamicEncoder::Software(enc) => enc.get_encoder(),
         }
     }
 }
@@ -99,12 +241,18 @@ impl ProcessingThread for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.process(frame),
             DynamicEncoder::Nvenc(enc) => enc.process(frame),
+            DynamicEncoder::Qsv(enc) => enc.process(frame),
+            DynamicEncoder::Amf(enc) => enc.process(frame),
+            DynamicEncoder::Software(enc) => enc.process(frame),
         }
     }
     fn thread_setup(&mut self) -> Result<()> {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.thread_setup(),
             DynamicEncoder::Nvenc(enc) => enc.thread_setup(),
+            DynamicEncoder::Qsv(enc) => enc.thread_setup(),
+            DynamicEncoder::Amf(enc) => enc.thread_setup(),
+            DynamicEncoder::Software(enc) => enc.thread_setup(),
         }
     }
 
@@ -112,14 +260,46 @@ impl ProcessingThread for DynamicEncoder {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.thread_teardown(),
             DynamicEncoder::Nvenc(enc) => enc.thread_teardown(),
+            DynamicEncoder::Qsv(enc) => enc.thread_teardown(),
+            DynamicEncoder::Amf(enc) => enc.thread_teardown(),
+            DynamicEncoder::Software(enc) => enc.thread_teardown(),
+        }
+    }
+
+    fn request_keyframe(&mut self) {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.request_keyframe(),
+            DynamicEncoder::Nvenc(enc) => enc.request_keyframe(),
+            DynamicEncoder::Qsv(enc) => enc.request_keyframe(),
+            DynamicEncoder::Amf(enc) => enc.request_keyframe(),
+            DynamicEncoder::Software(enc) => enc.request_keyframe(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.flush(),
+            DynamicEncoder::Nvenc(enc) => enc.flush(),
+            DynamicEncoder::Qsv(enc) => enc.flush(),
+            DynamicEncoder::Amf(enc) => enc.flush(),
+            DynamicEncoder::Software(enc) => enc.flush(),
+        }
+    }
+
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.attach_counters(counters),
+            DynamicEncoder::Nvenc(enc) => enc.attach_counters(counters),
+            DynamicEncoder::Qsv(enc) => enc.attach_counters(counters),
+            DynamicEncoder::Amf(enc) => enc.attach_counters(counters),
+            DynamicEncoder::Software(enc) => enc.attach_counters(counters),
         }
     }
 }
 
 impl PipewireSPA for DynamicEncoder {
     fn get_spa_definition() -> Result<pipewire::spa::pod::Object> {
-        let dummy_context = EglContext::new(100, 100)?;
-        match dummy_context.get_gpu_vendor() {
+        match detect_gpu_vendor()? {
             GpuVendor::NVIDIA => NvencEncoder::get_spa_definition(),
             GpuVendor::AMD | GpuVendor::INTEL => VaapiEncoder::get_spa_definition(),
             GpuVendor::UNKNOWN => Err(WaycapError::Init(
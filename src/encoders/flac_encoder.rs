@@ -0,0 +1,210 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use ffmpeg_next::{self as ffmpeg, Rational};
+use std::collections::VecDeque;
+
+use crate::types::{
+    audio_frame::EncodedAudioFrame, config::AudioEncoderConfig, error::WaycapError,
+};
+
+use super::audio::{apply_gain, match_channels, AudioEncoder};
+
+/// Block size used when the encoder doesn't ask for a specific one
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+/// Lossless audio encoder.
+///
+/// Samples are encoded as they are captured, without the RMS boost the lossy encoders apply.
+pub struct FlacEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Audio>,
+    next_pts: i64,
+    leftover_data: VecDeque<f32>,
+    encoded_samples_recv: Option<Receiver<EncodedAudioFrame>>,
+    encoded_samples_sender: Sender<EncodedAudioFrame>,
+    capture_timestamps: VecDeque<i64>,
+    config: AudioEncoderConfig,
+}
+
+impl FlacEncoder {
+    fn create_encoder(
+        config: &AudioEncoderConfig,
+    ) -> crate::types::error::Result<ffmpeg::codec::encoder::Audio> {
+        let encoder_codec = ffmpeg::codec::encoder::find(ffmpeg_next::codec::Id::FLAC)
+            .ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .audio()?;
+
+        encoder_ctx.set_rate(config.sample_rate as i32);
+        // FLAC has no float input, 24 bit samples stored in 32 bit is the closest match
+        encoder_ctx.set_format(ffmpeg::format::Sample::I32(
+            ffmpeg_next::format::sample::Type::Packed,
+        ));
+        encoder_ctx.set_time_base(Rational::new(1, config.sample_rate as i32));
+        encoder_ctx.set_frame_rate(Some(Rational::new(1, config.sample_rate as i32)));
+        encoder_ctx.set_channel_layout(config.channel_layout);
+        unsafe {
+            (*encoder_ctx.as_mut_ptr()).bits_per_raw_sample = 24;
+        }
+
+        Ok(encoder_ctx.open()?)
+    }
+
+    /// Number of samples per channel in each frame sent to the encoder
+    fn block_size(encoder: &ffmpeg::codec::encoder::Audio) -> usize {
+        match encoder.frame_size() {
+            0 => DEFAULT_BLOCK_SIZE,
+            size => size as usize,
+        }
+    }
+
+    /// Send interleaved `samples` to the encoder as a single frame, the last frame of a stream
+    /// may be shorter than the block size
+    fn encode_block(&mut self, samples: &[f32], timestamp: i64) -> crate::types::error::Result<()> {
+        let Some(ref mut encoder) = self.encoder else {
+            return Ok(());
+        };
+
+        let n_samples = samples.len() / encoder.channels() as usize;
+        let mut frame =
+            ffmpeg::frame::Audio::new(encoder.format(), n_samples, encoder.channel_layout());
+        // Packed samples, so every channel lives in the first plane
+        for (out, sample) in frame.data_mut(0).chunks_exact_mut(4).zip(samples) {
+            out.copy_from_slice(&to_i24(*sample).to_le_bytes());
+        }
+        frame.set_pts(Some(self.next_pts));
+        frame.set_rate(encoder.rate());
+
+        self.capture_timestamps.push_back(timestamp);
+        encoder.send_frame(&frame)?;
+        self.next_pts += n_samples as i64;
+
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                match self.encoded_samples_sender.try_send(EncodedAudioFrame::new(
+                    data.to_vec(),
+                    packet.pts().unwrap_or(0),
+                    self.capture_timestamps.pop_front().unwrap_or(0),
+                )) {
+                    Ok(_) => {}
+                    Err(crossbeam::channel::TrySendError::Full(_)) => {
+                        log::error!("Could not send encoded audio frame. Receiver is full");
+                    }
+                    Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
+                        return Err(WaycapError::Disconnected);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Convert a float sample to a 24 bit sample in the upper bits of an i32
+fn to_i24(sample: f32) -> i32 {
+    ((sample.clamp(-1.0, 1.0) * 8_388_607.0) as i32) << 8
+}
+
+impl AudioEncoder for FlacEncoder {
+    fn new(config: AudioEncoderConfig) -> crate::types::error::Result<Self>
+    where
+        Self: Sized,
+    {
+        let encoder = Self::create_encoder(&config)?;
+        let (frame_tx, frame_rx): (Sender<EncodedAudioFrame>, Receiver<EncodedAudioFrame>) =
+            bounded(config.buffer_frames);
+        Ok(Self {
+            encoder: Some(encoder),
+            next_pts: 0,
+            leftover_data: VecDeque::with_capacity(10),
+            encoded_samples_recv: Some(frame_rx),
+            encoded_samples_sender: frame_tx,
+            capture_timestamps: VecDeque::with_capacity(10),
+            config,
+        })
+    }
+
+    fn process(
+        &mut self,
+        raw_frame: crate::types::audio_frame::RawAudioFrame,
+    ) -> crate::types::error::Result<()> {
+        let Some(ref encoder) = self.encoder else {
+            return Ok(());
+        };
+
+        let n_channels = encoder.channels() as usize;
+        // Input is always stereo, the encoder may be mono
+        let mut samples = match_channels(raw_frame.samples, n_channels)?;
+        apply_gain(&mut samples, self.config.gain)?;
+
+        let chunk_size = Self::block_size(encoder) * n_channels;
+        self.leftover_data.extend(samples);
+
+        while self.leftover_data.len() >= chunk_size {
+            let block: Vec<f32> = self.leftover_data.drain(..chunk_size).collect();
+            self.encode_block(&block, raw_frame.timestamp_ns)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg_next::codec::encoder::Audio> {
+        &self.encoder
+    }
+
+    fn drain(&mut self) -> crate::types::error::Result<()> {
+        self.drain_collecting()?; // Discard frames
+        Ok(())
+    }
+
+    /// The tail is sent to the output like any other block, only the frames still in the
+    /// encoder after it are returned
+    fn drain_collecting(&mut self) -> crate::types::error::Result<Vec<EncodedAudioFrame>> {
+        // Lossless output shouldn't drop the tail, encode it as a short final block
+        if !self.leftover_data.is_empty() {
+            let block: Vec<f32> = self.leftover_data.drain(..).collect();
+            let timestamp = self.capture_timestamps.back().copied().unwrap_or(0);
+            match self.encode_block(&block, timestamp) {
+                // Nobody is left to receive the tail
+                Ok(()) | Err(WaycapError::Disconnected) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut frames = Vec::new();
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.send_eof()?;
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {
+                if let Some(data) = packet.data() {
+                    frames.push(EncodedAudioFrame::new(
+                        data.to_vec(),
+                        packet.pts().unwrap_or(0),
+                        self.capture_timestamps.pop_front().unwrap_or(0),
+                    ));
+                }
+            }
+        }
+
+        Ok(frames)
+    }
+
+    fn drop_encoder(&mut self) {
+        self.encoder.take();
+    }
+
+    fn reset(&mut self) -> crate::types::error::Result<()> {
+        self.drop_encoder();
+        self.capture_timestamps.clear();
+        self.leftover_data.clear();
+        self.encoder = Some(Self::create_encoder(&self.config)?);
+
+        Ok(())
+    }
+
+    fn get_encoded_recv(&mut self) -> Option<Receiver<EncodedAudioFrame>> {
+        self.encoded_samples_recv.clone()
+    }
+}
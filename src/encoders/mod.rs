@@ -1,9 +1,15 @@
+pub mod aac_encoder;
+pub mod amf_encoder;
 pub mod audio;
 mod cuda;
 pub mod dma_buf_encoder;
 pub mod dynamic_encoder;
+pub mod flac_encoder;
 pub mod nvenc_encoder;
 pub mod opus_encoder;
+pub mod pcm_encoder;
+pub mod qsv_encoder;
 pub mod rgba_image_encoder;
 pub mod vaapi_encoder;
 pub mod video;
+pub mod x264_encoder;
@@ -1,6 +1,7 @@
 use std::ptr::null_mut;
+use std::sync::{Arc, OnceLock};
 
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crossbeam::channel::Receiver;
 use cust::{
     prelude::Context,
     sys::{
@@ -10,6 +11,7 @@ use cust::{
         CUgraphicsResource, CUmemorytype, CUresult,
     },
 };
+use drm_fourcc::DrmFourcc;
 use ffmpeg_next::{
     self as ffmpeg,
     ffi::{
@@ -22,25 +24,27 @@ use ffmpeg_next::{
 use pipewire as pw;
 
 use crate::{
-    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder},
+    encoders::video::{EncodedOutput, PipewireSPA, ProcessingThread, VideoEncoder},
     types::{
-        config::QualityPreset,
+        config::{QualityPreset, RateControl, VideoEncoderConfig},
         error::{Result, WaycapError},
+        stats::FrameCounters,
         video_frame::{EncodedVideoFrame, RawVideoFrame},
     },
-    utils::{extract_dmabuf_planes, TIME_UNIT_NS},
+    utils::{drm_fourcc, extract_dmabuf_planes, TIME_UNIT_NS},
     waycap_egl::EglContext,
 };
 use khronos_egl::Image;
 
 use super::{
     cuda::{cuGraphicsGLRegisterImage, AVCUDADeviceContext},
-    video::{create_hw_frame_ctx, GOP_SIZE},
+    video::create_hw_frame_ctx,
 };
 
 // Literally stole these by looking at what OBS uses
 // just magic numbers to me no clue what these are
 // but they enable DMA Buf so it is what it is
+// Only used when EGL can't tell us the modifiers, see `supported_modifiers`
 const NVIDIA_MODIFIERS: &[i64] = &[
     216172782120099856,
     216172782120099857,
@@ -57,6 +61,8 @@ const NVIDIA_MODIFIERS: &[i64] = &[
     72057594037927935,
 ];
 
+const AV1_NVENC: &str = "av1_nvenc";
+
 /// Encoder which provides frames encoded using Nvenc
 ///
 /// Only available for Nvidia GPUs
@@ -65,14 +71,19 @@ pub struct NvencEncoder {
     width: u32,
     height: u32,
     encoder_name: String,
-    quality: QualityPreset,
-    encoded_frame_recv: Option<Receiver<EncodedVideoFrame>>,
-    encoded_frame_sender: Sender<EncodedVideoFrame>,
+    config: VideoEncoderConfig,
+    output: EncodedOutput,
 
     cuda_ctx: Context,
     graphics_resource: CUgraphicsResource,
-    egl_context: Option<Box<EglContext>>, // boxed egl context because its huge
+    /// Created on the processing thread unless one was passed to [`Self::set_egl_context`]
+    egl_context: Option<Arc<EglContext>>,
     egl_texture: u32,
+    /// Set by [`VideoEncoder::reset`], the texture and its CUDA registration are rebuilt on
+    /// the processing thread before the next frame since the contexts are only current there
+    gl_reset_pending: bool,
+    /// Capture timestamp of the first frame, for zero based PTS
+    first_timestamp: Option<i64>,
 }
 
 unsafe impl Send for NvencEncoder {}
@@ -86,30 +97,41 @@ impl VideoEncoder for NvencEncoder {
             self.width,
             self.height,
             &self.encoder_name,
-            &self.quality,
+            &self.config,
             &self.cuda_ctx,
         )?;
 
         self.encoder = Some(new_encoder);
+        self.gl_reset_pending = true;
         Ok(())
     }
 
+    fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.width = width;
+        self.height = height;
+        // Also rebuilds the texture before the next frame
+        self.reset()
+    }
+
     fn drop_processor(&mut self) {
         self.encoder.take();
     }
 
     fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
-        self.encoded_frame_recv.clone()
+        Some(self.output.receiver())
+    }
+
+    fn close_output(&mut self) {
+        self.output.close();
     }
 
     fn drain(&mut self) -> Result<()> {
-        if let Some(ref mut encoder) = self.encoder {
-            // Drain encoder
-            encoder.send_eof()?;
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {} // Discard these frames
-        }
-        Ok(())
+        self.output.discard(self.encoder.as_mut())
+    }
+
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedVideoFrame>> {
+        self.output.drain_frames(self.encoder.as_mut())
     }
 
     fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
@@ -118,10 +140,15 @@ impl VideoEncoder for NvencEncoder {
 }
 impl ProcessingThread for NvencEncoder {
     fn thread_setup(&mut self) -> Result<()> {
-        self.egl_context = Some(Box::new(EglContext::new(
-            self.width as i32,
-            self.height as i32,
-        )?));
+        match &self.egl_context {
+            Some(egl_context) => egl_context.make_current()?,
+            None => {
+                self.egl_context = Some(Arc::new(EglContext::new(
+                    self.width as i32,
+                    self.height as i32,
+                )?));
+            }
+        }
         self.make_current()?;
         self.init_gl(None)?;
         Ok(())
@@ -131,7 +158,23 @@ impl ProcessingThread for NvencEncoder {
         self.egl_context.as_mut().unwrap().release_current()
     }
 
+    fn request_keyframe(&mut self) {
+        self.output.force_keyframe = true;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.reset()
+    }
+
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        self.output.counters = counters;
+    }
+
     fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        if std::mem::take(&mut self.gl_reset_pending) {
+            self.rebuild_gl()?;
+        }
         match egl_img_from_dmabuf(self.egl_context.as_ref().unwrap(), &frame) {
             Ok(img) => {
                 if let Some(ref mut encoder) = self.encoder {
@@ -178,11 +221,16 @@ impl ProcessingThread for NvencEncoder {
                             )));
                         }
 
+                        // Copying only the region out of the texture crops the frame
+                        let (src_x, src_y) = self
+                            .config
+                            .region
+                            .map_or((0, 0), |region| (region.x, region.y));
                         let copy_params = CUDA_MEMCPY2D_v2 {
                             srcMemoryType: CUmemorytype::CU_MEMORYTYPE_ARRAY,
                             srcArray: cuda_array,
-                            srcXInBytes: 0,
-                            srcY: 0,
+                            srcXInBytes: (src_x * 4) as usize,
+                            srcY: src_y as usize,
                             srcHost: std::ptr::null(),
                             srcDevice: 0,
                             srcPitch: 0,
@@ -221,32 +269,11 @@ impl ProcessingThread for NvencEncoder {
                         gl::BindTexture(gl::TEXTURE_2D, 0);
                     }
 
-                    cuda_frame.set_pts(Some(frame.timestamp));
-                    encoder.send_frame(&cuda_frame)?;
-
-                    let mut packet = ffmpeg::codec::packet::Packet::empty();
-                    if encoder.receive_packet(&mut packet).is_ok() {
-                        if let Some(data) = packet.data() {
-                            match self.encoded_frame_sender.try_send(EncodedVideoFrame {
-                                data: data.to_vec(),
-                                is_keyframe: packet.is_key(),
-                                pts: packet.pts().unwrap_or(0),
-                                dts: packet.dts().unwrap_or(0),
-                            }) {
-                                Ok(_) => {}
-                                Err(crossbeam::channel::TrySendError::Full(_)) => {
-                                    log::error!(
-                                        "Could not send encoded video frame. Receiver is full"
-                                    );
-                                }
-                                Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                                    log::error!(
-                                        "Could not send encoded video frame. Receiver disconnected"
-                                    );
-                                }
-                            }
-                        };
-                    }
+                    cuda_frame.set_pts(Some(
+                        self.config
+                            .frame_pts(&mut self.first_timestamp, frame.timestamp),
+                    ));
+                    self.output.encode(encoder, &mut cuda_frame)?;
                 }
                 self.egl_context.as_ref().unwrap().destroy_image(img)?;
             }
@@ -258,6 +285,7 @@ impl ProcessingThread for NvencEncoder {
 
 impl PipewireSPA for NvencEncoder {
     fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        let modifiers = supported_modifiers();
         let nvidia_mod_property = pw::spa::pod::Property {
             key: pw::spa::param::format::FormatProperties::VideoModifier.as_raw(),
             flags: pw::spa::pod::PropertyFlags::empty(),
@@ -265,8 +293,8 @@ impl PipewireSPA for NvencEncoder {
                 pw::spa::utils::Choice::<i64>(
                     pw::spa::utils::ChoiceFlags::empty(),
                     pw::spa::utils::ChoiceEnum::<i64>::Enum {
-                        default: NVIDIA_MODIFIERS[0],
-                        alternatives: NVIDIA_MODIFIERS.to_vec(),
+                        default: modifiers[0],
+                        alternatives: modifiers,
                     },
                 ),
             )),
@@ -291,9 +319,10 @@ impl PipewireSPA for NvencEncoder {
                 Choice,
                 Enum,
                 Id,
-                pw::spa::param::video::VideoFormat::NV12,
-                pw::spa::param::video::VideoFormat::I420,
-                pw::spa::param::video::VideoFormat::BGRA
+                // Frames are imported as GL_TEXTURE_2D and copied as RGBA to CUDA, which
+                // only works for packed RGB formats
+                pw::spa::param::video::VideoFormat::BGRA,
+                pw::spa::param::video::VideoFormat::BGRx
             ),
             pw::spa::pod::property!(
                 pw::spa::param::format::FormatProperties::VideoSize,
@@ -326,10 +355,66 @@ impl PipewireSPA for NvencEncoder {
     }
 }
 
+/// Modifiers frames can be imported with, asked from EGL for every format the SPA definition
+/// offers. Falls back to [`NVIDIA_MODIFIERS`] if the driver can't be asked, which isn't cached
+/// so a later call can try again.
+fn supported_modifiers() -> Vec<i64> {
+    static MODIFIERS: OnceLock<Vec<i64>> = OnceLock::new();
+
+    if let Some(modifiers) = MODIFIERS.get() {
+        return modifiers.clone();
+    }
+    match query_modifiers() {
+        Ok(modifiers) if !modifiers.is_empty() => {
+            log::debug!("EGL supports the modifiers {modifiers:?}");
+            MODIFIERS.get_or_init(|| modifiers).clone()
+        }
+        Ok(_) => {
+            log::warn!("EGL reported no DMA-BUF modifiers, using the known NVIDIA ones");
+            NVIDIA_MODIFIERS.to_vec()
+        }
+        Err(e) => {
+            log::warn!("Could not query DMA-BUF modifiers, using the known NVIDIA ones: {e}");
+            NVIDIA_MODIFIERS.to_vec()
+        }
+    }
+}
+
+/// Modifiers EGL can import both BGRA and BGRx with, one modifier property covers all formats
+fn query_modifiers() -> Result<Vec<i64>> {
+    // Dummy dimensions, the context only lives long enough to ask EGL
+    let egl_ctx = EglContext::new(1, 1)?;
+    let mut modifiers = egl_ctx.query_dmabuf_modifiers(DrmFourcc::Argb8888)?;
+    let xrgb_modifiers = egl_ctx.query_dmabuf_modifiers(DrmFourcc::Xrgb8888)?;
+    modifiers.retain(|modifier| xrgb_modifiers.contains(modifier));
+    Ok(modifiers
+        .into_iter()
+        .map(|modifier| modifier as i64)
+        .collect())
+}
+
 fn egl_img_from_dmabuf(egl_ctx: &EglContext, raw_frame: &RawVideoFrame) -> Result<Image> {
     let dma_buf_planes = extract_dmabuf_planes(raw_frame)?;
 
-    let format = drm_fourcc::DrmFourcc::Argb8888 as u32;
+    // GL_TEXTURE_2D can't sample YUV images, the texture is copied to CUDA as RGBA
+    let format = match drm_fourcc(raw_frame.format) {
+        Some(
+            format @ (DrmFourcc::Argb8888
+            | DrmFourcc::Xrgb8888
+            | DrmFourcc::Abgr8888
+            | DrmFourcc::Xbgr8888
+            | DrmFourcc::Bgra8888
+            | DrmFourcc::Bgrx8888
+            | DrmFourcc::Rgba8888
+            | DrmFourcc::Rgbx8888),
+        ) => format as u32,
+        _ => {
+            return Err(WaycapError::Encoding(format!(
+                "NVENC can't import {:?} DMA-BUFs, only packed 8-bit RGB",
+                raw_frame.format
+            )))
+        }
+    };
     let modifier = raw_frame.modifier;
 
     let egl_image = egl_ctx.create_image_from_dmabuf(
@@ -346,37 +431,66 @@ fn egl_img_from_dmabuf(egl_ctx: &EglContext, raw_frame: &RawVideoFrame) -> Resul
 }
 
 impl NvencEncoder {
-    pub(crate) fn new(width: u32, height: u32, quality: QualityPreset) -> Result<Self> {
-        let encoder_name = "h264_nvenc";
-
-        let (frame_tx, frame_rx): (Sender<EncodedVideoFrame>, Receiver<EncodedVideoFrame>) =
-            bounded(10);
+    /// Create a new NVENC encoder using the given ffmpeg encoder name
+    /// (e.g. `h264_nvenc` or `av1_nvenc`).
+    pub(crate) fn new(
+        encoder_name: &str,
+        width: u32,
+        height: u32,
+        config: VideoEncoderConfig,
+    ) -> Result<Self> {
         let cuda_ctx = cust::quick_init().unwrap();
 
-        let encoder = Self::create_encoder(width, height, encoder_name, &quality, &cuda_ctx)?;
+        let encoder = Self::create_encoder(width, height, encoder_name, &config, &cuda_ctx)?;
 
         Ok(Self {
             encoder: Some(encoder),
             width,
             height,
             encoder_name: encoder_name.to_string(),
-            quality,
-            encoded_frame_recv: Some(frame_rx),
-            encoded_frame_sender: frame_tx,
+            output: EncodedOutput::new(config.buffer_frames, config.bitstream_format),
+            config,
             cuda_ctx,
             graphics_resource: null_mut(),
             egl_context: None,
             egl_texture: 0,
+            gl_reset_pending: false,
+            first_timestamp: None,
         })
     }
 
+    /// Import frames into `egl_context` instead of a context of our own.
+    /// Has to be set before the processing thread starts.
+    pub(crate) fn set_egl_context(&mut self, egl_context: Arc<EglContext>) {
+        self.egl_context = Some(egl_context);
+    }
+
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.config.quality = quality;
+        self.reset()
+    }
+
+    pub(crate) fn rotate_segment(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.output.segment_pending = true;
+        self.reset()
+    }
+
     fn create_encoder(
         width: u32,
         height: u32,
         encoder: &str,
-        quality: &QualityPreset,
+        config: &VideoEncoderConfig,
         cuda_ctx: &Context,
     ) -> Result<ffmpeg::codec::encoder::Video> {
+        // Frames are copied into the encoder as they are, without a filter graph to scale them
+        if config.output_size(width, height) != config.crop_size(width, height) {
+            return Err(WaycapError::Config(format!(
+                "NVENC can't scale the {width}x{height} stream to the requested capture resolution"
+            )));
+        }
+        let (width, height) = config.output_size(width, height);
         let encoder_codec =
             ffmpeg::codec::encoder::find_by_name(encoder).ok_or(ffmpeg::Error::EncoderNotFound)?;
 
@@ -387,7 +501,12 @@ impl NvencEncoder {
         encoder_ctx.set_width(width);
         encoder_ctx.set_height(height);
         encoder_ctx.set_format(ffmpeg::format::Pixel::CUDA);
-        encoder_ctx.set_bit_rate(16_000_000);
+        if let Some((bitrate, max_bitrate)) = config.rate_control().bitrates() {
+            encoder_ctx.set_bit_rate(bitrate as usize);
+            encoder_ctx.set_max_bit_rate(max_bitrate as usize);
+        }
+        encoder_ctx.set_color_range(config.color_range.ffmpeg_range());
+        encoder_ctx.set_colorspace(config.color_space.ffmpeg_space());
 
         unsafe {
             // Set up the cuda context
@@ -433,7 +552,7 @@ impl NvencEncoder {
             hw_frame_context.device_ctx = hw_device_ctx;
             // Decides buffer size if we do not pop frame from the encoder we cannot
             // keep pushing. Smaller better as we reserve less GPU memory
-            hw_frame_context.initial_pool_size = 2;
+            hw_frame_context.initial_pool_size = config.hw_pool_size(2);
 
             let err = av_hwframe_ctx_init(frame_ctx);
             if err < 0 {
@@ -449,43 +568,76 @@ impl NvencEncoder {
         }
 
         encoder_ctx.set_time_base(Rational::new(1, TIME_UNIT_NS as i32));
-        encoder_ctx.set_gop(GOP_SIZE);
+        encoder_ctx.set_gop(config.gop_size);
 
         let encoder_params = ffmpeg::codec::Parameters::new();
 
-        let opts = Self::get_encoder_params(quality);
+        let opts = Self::get_encoder_params(encoder, config);
 
         encoder_ctx.set_parameters(encoder_params)?;
-        let encoder = encoder_ctx.open_with(opts)?;
+        let encoder = encoder_ctx.open_with(opts).map_err(|e| match encoder {
+            // Only Ada Lovelace (RTX 40-series) and newer can encode AV1, older cards
+            // fail while opening the codec with a generic ffmpeg error
+            AV1_NVENC => WaycapError::Init(format!(
+                "AV1 NVENC encoding is not supported on this GPU \
+                 (requires an RTX 40-series or newer): {e}"
+            )),
+            _ => WaycapError::from(e),
+        })?;
 
         Ok(encoder)
     }
 
-    fn get_encoder_params(quality: &QualityPreset) -> ffmpeg::Dictionary<'_> {
+    fn get_encoder_params<'a>(
+        encoder: &str,
+        config: &VideoEncoderConfig,
+    ) -> ffmpeg::Dictionary<'a> {
         let mut opts = ffmpeg::Dictionary::new();
         opts.set("vsync", "vfr");
-        opts.set("rc", "vbr");
-        opts.set("tune", "hq");
-        match quality {
-            QualityPreset::Low => {
-                opts.set("preset", "p2");
-                opts.set("cq", "30");
-                opts.set("b:v", "20M");
-            }
-            QualityPreset::Medium => {
-                opts.set("preset", "p4");
-                opts.set("cq", "25");
-                opts.set("b:v", "40M");
+        opts.set("tune", config.nvenc_tune.ffmpeg_name());
+
+        // Only the speed preset is overridden, the rate control below still follows `quality`
+        let preset = match (config.nvenc_preset, config.quality) {
+            (Some(preset), _) => preset.ffmpeg_name(),
+            (None, QualityPreset::Low) => "p2",
+            (None, QualityPreset::Medium) => "p4",
+            (None, QualityPreset::High) => "p7",
+            (None, QualityPreset::Ultra) => "p7",
+        };
+        opts.set("preset", preset);
+
+        if let Some(b_frames) = config.b_frames {
+            opts.set("bf", &b_frames.to_string());
+        }
+        if let Some(lookahead) = config.lookahead {
+            opts.set("rc-lookahead", &lookahead.to_string());
+        }
+        // NVENC's equivalent of an async depth is the number of surfaces it encodes into
+        if let Some(depth) = config.async_depth {
+            opts.set("surfaces", &depth.to_string());
+        }
+
+        match config.rate_control() {
+            RateControl::ConstantQuality(cq) => {
+                // AV1's CQ goes up to 63 instead of 51
+                let cq = if encoder == AV1_NVENC {
+                    cq as u32 * 63 / 51
+                } else {
+                    cq as u32
+                };
+                // Without a bitrate NVENC's VBR only targets the CQ
+                opts.set("rc", "vbr");
+                opts.set("cq", &cq.to_string());
+                opts.set("b:v", "0");
             }
-            QualityPreset::High => {
-                opts.set("preset", "p7");
-                opts.set("cq", "20");
-                opts.set("b:v", "80M");
+            RateControl::ConstantBitrate(bitrate) => {
+                opts.set("rc", "cbr");
+                opts.set("b:v", &bitrate.to_string());
             }
-            QualityPreset::Ultra => {
-                opts.set("preset", "p7");
-                opts.set("cq", "15");
-                opts.set("b:v", "120M");
+            RateControl::VariableBitrate { target, max } => {
+                opts.set("rc", "vbr");
+                opts.set("b:v", &target.to_string());
+                opts.set("maxrate", &max.to_string());
             }
         }
         opts
@@ -498,7 +650,7 @@ impl NvencEncoder {
                 self.egl_context
                     .as_ref()
                     .unwrap()
-                    .create_persistent_texture()?;
+                    .create_persistent_texture(self.width as i32, self.height as i32)?;
                 self.egl_context.as_ref().unwrap().get_texture_id().unwrap()
             }
         };
@@ -532,6 +684,20 @@ impl NvencEncoder {
         Ok(())
     }
 
+    /// Replace the persistent texture and register the new one with CUDA.
+    /// Has to run on the processing thread.
+    fn rebuild_gl(&mut self) -> Result<()> {
+        let result = unsafe { cuGraphicsUnregisterResource(self.graphics_resource) };
+        if result != CUresult::CUDA_SUCCESS {
+            log::error!("Error unregistering graphics resource: {result:?}");
+        }
+        self.egl_context
+            .as_ref()
+            .unwrap()
+            .delete_texture(self.egl_texture);
+        self.init_gl(None)
+    }
+
     /// Set cuda  context to current thread
     fn make_current(&self) -> Result<()> {
         unsafe { cuCtxSetCurrent(self.cuda_ctx.as_raw()) };
@@ -552,11 +718,21 @@ impl Drop for NvencEncoder {
         }
         self.drop_processor();
 
-        self.egl_context.as_ref().unwrap().make_current().unwrap();
+        // Without an EGL context the processing thread never ran, so nothing was registered
+        // with CUDA, e.g. when building the capture failed
+        let Some(egl_context) = self.egl_context.as_ref() else {
+            return;
+        };
+        if let Err(e) = egl_context.make_current() {
+            log::error!("Could not make the EGL context current during drop: {e:?}");
+        }
         if let Err(e) = self.make_current() {
             log::error!("Could not make context current during drop: {e:?}");
         }
 
+        if self.graphics_resource.is_null() {
+            return;
+        }
         let result = unsafe { cuGraphicsUnregisterResource(self.graphics_resource) };
         if result != CUresult::CUDA_SUCCESS {
             log::error!("Error cleaning up graphics resource: {result:?}");
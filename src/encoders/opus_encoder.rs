@@ -1,10 +1,15 @@
 use crossbeam::channel::{bounded, Receiver, Sender};
 use ffmpeg_next::{self as ffmpeg, Rational};
-use std::collections::VecDeque;
+use std::{collections::VecDeque, mem::size_of};
 
-use crate::types::audio_frame::EncodedAudioFrame;
+use crate::types::{
+    audio_frame::EncodedAudioFrame, config::AudioEncoderConfig, error::WaycapError,
+};
 
-use super::audio::{boost_with_rms, AudioEncoder};
+use super::audio::{apply_gain, match_channels, AudioEncoder};
+
+/// Length of every Opus frame in milliseconds
+const FRAME_DURATION_MS: &str = "20";
 
 pub struct OpusEncoder {
     encoder: Option<ffmpeg::codec::encoder::Audio>,
@@ -13,10 +18,13 @@ pub struct OpusEncoder {
     encoded_samples_recv: Option<Receiver<EncodedAudioFrame>>,
     encoded_samples_sender: Sender<EncodedAudioFrame>,
     capture_timestamps: VecDeque<i64>,
+    config: AudioEncoderConfig,
 }
 
 impl OpusEncoder {
-    fn create_encoder() -> crate::types::error::Result<ffmpeg::codec::encoder::Audio> {
+    fn create_encoder(
+        config: &AudioEncoderConfig,
+    ) -> crate::types::error::Result<ffmpeg::codec::encoder::Audio> {
         let encoder_codec = ffmpeg::codec::encoder::find(ffmpeg_next::codec::Id::OPUS)
             .ok_or(ffmpeg::Error::EncoderNotFound)?;
 
@@ -24,35 +32,31 @@ impl OpusEncoder {
             .encoder()
             .audio()?;
 
-        encoder_ctx.set_rate(48000);
-        encoder_ctx.set_bit_rate(70_000);
+        encoder_ctx.set_rate(config.sample_rate as i32);
+        encoder_ctx.set_bit_rate(config.bitrate.unwrap_or(70_000) as usize);
         encoder_ctx.set_format(ffmpeg::format::Sample::F32(
             ffmpeg_next::format::sample::Type::Packed,
         ));
-        encoder_ctx.set_time_base(Rational::new(1, 48000));
-        encoder_ctx.set_frame_rate(Some(Rational::new(1, 48000)));
-        encoder_ctx.set_channel_layout(ffmpeg::channel_layout::ChannelLayout::STEREO);
-
-        let mut encoder = encoder_ctx.open()?;
+        encoder_ctx.set_time_base(Rational::new(1, config.sample_rate as i32));
+        encoder_ctx.set_frame_rate(Some(Rational::new(1, config.sample_rate as i32)));
+        encoder_ctx.set_channel_layout(config.channel_layout);
 
-        // Opus frame size is based on n channels so need to update it
-        unsafe {
-            (*encoder.as_mut_ptr()).frame_size =
-                (encoder.frame_size() as i32 * encoder.channels() as i32) as i32;
-        }
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("application", config.opus_application.ffmpeg_name());
+        opts.set("frame_duration", FRAME_DURATION_MS);
 
-        Ok(encoder)
+        Ok(encoder_ctx.open_with(opts)?)
     }
 }
 
 impl AudioEncoder for OpusEncoder {
-    fn new() -> crate::types::error::Result<Self>
+    fn new(config: AudioEncoderConfig) -> crate::types::error::Result<Self>
     where
         Self: Sized,
     {
-        let encoder = Self::create_encoder()?;
+        let encoder = Self::create_encoder(&config)?;
         let (frame_tx, frame_rx): (Sender<EncodedAudioFrame>, Receiver<EncodedAudioFrame>) =
-            bounded(10);
+            bounded(config.buffer_frames);
         Ok(Self {
             encoder: Some(encoder),
             next_pts: 0,
@@ -60,6 +64,7 @@ impl AudioEncoder for OpusEncoder {
             encoded_samples_recv: Some(frame_rx),
             encoded_samples_sender: frame_tx,
             capture_timestamps: VecDeque::with_capacity(10),
+            config,
         })
     }
 
@@ -69,36 +74,39 @@ impl AudioEncoder for OpusEncoder {
     ) -> crate::types::error::Result<()> {
         if let Some(ref mut encoder) = self.encoder {
             let n_channels = encoder.channels() as usize;
-            let total_samples = raw_frame.samples.len();
-
-            if !total_samples.is_multiple_of(n_channels) {
-                return Err(crate::types::error::WaycapError::FFmpeg(
-                    ffmpeg::Error::InvalidData,
-                ));
-            }
+            // Input is always stereo, the encoder may be mono
+            raw_frame.samples = match_channels(raw_frame.samples, n_channels)?;
 
+            // Samples per channel in a frame, 960 for 20ms at 48kHz. The interleaved samples
+            // of all channels for one frame make up a chunk
             let frame_size = encoder.frame_size() as usize;
+            let chunk_size = frame_size * n_channels;
 
-            // Boost the audio so that even if system audio level is low
+            // By default boost the audio so that even if system audio level is low
             // it's still audible in playback
-            boost_with_rms(&mut raw_frame.samples)?;
+            apply_gain(&mut raw_frame.samples, self.config.gain)?;
             self.leftover_data.extend(raw_frame.samples);
 
             // Send chunked frames to encoder
-            while self.leftover_data.len() >= frame_size {
-                let frame_samples: Vec<f32> = self.leftover_data.drain(..frame_size).collect();
+            while self.leftover_data.len() >= chunk_size {
+                let frame_samples: Vec<f32> = self.leftover_data.drain(..chunk_size).collect();
                 let mut frame = ffmpeg::frame::Audio::new(
                     encoder.format(),
                     frame_size,
                     encoder.channel_layout(),
                 );
 
-                // Capture time in vec
-                frame.plane_mut(0).copy_from_slice(&frame_samples);
+                // Packed samples share one plane, which `plane_mut::<f32>` only covers the
+                // first `frame_size` samples of, so copy the bytes of the whole chunk
+                let plane = frame.data_mut(0);
+                for (bytes, sample) in plane.chunks_exact_mut(size_of::<f32>()).zip(&frame_samples)
+                {
+                    bytes.copy_from_slice(&sample.to_ne_bytes());
+                }
                 frame.set_pts(Some(self.next_pts));
                 frame.set_rate(encoder.rate());
 
-                self.capture_timestamps.push_back(raw_frame.timestamp);
+                self.capture_timestamps.push_back(raw_frame.timestamp_ns);
                 encoder.send_frame(&frame)?;
 
                 // Try and get a frame back from encoder
@@ -106,19 +114,17 @@ impl AudioEncoder for OpusEncoder {
                 if encoder.receive_packet(&mut packet).is_ok() {
                     if let Some(data) = packet.data() {
                         let pts = packet.pts().unwrap_or(0);
-                        match self.encoded_samples_sender.try_send(EncodedAudioFrame {
-                            data: data.to_vec(),
+                        match self.encoded_samples_sender.try_send(EncodedAudioFrame::new(
+                            data.to_vec(),
                             pts,
-                            timestamp: self.capture_timestamps.pop_front().unwrap_or(0),
-                        }) {
+                            self.capture_timestamps.pop_front().unwrap_or(0),
+                        )) {
                             Ok(_) => {}
                             Err(crossbeam::channel::TrySendError::Full(_)) => {
                                 log::error!("Could not send encoded audio frame. Receiver is full");
                             }
                             Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                                log::error!(
-                                    "Could not send encoded audio frame. Receiver disconnected"
-                                );
+                                return Err(WaycapError::Disconnected);
                             }
                         }
                     }
@@ -136,13 +142,27 @@ impl AudioEncoder for OpusEncoder {
     }
 
     fn drain(&mut self) -> crate::types::error::Result<()> {
+        self.drain_collecting()?; // Discard frames
+        Ok(())
+    }
+
+    fn drain_collecting(&mut self) -> crate::types::error::Result<Vec<EncodedAudioFrame>> {
+        let mut frames = Vec::new();
         if let Some(ref mut encoder) = self.encoder {
             encoder.send_eof()?;
             let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {} // Discard frames
+            while encoder.receive_packet(&mut packet).is_ok() {
+                if let Some(data) = packet.data() {
+                    frames.push(EncodedAudioFrame::new(
+                        data.to_vec(),
+                        packet.pts().unwrap_or(0),
+                        self.capture_timestamps.pop_front().unwrap_or(0),
+                    ));
+                }
+            }
         }
 
-        Ok(())
+        Ok(frames)
     }
 
     fn drop_encoder(&mut self) {
@@ -152,7 +172,7 @@ impl AudioEncoder for OpusEncoder {
     fn reset(&mut self) -> crate::types::error::Result<()> {
         self.drop_encoder();
         self.capture_timestamps.clear();
-        self.encoder = Some(Self::create_encoder()?);
+        self.encoder = Some(Self::create_encoder(&self.config)?);
 
         Ok(())
     }
@@ -0,0 +1,93 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+
+use crate::types::{
+    audio_frame::{EncodedAudioFrame, RawAudioFrame},
+    config::{AudioEncoderConfig, AudioGain},
+    error::WaycapError,
+};
+
+use super::audio::{apply_gain, match_channels, AudioEncoder};
+
+/// Passes the captured samples through without encoding.
+///
+/// Each [`EncodedAudioFrame`] holds interleaved `f32` samples in the configured channel layout
+/// and sample rate as little endian bytes, and its `pts` counts samples per channel since the
+/// start of the capture. There is no ffmpeg codec behind this encoder so
+/// [`AudioEncoder::get_encoder`] always returns `None`.
+pub struct PcmEncoder {
+    encoder: Option<ffmpeg_next::codec::encoder::Audio>,
+    next_pts: i64,
+    n_channels: usize,
+    gain: AudioGain,
+    encoded_samples_recv: Option<Receiver<EncodedAudioFrame>>,
+    encoded_samples_sender: Sender<EncodedAudioFrame>,
+}
+
+impl AudioEncoder for PcmEncoder {
+    fn new(config: AudioEncoderConfig) -> crate::types::error::Result<Self>
+    where
+        Self: Sized,
+    {
+        let (frame_tx, frame_rx): (Sender<EncodedAudioFrame>, Receiver<EncodedAudioFrame>) =
+            bounded(config.buffer_frames);
+        Ok(Self {
+            encoder: None,
+            next_pts: 0,
+            n_channels: config.channel_layout.channels() as usize,
+            gain: config.gain,
+            encoded_samples_recv: Some(frame_rx),
+            encoded_samples_sender: frame_tx,
+        })
+    }
+
+    fn process(&mut self, raw_frame: RawAudioFrame) -> crate::types::error::Result<()> {
+        if raw_frame.samples.is_empty() {
+            return Ok(());
+        }
+
+        // Input is always stereo, the output may be mono
+        let mut samples = match_channels(raw_frame.samples, self.n_channels)?;
+        apply_gain(&mut samples, self.gain)?;
+        let data: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let pts = self.next_pts;
+        self.next_pts += (samples.len() / self.n_channels) as i64;
+
+        match self.encoded_samples_sender.try_send(EncodedAudioFrame::new(
+            data,
+            pts,
+            raw_frame.timestamp_ns,
+        )) {
+            Ok(_) => {}
+            Err(crossbeam::channel::TrySendError::Full(_)) => {
+                log::error!("Could not send encoded audio frame. Receiver is full");
+            }
+            Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
+                return Err(WaycapError::Disconnected);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg_next::codec::encoder::Audio> {
+        &self.encoder
+    }
+
+    fn drain(&mut self) -> crate::types::error::Result<()> {
+        Ok(())
+    }
+
+    fn drop_encoder(&mut self) {}
+
+    fn reset(&mut self) -> crate::types::error::Result<()> {
+        self.next_pts = 0;
+        Ok(())
+    }
+
+    fn get_encoded_recv(&mut self) -> Option<Receiver<EncodedAudioFrame>> {
+        self.encoded_samples_recv.clone()
+    }
+}
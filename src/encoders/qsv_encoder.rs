@@ -0,0 +1,529 @@
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::Arc;
+
+use crate::{
+    encoders::{
+        vaapi_encoder::drm_prime_frame,
+        video::{
+            drain_filter_graph, memory_frame, EncodedOutput, PipewireSPA, ProcessingThread,
+            VideoEncoder,
+        },
+    },
+    types::{
+        config::{QualityPreset, RateControl, VideoEncoderConfig},
+        error::{Result, WaycapError},
+        stats::FrameCounters,
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+    utils::TIME_UNIT_NS,
+};
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{
+        av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_create_derived,
+        av_hwframe_ctx_create_derived, av_hwframe_ctx_init, AVBufferRef, AVHWDeviceContext,
+        AVHWDeviceType, AVHWFramesContext, AVPixelFormat,
+    },
+    Rational,
+};
+use pipewire as pw;
+
+use super::video::{create_hw_device, create_hw_frame_ctx, with_drm_device};
+
+/// Encoder which encodes frames using Intel Quick Sync
+///
+/// Frames are imported and scaled through VAAPI like [`crate::VaapiEncoder`] does, then mapped
+/// to QSV surfaces for encoding.
+pub struct QsvEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Video>,
+    width: u32,
+    height: u32,
+    encoder_name: String,
+    config: VideoEncoderConfig,
+    output: EncodedOutput,
+    filter_graph: Option<ffmpeg::filter::Graph>,
+    /// Uploads frames in memory instead of mapping DMA-BUFs, created on the first such frame
+    upload_graph: Option<ffmpeg::filter::Graph>,
+    /// Capture timestamp of the first frame, for zero based PTS
+    first_timestamp: Option<i64>,
+    /// VAAPI frames the QSV frames are derived from, DMA-BUF frames are imported into these
+    vaapi_frames_ctx: *mut AVBufferRef,
+}
+
+unsafe impl Send for QsvEncoder {}
+unsafe impl Sync for QsvEncoder {}
+
+impl ProcessingThread for QsvEncoder {
+    fn request_keyframe(&mut self) {
+        self.output.force_keyframe = true;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.reset()
+    }
+
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        self.output.counters = counters;
+    }
+
+    fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            if frame.dmabuf_fd.is_some() {
+                let mut drm_frame = drm_prime_frame(&frame, self.vaapi_frames_ctx)?;
+                drm_frame.set_pts(Some(
+                    self.config
+                        .frame_pts(&mut self.first_timestamp, frame.timestamp),
+                ));
+                self.output.encode_filtered(
+                    self.filter_graph.as_mut().unwrap(),
+                    encoder,
+                    &drm_frame,
+                )?;
+            } else if !frame.data.is_empty() {
+                // The compositor didn't share a DMA-BUF, copy the frame to a VAAPI surface
+                let upload_graph = match self.upload_graph {
+                    Some(ref mut graph) => graph,
+                    None => {
+                        log::info!("Got a frame in memory, uploading frames to VAAPI surfaces");
+                        self.upload_graph.insert(Self::create_filter_graph(
+                            encoder,
+                            self.vaapi_frames_ctx,
+                            self.width,
+                            self.height,
+                            &self.config,
+                            true,
+                        )?)
+                    }
+                };
+                let mut sw_frame = memory_frame(&frame, self.config.bit_depth)?;
+                sw_frame.set_pts(Some(
+                    self.config
+                        .frame_pts(&mut self.first_timestamp, frame.timestamp),
+                ));
+                self.output
+                    .encode_filtered(upload_graph, encoder, &sw_frame)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VideoEncoder for QsvEncoder {
+    type Output = EncodedVideoFrame;
+    fn reset(&mut self) -> Result<()> {
+        self.drop_processor();
+        let (new_encoder, vaapi_frames_ctx) = Self::create_encoder(
+            self.width,
+            self.height,
+            &self.encoder_name,
+            &mut self.config,
+        )?;
+
+        let new_filter_graph = Self::create_filter_graph(
+            &new_encoder,
+            vaapi_frames_ctx,
+            self.width,
+            self.height,
+            &self.config,
+            false,
+        )?;
+
+        self.encoder = Some(new_encoder);
+        self.filter_graph = Some(new_filter_graph);
+        self.vaapi_frames_ctx = vaapi_frames_ctx;
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
+    fn drop_processor(&mut self) {
+        self.encoder.take();
+        self.filter_graph.take();
+        self.upload_graph.take();
+        if !self.vaapi_frames_ctx.is_null() {
+            unsafe { av_buffer_unref(&mut self.vaapi_frames_ctx) };
+        }
+    }
+
+    fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
+        Some(self.output.receiver())
+    }
+
+    fn close_output(&mut self) {
+        self.output.close();
+    }
+
+    /// Drain the filter graph and encoder of any remaining frames it is processing
+    fn drain(&mut self) -> Result<()> {
+        self.drain_filter_graphs()?;
+        self.output.discard(self.encoder.as_mut())
+    }
+
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedVideoFrame>> {
+        self.drain_filter_graphs()?;
+        self.output.drain_frames(self.encoder.as_mut())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.encoder
+    }
+}
+
+impl PipewireSPA for QsvEncoder {
+    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        Ok(pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::Raw
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoModifier,
+                Long,
+                0
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
+                Id,
+                pw::spa::param::video::VideoFormat::NV12,
+                pw::spa::param::video::VideoFormat::I420,
+                pw::spa::param::video::VideoFormat::BGRA,
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoSize,
+                Choice,
+                Range,
+                Rectangle,
+                pw::spa::utils::Rectangle {
+                    width: 2560,
+                    height: 1440
+                }, // Default
+                pw::spa::utils::Rectangle {
+                    width: 1,
+                    height: 1
+                }, // Min
+                pw::spa::utils::Rectangle {
+                    width: 4096,
+                    height: 4096
+                } // Max
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFramerate,
+                Choice,
+                Range,
+                Fraction,
+                pw::spa::utils::Fraction { num: 240, denom: 1 }, // Default
+                pw::spa::utils::Fraction { num: 0, denom: 1 },   // Min
+                pw::spa::utils::Fraction { num: 244, denom: 1 }  // Max
+            ),
+        ))
+    }
+}
+
+impl QsvEncoder {
+    /// Create a new QSV encoder using the given ffmpeg encoder name (e.g. `h264_qsv`).
+    pub(crate) fn new(
+        encoder_name: &str,
+        width: u32,
+        height: u32,
+        mut config: VideoEncoderConfig,
+    ) -> Result<Self> {
+        let (encoder, vaapi_frames_ctx) =
+            Self::create_encoder(width, height, encoder_name, &mut config)?;
+
+        let filter_graph = Some(Self::create_filter_graph(
+            &encoder,
+            vaapi_frames_ctx,
+            width,
+            height,
+            &config,
+            false,
+        )?);
+
+        Ok(Self {
+            encoder: Some(encoder),
+            width,
+            height,
+            encoder_name: encoder_name.to_string(),
+            output: EncodedOutput::new(config.buffer_frames, config.bitstream_format),
+            config,
+            filter_graph,
+            upload_graph: None,
+            first_timestamp: None,
+            vaapi_frames_ctx,
+        })
+    }
+
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.config.quality = quality;
+        self.reset()
+    }
+
+    pub(crate) fn rotate_segment(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.output.segment_pending = true;
+        self.reset()
+    }
+
+    /// Send the frames still in the filter graphs to the encoder
+    fn drain_filter_graphs(&mut self) -> Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            for graph in [&mut self.filter_graph, &mut self.upload_graph]
+                .into_iter()
+                .flatten()
+            {
+                drain_filter_graph(graph, encoder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the encoder on the configured DRM device, or the first one it works on which
+    /// is then kept in `config` for later resets
+    fn create_encoder(
+        width: u32,
+        height: u32,
+        encoder: &str,
+        config: &mut VideoEncoderConfig,
+    ) -> Result<(ffmpeg::codec::encoder::Video, *mut AVBufferRef)> {
+        let (encoder, drm_device) = with_drm_device(config.drm_device.as_deref(), |device| {
+            Self::open_encoder(width, height, encoder, config, device)
+        })?;
+        config.drm_device = Some(drm_device);
+        Ok(encoder)
+    }
+
+    fn open_encoder(
+        width: u32,
+        height: u32,
+        encoder: &str,
+        config: &VideoEncoderConfig,
+        drm_device: &Path,
+    ) -> Result<(ffmpeg::codec::encoder::Video, *mut AVBufferRef)> {
+        let (width, height) = config.output_size(width, height);
+        let encoder_codec =
+            ffmpeg::codec::encoder::find_by_name(encoder).ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .video()?;
+
+        encoder_ctx.set_width(width);
+        encoder_ctx.set_height(height);
+        encoder_ctx.set_format(ffmpeg::format::Pixel::QSV);
+        if let Some((bitrate, max_bitrate)) = config.rate_control().bitrates() {
+            encoder_ctx.set_bit_rate(bitrate as usize);
+            encoder_ctx.set_max_bit_rate(max_bitrate as usize);
+        }
+        encoder_ctx.set_color_range(config.color_range.ffmpeg_range());
+        encoder_ctx.set_colorspace(config.color_space.ffmpeg_space());
+
+        // QSV on Linux runs on top of VAAPI, so open the VAAPI device and derive QSV from it
+        let mut vaapi_device =
+            create_hw_device(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI, drm_device)?;
+        let mut vaapi_frames_ctx = create_hw_frame_ctx(vaapi_device)?;
+
+        unsafe {
+            let mut qsv_device: *mut AVBufferRef = null_mut();
+            let err = av_hwdevice_ctx_create_derived(
+                &mut qsv_device,
+                AVHWDeviceType::AV_HWDEVICE_TYPE_QSV,
+                vaapi_device,
+                0,
+            );
+            if err < 0 {
+                av_buffer_unref(&mut vaapi_frames_ctx);
+                av_buffer_unref(&mut vaapi_device);
+                return Err(WaycapError::Init(format!(
+                    "Could not create a QSV device from VAAPI: {err:?}",
+                )));
+            }
+
+            let hw_frame_context = &mut *((*vaapi_frames_ctx).data as *mut AVHWFramesContext);
+            hw_frame_context.width = width as i32;
+            hw_frame_context.height = height as i32;
+            hw_frame_context.sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+            hw_frame_context.format = AVPixelFormat::AV_PIX_FMT_VAAPI;
+            hw_frame_context.device_ref = av_buffer_ref(vaapi_device);
+            hw_frame_context.device_ctx = (*vaapi_device).data as *mut AVHWDeviceContext;
+            // QSV needs a fixed size pool to derive its frames from
+            hw_frame_context.initial_pool_size = config.hw_pool_size(4);
+
+            let err = av_hwframe_ctx_init(vaapi_frames_ctx);
+            if err < 0 {
+                av_buffer_unref(&mut qsv_device);
+                av_buffer_unref(&mut vaapi_frames_ctx);
+                av_buffer_unref(&mut vaapi_device);
+                return Err(WaycapError::Init(format!(
+                    "Error trying to initialize hw frame context: {err:?}",
+                )));
+            }
+
+            let mut qsv_frames_ctx: *mut AVBufferRef = null_mut();
+            let err = av_hwframe_ctx_create_derived(
+                &mut qsv_frames_ctx,
+                AVPixelFormat::AV_PIX_FMT_QSV,
+                qsv_device,
+                vaapi_frames_ctx,
+                0,
+            );
+            if err < 0 {
+                av_buffer_unref(&mut qsv_device);
+                av_buffer_unref(&mut vaapi_frames_ctx);
+                av_buffer_unref(&mut vaapi_device);
+                return Err(WaycapError::Init(format!(
+                    "Could not derive QSV frames from VAAPI frames: {err:?}",
+                )));
+            }
+
+            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = av_buffer_ref(qsv_device);
+            (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = av_buffer_ref(qsv_frames_ctx);
+
+            av_buffer_unref(&mut qsv_frames_ctx);
+            av_buffer_unref(&mut qsv_device);
+            av_buffer_unref(&mut vaapi_device);
+        }
+
+        encoder_ctx.set_time_base(Rational::new(1, TIME_UNIT_NS as i32));
+
+        // Needed to insert I-Frames more frequently so we don't lose full seconds
+        // when popping frames from the front
+        encoder_ctx.set_gop(config.gop_size);
+
+        let encoder_params = ffmpeg::codec::Parameters::new();
+
+        let opts = Self::get_encoder_params(config);
+
+        encoder_ctx.set_parameters(encoder_params)?;
+        let encoder = match encoder_ctx.open_with(opts) {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                unsafe { av_buffer_unref(&mut vaapi_frames_ctx) };
+                return Err(e.into());
+            }
+        };
+        Ok((encoder, vaapi_frames_ctx))
+    }
+
+    fn get_encoder_params(config: &VideoEncoderConfig) -> ffmpeg::Dictionary<'_> {
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("vsync", "vfr");
+
+        let preset = match config.quality {
+            QualityPreset::Low => "veryfast",
+            QualityPreset::Medium => "medium",
+            QualityPreset::High => "slow",
+            QualityPreset::Ultra => "veryslow",
+        };
+        opts.set("preset", preset);
+        if let Some(depth) = config.async_depth {
+            opts.set("async_depth", &depth.to_string());
+        }
+
+        // ICQ for constant quality, otherwise QSV picks CBR or VBR from the bitrates set on
+        // the encoder context
+        if let RateControl::ConstantQuality(quality) = config.rate_control() {
+            opts.set("global_quality", &quality.to_string());
+        }
+        opts
+    }
+
+    /// Graph turning captured frames into QSV surfaces, through VAAPI surfaces which DMA-BUFs
+    /// are mapped to. With `upload` frames in memory are copied to new VAAPI surfaces instead.
+    fn create_filter_graph(
+        encoder: &ffmpeg::codec::encoder::Video,
+        vaapi_frames_ctx: *mut AVBufferRef,
+        width: u32,
+        height: u32,
+        config: &VideoEncoderConfig,
+        upload: bool,
+    ) -> Result<ffmpeg::filter::Graph> {
+        let mut graph = ffmpeg::filter::Graph::new();
+
+        let args = format!("video_size={width}x{height}:pix_fmt=bgra:time_base=1/{TIME_UNIT_NS}",);
+
+        let mut input = graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &args)?;
+
+        let mut vaapi_map = if upload {
+            graph.add(&ffmpeg::filter::find("hwupload").unwrap(), "vaapi_map", "")?
+        } else {
+            graph.add(
+                &ffmpeg::filter::find("hwmap").unwrap(),
+                "vaapi_map",
+                "mode=read+write:derive_device=vaapi",
+            )?
+        };
+
+        // Cropping hardware frames only sets the crop fields, scale_vaapi then reads just
+        // that part of the surface
+        let (out_width, out_height) = config.output_size(width, height);
+        let (crop_width, crop_height) = config.crop_size(width, height);
+        let (x, y) = config.region.map_or((0, 0), |region| (region.x, region.y));
+        let crop_args = format!("w={crop_width}:h={crop_height}:x={x}:y={y}");
+        let mut crop = graph.add(&ffmpeg::filter::find("crop").unwrap(), "crop", &crop_args)?;
+
+        let scale_args = format!(
+            "w={out_width}:h={out_height}:format=nv12:out_range={}:out_color_matrix={}",
+            config.color_range.filter_name(),
+            config.color_space.vaapi_name()
+        );
+        let mut scale = graph.add(
+            &ffmpeg::filter::find("scale_vaapi").unwrap(),
+            "scale",
+            &scale_args,
+        )?;
+
+        let mut qsv_map = graph.add(
+            &ffmpeg::filter::find("hwmap").unwrap(),
+            "qsv_map",
+            "derive_device=qsv",
+        )?;
+
+        let mut out = graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+        unsafe {
+            let vaapi_frames = (*vaapi_frames_ctx).data as *mut AVHWFramesContext;
+            (*vaapi_map.as_mut_ptr()).hw_device_ctx = av_buffer_ref((*vaapi_frames).device_ref);
+
+            let qsv_device = (*encoder.as_ptr()).hw_device_ctx;
+            (*qsv_map.as_mut_ptr()).hw_device_ctx = av_buffer_ref(qsv_device);
+        }
+
+        input.link(0, &mut vaapi_map, 0);
+        vaapi_map.link(0, &mut crop, 0);
+        crop.link(0, &mut scale, 0);
+        scale.link(0, &mut qsv_map, 0);
+        qsv_map.link(0, &mut out, 0);
+
+        graph.validate()?;
+        log::trace!("QSV Graph\n{}", graph.dump());
+
+        Ok(graph)
+    }
+}
+
+impl Drop for QsvEncoder {
+    fn drop(&mut self) {
+        if let Err(e) = self.drain() {
+            log::error!("Error while draining qsv encoder during drop: {e:?}");
+        }
+        self.drop_processor();
+    }
+}
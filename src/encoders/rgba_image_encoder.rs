@@ -1,21 +1,31 @@
+use std::sync::Arc;
+
 use crate::{
     encoders::video::{PipewireSPA, ProcessingThread},
-    types::video_frame::RawVideoFrame,
+    types::{error::WaycapError, stats::FrameCounters, video_frame::RawVideoFrame},
     VideoEncoder,
 };
 use crossbeam::channel::{Receiver, Sender};
 
 use crate::types::error::Result;
 use pipewire as pw;
+use pw::spa::param::video::VideoFormat;
+
+const BYTES_PER_PIXEL: usize = 4;
 
 /// "Encoder" which outputs image::RgbaImage
 ///
 /// This is entirely CPU side, and won't ever be as fast as [`NvencEncoder`] or [`VaapiEncoder`].
 /// Don't use this to record video!
 /// It will likely benefit from compile time optimizations a lot, due to the BGRA to RGBA image conversion.
+///
+/// BGRA is preferred, but compositors which only hand out I420 or NV12 for software readback
+/// work too, those frames are converted from YUV to RGBA.
 pub struct RgbaImageEncoder {
     image_sender: Sender<image::RgbaImage>,
     image_receiver: Receiver<image::RgbaImage>,
+    bgra_output: bool,
+    counters: Arc<FrameCounters>,
 }
 
 impl Default for RgbaImageEncoder {
@@ -24,24 +34,72 @@ impl Default for RgbaImageEncoder {
         Self {
             image_sender,
             image_receiver,
+            bgra_output: false,
+            counters: Arc::default(),
         }
     }
 }
 
+impl RgbaImageEncoder {
+    /// Skip the BGRA to RGBA conversion and output the bytes as PipeWire delivers them.
+    ///
+    /// The images still come as [`image::RgbaImage`] but hold BGRA pixels, useful when the
+    /// consumer (e.g. a GPU texture upload) wants BGRA anyway.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use waycap_rs::{Capture, RgbaImageEncoder};
+    /// # use waycap_rs::types::error::Result;
+    /// # fn thing() -> Result<()> {
+    /// let mut capture =
+    ///     Capture::new_with_encoder(RgbaImageEncoder::default().with_bgra_output(), false, 30)?;
+    /// let bgra_frame = capture.get_output().recv().unwrap();
+    /// # Ok(())}
+    /// ```
+    pub fn with_bgra_output(mut self) -> Self {
+        self.bgra_output = true;
+        self
+    }
+}
+
 impl ProcessingThread for RgbaImageEncoder {
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        self.counters = counters;
+    }
+
     fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
-        let mut raw = frame.data.clone();
-        bgra_to_rgba_inplace(&mut raw);
-        let image =
-            image::RgbaImage::from_raw(frame.dimensions.width, frame.dimensions.height, raw)
-                .unwrap();
+        let (width, height) = (frame.dimensions.width, frame.dimensions.height);
+        let (format, received) = (frame.format, frame.data.len());
+        let pixels = match format {
+            VideoFormat::BGRA => packed_rows(frame).map(|mut raw| {
+                if !self.bgra_output {
+                    bgra_to_rgba_inplace(&mut raw);
+                }
+                raw
+            }),
+            VideoFormat::I420 | VideoFormat::NV12 => yuv420_to_rgba(&frame),
+            format => {
+                return Err(WaycapError::Encoding(format!(
+                    "Can't convert {format:?} frames to images"
+                )))
+            }
+        };
+        let Some(image) =
+            pixels.and_then(|pixels| image::RgbaImage::from_raw(width, height, pixels))
+        else {
+            self.counters.frame_dropped();
+            log::error!("{format:?} frame of {received} bytes is too small for {width}x{height}");
+            return Ok(());
+        };
         match self.image_sender.try_send(image) {
-            Ok(_) => {}
+            Ok(_) => self.counters.frame_encoded(),
             Err(crossbeam::channel::TrySendError::Full(_)) => {
+                self.counters.frame_dropped();
                 log::error!("Could not send encoded video frame. Receiver is full");
             }
             Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                log::error!("Could not send encoded video frame. Receiver disconnected");
+                return Err(WaycapError::Disconnected);
             }
         }
         Ok(())
@@ -85,10 +143,16 @@ impl PipewireSPA for RgbaImageEncoder {
                 Id,
                 pw::spa::param::format::MediaSubtype::Raw
             ),
+            // BGRA is the default, the alternatives list it again to keep it acceptable
             pw::spa::pod::property!(
                 pw::spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
                 Id,
-                pw::spa::param::video::VideoFormat::BGRA
+                pw::spa::param::video::VideoFormat::BGRA,
+                pw::spa::param::video::VideoFormat::BGRA,
+                pw::spa::param::video::VideoFormat::I420,
+                pw::spa::param::video::VideoFormat::NV12,
             ),
             pw::spa::pod::property!(
                 pw::spa::param::format::FormatProperties::VideoSize,
@@ -136,3 +200,89 @@ pub fn bgra_to_rgba_inplace(buf: &mut [u8]) {
         *p = rgba.to_be_bytes();
     }
 }
+
+/// Rows of a packed 4 byte per pixel frame without the padding of `frame.stride`, `None` when
+/// the frame holds less data than its size needs
+fn packed_rows(mut frame: RawVideoFrame) -> Option<Vec<u8>> {
+    let (width, height) = (
+        frame.dimensions.width as usize,
+        frame.dimensions.height as usize,
+    );
+    let row_len = width * BYTES_PER_PIXEL;
+    let stride = row_stride(frame.stride, row_len);
+
+    // Tightly packed frames are handed on without copying
+    if stride == row_len && frame.offset == 0 && frame.data.len() >= row_len * height {
+        frame.data.truncate(row_len * height);
+        return Some(frame.data);
+    }
+    let data = frame.data.get(frame.offset as usize..)?;
+    let mut pixels = Vec::with_capacity(row_len * height);
+    for row in 0..height {
+        pixels.extend_from_slice(data.get(row * stride..row * stride + row_len)?);
+    }
+    Some(pixels)
+}
+
+/// Convert an I420 or NV12 frame to RGBA, `None` when the frame holds less data than its size
+/// needs.
+///
+/// The planes are expected one after another in `frame.data`, starting at `frame.offset`. The
+/// luma plane uses `frame.stride`, I420 chroma planes half of it and the interleaved NV12
+/// chroma plane all of it. Colors are converted with BT.601 limited range coefficients.
+fn yuv420_to_rgba(frame: &RawVideoFrame) -> Option<Vec<u8>> {
+    let (width, height) = (
+        frame.dimensions.width as usize,
+        frame.dimensions.height as usize,
+    );
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let y_stride = row_stride(frame.stride, width);
+    let data = frame.data.get(frame.offset as usize..)?;
+
+    let y_plane = data.get(..y_stride * height)?;
+    let chroma = &data[y_plane.len()..];
+    // Offsets of the U and V samples of a chroma pixel within its row, and the row stride
+    let (u_offset, v_offset, step, chroma_stride) = if frame.format == VideoFormat::NV12 {
+        (0, 1, 2, y_stride.max(chroma_width * 2))
+    } else {
+        let stride = y_stride.div_ceil(2).max(chroma_width);
+        (0, stride * chroma_height, 1, stride)
+    };
+    let chroma_len = if frame.format == VideoFormat::NV12 {
+        chroma_stride * chroma_height
+    } else {
+        chroma_stride * chroma_height * 2
+    };
+    let chroma = chroma.get(..chroma_len)?;
+
+    let mut pixels = Vec::with_capacity(width * height * BYTES_PER_PIXEL);
+    for row in 0..height {
+        let y_row = &y_plane[row * y_stride..];
+        let chroma_row = &chroma[row / 2 * chroma_stride..];
+        for x in 0..width {
+            let c = y_row[x] as i32 - 16;
+            let d = chroma_row[u_offset + x / 2 * step] as i32 - 128;
+            let e = chroma_row[v_offset + x / 2 * step] as i32 - 128;
+            pixels.extend_from_slice(&[
+                clamp_channel(298 * c + 409 * e + 128),
+                clamp_channel(298 * c - 100 * d - 208 * e + 128),
+                clamp_channel(298 * c + 516 * d + 128),
+                255,
+            ]);
+        }
+    }
+    Some(pixels)
+}
+
+/// `stride` of a frame, or `row_len` when the producer didn't set one
+fn row_stride(stride: i32, row_len: usize) -> usize {
+    match stride {
+        stride if stride > 0 => (stride as usize).max(row_len),
+        _ => row_len,
+    }
+}
+
+/// Scale a fixed point color channel back to 8 bit
+fn clamp_channel(value: i32) -> u8 {
+    (value >> 8).clamp(0, 255) as u8
+}
@@ -1,28 +1,33 @@
+use std::path::Path;
 use std::ptr::null_mut;
+use std::sync::Arc;
 
 use crate::{
-    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder},
+    encoders::video::{
+        drain_filter_graph, memory_frame, EncodedOutput, PipewireSPA, ProcessingThread,
+        VideoEncoder,
+    },
     types::{
-        config::QualityPreset,
+        config::{BitDepth, QualityPreset, RateControl, VideoEncoderConfig},
         error::{Result, WaycapError},
+        stats::FrameCounters,
         video_frame::{EncodedVideoFrame, RawVideoFrame},
     },
-    utils::TIME_UNIT_NS,
+    utils::{drm_descriptor, TIME_UNIT_NS},
 };
-use crossbeam::channel::{bounded, Receiver, Sender};
-use drm_fourcc::DrmFourcc;
+use crossbeam::channel::Receiver;
 use ffmpeg_next::{
     self as ffmpeg,
     ffi::{
         av_buffer_create, av_buffer_default_free, av_buffer_ref, av_buffer_unref,
-        av_hwframe_ctx_init, AVDRMFrameDescriptor, AVHWDeviceContext, AVHWFramesContext,
-        AVPixelFormat,
+        av_hwframe_ctx_init, AVBufferRef, AVDRMFrameDescriptor, AVHWDeviceContext,
+        AVHWFramesContext, AVPixelFormat,
     },
     Rational,
 };
 use pipewire as pw;
 
-use super::video::{create_hw_device, create_hw_frame_ctx, GOP_SIZE};
+use super::video::{create_hw_device, create_hw_frame_ctx, with_drm_device};
 
 /// Encoder which encodes frames using Vaapi
 pub struct VaapiEncoder {
@@ -30,97 +35,65 @@ pub struct VaapiEncoder {
     width: u32,
     height: u32,
     encoder_name: String,
-    quality: QualityPreset,
-    encoded_frame_recv: Option<Receiver<EncodedVideoFrame>>,
-    encoded_frame_sender: Sender<EncodedVideoFrame>,
+    config: VideoEncoderConfig,
+    output: EncodedOutput,
     filter_graph: Option<ffmpeg::filter::Graph>,
+    /// Uploads frames in memory instead of mapping DMA-BUFs, created on the first such frame
+    upload_graph: Option<ffmpeg::filter::Graph>,
+    /// Capture timestamp of the first frame, for zero based PTS
+    first_timestamp: Option<i64>,
 }
 
 impl ProcessingThread for VaapiEncoder {
+    fn request_keyframe(&mut self) {
+        self.output.force_keyframe = true;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.reset()
+    }
+
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        self.output.counters = counters;
+    }
+
     fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
         if let Some(ref mut encoder) = self.encoder {
-            if let Some(fd) = frame.dmabuf_fd {
-                let mut drm_frame = ffmpeg::util::frame::Video::new(
-                    ffmpeg_next::format::Pixel::DRM_PRIME,
-                    encoder.width(),
-                    encoder.height(),
-                );
-                unsafe {
-                    // Create DRM descriptor that points to the DMA buffer
-                    let drm_desc =
-                        Box::into_raw(Box::new(std::mem::zeroed::<AVDRMFrameDescriptor>()));
-
-                    (*drm_desc).nb_objects = 1;
-                    (*drm_desc).objects[0].fd = fd;
-                    (*drm_desc).objects[0].size = 0;
-                    (*drm_desc).objects[0].format_modifier = 0;
-
-                    (*drm_desc).nb_layers = 1;
-                    (*drm_desc).layers[0].format = DrmFourcc::Argb8888 as u32;
-                    (*drm_desc).layers[0].nb_planes = 1;
-                    (*drm_desc).layers[0].planes[0].object_index = 0;
-                    (*drm_desc).layers[0].planes[0].offset = frame.offset as isize;
-                    (*drm_desc).layers[0].planes[0].pitch = frame.stride as isize;
-
-                    // Attach descriptor to frame
-                    (*drm_frame.as_mut_ptr()).data[0] = drm_desc as *mut u8;
-                    (*drm_frame.as_mut_ptr()).buf[0] = av_buffer_create(
-                        drm_desc as *mut u8,
-                        std::mem::size_of::<AVDRMFrameDescriptor>(),
-                        Some(av_buffer_default_free),
-                        null_mut(),
-                        0,
-                    );
-
-                    (*drm_frame.as_mut_ptr()).hw_frames_ctx =
-                        av_buffer_ref((*encoder.as_ptr()).hw_frames_ctx);
-                }
-
-                drm_frame.set_pts(Some(frame.timestamp));
-                self.filter_graph
-                    .as_mut()
-                    .unwrap()
-                    .get("in")
-                    .unwrap()
-                    .source()
-                    .add(&drm_frame)
-                    .unwrap();
-
-                let mut filtered = ffmpeg::util::frame::Video::empty();
-                if self
-                    .filter_graph
-                    .as_mut()
-                    .unwrap()
-                    .get("out")
-                    .unwrap()
-                    .sink()
-                    .frame(&mut filtered)
-                    .is_ok()
-                {
-                    encoder.send_frame(&filtered)?;
-                }
-            }
-
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            if encoder.receive_packet(&mut packet).is_ok() {
-                if let Some(data) = packet.data() {
-                    match self.encoded_frame_sender.try_send(EncodedVideoFrame {
-                        data: data.to_vec(),
-                        is_keyframe: packet.is_key(),
-                        pts: packet.pts().unwrap_or(0),
-                        dts: packet.dts().unwrap_or(0),
-                    }) {
-                        Ok(_) => {}
-                        Err(crossbeam::channel::TrySendError::Full(_)) => {
-                            log::error!("Could not send encoded video frame. Receiver is full");
-                        }
-                        Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                            log::error!(
-                                "Could not send encoded video frame. Receiver disconnected"
-                            );
-                        }
+            if frame.dmabuf_fd.is_some() {
+                let mut drm_frame =
+                    drm_prime_frame(&frame, unsafe { (*encoder.as_ptr()).hw_frames_ctx })?;
+                drm_frame.set_pts(Some(
+                    self.config
+                        .frame_pts(&mut self.first_timestamp, frame.timestamp),
+                ));
+                self.output.encode_filtered(
+                    self.filter_graph.as_mut().unwrap(),
+                    encoder,
+                    &drm_frame,
+                )?;
+            } else if !frame.data.is_empty() {
+                // The compositor didn't share a DMA-BUF, copy the frame to a VAAPI surface
+                let upload_graph = match self.upload_graph {
+                    Some(ref mut graph) => graph,
+                    None => {
+                        log::info!("Got a frame in memory, uploading frames to VAAPI surfaces");
+                        self.upload_graph.insert(Self::create_filter_graph(
+                            encoder,
+                            self.width,
+                            self.height,
+                            &self.config,
+                            true,
+                        )?)
                     }
                 };
+                let mut sw_frame = memory_frame(&frame, self.config.bit_depth)?;
+                sw_frame.set_pts(Some(
+                    self.config
+                        .frame_pts(&mut self.first_timestamp, frame.timestamp),
+                ));
+                self.output
+                    .encode_filtered(upload_graph, encoder, &sw_frame)?;
             }
         }
         Ok(())
@@ -131,49 +104,51 @@ impl VideoEncoder for VaapiEncoder {
     type Output = EncodedVideoFrame;
     fn reset(&mut self) -> Result<()> {
         self.drop_processor();
-        let new_encoder =
-            Self::create_encoder(self.width, self.height, &self.encoder_name, &self.quality)?;
+        let new_encoder = Self::create_encoder(
+            self.width,
+            self.height,
+            &self.encoder_name,
+            &mut self.config,
+        )?;
 
-        let new_filter_graph = Self::create_filter_graph(&new_encoder, self.width, self.height)?;
+        let new_filter_graph =
+            Self::create_filter_graph(&new_encoder, self.width, self.height, &self.config, false)?;
 
         self.encoder = Some(new_encoder);
         self.filter_graph = Some(new_filter_graph);
         Ok(())
     }
 
+    fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
     fn drop_processor(&mut self) {
         self.encoder.take();
         self.filter_graph.take();
+        self.upload_graph.take();
     }
 
     fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
-        self.encoded_frame_recv.clone()
+        Some(self.output.receiver())
+    }
+
+    fn close_output(&mut self) {
+        self.output.close();
     }
 
     /// Drain the filter graph and encoder of any remaining frames it is processing
     fn drain(&mut self) -> Result<()> {
-        if let Some(ref mut encoder) = self.encoder {
-            // Drain the filter graph
-            let mut filtered = ffmpeg::util::frame::Video::empty();
-            while self
-                .filter_graph
-                .as_mut()
-                .unwrap()
-                .get("out")
-                .unwrap()
-                .sink()
-                .frame(&mut filtered)
-                .is_ok()
-            {
-                encoder.send_frame(&filtered)?;
-            }
+        self.drain_filter_graphs()?;
+        self.output.discard(self.encoder.as_mut())
+    }
 
-            // Drain encoder
-            encoder.send_eof()?;
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {} // Discard these frames
-        }
-        Ok(())
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedVideoFrame>> {
+        self.drain_filter_graphs()?;
+        self.output.drain_frames(self.encoder.as_mut())
     }
     fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
         &self.encoder
@@ -241,32 +216,109 @@ impl PipewireSPA for VaapiEncoder {
 }
 
 impl VaapiEncoder {
-    pub(crate) fn new(width: u32, height: u32, quality: QualityPreset) -> Result<Self> {
-        let encoder_name = "h264_vaapi";
-        let encoder = Self::create_encoder(width, height, encoder_name, &quality)?;
+    /// Same as [`PipewireSPA::get_spa_definition`] but only offering 10-bit formats
+    pub(crate) fn get_10bit_spa_definition() -> Result<pw::spa::pod::Object> {
+        let mut object = Self::get_spa_definition()?;
+        let formats = pw::spa::pod::property!(
+            pw::spa::param::format::FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            pw::spa::param::video::VideoFormat::P010_10LE,
+            pw::spa::param::video::VideoFormat::xRGB_210LE,
+            pw::spa::param::video::VideoFormat::xBGR_210LE,
+        );
+        for property in object.properties.iter_mut() {
+            if property.key == formats.key {
+                *property = formats.clone();
+            }
+        }
+        Ok(object)
+    }
+
+    /// Create a new VAAPI encoder using the given ffmpeg encoder name
+    /// (e.g. `h264_vaapi`, `hevc_vaapi` or `vp9_vaapi`).
+    ///
+    /// All of them take NV12 surfaces so the filter graph is the same. ffmpeg flags VP9
+    /// keyframes on the packet like it does for H.264/H.265, so `is_keyframe` holds for all.
+    pub(crate) fn new(
+        encoder_name: &str,
+        width: u32,
+        height: u32,
+        mut config: VideoEncoderConfig,
+    ) -> Result<Self> {
+        let encoder = Self::create_encoder(width, height, encoder_name, &mut config)?;
 
-        let (frame_tx, frame_rx): (Sender<EncodedVideoFrame>, Receiver<EncodedVideoFrame>) =
-            bounded(10);
-        let filter_graph = Some(Self::create_filter_graph(&encoder, width, height)?);
+        let filter_graph = Some(Self::create_filter_graph(
+            &encoder, width, height, &config, false,
+        )?);
 
         Ok(Self {
             encoder: Some(encoder),
             width,
             height,
             encoder_name: encoder_name.to_string(),
-            quality,
-            encoded_frame_recv: Some(frame_rx),
-            encoded_frame_sender: frame_tx,
+            output: EncodedOutput::new(config.buffer_frames, config.bitstream_format),
+            config,
             filter_graph,
+            upload_graph: None,
+            first_timestamp: None,
         })
     }
 
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.config.quality = quality;
+        self.reset()
+    }
+
+    pub(crate) fn rotate_segment(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.output.segment_pending = true;
+        self.reset()
+    }
+
+    /// Send the frames still in the filter graphs to the encoder
+    fn drain_filter_graphs(&mut self) -> Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            for graph in [&mut self.filter_graph, &mut self.upload_graph]
+                .into_iter()
+                .flatten()
+            {
+                drain_filter_graph(graph, encoder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the encoder on the configured DRM device, or the first one it works on which
+    /// is then kept in `config` for later resets
     fn create_encoder(
         width: u32,
         height: u32,
         encoder: &str,
-        quality: &QualityPreset,
+        config: &mut VideoEncoderConfig,
     ) -> Result<ffmpeg::codec::encoder::Video> {
+        if config.bit_depth == BitDepth::Ten && encoder != "hevc_vaapi" {
+            return Err(WaycapError::Init(format!(
+                "{encoder} does not support 10-bit encoding, use hevc_vaapi"
+            )));
+        }
+        let (encoder, drm_device) = with_drm_device(config.drm_device.as_deref(), |device| {
+            Self::open_encoder(width, height, encoder, config, device)
+        })?;
+        config.drm_device = Some(drm_device);
+        Ok(encoder)
+    }
+
+    fn open_encoder(
+        width: u32,
+        height: u32,
+        encoder: &str,
+        config: &VideoEncoderConfig,
+        drm_device: &Path,
+    ) -> Result<ffmpeg::codec::encoder::Video> {
+        let (width, height) = config.output_size(width, height);
         let encoder_codec =
             ffmpeg::codec::encoder::find_by_name(encoder).ok_or(ffmpeg::Error::EncoderNotFound)?;
 
@@ -277,23 +329,34 @@ impl VaapiEncoder {
         encoder_ctx.set_width(width);
         encoder_ctx.set_height(height);
         encoder_ctx.set_format(ffmpeg::format::Pixel::VAAPI);
+        if let Some((bitrate, max_bitrate)) = config.rate_control().bitrates() {
+            encoder_ctx.set_bit_rate(bitrate as usize);
+            encoder_ctx.set_max_bit_rate(max_bitrate as usize);
+        }
+        encoder_ctx.set_color_range(config.color_range.ffmpeg_range());
+        encoder_ctx.set_colorspace(config.color_space.ffmpeg_space());
         // Configuration inspiration from
         // https://git.dec05eba.com/gpu-screen-recorder/tree/src/capture/xcomposite_drm.c?id=8cbdb596ebf79587a432ed40583630b6cd39ed88
-        let mut vaapi_device =
-            create_hw_device(ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI)?;
+        let mut vaapi_device = create_hw_device(
+            ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+            drm_device,
+        )?;
         let mut frame_ctx = create_hw_frame_ctx(vaapi_device)?;
 
         unsafe {
             let hw_frame_context = &mut *((*frame_ctx).data as *mut AVHWFramesContext);
             hw_frame_context.width = width as i32;
             hw_frame_context.height = height as i32;
-            hw_frame_context.sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+            hw_frame_context.sw_format = match config.bit_depth {
+                BitDepth::Eight => AVPixelFormat::AV_PIX_FMT_NV12,
+                BitDepth::Ten => AVPixelFormat::AV_PIX_FMT_P010LE,
+            };
             hw_frame_context.format = encoder_ctx.format().into();
             hw_frame_context.device_ref = av_buffer_ref(vaapi_device);
             hw_frame_context.device_ctx = (*vaapi_device).data as *mut AVHWDeviceContext;
             // Decides buffer size if we do not pop frame from the encoder we cannot
             // keep pushing. Smaller better as we reserve less GPU memory
-            hw_frame_context.initial_pool_size = 2;
+            hw_frame_context.initial_pool_size = config.hw_pool_size(2);
 
             let err = av_hwframe_ctx_init(frame_ctx);
             if err < 0 {
@@ -314,56 +377,110 @@ impl VaapiEncoder {
 
         // Needed to insert I-Frames more frequently so we don't lose full seconds
         // when popping frames from the front
-        encoder_ctx.set_gop(GOP_SIZE);
+        encoder_ctx.set_gop(config.gop_size);
 
         let encoder_params = ffmpeg::codec::Parameters::new();
 
-        let opts = Self::get_encoder_params(quality);
+        let opts = Self::get_encoder_params(encoder, config);
 
         encoder_ctx.set_parameters(encoder_params)?;
         let encoder = encoder_ctx.open_with(opts)?;
         Ok(encoder)
     }
 
-    fn get_encoder_params(quality: &QualityPreset) -> ffmpeg::Dictionary<'_> {
+    fn get_encoder_params<'a>(
+        encoder: &str,
+        config: &VideoEncoderConfig,
+    ) -> ffmpeg::Dictionary<'a> {
         let mut opts = ffmpeg::Dictionary::new();
         opts.set("vsync", "vfr");
-        opts.set("rc", "VBR");
-        match quality {
-            QualityPreset::Low => {
-                opts.set("qp", "30");
-            }
-            QualityPreset::Medium => {
-                opts.set("qp", "25");
-            }
-            QualityPreset::High => {
-                opts.set("qp", "20");
+        if let Some(depth) = config.async_depth {
+            opts.set("async_depth", &depth.to_string());
+        }
+
+        if encoder == "vp9_vaapi" {
+            return Self::get_vp9_params(opts, config);
+        }
+
+        if config.bit_depth == BitDepth::Ten {
+            opts.set("profile", "main10");
+        }
+        if let Some(b_frames) = config.b_frames {
+            opts.set("bf", &b_frames.to_string());
+        }
+
+        // The bitrates themselves are set on the encoder context
+        match config.rate_control() {
+            RateControl::ConstantQuality(qp) => {
+                opts.set("rc_mode", "CQP");
+                opts.set("qp", &qp.to_string());
             }
-            QualityPreset::Ultra => {
-                opts.set("qp", "15");
+            RateControl::ConstantBitrate(_) => opts.set("rc_mode", "CBR"),
+            RateControl::VariableBitrate { .. } => opts.set("rc_mode", "VBR"),
+        }
+        opts
+    }
+
+    /// `vp9_vaapi` has no `qp` option, constant quality is set through `global_quality`
+    /// which is a VP9 quantizer index (0-255) instead
+    fn get_vp9_params<'a>(
+        mut opts: ffmpeg::Dictionary<'a>,
+        config: &VideoEncoderConfig,
+    ) -> ffmpeg::Dictionary<'a> {
+        match config.rate_control() {
+            RateControl::ConstantQuality(qp) => {
+                opts.set("rc_mode", "CQP");
+                opts.set("global_quality", &(qp as u32 * 5).min(255).to_string());
             }
+            RateControl::ConstantBitrate(_) => opts.set("rc_mode", "CBR"),
+            RateControl::VariableBitrate { .. } => opts.set("rc_mode", "VBR"),
         }
         opts
     }
 
+    /// Graph turning captured frames into surfaces for the encoder. DMA-BUFs are mapped to
+    /// VAAPI surfaces, with `upload` frames in memory are copied to new ones instead.
     fn create_filter_graph(
         encoder: &ffmpeg::codec::encoder::Video,
         width: u32,
         height: u32,
+        config: &VideoEncoderConfig,
+        upload: bool,
     ) -> Result<ffmpeg::filter::Graph> {
         let mut graph = ffmpeg::filter::Graph::new();
 
-        let args = format!("video_size={width}x{height}:pix_fmt=bgra:time_base=1/1000000",);
+        let (in_format, out_format) = match config.bit_depth {
+            BitDepth::Eight => ("bgra", "nv12"),
+            BitDepth::Ten => ("x2rgb10le", "p010"),
+        };
+        let args =
+            format!("video_size={width}x{height}:pix_fmt={in_format}:time_base=1/{TIME_UNIT_NS}",);
 
         let mut input = graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &args)?;
 
-        let mut hwmap = graph.add(
-            &ffmpeg::filter::find("hwmap").unwrap(),
-            "hwmap",
-            "mode=read+write:derive_device=vaapi",
-        )?;
-
-        let scale_args = format!("w={width}:h={height}:format=nv12:out_range=tv");
+        let mut hwmap = if upload {
+            graph.add(&ffmpeg::filter::find("hwupload").unwrap(), "hwmap", "")?
+        } else {
+            graph.add(
+                &ffmpeg::filter::find("hwmap").unwrap(),
+                "hwmap",
+                "mode=read+write:derive_device=vaapi",
+            )?
+        };
+
+        // Cropping hardware frames only sets the crop fields, scale_vaapi then reads just
+        // that part of the surface
+        let (out_width, out_height) = config.output_size(width, height);
+        let (crop_width, crop_height) = config.crop_size(width, height);
+        let (x, y) = config.region.map_or((0, 0), |region| (region.x, region.y));
+        let crop_args = format!("w={crop_width}:h={crop_height}:x={x}:y={y}");
+        let mut crop = graph.add(&ffmpeg::filter::find("crop").unwrap(), "crop", &crop_args)?;
+
+        let scale_args = format!(
+            "w={out_width}:h={out_height}:format={out_format}:out_range={}:out_color_matrix={}",
+            config.color_range.filter_name(),
+            config.color_space.vaapi_name()
+        );
         let mut scale = graph.add(
             &ffmpeg::filter::find("scale_vaapi").unwrap(),
             "scale",
@@ -378,7 +495,8 @@ impl VaapiEncoder {
         }
 
         input.link(0, &mut hwmap, 0);
-        hwmap.link(0, &mut scale, 0);
+        hwmap.link(0, &mut crop, 0);
+        crop.link(0, &mut scale, 0);
         scale.link(0, &mut out, 0);
 
         graph.validate()?;
@@ -388,6 +506,36 @@ impl VaapiEncoder {
     }
 }
 
+/// Wrap a DMA-BUF frame in a DRM PRIME frame for a filter graph mapping it to
+/// `hw_frames_ctx`, which VAAPI, QSV and AMF all import DMA-BUFs through
+pub(crate) fn drm_prime_frame(
+    frame: &RawVideoFrame,
+    hw_frames_ctx: *mut AVBufferRef,
+) -> Result<ffmpeg::util::frame::Video> {
+    let mut drm_frame = ffmpeg::util::frame::Video::new(
+        ffmpeg_next::format::Pixel::DRM_PRIME,
+        frame.dimensions.width,
+        frame.dimensions.height,
+    );
+    unsafe {
+        // Create DRM descriptor that points to the DMA buffer
+        let drm_desc = Box::into_raw(Box::new(drm_descriptor(frame)?));
+
+        // Attach descriptor to frame
+        (*drm_frame.as_mut_ptr()).data[0] = drm_desc as *mut u8;
+        (*drm_frame.as_mut_ptr()).buf[0] = av_buffer_create(
+            drm_desc as *mut u8,
+            std::mem::size_of::<AVDRMFrameDescriptor>(),
+            Some(av_buffer_default_free),
+            null_mut(),
+            0,
+        );
+
+        (*drm_frame.as_mut_ptr()).hw_frames_ctx = av_buffer_ref(hw_frames_ctx);
+    }
+    Ok(drm_frame)
+}
+
 impl Drop for VaapiEncoder {
     fn drop(&mut self) {
         if let Err(e) = self.drain() {
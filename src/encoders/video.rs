@@ -1,12 +1,18 @@
 use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::sync::Arc;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use crate::types::config::{BitDepth, BitstreamFormat};
 use crate::types::error::{Result, WaycapError};
-use crate::types::video_frame::RawVideoFrame;
-use crate::CaptureControls;
-use crossbeam::channel::Receiver;
+use crate::types::stats::FrameCounters;
+use crate::types::video_frame::{EncodedVideoFrame, RawVideoFrame};
+use crate::utils::spawn_named;
+use crate::{CaptureControls, Resolution};
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
 use crossbeam::select;
 use ffmpeg::ffi::{av_hwdevice_ctx_create, av_hwframe_ctx_alloc, AVBufferRef};
 use ffmpeg_next::{self as ffmpeg};
@@ -15,6 +21,9 @@ use std::sync::Mutex;
 
 pub const GOP_SIZE: u32 = 30;
 
+/// Where the DRM render nodes are probed when no device is configured
+const DRM_DEVICE_DIR: &str = "/dev/dri";
+
 /// Base trait for video encoders. defines the output type of an encoder.
 ///
 /// To use this, implement either [`ProcessingThread::process`] for processing individual frames on
@@ -23,9 +32,24 @@ pub trait VideoEncoder: Send + 'static {
     type Output;
 
     fn reset(&mut self) -> Result<()>;
+    /// Re-create the encoder for a new input resolution, called when the stream
+    /// resolution changes mid-capture. The output receiver must stay valid.
+    fn reconfigure(&mut self, _width: u32, _height: u32) -> Result<()> {
+        Ok(())
+    }
     fn output(&mut self) -> Option<Receiver<Self::Output>>;
+    /// Disconnect the receivers from [`VideoEncoder::output`] once they read the frames still
+    /// in the channel, called when the capture failed.
+    /// Encoders whose output channel closes on its own can ignore this
+    fn close_output(&mut self) {}
     fn drop_processor(&mut self);
     fn drain(&mut self) -> Result<()>;
+    /// Like [`VideoEncoder::drain`], but return the frames still in the encoder instead of
+    /// discarding them. Encoders which can't hand them back return none
+    fn drain_collecting(&mut self) -> Result<Vec<Self::Output>> {
+        self.drain()?;
+        Ok(Vec::new())
+    }
     fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video>;
 }
 
@@ -51,6 +75,18 @@ pub trait ProcessingThread: StartVideoEncoder {
     fn thread_teardown(&mut self) -> Result<()> {
         Ok(())
     }
+    /// Encode the next processed frame as a keyframe.
+    /// Encoders without keyframes can ignore this
+    fn request_keyframe(&mut self) {}
+    /// Send the frames still in the encoder to the output and leave it ready for new frames,
+    /// called once the frame limit is reached. Encoders which don't hold frames back can
+    /// ignore this
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Hand the encoder the counters behind [`crate::Capture::stats`], called before
+    /// [`ProcessingThread::thread_setup`]. Encoders that don't count their output can ignore this
+    fn attach_counters(&mut self, _counters: Arc<FrameCounters>) {}
 }
 
 /// Default impl for all VideoEncoders which use a normal processing thread
@@ -69,42 +105,114 @@ where
                 .expect("start_processing should be called after Capture.video_encoder is set"),
         );
         let controls = Arc::clone(&capture.controls);
+        encoder
+            .lock()
+            .unwrap()
+            .attach_counters(Arc::clone(controls.counters()));
 
-        let handle = std::thread::spawn(move || -> Result<()> {
-            encoder.as_ref().lock().unwrap().thread_setup()?;
-
-            let ret = default_processing_loop(input, controls, Arc::clone(&encoder));
-
-            encoder.as_ref().lock().unwrap().thread_teardown()?;
-            ret
-        });
+        let handle = spawn_processing_thread(encoder, controls, input);
         capture.worker_handles.push(handle);
         Ok(())
     }
 }
 
-/// Default processing loop function. Handles stop/pause and frame interval changes
+/// Run [`default_processing_loop`] for `encoder` on a new thread, wrapped in its
+/// [`ProcessingThread::thread_setup`] and [`ProcessingThread::thread_teardown`]
+pub(crate) fn spawn_processing_thread<V: ProcessingThread>(
+    encoder: Arc<Mutex<V>>,
+    controls: Arc<CaptureControls>,
+    input: Receiver<RawVideoFrame>,
+) -> JoinHandle<Result<()>> {
+    spawn_named("waycap-video-enc", move || -> Result<()> {
+        encoder.as_ref().lock().unwrap().thread_setup()?;
+
+        let ret = default_processing_loop(input, Arc::clone(&controls), Arc::clone(&encoder));
+
+        let mut encoder = encoder.as_ref().lock().unwrap();
+        if controls.has_failed() {
+            encoder.close_output();
+        }
+        encoder.thread_teardown()?;
+        ret
+    })
+}
+
+/// Default processing loop function. Handles stop/pause and frame interval changes.
+///
+/// Keeps frame timestamps strictly increasing: when the stream clock repeats or goes back, the
+/// frame is moved to one nanosecond after the previous one and later frames are shifted by the
+/// same amount, so the framerate check doesn't stall until the clock catches up again.
+///
+/// With a [`CaptureControls::frame_limit`] the encoder is flushed and the capture stopped once
+/// that many frames went into the encoder.
 pub fn default_processing_loop<V: ProcessingThread>(
     input: Receiver<RawVideoFrame>,
     controls: Arc<CaptureControls>,
     thread_self: Arc<Mutex<V>>,
 ) -> Result<()> {
     let mut last_timestamp: u64 = 0;
-    let mut frame_interval = controls.frame_interval_ns();
+    // Added to every timestamp after the clock went backwards
+    let mut clock_offset: u64 = 0;
+    let mut dimensions: Option<(u32, u32)> = None;
+    let mut frames_encoded: u64 = 0;
+    // Requests from before the loop started are covered by the first frame being a keyframe
+    let mut keyframe_requests = controls.keyframe_requests();
 
     while !controls.is_stopped() {
-        if controls.is_paused() {
-            std::thread::sleep(Duration::from_millis(100));
+        if !controls.is_started() || controls.is_paused() {
+            controls.wait_until_running();
             continue;
         }
         select! {
             recv(input) -> raw_frame => {
                 match raw_frame {
-                    Ok(raw_frame) => {
-                        let current_time = raw_frame.timestamp as u64;
+                    Ok(mut raw_frame) => {
+                        let current_time = monotonic_timestamp(
+                            raw_frame.timestamp,
+                            last_timestamp,
+                            &mut clock_offset,
+                        );
+                        raw_frame.timestamp = current_time as i64;
+                        // Re-read every frame so changes to the target framerate apply
+                        // right away
+                        let frame_interval = controls.frame_interval_ns();
                         if current_time >= last_timestamp + frame_interval {
-                            thread_self.lock().unwrap().process(raw_frame)?;
+                            let mut encoder = thread_self.lock().unwrap();
+                            let frame_size =
+                                (raw_frame.dimensions.width, raw_frame.dimensions.height);
+                            if dimensions.is_some_and(|size| size != frame_size) {
+                                log::info!(
+                                    "Stream resolution changed to {}x{}, reconfiguring encoder",
+                                    frame_size.0,
+                                    frame_size.1
+                                );
+                                encoder.reconfigure(frame_size.0, frame_size.1)?;
+                            }
+                            dimensions = Some(frame_size);
+                            let requests = controls.keyframe_requests();
+                            if requests != keyframe_requests {
+                                keyframe_requests = requests;
+                                encoder.request_keyframe();
+                            }
+                            match encoder.process(raw_frame) {
+                                Ok(()) => {}
+                                Err(WaycapError::Disconnected) => {
+                                    log::info!("Video output receiver dropped, stopping capture");
+                                    controls.stop();
+                                    break;
+                                }
+                                Err(e) => return Err(e),
+                            }
                             last_timestamp = current_time;
+
+                            frames_encoded += 1;
+                            if controls.frame_limit().is_some_and(|limit| frames_encoded >= limit) {
+                                // Only frames which made it past the framerate limit count
+                                encoder.flush()?;
+                                log::info!("Encoded {frames_encoded} frames, stopping capture");
+                                controls.stop();
+                                break;
+                            }
                         }
                     }
                     Err(_) => {
@@ -114,18 +222,92 @@ pub fn default_processing_loop<V: ProcessingThread>(
                 }
             }
             default(Duration::from_millis(100)) => {
-                // Timeout to change fps if needed and check stop/pause flags periodically
-                frame_interval = controls.frame_interval_ns();
+                // Timeout to check stop/pause flags periodically
             }
         }
     }
     Ok(())
 }
 
+/// Shift `timestamp` by `clock_offset`. When that doesn't land after `last_timestamp` the
+/// offset grows so the frame lands one nanosecond after it.
+fn monotonic_timestamp(timestamp: i64, last_timestamp: u64, clock_offset: &mut u64) -> u64 {
+    let current_time = timestamp as u64 + *clock_offset;
+    if current_time > last_timestamp {
+        return current_time;
+    }
+    log::debug!(
+        "Frame timestamp went back by {}ns, bumping it past the last frame",
+        last_timestamp - current_time
+    );
+    *clock_offset += last_timestamp + 1 - current_time;
+    last_timestamp + 1
+}
+
 pub trait PipewireSPA {
     fn get_spa_definition() -> Result<spa::pod::Object>;
 }
 
+/// Highest framerate the SPA definitions accept
+const MAX_SPA_FRAMERATE: u64 = 244;
+
+/// Largest width and height the encoders offer PipeWire
+pub(crate) const MAX_CAPTURE_SIZE: u32 = 4096;
+
+/// Ask PipeWire for `target_fps` by making it the default of the `VideoFramerate` range,
+/// adding the range to `object` if it has none.
+///
+/// Compositors are free to ignore this and keep sending frames at the output's refresh
+/// rate, [`default_processing_loop`] still drops the extra frames in that case.
+pub(crate) fn set_spa_framerate(object: &mut spa::pod::Object, target_fps: u64) {
+    // 0 means unthrottled, take as many frames as the compositor can send
+    let target_fps = match target_fps {
+        0 => MAX_SPA_FRAMERATE,
+        fps => fps.min(MAX_SPA_FRAMERATE),
+    };
+    let framerate = spa::pod::property!(
+        spa::param::format::FormatProperties::VideoFramerate,
+        Choice,
+        Range,
+        Fraction,
+        spa::utils::Fraction {
+            num: target_fps as u32,
+            denom: 1
+        }, // Default
+        spa::utils::Fraction { num: 0, denom: 1 }, // Min
+        spa::utils::Fraction {
+            num: MAX_SPA_FRAMERATE as u32,
+            denom: 1
+        }  // Max
+    );
+    match object
+        .properties
+        .iter_mut()
+        .find(|property| property.key == framerate.key)
+    {
+        Some(property) => *property = framerate,
+        None => object.properties.push(framerate),
+    }
+}
+
+/// Ask the compositor for streams of `size` by making it the default of `object`'s size
+/// range. It may still pick another size within the range.
+pub(crate) fn set_spa_size(object: &mut spa::pod::Object, size: Resolution) {
+    let key = spa::param::format::FormatProperties::VideoSize.as_raw();
+    for property in object.properties.iter_mut().filter(|p| p.key == key) {
+        if let spa::pod::Value::Choice(spa::pod::ChoiceValue::Rectangle(spa::utils::Choice(
+            _,
+            spa::utils::ChoiceEnum::Range { default, .. },
+        ))) = &mut property.value
+        {
+            *default = spa::utils::Rectangle {
+                width: size.width,
+                height: size.height,
+            };
+        }
+    }
+}
+
 pub fn create_hw_frame_ctx(device: *mut AVBufferRef) -> Result<*mut AVBufferRef> {
     unsafe {
         let frame = av_hwframe_ctx_alloc(device);
@@ -140,10 +322,327 @@ pub fn create_hw_frame_ctx(device: *mut AVBufferRef) -> Result<*mut AVBufferRef>
     }
 }
 
-pub fn create_hw_device(device_type: ffmpeg_next::ffi::AVHWDeviceType) -> Result<*mut AVBufferRef> {
+/// Holds back each encoded frame until the next one arrives so its duration can be set from
+/// the difference of their DTS
+#[derive(Default)]
+struct FrameDurations {
+    pending: Option<EncodedVideoFrame>,
+    last_duration: i64,
+}
+
+impl FrameDurations {
+    /// Hold back `frame` and return the one before it with its duration set
+    fn push(&mut self, frame: EncodedVideoFrame) -> Option<EncodedVideoFrame> {
+        let next_dts = frame.dts;
+        let mut previous = self.pending.replace(frame)?;
+        let duration = next_dts - previous.dts;
+        if duration > 0 {
+            self.last_duration = duration;
+        }
+        previous.duration = self.last_duration;
+        Some(previous)
+    }
+
+    /// The held back frame, which lasts as long as the one before it since nothing follows
+    fn flush(&mut self) -> Option<EncodedVideoFrame> {
+        let mut frame = self.pending.take()?;
+        frame.duration = self.last_duration;
+        Some(frame)
+    }
+}
+
+/// Output side shared by the ffmpeg encoders. Turns the encoder's packets into
+/// [`EncodedVideoFrame`]s and sends each one once the next gives its duration.
+pub(crate) struct EncodedOutput {
+    bitstream_format: BitstreamFormat,
+    receiver: Receiver<EncodedVideoFrame>,
+    sender: Sender<EncodedVideoFrame>,
+    pub(crate) counters: Arc<FrameCounters>,
+    /// Encode the next frame as a keyframe
+    pub(crate) force_keyframe: bool,
+    /// Mark the next keyframe as the start of a new segment
+    pub(crate) segment_pending: bool,
+    frame_durations: FrameDurations,
+}
+
+impl EncodedOutput {
+    pub(crate) fn new(buffer_frames: usize, bitstream_format: BitstreamFormat) -> Self {
+        let (sender, receiver) = bounded(buffer_frames);
+        Self {
+            bitstream_format,
+            receiver,
+            sender,
+            counters: Arc::default(),
+            force_keyframe: false,
+            segment_pending: false,
+            frame_durations: FrameDurations::default(),
+        }
+    }
+
+    pub(crate) fn receiver(&self) -> Receiver<EncodedVideoFrame> {
+        self.receiver.clone()
+    }
+
+    /// See [`VideoEncoder::close_output`]
+    pub(crate) fn close(&mut self) {
+        // Replacing the sender drops the only one of the output channel
+        self.sender = bounded(1).0;
+    }
+
+    /// Push `input` through `graph`, encode the filtered frame and send the packets the
+    /// encoder has ready
+    pub(crate) fn encode_filtered(
+        &mut self,
+        graph: &mut ffmpeg::filter::Graph,
+        encoder: &mut ffmpeg::codec::encoder::Video,
+        input: &ffmpeg::util::frame::Video,
+    ) -> Result<()> {
+        graph.get("in").unwrap().source().add(input)?;
+
+        let mut filtered = ffmpeg::util::frame::Video::empty();
+        if graph
+            .get("out")
+            .unwrap()
+            .sink()
+            .frame(&mut filtered)
+            .is_err()
+        {
+            return self.receive_packets(encoder, None);
+        }
+        self.encode(encoder, &mut filtered)
+    }
+
+    /// Encode `frame` and send the packets the encoder has ready
+    pub(crate) fn encode(
+        &mut self,
+        encoder: &mut ffmpeg::codec::encoder::Video,
+        frame: &mut ffmpeg::util::frame::Video,
+    ) -> Result<()> {
+        if std::mem::take(&mut self.force_keyframe) {
+            frame.set_kind(ffmpeg::picture::Type::I);
+        }
+        let encode_start = self.counters.timing_enabled().then(Instant::now);
+        encoder.send_frame(frame)?;
+        self.receive_packets(encoder, encode_start)
+    }
+
+    fn receive_packets(
+        &mut self,
+        encoder: &mut ffmpeg::codec::encoder::Video,
+        mut encode_start: Option<Instant>,
+    ) -> Result<()> {
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(start) = encode_start.take() {
+                self.counters.record_encode_latency(start.elapsed());
+            }
+            let starts_segment = packet.is_key() && std::mem::take(&mut self.segment_pending);
+            let Some(frame) = self.encoded_frame(encoder, &packet, starts_segment) else {
+                continue;
+            };
+            if let Some(frame) = self.frame_durations.push(frame) {
+                match self.sender.try_send(frame) {
+                    Ok(_) => self.counters.frame_encoded(),
+                    Err(TrySendError::Full(_)) => {
+                        self.counters.frame_dropped();
+                        log::error!("Could not send encoded video frame. Receiver is full");
+                    }
+                    Err(TrySendError::Disconnected(_)) => return Err(WaycapError::Disconnected),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encoded_frame(
+        &self,
+        encoder: &ffmpeg::codec::encoder::Video,
+        packet: &ffmpeg::codec::packet::Packet,
+        starts_segment: bool,
+    ) -> Option<EncodedVideoFrame> {
+        Some(EncodedVideoFrame {
+            data: bitstream_data(self.bitstream_format, encoder.id(), packet.data()?),
+            is_keyframe: packet.is_key(),
+            pts: packet.pts().unwrap_or(0),
+            dts: packet.dts().unwrap_or(0),
+            starts_segment,
+            duration: 0,
+        })
+    }
+
+    /// Drain `encoder` and return the remaining frames, including the one held back until
+    /// its duration is known
+    pub(crate) fn drain_frames(
+        &mut self,
+        encoder: Option<&mut ffmpeg::codec::encoder::Video>,
+    ) -> Result<Vec<EncodedVideoFrame>> {
+        let mut frames = Vec::new();
+        if let Some(encoder) = encoder {
+            encoder.send_eof()?;
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {
+                if let Some(frame) = self.encoded_frame(encoder, &packet, false) {
+                    frames.extend(self.frame_durations.push(frame));
+                }
+            }
+        }
+        frames.extend(self.frame_durations.flush());
+        Ok(frames)
+    }
+
+    /// Drain `encoder`, sending the remaining frames to the output instead of discarding them
+    pub(crate) fn flush(
+        &mut self,
+        encoder: Option<&mut ffmpeg::codec::encoder::Video>,
+    ) -> Result<()> {
+        for frame in self.drain_frames(encoder)? {
+            send_drained(&self.sender, &self.counters, frame);
+        }
+        Ok(())
+    }
+
+    /// Drain `encoder` and discard the frames still in it. Frames it already encoded go
+    /// out, including the one held back for its duration.
+    pub(crate) fn discard(
+        &mut self,
+        encoder: Option<&mut ffmpeg::codec::encoder::Video>,
+    ) -> Result<()> {
+        if let Some(frame) = self.frame_durations.flush() {
+            send_drained(&self.sender, &self.counters, frame);
+        }
+        if let Some(encoder) = encoder {
+            encoder.send_eof()?;
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {}
+        }
+        Ok(())
+    }
+}
+
+/// Send a frame drained from an encoder, dropping it if the receiver is full
+fn send_drained(
+    sender: &Sender<EncodedVideoFrame>,
+    counters: &FrameCounters,
+    frame: EncodedVideoFrame,
+) {
+    if sender.try_send(frame).is_err() {
+        counters.frame_dropped();
+        log::error!("Could not send drained video frame");
+    } else {
+        counters.frame_encoded();
+    }
+}
+
+/// Send the frames still in `graph` to `encoder`
+pub(crate) fn drain_filter_graph(
+    graph: &mut ffmpeg::filter::Graph,
+    encoder: &mut ffmpeg::codec::encoder::Video,
+) -> Result<()> {
+    let mut filtered = ffmpeg::util::frame::Video::empty();
+    while graph
+        .get("out")
+        .unwrap()
+        .sink()
+        .frame(&mut filtered)
+        .is_ok()
+    {
+        encoder.send_frame(&filtered)?;
+    }
+    Ok(())
+}
+
+/// Copy a frame in memory into an ffmpeg frame, row by row as the strides of the two usually
+/// differ. Only packed RGB formats can be copied.
+pub(crate) fn memory_frame(
+    frame: &RawVideoFrame,
+    bit_depth: BitDepth,
+) -> Result<ffmpeg::util::frame::Video> {
+    use spa::param::video::VideoFormat;
+
+    let pixel = match (frame.format, bit_depth) {
+        (VideoFormat::BGRA | VideoFormat::BGRx, BitDepth::Eight) => ffmpeg::format::Pixel::BGRA,
+        (VideoFormat::xRGB_210LE, BitDepth::Ten) => ffmpeg::format::Pixel::X2RGB10LE,
+        (format, _) => {
+            return Err(WaycapError::Encoding(format!(
+                "Can't encode {format:?} frames in memory, only packed RGB"
+            )))
+        }
+    };
+    let (width, height) = (frame.dimensions.width, frame.dimensions.height);
+    let row_len = width as usize * 4;
+    let src_stride = match frame.stride {
+        stride if stride > 0 => stride as usize,
+        _ => row_len,
+    };
+
+    let mut sw_frame = ffmpeg::util::frame::Video::new(pixel, width, height);
+    let dst_stride = sw_frame.stride(0);
+    let dst = sw_frame.data_mut(0);
+    for row in 0..height as usize {
+        let src = frame.offset as usize + row * src_stride;
+        let src_row = frame.data.get(src..src + row_len).ok_or_else(|| {
+            WaycapError::Encoding(format!(
+                "Frame of {} bytes is too small for {width}x{height}",
+                frame.data.len()
+            ))
+        })?;
+        dst[row * dst_stride..row * dst_stride + row_len].copy_from_slice(src_row);
+    }
+    Ok(sw_frame)
+}
+
+/// Bytes of an encoded packet of `codec` in the configured bitstream format.
+/// Encoders write Annex-B, so only H.264 and H.265 packets converted to AVCC change.
+fn bitstream_data(format: BitstreamFormat, codec: ffmpeg::codec::Id, data: &[u8]) -> Vec<u8> {
+    match (format, codec) {
+        (BitstreamFormat::Avcc, ffmpeg::codec::Id::H264 | ffmpeg::codec::Id::HEVC) => {
+            annexb_to_avcc(data)
+        }
+        _ => data.to_vec(),
+    }
+}
+
+/// Replace the start codes of an Annex-B packet with the length of each NAL unit.
+/// Packets without start codes are returned as they are.
+fn annexb_to_avcc(data: &[u8]) -> Vec<u8> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    if starts.is_empty() {
+        return data.to_vec();
+    }
+
+    let mut avcc = Vec::with_capacity(data.len() + starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).map_or(data.len(), |next| next - 3);
+        // NAL units end in a set bit, trailing zeros are the first byte of a 4 byte start code
+        let mut nal = &data[start..end];
+        while let [rest @ .., 0] = nal {
+            nal = rest;
+        }
+        if !nal.is_empty() {
+            avcc.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            avcc.extend_from_slice(nal);
+        }
+    }
+    avcc
+}
+
+pub fn create_hw_device(
+    device_type: ffmpeg_next::ffi::AVHWDeviceType,
+    drm_device: &Path,
+) -> Result<*mut AVBufferRef> {
+    let device_path = CString::new(drm_device.as_os_str().as_bytes())
+        .map_err(|_| WaycapError::Device(format!("Invalid device path {drm_device:?}")))?;
     unsafe {
         let mut device: *mut AVBufferRef = null_mut();
-        let device_path = CString::new("/dev/dri/renderD128").unwrap();
         let ret = av_hwdevice_ctx_create(
             &mut device,
             device_type,
@@ -160,3 +659,309 @@ pub fn create_hw_device(device_type: ffmpeg_next::ffi::AVHWDeviceType) -> Result
         Ok(device)
     }
 }
+
+/// Run `open` on `drm_device`, or when it's `None` on every `/dev/dri/renderD12*` node in
+/// order until it succeeds. Returns the result along with the node it was created on.
+///
+/// On hybrid graphics laptops only one of the nodes may be able to encode, so `open` should
+/// create everything that depends on the GPU, not just the device.
+pub(crate) fn with_drm_device<T>(
+    drm_device: Option<&Path>,
+    mut open: impl FnMut(&Path) -> Result<T>,
+) -> Result<(T, PathBuf)> {
+    if let Some(device) = drm_device {
+        return open(device).map(|value| (value, device.to_path_buf()));
+    }
+
+    let mut tried = Vec::new();
+    for node in render_nodes() {
+        match open(&node) {
+            Ok(value) => {
+                log::debug!("Using DRM device {}", node.display());
+                return Ok((value, node));
+            }
+            Err(e) => {
+                log::info!("Can't use DRM device {}: {e}", node.display());
+                tried.push(node.display().to_string());
+            }
+        }
+    }
+
+    Err(WaycapError::Device(if tried.is_empty() {
+        format!("No DRM render nodes found in {DRM_DEVICE_DIR}")
+    } else {
+        format!(
+            "None of the DRM render nodes could be used, tried {}",
+            tried.join(", ")
+        )
+    }))
+}
+
+/// `renderD12*` nodes in [`DRM_DEVICE_DIR`], sorted so `renderD128` comes first
+fn render_nodes() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(DRM_DEVICE_DIR) else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("renderD12"))
+        })
+        .collect();
+    nodes.sort();
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::bounded;
+    use pipewire::spa::{param::video::VideoFormat, utils::Rectangle};
+
+    /// Encoder which only records the timestamps of the frames it is given
+    #[derive(Default)]
+    struct TimestampRecorder {
+        timestamps: Vec<i64>,
+        /// Number of frames processed when each flush happened
+        flushes: Vec<usize>,
+        encoder: Option<ffmpeg::codec::encoder::Video>,
+    }
+
+    impl VideoEncoder for TimestampRecorder {
+        type Output = ();
+
+        fn reset(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn output(&mut self) -> Option<Receiver<Self::Output>> {
+            None
+        }
+        fn drop_processor(&mut self) {}
+        fn drain(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+            &self.encoder
+        }
+    }
+
+    impl ProcessingThread for TimestampRecorder {
+        fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+            self.timestamps.push(frame.timestamp);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.flushes.push(self.timestamps.len());
+            Ok(())
+        }
+    }
+
+    fn frame(timestamp: i64) -> RawVideoFrame {
+        RawVideoFrame {
+            data: vec![0; 4 * 4 * 4],
+            timestamp,
+            dmabuf_fd: None,
+            planes: Vec::new(),
+            stride: 4 * 4,
+            offset: 0,
+            size: 4 * 4 * 4,
+            modifier: 0,
+            format: VideoFormat::BGRx,
+            dimensions: Rectangle {
+                width: 4,
+                height: 4,
+            },
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn target_fps_change_applies_mid_capture() {
+        const SOURCE_INTERVAL: i64 = 5_000_000;
+        const START: i64 = 1_000_000_000;
+        // Frames from before the change, the rest come after it
+        const FRAMES_BEFORE: i64 = 100;
+        const FRAMES_AFTER: i64 = 200;
+
+        let controls = Arc::new(CaptureControls::from_fps(50));
+        controls.start();
+        let recorder = Arc::new(Mutex::new(TimestampRecorder::default()));
+        // Rendezvous channel, each send returns once the loop is done with the frame before
+        let (frame_tx, frame_rx) = bounded(0);
+        let handle = {
+            let controls = Arc::clone(&controls);
+            let recorder = Arc::clone(&recorder);
+            std::thread::spawn(move || default_processing_loop(frame_rx, controls, recorder))
+        };
+
+        for i in 0..FRAMES_BEFORE {
+            frame_tx.send(frame(START + i * SOURCE_INTERVAL)).unwrap();
+        }
+        controls.set_target_fps(10);
+        for i in FRAMES_BEFORE..FRAMES_BEFORE + FRAMES_AFTER {
+            frame_tx.send(frame(START + i * SOURCE_INTERVAL)).unwrap();
+        }
+        drop(frame_tx);
+        handle.join().unwrap().unwrap();
+
+        let change = START + (FRAMES_BEFORE - 1) * SOURCE_INTERVAL;
+        let timestamps = &recorder.lock().unwrap().timestamps;
+        let gaps = |range: &dyn Fn(i64) -> bool| -> Vec<i64> {
+            timestamps
+                .windows(2)
+                .filter(|pair| range(pair[0]) && range(pair[1]))
+                .map(|pair| pair[1] - pair[0])
+                .collect()
+        };
+
+        // The last frame sent before the change may have been throttled with either rate,
+        // so only the gaps on one side of it are checked
+        let before = gaps(&|timestamp| timestamp < change);
+        let after = gaps(&|timestamp| timestamp > change);
+        assert_eq!(timestamps.first(), Some(&START));
+        assert!(
+            before.len() >= 20,
+            "too few frames before the change: {before:?}"
+        );
+        assert!(before.iter().all(|&gap| gap == 20_000_000), "{before:?}");
+        assert!(
+            after.len() >= 8,
+            "too few frames after the change: {after:?}"
+        );
+        assert!(after.iter().all(|&gap| gap == 100_000_000), "{after:?}");
+    }
+
+    #[test]
+    fn equal_timestamp_is_bumped_past_the_last_frame() {
+        let mut clock_offset = 0;
+        assert_eq!(monotonic_timestamp(1_000, 1_000, &mut clock_offset), 1_001);
+        assert_eq!(clock_offset, 1);
+        // Later frames are shifted by the same amount
+        assert_eq!(monotonic_timestamp(2_000, 1_001, &mut clock_offset), 2_001);
+    }
+
+    #[test]
+    fn backwards_timestamp_is_bumped_past_the_last_frame() {
+        let mut clock_offset = 0;
+        assert_eq!(monotonic_timestamp(500, 1_000, &mut clock_offset), 1_001);
+        assert_eq!(clock_offset, 501);
+        assert_eq!(monotonic_timestamp(600, 1_001, &mut clock_offset), 1_101);
+    }
+
+    #[test]
+    fn processing_loop_keeps_timestamps_increasing() {
+        let controls = Arc::new(CaptureControls::from_fps(0));
+        controls.start();
+        let recorder = Arc::new(Mutex::new(TimestampRecorder::default()));
+        let (frame_tx, frame_rx) = bounded(0);
+        let handle = {
+            let controls = Arc::clone(&controls);
+            let recorder = Arc::clone(&recorder);
+            std::thread::spawn(move || default_processing_loop(frame_rx, controls, recorder))
+        };
+
+        for timestamp in [1_000, 1_000, 900, 2_000] {
+            frame_tx.send(frame(timestamp)).unwrap();
+        }
+        drop(frame_tx);
+        handle.join().unwrap().unwrap();
+
+        assert_eq!(
+            recorder.lock().unwrap().timestamps,
+            [1_000, 1_001, 1_002, 2_102]
+        );
+    }
+
+    #[test]
+    fn frame_limit_flushes_and_stops_after_n_frames() {
+        const LIMIT: u64 = 3;
+
+        let controls = Arc::new(CaptureControls::from_fps(0));
+        controls.set_frame_limit(LIMIT);
+        controls.start();
+        let recorder = Arc::new(Mutex::new(TimestampRecorder::default()));
+        let (frame_tx, frame_rx) = bounded(0);
+        let handle = {
+            let controls = Arc::clone(&controls);
+            let recorder = Arc::clone(&recorder);
+            std::thread::spawn(move || default_processing_loop(frame_rx, controls, recorder))
+        };
+
+        // Sends fail once the loop stopped and dropped the receiver
+        for i in 1..=2 * LIMIT as i64 {
+            if frame_tx.send(frame(i * 1_000_000)).is_err() {
+                break;
+            }
+        }
+        handle.join().unwrap().unwrap();
+
+        let recorder = recorder.lock().unwrap();
+        assert_eq!(recorder.timestamps.len(), LIMIT as usize);
+        assert_eq!(recorder.flushes, [LIMIT as usize]);
+        assert!(controls.is_stopped());
+    }
+
+    #[test]
+    fn annexb_to_avcc_handles_3_and_4_byte_start_codes() {
+        let annexb = [0, 0, 0, 1, 0x67, 0xaa, 0, 0, 1, 0x68, 0xbb];
+        assert_eq!(
+            annexb_to_avcc(&annexb),
+            [0, 0, 0, 2, 0x67, 0xaa, 0, 0, 0, 2, 0x68, 0xbb]
+        );
+    }
+
+    #[test]
+    fn annexb_to_avcc_skips_empty_nal_units() {
+        // Back to back start codes, and one at the very end
+        let annexb = [0, 0, 1, 0, 0, 1, 0x65, 0x88, 0, 0, 0, 1];
+        assert_eq!(annexb_to_avcc(&annexb), [0, 0, 0, 2, 0x65, 0x88]);
+        assert!(annexb_to_avcc(&[0, 0, 1]).is_empty());
+    }
+
+    #[test]
+    fn annexb_to_avcc_keeps_packets_without_start_codes() {
+        let avcc = [0, 0, 0, 2, 0x65, 0x88];
+        assert_eq!(annexb_to_avcc(&avcc), avcc);
+    }
+
+    fn encoded(dts: i64) -> EncodedVideoFrame {
+        EncodedVideoFrame {
+            data: Vec::new(),
+            is_keyframe: false,
+            pts: dts,
+            dts,
+            starts_segment: false,
+            duration: 0,
+        }
+    }
+
+    #[test]
+    fn frame_durations_come_from_the_next_dts() {
+        let mut durations = FrameDurations::default();
+        assert!(durations.flush().is_none());
+        assert!(durations.push(encoded(0)).is_none());
+
+        let first = durations.push(encoded(10)).unwrap();
+        assert_eq!((first.dts, first.duration), (0, 10));
+        let second = durations.push(encoded(30)).unwrap();
+        assert_eq!((second.dts, second.duration), (10, 20));
+
+        // Nothing follows the last frame, it lasts as long as the one before it
+        let last = durations.flush().unwrap();
+        assert_eq!((last.dts, last.duration), (30, 20));
+        assert!(durations.flush().is_none());
+    }
+
+    #[test]
+    fn frame_durations_keep_the_last_duration_when_dts_does_not_advance() {
+        let mut durations = FrameDurations::default();
+        durations.push(encoded(0));
+        assert_eq!(durations.push(encoded(10)).unwrap().duration, 10);
+        assert_eq!(durations.push(encoded(10)).unwrap().duration, 10);
+        assert_eq!(durations.push(encoded(5)).unwrap().duration, 10);
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::Arc;
+
+use crate::{
+    encoders::video::{
+        drain_filter_graph, memory_frame, set_spa_framerate, EncodedOutput, PipewireSPA,
+        ProcessingThread, VideoEncoder, MAX_CAPTURE_SIZE,
+    },
+    types::{
+        config::{BitDepth, QualityPreset, RateControl, VideoEncoderConfig},
+        error::{Result, WaycapError},
+        stats::FrameCounters,
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+    utils::TIME_UNIT_NS,
+};
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{self as ffmpeg, Rational};
+use pipewire as pw;
+
+/// Encoder which encodes frames on the CPU using libx264
+///
+/// Meant as a fallback for machines without a supported GPU (VMs, headless CI), it is a lot
+/// slower than [`crate::VaapiEncoder`] or [`crate::NvencEncoder`] as every frame is copied out
+/// of PipeWire and converted to YUV on the CPU.
+pub struct X264Encoder {
+    encoder: Option<ffmpeg::codec::encoder::Video>,
+    width: u32,
+    height: u32,
+    config: VideoEncoderConfig,
+    output: EncodedOutput,
+    filter_graph: Option<ffmpeg::filter::Graph>,
+    /// Capture timestamp of the first frame, for zero based PTS
+    first_timestamp: Option<i64>,
+}
+
+impl ProcessingThread for X264Encoder {
+    fn request_keyframe(&mut self) {
+        self.output.force_keyframe = true;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.reset()
+    }
+
+    fn attach_counters(&mut self, counters: Arc<FrameCounters>) {
+        self.output.counters = counters;
+    }
+
+    fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            if frame.data.is_empty() {
+                log::error!("Software encoding needs frames in memory, got a DMA-BUF");
+                return Ok(());
+            }
+
+            let mut bgra_frame = memory_frame(&frame, BitDepth::Eight)?;
+            bgra_frame.set_pts(Some(
+                self.config
+                    .frame_pts(&mut self.first_timestamp, frame.timestamp),
+            ));
+            self.output.encode_filtered(
+                self.filter_graph.as_mut().unwrap(),
+                encoder,
+                &bgra_frame,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VideoEncoder for X264Encoder {
+    type Output = EncodedVideoFrame;
+    fn reset(&mut self) -> Result<()> {
+        self.drop_processor();
+        let new_encoder = Self::create_encoder(self.width, self.height, &self.config)?;
+        let new_filter_graph = Self::create_filter_graph(self.width, self.height, &self.config)?;
+
+        self.encoder = Some(new_encoder);
+        self.filter_graph = Some(new_filter_graph);
+        Ok(())
+    }
+
+    fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
+    fn drop_processor(&mut self) {
+        self.encoder.take();
+        self.filter_graph.take();
+    }
+
+    fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
+        Some(self.output.receiver())
+    }
+
+    fn close_output(&mut self) {
+        self.output.close();
+    }
+
+    /// Drain the filter graph and encoder of any remaining frames it is processing
+    fn drain(&mut self) -> Result<()> {
+        self.drain_filter_graph()?;
+        self.output.discard(self.encoder.as_mut())
+    }
+
+    fn drain_collecting(&mut self) -> Result<Vec<EncodedVideoFrame>> {
+        self.drain_filter_graph()?;
+        self.output.drain_frames(self.encoder.as_mut())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.encoder
+    }
+}
+
+impl PipewireSPA for X264Encoder {
+    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        let mut object = pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::Raw
+            ),
+            // Alpha is dropped when converting to YUV, so both read the same
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
+                Id,
+                pw::spa::param::video::VideoFormat::BGRA,
+                pw::spa::param::video::VideoFormat::BGRx,
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoSize,
+                Choice,
+                Range,
+                Rectangle,
+                pw::spa::utils::Rectangle {
+                    width: 2560,
+                    height: 1440
+                }, // Default
+                pw::spa::utils::Rectangle {
+                    width: 1,
+                    height: 1
+                }, // Min
+                pw::spa::utils::Rectangle {
+                    width: MAX_CAPTURE_SIZE,
+                    height: MAX_CAPTURE_SIZE
+                } // Max
+            ),
+        );
+        set_spa_framerate(&mut object, 0);
+        Ok(object)
+    }
+}
+
+impl X264Encoder {
+    pub(crate) fn new(width: u32, height: u32, config: VideoEncoderConfig) -> Result<Self> {
+        let encoder = Self::create_encoder(width, height, &config)?;
+
+        let filter_graph = Some(Self::create_filter_graph(width, height, &config)?);
+
+        Ok(Self {
+            encoder: Some(encoder),
+            width,
+            height,
+            output: EncodedOutput::new(config.buffer_frames, config.bitstream_format),
+            config,
+            filter_graph,
+            first_timestamp: None,
+        })
+    }
+
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.config.quality = quality;
+        self.reset()
+    }
+
+    pub(crate) fn rotate_segment(&mut self) -> Result<()> {
+        self.output.flush(self.encoder.as_mut())?;
+        self.output.segment_pending = true;
+        self.reset()
+    }
+
+    /// Send the frames still in the filter graph to the encoder
+    fn drain_filter_graph(&mut self) -> Result<()> {
+        if let (Some(encoder), Some(graph)) = (self.encoder.as_mut(), self.filter_graph.as_mut()) {
+            drain_filter_graph(graph, encoder)?;
+        }
+        Ok(())
+    }
+
+    fn create_encoder(
+        width: u32,
+        height: u32,
+        config: &VideoEncoderConfig,
+    ) -> Result<ffmpeg::codec::encoder::Video> {
+        if config.bit_depth == BitDepth::Ten {
+            return Err(WaycapError::Init(
+                "libx264 does not support 10-bit encoding, use hevc_vaapi".to_string(),
+            ));
+        }
+
+        let (width, height) = config.output_size(width, height);
+        let encoder_codec = ffmpeg::codec::encoder::find_by_name("libx264")
+            .ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .video()?;
+
+        encoder_ctx.set_width(width);
+        encoder_ctx.set_height(height);
+        encoder_ctx.set_format(ffmpeg::format::Pixel::YUV420P);
+        if let Some((bitrate, max_bitrate)) = config.rate_control().bitrates() {
+            encoder_ctx.set_bit_rate(bitrate as usize);
+            encoder_ctx.set_max_bit_rate(max_bitrate as usize);
+        }
+        encoder_ctx.set_color_range(config.color_range.ffmpeg_range());
+        encoder_ctx.set_colorspace(config.color_space.ffmpeg_space());
+        encoder_ctx.set_time_base(Rational::new(1, TIME_UNIT_NS as i32));
+        encoder_ctx.set_gop(config.gop_size);
+
+        let encoder_params = ffmpeg::codec::Parameters::new();
+        let opts = Self::get_encoder_params(config);
+
+        encoder_ctx.set_parameters(encoder_params)?;
+        let encoder = encoder_ctx.open_with(opts)?;
+        Ok(encoder)
+    }
+
+    fn get_encoder_params<'a>(config: &VideoEncoderConfig) -> ffmpeg::Dictionary<'a> {
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("vsync", "vfr");
+        // Keep up with the capture on a CPU, and don't hold frames back for lookahead
+        opts.set("preset", "veryfast");
+        opts.set("tune", "zerolatency");
+
+        // The bitrates are set on the encoder context, x264 only enforces the maximum with a
+        // VBV buffer though
+        match config.rate_control() {
+            RateControl::ConstantQuality(crf) => opts.set("crf", &crf.to_string()),
+            RateControl::ConstantBitrate(bitrate) => {
+                opts.set("bufsize", &bitrate.to_string());
+                opts.set("nal-hrd", "cbr");
+            }
+            RateControl::VariableBitrate { max, .. } => opts.set("bufsize", &max.to_string()),
+        }
+        opts
+    }
+
+    fn create_filter_graph(
+        width: u32,
+        height: u32,
+        config: &VideoEncoderConfig,
+    ) -> Result<ffmpeg::filter::Graph> {
+        let mut graph = ffmpeg::filter::Graph::new();
+
+        let args = format!("video_size={width}x{height}:pix_fmt=bgra:time_base=1/{TIME_UNIT_NS}",);
+        let mut input = graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &args)?;
+
+        let (out_width, out_height) = config.output_size(width, height);
+        let (crop_width, crop_height) = config.crop_size(width, height);
+        let (x, y) = config.region.map_or((0, 0), |region| (region.x, region.y));
+        let crop_args = format!("w={crop_width}:h={crop_height}:x={x}:y={y}");
+        let mut crop = graph.add(&ffmpeg::filter::find("crop").unwrap(), "crop", &crop_args)?;
+
+        let scale_args = format!(
+            "w={out_width}:h={out_height}:out_range={}:out_color_matrix={}",
+            config.color_range.filter_name(),
+            config.color_space.swscale_name()
+        );
+        let mut scale = graph.add(
+            &ffmpeg::filter::find("scale").unwrap(),
+            "scale",
+            &scale_args,
+        )?;
+
+        let mut format = graph.add(
+            &ffmpeg::filter::find("format").unwrap(),
+            "format",
+            "pix_fmts=yuv420p",
+        )?;
+
+        let mut out = graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+
+        input.link(0, &mut crop, 0);
+        crop.link(0, &mut scale, 0);
+        scale.link(0, &mut format, 0);
+        format.link(0, &mut out, 0);
+
+        graph.validate()?;
+        log::trace!("x264 Graph\n{}", graph.dump());
+
+        Ok(graph)
+    }
+}
+
+impl Drop for X264Encoder {
+    fn drop(&mut self) {
+        if let Err(e) = self.drain() {
+            log::error!("Error while draining x264 encoder during drop: {e:?}");
+        }
+        self.drop_processor();
+    }
+}
@@ -6,12 +6,14 @@
 //!
 //! ## Features
 //!
-//! - Hardware-accelerated encoding (VAAPI and NVENC)
+//! - Hardware-accelerated encoding (VAAPI, NVENC and QSV), H.264 and HEVC
+//! - Software H.264 encoding with libx264 as a fallback
 //! - No Copy approach to encoding video frames utilizing DMA Buffers
 //! - Audio capture support
 //! - Multiple quality presets
 //! - Cursor visibility control
 //! - Fine-grained control over capture (start, pause, resume)
+//! - Async `Stream`s of encoded frames with the `tokio` feature
 //!
 //! ## Platform Support
 //!
@@ -54,50 +56,111 @@
 
 #![warn(clippy::all)]
 use std::{
+    collections::HashMap,
+    os::fd::{BorrowedFd, IntoRawFd, RawFd},
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         mpsc::{self},
-        Arc,
+        Arc, Condvar,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use capture::{audio::AudioCapture, video::VideoCapture, Terminate};
+use capture::{
+    audio::{AudioCapture, AudioSource},
+    composite, test_pattern,
+    video::{find_output_node, VideoCapture},
+    Terminate,
+};
 use crossbeam::{
-    channel::{bounded, Receiver, Sender},
+    channel::{bounded, Receiver, Sender, TrySendError},
     select,
 };
-use encoders::{audio::AudioEncoder, opus_encoder::OpusEncoder};
-use portal_screencast_waycap::{CursorMode, ScreenCast, SourceType};
+use encoders::{
+    aac_encoder::AacEncoder,
+    audio::{rms, AudioEncoder},
+    flac_encoder::FlacEncoder,
+    opus_encoder::OpusEncoder,
+    pcm_encoder::PcmEncoder,
+};
+use portal_screencast_waycap::{ActiveScreenCast, PersistMode, PortalError, ScreenCast};
 use std::sync::Mutex;
 use types::{
     audio_frame::{EncodedAudioFrame, RawAudioFrame},
-    config::{AudioEncoder as AudioEncoderType, QualityPreset, VideoEncoder as VideoEncoderType},
+    config::{
+        AudioEncoder as AudioEncoderType, AudioEncoderConfig, BitDepth, QualityPreset,
+        VideoEncoder as VideoEncoderType, VideoEncoderConfig, DEFAULT_BUFFER_FRAMES,
+    },
     error::{Result, WaycapError},
-    video_frame::{EncodedVideoFrame, RawVideoFrame},
+    stats::{AudioLevel, CaptureStats, FrameCounters},
+    video_frame::{EncodedVideoFrame, RawVideoFrame, Transform},
 };
 
 mod capture;
 mod encoders;
 pub mod pipeline;
+#[cfg(feature = "tokio")]
+mod stream;
 pub mod types;
 mod utils;
 mod waycap_egl;
 
+pub use crate::encoders::amf_encoder::AmfEncoder;
 pub use crate::encoders::dma_buf_encoder::DmaBufEncoder;
 pub use crate::encoders::dynamic_encoder::DynamicEncoder;
 pub use crate::encoders::nvenc_encoder::NvencEncoder;
+pub use crate::encoders::qsv_encoder::QsvEncoder;
 pub use crate::encoders::rgba_image_encoder::RgbaImageEncoder;
 pub use crate::encoders::vaapi_encoder::VaapiEncoder;
+pub use crate::encoders::x264_encoder::X264Encoder;
+pub use drm_fourcc::DrmFourcc;
 pub use encoders::video::VideoEncoder;
+pub use pipewire::spa::param::video::VideoFormat;
+pub use portal_screencast_waycap::{CursorMode, SourceType};
 pub use utils::TIME_UNIT_NS;
+pub use waycap_egl::{EglContext, GpuVendor};
 
-use crate::encoders::video::{PipewireSPA, StartVideoEncoder};
+use crate::encoders::video::{
+    set_spa_framerate, set_spa_size, spawn_processing_thread, PipewireSPA, StartVideoEncoder,
+    MAX_CAPTURE_SIZE,
+};
+use crate::pipeline::detect_gpu_vendor;
+use crate::utils::spawn_named;
 
 /// Target Screen Resolution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Resolution {
-    width: u32,
-    height: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What a video stream records, read from its PipeWire node, see [`Capture::source_info`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceInfo {
+    /// PipeWire node id of the stream
+    pub node_id: u32,
+    /// `node.name` of the node, set by the compositor's portal
+    pub node_name: Option<String>,
+    /// `node.description` of the node, where compositors which name their streams after the
+    /// recorded output or window put that name
+    pub output_name: Option<String>,
+    /// Most frames per second the stream negotiated to send, the output's refresh rate for
+    /// compositors which only send frames on changes. `None` until the format is negotiated
+    pub refresh_rate: Option<f64>,
+    /// Every property of the node, for compositor specific ones
+    pub properties: HashMap<String, String>,
+}
+
+impl SourceInfo {
+    pub(crate) fn from_properties(node_id: u32, properties: HashMap<String, String>) -> Self {
+        Self {
+            node_id,
+            node_name: properties.get("node.name").cloned(),
+            output_name: properties.get("node.description").cloned(),
+            refresh_rate: None,
+            properties,
+        }
+    }
 }
 
 /// Main capture instance for recording screen content and audio.
@@ -134,36 +197,125 @@ pub struct Resolution {
 /// ```
 pub struct Capture<V: VideoEncoder + Send> {
     controls: Arc<CaptureControls>,
+    resolution: Resolution,
     worker_handles: Vec<std::thread::JoinHandle<Result<()>>>,
 
     video_encoder: Option<Arc<Mutex<V>>>,
-    pw_video_terminate_tx: Option<pipewire::channel::Sender<Terminate>>,
+    /// Extra encoders fed the same frames as `video_encoder`
+    additional_video_encoders: Vec<Arc<Mutex<V>>>,
+    /// One per captured stream
+    pw_video_terminate_txs: Vec<pipewire::channel::Sender<Terminate>>,
 
     audio_encoder: Option<Arc<Mutex<dyn AudioEncoder + Send>>>,
     pw_audio_terminate_tx: Option<pipewire::channel::Sender<Terminate>>,
+
+    mic_encoder: Option<Arc<Mutex<dyn AudioEncoder + Send>>>,
+    pw_mic_terminate_tx: Option<pipewire::channel::Sender<Terminate>>,
+
+    restore_token: Option<String>,
+    /// What the capture was built with, for [`Capture::restart`]
+    restart_params: Option<CaptureParams>,
+}
+
+/// Settings of a capture with the built-in encoders, kept to open it again on restart
+#[derive(Clone)]
+pub(crate) struct CaptureParams {
+    pub(crate) video_encoder_type: Option<VideoEncoderType>,
+    pub(crate) audio_encoder_type: AudioEncoderType,
+    pub(crate) audio_config: AudioEncoderConfig,
+    pub(crate) video_config: VideoEncoderConfig,
+    pub(crate) cursor_mode: CursorMode,
+    pub(crate) include_audio: bool,
+    pub(crate) include_mic: bool,
+    pub(crate) audio_device: Option<u32>,
+    pub(crate) output: Option<String>,
+    pub(crate) combine_outputs: bool,
+    pub(crate) source_type: SourceType,
+    pub(crate) software_fallback: bool,
+    pub(crate) additional_encoders: Vec<(VideoEncoderType, QualityPreset)>,
+    pub(crate) egl_context: Option<Arc<EglContext>>,
 }
 
 /// Controls for the capture, allows you to pause/resume processing
+///
+/// A capture goes through three states: built but not yet started, running, and stopped.
+/// While running it can be paused and resumed any number of times. [`Self::is_paused`] only
+/// reports pauses requested with [`Self::pause`], use [`Self::is_started`] to know whether
+/// [`Capture::start`] was called yet.
 #[derive(Debug)]
 pub struct CaptureControls {
     stop_flag: AtomicBool,
+    start_flag: AtomicBool,
     pause_flag: AtomicBool,
+    /// Bumped by every keyframe request, each encoder compares it to the last one it saw
+    keyframe_requests: AtomicU64,
     target_fps: AtomicU64,
+    /// Nanoseconds between encoded frames overriding `target_fps`, 0 to follow it
+    frame_interval: AtomicU64,
+    /// Frames to encode before stopping, 0 for no limit
+    frame_limit: AtomicU64,
+    counters: Arc<FrameCounters>,
+    audio_level: AudioLevel,
+    /// Error which stopped the capture, with the variant to report it as
+    error: Mutex<Option<(fn(String) -> WaycapError, String)>>,
+    /// Reconnect the video stream when the compositor drops it instead of failing
+    auto_reconnect: AtomicBool,
+    /// Pixel format and DRM modifier the video stream settled on
+    negotiated_format: Mutex<Option<(VideoFormat, u64)>>,
+    /// Node of the first video stream
+    source_info: Mutex<Option<SourceInfo>>,
+    /// Last transform of the video stream
+    transform: Mutex<Transform>,
+    /// The compositor shares frames as DMA-BUFs rather than copying them into memory
+    dmabuf: AtomicBool,
+    /// Notified whenever the capture is started, paused, resumed or stopped
+    state_lock: Mutex<()>,
+    state_changed: Condvar,
+    pause_clock: Mutex<PauseClock>,
+}
+
+/// Time spent paused, to take it out of the timestamps
+#[derive(Debug, Default)]
+struct PauseClock {
+    enabled: bool,
+    /// Monotonic time the current pause started at
+    paused_at: Option<i64>,
+    /// Monotonic time each pause ended at, with the total time paused up to then
+    resumes: Vec<(i64, i64)>,
 }
 
 impl CaptureControls {
     fn from_fps(target_fps: u64) -> Self {
         Self {
             stop_flag: AtomicBool::new(false),
-            pause_flag: AtomicBool::new(true),
+            start_flag: AtomicBool::new(false),
+            pause_flag: AtomicBool::new(false),
+            keyframe_requests: AtomicU64::new(0),
             target_fps: AtomicU64::new(target_fps),
+            frame_interval: AtomicU64::new(0),
+            frame_limit: AtomicU64::new(0),
+            counters: Arc::default(),
+            audio_level: AudioLevel::default(),
+            error: Mutex::new(None),
+            auto_reconnect: AtomicBool::new(false),
+            negotiated_format: Mutex::new(None),
+            source_info: Mutex::new(None),
+            transform: Mutex::new(Transform::Normal),
+            dmabuf: AtomicBool::new(false),
+            state_lock: Mutex::new(()),
+            state_changed: Condvar::new(),
+            pause_clock: Mutex::default(),
         }
     }
-    /// True when stopped or paused
+    /// True when frames should not be encoded: not started yet, paused or stopped
     pub fn skip_processing(&self) -> bool {
-        self.is_paused() || self.is_stopped()
+        !self.is_started() || self.is_paused() || self.is_stopped()
     }
-    /// Check if processing is currently paused
+    /// Check if [`Capture::start`] has been called
+    pub fn is_started(&self) -> bool {
+        self.start_flag.load(Ordering::Acquire)
+    }
+    /// Check if processing is currently paused by [`Self::pause`]
     pub fn is_paused(&self) -> bool {
         self.pause_flag.load(Ordering::Acquire)
     }
@@ -176,21 +328,239 @@ impl CaptureControls {
     /// This is final, use [`CaptureControls::pause`] if you want to resume later.
     pub fn stop(&self) {
         self.stop_flag.store(true, Ordering::Release);
+        self.notify_state_changed();
     }
 
     /// Pause processing
+    ///
+    /// Timestamps keep counting through the pause, so the recording freezes for as long as
+    /// it lasted unless built with
+    /// [`CaptureBuilder::with_pause_removes_gap`](crate::pipeline::builder::CaptureBuilder::with_pause_removes_gap).
     pub fn pause(&self) {
+        {
+            let mut clock = self.pause_clock.lock().unwrap();
+            if clock.enabled && clock.paused_at.is_none() && !self.skip_processing() {
+                clock.paused_at = Some(utils::monotonic_ns());
+            }
+        }
         self.pause_flag.store(true, Ordering::Release);
+        self.notify_state_changed();
     }
 
     /// Resume processing
+    ///
+    /// This does not start a capture that was never started, see [`Capture::start`].
     pub fn resume(&self) {
+        self.end_pause();
         self.pause_flag.store(false, Ordering::Release);
+        self.notify_state_changed();
+    }
+
+    /// Clear what a closed capture leaves behind so [`Capture::restart`] can run it again.
+    /// Settings, counters and the time paused are kept.
+    fn rearm(&self) {
+        self.stop_flag.store(false, Ordering::Release);
+        self.start_flag.store(false, Ordering::Release);
+        self.pause_flag.store(false, Ordering::Release);
+        *self.error.lock().unwrap() = None;
+        *self.negotiated_format.lock().unwrap() = None;
+        *self.source_info.lock().unwrap() = None;
+        *self.transform.lock().unwrap() = Transform::Normal;
+        self.dmabuf.store(false, Ordering::Release);
+        self.notify_state_changed();
+    }
+
+    fn start(&self) {
+        self.end_pause();
+        self.pause_flag.store(false, Ordering::Release);
+        self.start_flag.store(true, Ordering::Release);
+        self.notify_state_changed();
+    }
+
+    /// Count the time since [`Self::pause`] as paused, before frames are let through again
+    fn end_pause(&self) {
+        let mut clock = self.pause_clock.lock().unwrap();
+        if let Some(paused_at) = clock.paused_at.take() {
+            let now = utils::monotonic_ns();
+            let total = clock.resumes.last().map_or(0, |(_, total)| *total) + now - paused_at;
+            clock.resumes.push((now, total));
+        }
+    }
+
+    pub(crate) fn enable_pause_gap_removal(&self) {
+        self.pause_clock.lock().unwrap().enabled = true;
+    }
+
+    /// `timestamp` with the time paused before it taken out when pauses remove their gap.
+    /// Audio and video go through the same clock so they stay in sync.
+    pub(crate) fn without_pauses(&self, timestamp: i64) -> i64 {
+        let clock = self.pause_clock.lock().unwrap();
+        let paused = clock
+            .resumes
+            .iter()
+            .rev()
+            .find(|(resumed_at, _)| *resumed_at <= timestamp)
+            .map_or(0, |(_, total)| *total);
+        timestamp - paused
+    }
+
+    fn notify_state_changed(&self) {
+        // Taking the lock makes sure a waiter either sees the new flags or is already parked
+        drop(self.state_lock.lock().unwrap());
+        self.state_changed.notify_all();
+    }
+
+    /// Block until frames should be processed again or the capture is stopped.
+    /// Returns immediately if the capture is running.
+    pub fn wait_until_running(&self) {
+        let guard = self.state_lock.lock().unwrap();
+        let _guard = self
+            .state_changed
+            .wait_while(guard, |_| {
+                !self.is_stopped() && (!self.is_started() || self.is_paused())
+            })
+            .unwrap();
+    }
+
+    /// Force the next video frame to be encoded as a keyframe,
+    /// e.g. when a new viewer joins a live stream. Applies to every encoder of the capture.
+    pub fn request_keyframe(&self) {
+        self.keyframe_requests.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Number of keyframe requests so far. An encoder owes a keyframe whenever this changed
+    /// since it last looked, so every encoder of a capture sees every request.
+    pub fn keyframe_requests(&self) -> u64 {
+        self.keyframe_requests.load(Ordering::Acquire)
+    }
+
+    /// Stop the capture because PipeWire reported an error which it can't recover from
+    pub(crate) fn fail(&self, error: String) {
+        *self.error.lock().unwrap() = Some((WaycapError::PipeWire, error));
+        self.stop();
+    }
+
+    /// Stop the capture because the video stream stopped streaming and couldn't be brought back
+    pub(crate) fn fail_stream(&self, error: String) {
+        *self.error.lock().unwrap() = Some((WaycapError::Stream, error));
+        self.stop();
+    }
+
+    /// True if the capture was stopped by a PipeWire or stream error
+    pub fn has_failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn set_frame_limit(&self, frames: u64) {
+        self.frame_limit.store(frames, Ordering::Release);
+    }
+
+    /// Frames each video encoder encodes before the capture stops on its own, see
+    /// [`CaptureBuilder::with_frame_limit`](crate::pipeline::builder::CaptureBuilder::with_frame_limit)
+    pub fn frame_limit(&self) -> Option<u64> {
+        match self.frame_limit.load(Ordering::Acquire) {
+            0 => None,
+            frames => Some(frames),
+        }
+    }
+
+    pub(crate) fn enable_auto_reconnect(&self) {
+        self.auto_reconnect.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn auto_reconnect(&self) -> bool {
+        self.auto_reconnect.load(Ordering::Acquire)
+    }
+
+    /// Keep `info` unless a stream before it already set its own
+    pub(crate) fn set_source_info(&self, info: SourceInfo) {
+        self.source_info.lock().unwrap().get_or_insert(info);
+    }
+
+    pub(crate) fn set_source_refresh_rate(&self, node_id: u32, refresh_rate: f64) {
+        if let Some(info) = self.source_info.lock().unwrap().as_mut() {
+            if info.node_id == node_id {
+                info.refresh_rate = Some(refresh_rate);
+            }
+        }
+    }
+
+    pub(crate) fn set_transform(&self, transform: Transform) {
+        *self.transform.lock().unwrap() = transform;
+    }
+
+    pub(crate) fn set_negotiated_format(&self, format: VideoFormat, modifier: u64) {
+        *self.negotiated_format.lock().unwrap() = Some((format, modifier));
+    }
+
+    pub(crate) fn set_dmabuf(&self, dmabuf: bool) {
+        self.dmabuf.store(dmabuf, Ordering::Release);
+    }
+
+    pub(crate) fn is_dmabuf(&self) -> bool {
+        self.dmabuf.load(Ordering::Acquire)
+    }
+
+    /// The error which stopped the capture, e.g. [`WaycapError::PipeWire`] because the recorded
+    /// output or audio device disappeared, or [`WaycapError::Stream`] because the video stream
+    /// stopped streaming while recording
+    pub fn last_error(&self) -> Option<WaycapError> {
+        self.error
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|(variant, message)| variant(message))
     }
 
-    /// Frame interval in nanoseconds
+    /// Frame interval in nanoseconds, 0 when the framerate is unthrottled
     pub fn frame_interval_ns(&self) -> u64 {
-        TIME_UNIT_NS / self.target_fps.load(Ordering::Acquire)
+        match self.frame_interval.load(Ordering::Acquire) {
+            0 => {}
+            interval => return interval,
+        }
+        match self.target_fps.load(Ordering::Acquire) {
+            0 => 0,
+            fps => TIME_UNIT_NS / fps,
+        }
+    }
+
+    /// Encode at most one frame per `interval`, for intervals a whole number of frames per
+    /// second can't express, e.g. one frame every 2.5 seconds. Takes effect with the next
+    /// captured frame and lasts until [`Self::set_target_fps`] is called.
+    ///
+    /// A zero `interval` goes back to the target framerate.
+    pub fn set_frame_interval(&self, interval: Duration) {
+        let interval = u64::try_from(interval.as_nanos()).unwrap_or(u64::MAX);
+        self.frame_interval.store(interval, Ordering::Release);
+    }
+
+    /// Change the target framerate while capturing, e.g. to drop to 15 fps while the recorded
+    /// window is idle. Takes effect with the next captured frame.
+    ///
+    /// 0 removes the limit so every frame the compositor sends is encoded, values above one
+    /// frame per nanosecond are clamped. The framerate asked from PipeWire when the capture
+    /// started is not renegotiated, so going above it only helps if the compositor sends
+    /// frames faster than that anyway.
+    pub fn set_target_fps(&self, fps: u64) {
+        self.frame_interval.store(0, Ordering::Release);
+        self.target_fps
+            .store(fps.min(TIME_UNIT_NS), Ordering::Release);
+    }
+
+    /// Video frame counters, shared with the encoder so it can count its output
+    pub fn counters(&self) -> &Arc<FrameCounters> {
+        &self.counters
+    }
+
+    /// Level of the captured system audio, stays at 0 for captures without audio
+    pub fn audio_level(&self) -> &AudioLevel {
+        &self.audio_level
+    }
+
+    /// True if the system audio has been silent for at least `duration`, e.g. because the
+    /// default sink is muted or nothing is playing. Time spent paused counts as silence.
+    pub fn is_audio_silent(&self, duration: Duration) -> bool {
+        self.audio_level.is_silent_for(duration)
     }
 }
 
@@ -199,6 +569,10 @@ impl CaptureControls {
 pub struct ReadyState {
     audio: AtomicBool,
     video: AtomicBool,
+    mic: AtomicBool,
+    /// Notified whenever one of the flags changes
+    lock: Mutex<()>,
+    changed: Condvar,
 }
 
 impl ReadyState {
@@ -208,10 +582,44 @@ impl ReadyState {
     pub fn audio_ready(&self) -> bool {
         self.audio.load(Ordering::Acquire)
     }
-    fn wait_for_both(&self) {
-        while !self.audio.load(Ordering::Acquire) || !self.video.load(Ordering::Acquire) {
-            std::thread::sleep(Duration::from_millis(100));
-        }
+    pub fn mic_ready(&self) -> bool {
+        self.mic.load(Ordering::Acquire)
+    }
+    pub(crate) fn set_video(&self, ready: bool) {
+        self.set(&self.video, ready);
+    }
+    pub(crate) fn set_audio(&self, ready: bool) {
+        self.set(&self.audio, ready);
+    }
+    pub(crate) fn set_mic(&self, ready: bool) {
+        self.set(&self.mic, ready);
+    }
+    fn set(&self, flag: &AtomicBool, ready: bool) {
+        flag.store(ready, Ordering::Release);
+        // Taking the lock makes sure a waiter either sees the new value or is already parked
+        drop(self.lock.lock().unwrap());
+        self.changed.notify_all();
+    }
+    /// Wait for the audio and video streams to start, returns false if they did not within
+    /// `timeout`
+    fn wait_for_both(&self, timeout: Duration) -> bool {
+        let guard = self.lock.lock().unwrap();
+        let (_guard, result) = self
+            .changed
+            .wait_timeout_while(guard, timeout, |_| {
+                !self.audio_ready() || !self.video_ready()
+            })
+            .unwrap();
+        !result.timed_out()
+    }
+    /// Wait for the microphone stream to start, returns false if it did not within `timeout`
+    fn wait_for_mic(&self, timeout: Duration) -> bool {
+        let guard = self.lock.lock().unwrap();
+        let (_guard, result) = self
+            .changed
+            .wait_timeout_while(guard, timeout, |_| !self.mic_ready())
+            .unwrap();
+        !result.timed_out()
     }
 }
 
@@ -220,139 +628,485 @@ impl<V: VideoEncoder + PipewireSPA + StartVideoEncoder> Capture<V> {
     where
         V: 'static,
     {
+        let cursor_mode = if include_cursor {
+            CursorMode::EMBEDDED
+        } else {
+            CursorMode::HIDDEN
+        };
+        let mut capture = Self::open_with_encoder(
+            video_encoder,
+            AudioEncoderType::Opus,
+            AudioEncoderConfig::default(),
+            cursor_mode,
+            false,
+            false,
+            None,
+            None,
+            SourceType::all(),
+            None,
+            target_fps,
+            DEFAULT_BUFFER_FRAMES,
+        )?;
+        capture.start()?;
+        Ok(capture)
+    }
+
+    /// Open a capture which encodes with `video_encoder`, like [`Capture::new`] does with the
+    /// built-in encoders. Used by [`Self::new_with_encoder`] and
+    /// [`CaptureBuilder::build_with_encoder`](crate::pipeline::builder::CaptureBuilder::build_with_encoder)
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn open_with_encoder(
+        video_encoder: V,
+        audio_encoder_type: AudioEncoderType,
+        audio_config: AudioEncoderConfig,
+        cursor_mode: CursorMode,
+        include_audio: bool,
+        include_mic: bool,
+        audio_device: Option<u32>,
+        output: Option<String>,
+        source_type: SourceType,
+        restore_token: Option<String>,
+        target_fps: u64,
+        buffer_frames: usize,
+    ) -> Result<Self> {
         let mut _self = Self {
             controls: Arc::new(CaptureControls::from_fps(target_fps)),
+            resolution: Resolution::default(),
             worker_handles: Vec::new(),
             video_encoder: Some(Arc::new(Mutex::new(video_encoder))),
+            additional_video_encoders: Vec::new(),
             audio_encoder: None,
-            pw_video_terminate_tx: None,
+            pw_video_terminate_txs: Vec::new(),
             pw_audio_terminate_tx: None,
+            mic_encoder: None,
+            pw_mic_terminate_tx: None,
+            restore_token: None,
+            restart_params: None,
         };
 
-        let (frame_rx, ready_state, _) = _self.start_pipewire_video(include_cursor)?;
-
-        std::thread::sleep(Duration::from_millis(100));
-        ready_state.audio.store(true, Ordering::Release);
-        _self.start().unwrap();
+        let (frame_rx, ready_state, _) = _self.start_pipewire_video(
+            cursor_mode,
+            output,
+            false,
+            source_type,
+            restore_token,
+            V::get_spa_definition,
+            None,
+            buffer_frames,
+        )?;
 
-        ready_state.wait_for_both();
+        _self.start_system_audio(
+            &ready_state,
+            audio_encoder_type,
+            audio_config,
+            include_audio,
+            audio_device,
+        )?;
+        if include_mic {
+            _self.start_mic(&ready_state, audio_encoder_type, audio_config)?;
+        }
 
         V::start_processing(&mut _self, frame_rx)?;
 
         log::info!("Capture started successfully.");
         Ok(_self)
     }
+    #[allow(clippy::too_many_arguments)]
     fn start_pipewire_video(
         &mut self,
-        include_cursor: bool,
+        cursor_mode: CursorMode,
+        output: Option<String>,
+        combine_outputs: bool,
+        source_type: SourceType,
+        restore_token: Option<String>,
+        spa_definition: fn() -> Result<pipewire::spa::pod::Object>,
+        capture_size: Option<Resolution>,
+        buffer_frames: usize,
     ) -> Result<(Receiver<RawVideoFrame>, Arc<ReadyState>, Resolution)> {
-        let (frame_tx, frame_rx): (Sender<RawVideoFrame>, Receiver<RawVideoFrame>) = bounded(10);
+        let (frame_tx, frame_rx): (Sender<RawVideoFrame>, Receiver<RawVideoFrame>) =
+            bounded(buffer_frames);
 
         let ready_state = Arc::new(ReadyState::default());
-        let ready_state_pw = Arc::clone(&ready_state);
-
-        let (pw_sender, pw_recv) = pipewire::channel::channel();
-        self.pw_video_terminate_tx = Some(pw_sender);
 
-        let (reso_sender, reso_recv) = mpsc::channel::<Resolution>();
-
-        let mut screen_cast = ScreenCast::new()?;
-        screen_cast.set_source_types(SourceType::all());
-        screen_cast.set_cursor_mode(if include_cursor {
-            CursorMode::EMBEDDED
-        } else {
-            CursorMode::HIDDEN
-        });
-        let active_cast = screen_cast.start(None)?;
-        let fd = active_cast.pipewire_fd();
-        let stream = active_cast.streams().next().unwrap();
-        let stream_node = stream.pipewire_node();
-        let controls = Arc::clone(&self.controls);
-        self.worker_handles
-            .push(std::thread::spawn(move || -> Result<()> {
-                let mut video_cap = match VideoCapture::new(
-                    fd,
-                    stream_node,
-                    ready_state_pw,
-                    controls,
-                    reso_sender,
-                    frame_tx,
-                    pw_recv,
-                    V::get_spa_definition()?,
-                ) {
-                    Ok(pw_capture) => pw_capture,
+        // Let the user pick several outputs so we have something to choose from
+        let multiple = output.is_some() || combine_outputs;
+        let active_cast = match restore_token {
+            Some(token) => {
+                match start_screen_cast(cursor_mode, source_type, multiple, Some(&token)) {
+                    Ok(cast) => cast,
+                    Err(PortalError::Cancelled) => return Err(PortalError::Cancelled.into()),
                     Err(e) => {
-                        log::error!("Error initializing pipewire struct: {e:}");
-                        return Err(e);
+                        log::warn!("Portal rejected the restore token ({e}), prompting again");
+                        start_screen_cast(cursor_mode, source_type, multiple, None)?
                     }
-                };
-
-                video_cap.run()?;
+                }
+            }
+            None => start_screen_cast(cursor_mode, source_type, multiple, None)?,
+        };
+        self.restore_token = active_cast.restore_token().map(str::to_owned);
+        let fd = active_cast.pipewire_fd();
+        let stream_nodes: Vec<u32> = match output {
+            Some(name) => {
+                let nodes: Vec<u32> = active_cast.streams().map(|s| s.pipewire_node()).collect();
+                match find_output_node(fd, &nodes, &name)? {
+                    Some(node) => vec![node],
+                    None => {
+                        let _ = active_cast.close();
+                        return Err(WaycapError::Config(format!(
+                            "No screencast stream found for output {name}"
+                        )));
+                    }
+                }
+            }
+            None if combine_outputs => {
+                // Left to right like the outputs are arranged, streams without a position
+                // keep the portal's order
+                let mut streams: Vec<_> = active_cast.streams().collect();
+                streams.sort_by_key(|s| s.position().unwrap_or_default());
+                streams.iter().map(|s| s.pipewire_node()).collect()
+            }
+            None => active_cast
+                .streams()
+                .next()
+                .map(|s| s.pipewire_node())
+                .into_iter()
+                .collect(),
+        };
+        if stream_nodes.is_empty() {
+            let _ = active_cast.close();
+            return Err(WaycapError::Portal(
+                "The portal did not offer any screencast stream".into(),
+            ));
+        }
 
-                let _ = active_cast.close(); // Keep this alive until the thread ends
-                Ok(())
-            }));
+        // Every stream gets its own PipeWire connection, which takes ownership of its fd
+        let mut stream_fds = vec![fd];
+        for _ in 1..stream_nodes.len() {
+            let dup = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+            stream_fds.push(dup.into_raw_fd());
+        }
 
-        // Wait to get back a negotiated resolution from pipewire
-        let timeout = Duration::from_secs(5);
-        let start = Instant::now();
-        let resolution = loop {
-            if let Ok(reso) = reso_recv.recv() {
-                break reso;
-            }
+        let combine = stream_nodes.len() > 1;
+        let target_fps = self.framerate();
+        let mut frame_tx = Some(frame_tx);
+        let mut active_cast = Some(active_cast);
+        let mut stream_inputs = Vec::new();
+        let mut sizes = Vec::new();
+        for (stream_node, stream_fd) in stream_nodes.into_iter().zip(stream_fds) {
+            let stream_tx = if combine {
+                let (tx, rx) = bounded(buffer_frames);
+                stream_inputs.push(rx);
+                tx
+            } else {
+                frame_tx.take().unwrap()
+            };
+            let (pw_sender, pw_recv) = pipewire::channel::channel();
+            self.pw_video_terminate_txs.push(pw_sender);
+            let (reso_sender, reso_recv) = mpsc::channel::<Resolution>();
 
-            if start.elapsed() > timeout {
-                log::error!("Timeout waiting for PipeWire negotiated resolution.");
-                return Err(WaycapError::Init(
-                    "Timed out waiting for pipewire to negotiate video resolution".into(),
-                ));
-            }
+            self.worker_handles.push(spawn_video_capture(
+                stream_fd,
+                stream_node,
+                Arc::clone(&ready_state),
+                Arc::clone(&self.controls),
+                reso_sender,
+                stream_tx,
+                pw_recv,
+                spa_definition,
+                capture_size,
+                target_fps,
+                // The first stream keeps the portal session open
+                active_cast.take(),
+            ));
+            sizes.push(wait_for_resolution(&reso_recv)?);
+        }
 
-            std::thread::sleep(Duration::from_millis(100));
+        let resolution = if combine {
+            let (offsets, (width, height)) = composite::side_by_side(&sizes);
+            log::info!("Combining {} outputs into {width}x{height}", sizes.len());
+            let slots = offsets.into_iter().zip(sizes.iter().map(|size| size.width));
+            self.worker_handles.push(composite::composite_frames(
+                stream_inputs.into_iter().zip(slots).collect(),
+                (width, height),
+                // Frames waiting in the channel, the one being encoded and the one being drawn
+                buffer_frames + 2,
+                frame_tx.take().unwrap(),
+            ));
+            Resolution { width, height }
+        } else {
+            sizes[0]
         };
 
+        self.resolution = resolution;
         Ok((frame_rx, ready_state, resolution))
     }
 
     fn start_pipewire_audio(
         &mut self,
         audio_encoder_type: AudioEncoderType,
+        audio_config: AudioEncoderConfig,
         ready_state: Arc<ReadyState>,
+        source: AudioSource,
+        target_node: Option<u32>,
     ) -> Result<Receiver<RawAudioFrame>> {
         let (pw_audio_sender, pw_audio_recv) = pipewire::channel::channel();
-        self.pw_audio_terminate_tx = Some(pw_audio_sender);
-        let (audio_tx, audio_rx): (Sender<RawAudioFrame>, Receiver<RawAudioFrame>) = bounded(10);
+        let (audio_tx, audio_rx): (Sender<RawAudioFrame>, Receiver<RawAudioFrame>) =
+            bounded(audio_config.buffer_frames);
         let controls = Arc::clone(&self.controls);
-        let pw_audio_worker = std::thread::spawn(move || -> Result<()> {
-            log::debug!("Starting audio stream");
-            let audio_cap = AudioCapture::new(ready_state);
-            audio_cap.run(audio_tx, pw_audio_recv, controls)?;
+        let thread_name = format!("{}-pw", source.thread_prefix());
+        let pw_audio_worker = spawn_named(&thread_name, move || -> Result<()> {
+            log::debug!("Starting {source:?} stream");
+            let audio_cap = AudioCapture::new(
+                ready_state,
+                controls,
+                source,
+                audio_config.sample_rate,
+                target_node,
+            );
+            audio_cap.run(audio_tx, pw_audio_recv)?;
             Ok(())
         });
 
         self.worker_handles.push(pw_audio_worker);
 
         let enc: Arc<Mutex<dyn AudioEncoder + Send>> = match audio_encoder_type {
-            AudioEncoderType::Opus => Arc::new(Mutex::new(OpusEncoder::new()?)),
+            AudioEncoderType::Opus => Arc::new(Mutex::new(OpusEncoder::new(audio_config)?)),
+            AudioEncoderType::Aac => Arc::new(Mutex::new(AacEncoder::new(audio_config)?)),
+            AudioEncoderType::Flac => Arc::new(Mutex::new(FlacEncoder::new(audio_config)?)),
+            AudioEncoderType::Pcm => Arc::new(Mutex::new(PcmEncoder::new(audio_config)?)),
         };
 
-        self.audio_encoder = Some(enc);
+        match source {
+            AudioSource::SystemAudio => {
+                self.pw_audio_terminate_tx = Some(pw_audio_sender);
+                self.audio_encoder = Some(enc);
+            }
+            AudioSource::Microphone => {
+                self.pw_mic_terminate_tx = Some(pw_audio_sender);
+                self.mic_encoder = Some(enc);
+            }
+        }
 
         Ok(audio_rx)
     }
+
+    /// Record and encode the system audio if `include_audio`, then wait until both video and
+    /// audio are streaming
+    fn start_system_audio(
+        &mut self,
+        ready_state: &Arc<ReadyState>,
+        audio_encoder_type: AudioEncoderType,
+        audio_config: AudioEncoderConfig,
+        include_audio: bool,
+        audio_device: Option<u32>,
+    ) -> Result<()> {
+        if include_audio {
+            println!("including audio");
+            let audio_rx = self.start_pipewire_audio(
+                audio_encoder_type,
+                audio_config,
+                Arc::clone(ready_state),
+                AudioSource::SystemAudio,
+                audio_device,
+            )?;
+            // Wait until both either threads are ready, without a sink to link to the audio
+            // stream never starts
+            if !ready_state.wait_for_both(audio_config.start_timeout) {
+                return Err(WaycapError::Init(format!(
+                    "Audio and video streams did not both start within {:?} (audio: {}, video: {})",
+                    audio_config.start_timeout,
+                    ready_state.audio_ready(),
+                    ready_state.video_ready()
+                )));
+            }
+            let audio_loop = audio_encoding_loop(
+                Arc::clone(self.audio_encoder.as_ref().unwrap()),
+                audio_rx,
+                Arc::clone(&self.controls),
+                AudioSource::SystemAudio,
+            );
+
+            self.worker_handles.push(audio_loop);
+        } else {
+            println!("No audio");
+            ready_state.set_audio(true);
+            if !ready_state.wait_for_both(audio_config.start_timeout) {
+                return Err(WaycapError::Init(format!(
+                    "Video stream did not start within {:?}",
+                    audio_config.start_timeout
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Record and encode the default microphone
+    fn start_mic(
+        &mut self,
+        ready_state: &Arc<ReadyState>,
+        audio_encoder_type: AudioEncoderType,
+        audio_config: AudioEncoderConfig,
+    ) -> Result<()> {
+        let mic_rx = self.start_pipewire_audio(
+            audio_encoder_type,
+            audio_config,
+            Arc::clone(ready_state),
+            AudioSource::Microphone,
+            None,
+        )?;
+        // Without a microphone the stream never gets linked, so don't wait forever
+        if !ready_state.wait_for_mic(audio_config.start_timeout) {
+            return Err(WaycapError::Device(
+                "No microphone available, the microphone stream never started".into(),
+            ));
+        }
+        let mic_loop = audio_encoding_loop(
+            Arc::clone(self.mic_encoder.as_ref().unwrap()),
+            mic_rx,
+            Arc::clone(&self.controls),
+            AudioSource::Microphone,
+        );
+
+        self.worker_handles.push(mic_loop);
+        Ok(())
+    }
 }
 impl<V: VideoEncoder> Capture<V> {
     /// Enables capture streams to send their frames to their encoders
+    ///
+    /// Frames captured before this are dropped. Also clears a pause requested with
+    /// [`CaptureControls::pause`].
     pub fn start(&mut self) -> Result<()> {
-        self.controls.resume();
+        self.controls.start();
         Ok(())
     }
 
+    /// Width and height of the captured stream as negotiated with PipeWire when the capture
+    /// was built, or of the region set with
+    /// [`CaptureBuilder::with_region`](crate::pipeline::builder::CaptureBuilder::with_region),
+    /// or the size asked for with
+    /// [`CaptureBuilder::with_capture_resolution`](crate::pipeline::builder::CaptureBuilder::with_capture_resolution)
+    /// which the encoder scales to if the compositor didn't honor it.
+    /// If the stream is renegotiated later the encoder follows the new size, unless a capture
+    /// resolution was asked for.
+    ///
+    /// For captures built with the built-in encoders this is the encoded size, which
+    /// is rounded down to even numbers, so odd sized window captures lose a column or row.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.resolution.width, self.resolution.height)
+    }
+
+    /// Pixel format of the raw frames as negotiated with PipeWire, e.g. [`VideoFormat::BGRA`]
+    /// for CPU frames or [`VideoFormat::NV12`] for DMA-BUFs some compositors hand out.
+    ///
+    /// Every [`RawVideoFrame`] passed to the encoder has this format, so GPU consumers like
+    /// [`DmaBufEncoder`] users can set up their import before the first frame arrives.
+    /// Follows renegotiations of the stream, [`VideoFormat::Unknown`] if nothing was
+    /// negotiated yet.
+    pub fn negotiated_format(&self) -> VideoFormat {
+        self.controls
+            .negotiated_format
+            .lock()
+            .unwrap()
+            .map_or(VideoFormat::Unknown, |(format, _)| format)
+    }
+
+    /// Node the video is recorded from, to check the capture records the intended output or
+    /// to store in the recording's metadata.
+    ///
+    /// Read once the stream was set up, the refresh rate follows renegotiations. Captures of
+    /// several outputs report the first one, test pattern captures an empty [`SourceInfo`].
+    pub fn source_info(&self) -> SourceInfo {
+        self.controls
+            .source_info
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Transform of the recorded output, e.g. [`Transform::Rotate90`] for a portrait monitor.
+    ///
+    /// Frames are encoded without it, so a portrait capture comes out sideways unless it is
+    /// rotated when played back. [`crate::pipeline::muxer::Muxer`] stores it in the file as a
+    /// display matrix, other consumers can rotate with it. [`Transform::Normal`] when the
+    /// compositor doesn't send one, follows changes while recording.
+    pub fn transform(&self) -> Transform {
+        *self.controls.transform.lock().unwrap()
+    }
+
+    /// DRM format modifier of the negotiated DMA-BUFs, see [`Self::negotiated_format`].
+    /// 0 (linear) for frames in CPU memory or before anything was negotiated.
+    pub fn negotiated_modifier(&self) -> u64 {
+        self.controls
+            .negotiated_format
+            .lock()
+            .unwrap()
+            .map_or(0, |(_, modifier)| modifier)
+    }
+
+    /// True when the compositor shares frames as DMA-BUFs, false when it copies them into
+    /// memory buffers, e.g. in some nested compositors, VMs or remote sessions.
+    ///
+    /// Known once the capture was built. NVENC and AMF only import DMA-BUFs, so building a
+    /// capture with one of them fails with [`WaycapError::Stream`] when only memory buffers
+    /// were negotiated. VAAPI and QSV fall back to uploading the frames, which costs CPU time.
+    pub fn is_dmabuf(&self) -> bool {
+        self.controls.is_dmabuf()
+    }
+
+    /// Token to skip the portal dialog next time, pass it to
+    /// [`CaptureBuilder::with_restore_token`](crate::pipeline::builder::CaptureBuilder::with_restore_token).
+    ///
+    /// Tokens can only be used once, store the one of the latest capture. `None` if the portal
+    /// doesn't support restoring sessions.
+    pub fn restore_token(&self) -> Option<String> {
+        self.restore_token.clone()
+    }
+
+    /// Target framerate frames are encoded at
+    pub fn framerate(&self) -> u64 {
+        self.controls.target_fps.load(Ordering::Acquire)
+    }
+
     /// Temporarily stops the recording by blocking frames from being sent to the encoders
     pub fn controls(&mut self) -> Arc<CaptureControls> {
         Arc::clone(&self.controls)
     }
 
+    /// Snapshot of how many video frames were captured, dropped and encoded so far
+    ///
+    /// A growing `frames_dropped` means the encoder or the consumer of [`Self::get_output`]
+    /// can't keep up with the target framerate.
+    pub fn stats(&self) -> CaptureStats {
+        self.controls.counters().snapshot()
+    }
+
+    /// Average time the last frames spent in the video encoder, from handing over the frame
+    /// until an encoded packet came out.
+    ///
+    /// Only measured when built with
+    /// [`CaptureBuilder::with_timing`](crate::pipeline::builder::CaptureBuilder::with_timing),
+    /// `None` otherwise or before the first frame was encoded. Useful to compare quality presets
+    /// on the hardware at hand.
+    pub fn encode_latency(&self) -> Option<Duration> {
+        self.controls.counters().encode_latency()
+    }
+
+    /// Rolling RMS level of the captured system audio, from 0.0 to 1.0, e.g. for a level
+    /// meter. Always 0 for captures without audio.
+    pub fn audio_level(&self) -> f32 {
+        self.controls.audio_level.level()
+    }
+
+    /// Error which stopped the capture, `None` while it's running fine.
+    ///
+    /// When PipeWire fails the capture is stopped and the video receivers disconnect once the
+    /// frames still in them were read, so a `recv` returning an error is a good time to check.
+    pub fn last_error(&self) -> Option<WaycapError> {
+        self.controls.last_error()
+    }
+
     /// Stop recording and drain the encoders of any last frames they have in their internal
     /// buffers. These frames are discarded.
     pub fn finish(&mut self) -> Result<()> {
@@ -360,47 +1114,94 @@ impl<V: VideoEncoder> Capture<V> {
         if let Some(ref mut enc) = self.video_encoder {
             enc.lock().unwrap().drain()?;
         }
+        for enc in &self.additional_video_encoders {
+            enc.lock().unwrap().drain()?;
+        }
         if let Some(ref mut enc) = self.audio_encoder {
             enc.lock().unwrap().drain()?;
         }
+        if let Some(ref mut enc) = self.mic_encoder {
+            enc.lock().unwrap().drain()?;
+        }
         Ok(())
     }
 
+    /// Like [`Self::finish`], but return the last frames of the encoders instead of discarding
+    /// them, e.g. to append them to a recording.
+    ///
+    /// The video frames are those of the main video encoder, the audio frames those of the
+    /// system audio followed by those of the microphone. The last frames of additional video
+    /// encoders are still discarded.
+    pub fn finish_collecting(&mut self) -> Result<(Vec<V::Output>, Vec<EncodedAudioFrame>)> {
+        self.controls.pause();
+        let mut video = Vec::new();
+        if let Some(ref mut enc) = self.video_encoder {
+            video = enc.lock().unwrap().drain_collecting()?;
+        }
+        for enc in &self.additional_video_encoders {
+            enc.lock().unwrap().drain()?;
+        }
+        let mut audio = Vec::new();
+        if let Some(ref mut enc) = self.audio_encoder {
+            audio.extend(enc.lock().unwrap().drain_collecting()?);
+        }
+        if let Some(ref mut enc) = self.mic_encoder {
+            audio.extend(enc.lock().unwrap().drain_collecting()?);
+        }
+        Ok((video, audio))
+    }
+
     /// Resets the encoder states so we can resume encoding from within this same session
     pub fn reset(&mut self) -> Result<()> {
         if let Some(ref mut enc) = self.video_encoder {
             enc.lock().unwrap().reset()?;
         }
+        for enc in &self.additional_video_encoders {
+            enc.lock().unwrap().reset()?;
+        }
         if let Some(ref mut enc) = self.audio_encoder {
             enc.lock().unwrap().reset()?;
         }
+        if let Some(ref mut enc) = self.mic_encoder {
+            enc.lock().unwrap().reset()?;
+        }
 
         Ok(())
     }
 
-    /// Close the connection. Once called the struct cannot be re-used and must be re-built with
-    /// the [`crate::pipeline::builder::CaptureBuilder`] to record again.
+    /// Close the connection. Once called the struct can only record again after
+    /// [`Capture::restart`], which skips the portal dialog, or by re-building it with the
+    /// [`crate::pipeline::builder::CaptureBuilder`].
     /// If your goal is to temporarily stop recording use [`Self::pause`] or [`Self::finish`] + [`Self::reset`]
     pub fn close(&mut self) -> Result<()> {
         self.finish()?;
         self.controls.stop();
-        if let Some(pw_vid) = &self.pw_video_terminate_tx {
+        for pw_vid in &self.pw_video_terminate_txs {
             let _ = pw_vid.send(Terminate {});
         }
         if let Some(pw_aud) = &self.pw_audio_terminate_tx {
             let _ = pw_aud.send(Terminate {});
         }
+        if let Some(pw_mic) = &self.pw_mic_terminate_tx {
+            let _ = pw_mic.send(Terminate {});
+        }
 
         for handle in self.worker_handles.drain(..) {
             let _ = handle.join();
         }
 
         drop(self.video_encoder.take());
+        self.additional_video_encoders.clear();
         drop(self.audio_encoder.take());
+        drop(self.mic_encoder.take());
 
         Ok(())
     }
 
+    /// Receiver for the output of the video encoder
+    ///
+    /// Dropping it stops the capture, the PipeWire and encoder threads exit on their own.
+    /// [`Self::close`] is still needed to drain the encoders.
     pub fn get_output(&mut self) -> Receiver<V::Output> {
         self.video_encoder
             .as_mut()
@@ -410,57 +1211,373 @@ impl<V: VideoEncoder> Capture<V> {
             .output()
             .unwrap()
     }
+
+    /// Receivers for the encoders added with
+    /// [`crate::pipeline::builder::CaptureBuilder::with_additional_video_encoder`], in the order
+    /// they were added. Empty when there are none.
+    pub fn get_additional_video_receivers(&mut self) -> Vec<Receiver<V::Output>> {
+        self.additional_video_encoders
+            .iter()
+            .map(|enc| enc.lock().unwrap().output().unwrap())
+            .collect()
+    }
+
+    /// Call `f` with every video frame the encoder outputs.
+    ///
+    /// `f` runs on its own worker thread which is joined on [`Self::close`], so there's no need
+    /// to poll [`Self::get_output`] yourself.
+    pub fn on_video_frame<F>(&mut self, f: F)
+    where
+        F: FnMut(&V::Output) + Send + 'static,
+        V::Output: Send + 'static,
+    {
+        let recv = self.get_output();
+        let handle = callback_loop(recv, Arc::clone(&self.controls), f);
+        self.worker_handles.push(handle);
+    }
 }
 
 impl Capture<DynamicEncoder> {
-    pub fn new(
-        video_encoder_type: Option<VideoEncoderType>,
-        audio_encoder_type: AudioEncoderType,
-        quality: QualityPreset,
-        include_cursor: bool,
-        include_audio: bool,
+    /// Open a capture with the built-in encoders, used by
+    /// [`CaptureBuilder::build`](crate::pipeline::builder::CaptureBuilder::build)
+    pub(crate) fn new(
+        params: CaptureParams,
+        restore_token: Option<String>,
         target_fps: u64,
     ) -> Result<Self> {
+        Self::open(
+            params,
+            restore_token,
+            Arc::new(CaptureControls::from_fps(target_fps)),
+        )
+    }
+
+    /// True if the video PTS start at 0 instead of being capture timestamps, see
+    /// [`CaptureBuilder::with_zero_based_pts`](crate::pipeline::builder::CaptureBuilder::with_zero_based_pts)
+    pub(crate) fn zero_based_pts(&self) -> bool {
+        self.restart_params
+            .as_ref()
+            .is_some_and(|params| params.video_config.zero_based_pts)
+    }
+
+    /// Open the portal session, streams and encoders of a capture driven by `controls`
+    fn open(
+        params: CaptureParams,
+        restore_token: Option<String>,
+        controls: Arc<CaptureControls>,
+    ) -> Result<Self> {
+        let CaptureParams {
+            video_encoder_type,
+            audio_encoder_type,
+            audio_config,
+            video_config,
+            cursor_mode,
+            include_audio,
+            include_mic,
+            audio_device,
+            output,
+            combine_outputs,
+            source_type,
+            software_fallback,
+            additional_encoders,
+            egl_context,
+        } = params.clone();
+        let video_encoder_type = match video_encoder_type {
+            None if software_fallback && detect_gpu_vendor()? == GpuVendor::UNKNOWN => {
+                log::warn!("No supported GPU found, falling back to software encoding");
+                Some(VideoEncoderType::H264Software)
+            }
+            encoder_type => encoder_type,
+        };
+        let spa_definition: fn() -> Result<pipewire::spa::pod::Object> =
+            match (video_encoder_type, video_config.bit_depth) {
+                (Some(VideoEncoderType::H264Software), _) => X264Encoder::get_spa_definition,
+                // Only VAAPI encodes 10-bit, the encoder rejects the config otherwise
+                (_, BitDepth::Ten) => VaapiEncoder::get_10bit_spa_definition,
+                _ => DynamicEncoder::get_spa_definition,
+            };
+        // Software encoders need frames in CPU memory, hardware ones DMA-BUFs
+        let is_software = matches!(video_encoder_type, Some(VideoEncoderType::H264Software));
+        if additional_encoders
+            .iter()
+            .any(|(enc, _)| matches!(enc, VideoEncoderType::H264Software) != is_software)
+        {
+            return Err(WaycapError::Config(
+                "Software and hardware video encoders can't share a capture".to_string(),
+            ));
+        }
+        if combine_outputs && video_config.bit_depth == BitDepth::Ten {
+            return Err(WaycapError::Config(
+                "Combined outputs are only recorded with 8-bit depth".to_string(),
+            ));
+        }
+        if combine_outputs && output.is_some() {
+            return Err(WaycapError::Config(
+                "Combined outputs can't be limited to a single output".to_string(),
+            ));
+        }
+
         let mut _self = Self {
-            controls: Arc::new(CaptureControls::from_fps(target_fps)),
+            controls,
+            resolution: Resolution::default(),
             worker_handles: Vec::new(),
             video_encoder: None,
+            additional_video_encoders: Vec::new(),
             audio_encoder: None,
-            pw_video_terminate_tx: None,
+            pw_video_terminate_txs: Vec::new(),
             pw_audio_terminate_tx: None,
+            mic_encoder: None,
+            pw_mic_terminate_tx: None,
+            restore_token: None,
+            restart_params: Some(params),
         };
 
-        let (frame_rx, ready_state, resolution) = _self.start_pipewire_video(include_cursor)?;
+        let (frame_rx, ready_state, resolution) = _self.start_pipewire_video(
+            cursor_mode,
+            output,
+            combine_outputs,
+            source_type,
+            restore_token,
+            spa_definition,
+            video_config.capture_resolution,
+            video_config.buffer_frames,
+        )?;
 
-        _self.video_encoder = Some(Arc::new(Mutex::new(DynamicEncoder::new(
+        if let Some(region) = video_config.region {
+            if !region.fits_in(resolution.width, resolution.height) {
+                return Err(WaycapError::Config(format!(
+                    "Region {region:?} does not fit in the {}x{} output",
+                    resolution.width, resolution.height
+                )));
+            }
+        }
+        let (crop_width, crop_height) = video_config.crop_size(resolution.width, resolution.height);
+        if video_config.region.is_none()
+            && (crop_width, crop_height) != (resolution.width, resolution.height)
+        {
+            log::info!(
+                "Cropping the odd sized {}x{} stream to {crop_width}x{crop_height} for encoding",
+                resolution.width,
+                resolution.height
+            );
+        }
+        let (width, height) = video_config.output_size(resolution.width, resolution.height);
+        if (width, height) != (crop_width, crop_height) {
+            log::info!(
+                "The compositor sent {}x{} instead of the requested capture resolution, \
+                 scaling to {width}x{height} in the encoder",
+                resolution.width,
+                resolution.height
+            );
+        }
+        _self.resolution = Resolution { width, height };
+
+        _self.start_system_audio(
+            &ready_state,
+            audio_encoder_type,
+            audio_config,
+            include_audio,
+            audio_device,
+        )?;
+
+        // Buffers were allocated once the video stream started, NVENC and AMF would fail on
+        // the first frame of a stream without DMA-BUFs. Checked before building the encoders,
+        // which set up GPU state that is only torn down after the capture ran
+        if !_self.controls.is_dmabuf() {
+            let mut needs_dmabuf = DynamicEncoder::needs_dmabuf(video_encoder_type)?;
+            for (encoder_type, _) in &additional_encoders {
+                needs_dmabuf |= DynamicEncoder::needs_dmabuf(Some(*encoder_type))?;
+            }
+            if needs_dmabuf {
+                return Err(WaycapError::Stream(
+                    "The compositor did not negotiate DMA-BUFs, which NVENC and AMF need. \
+                     Use a VAAPI, QSV or the software encoder for captures with frames in memory"
+                        .to_string(),
+                ));
+            }
+        }
+
+        let mut video_encoder = DynamicEncoder::new(
             video_encoder_type,
             resolution.width,
             resolution.height,
-            quality,
-        )?)));
+            video_config.clone(),
+        )?;
+        if let Some(egl_context) = egl_context {
+            video_encoder.set_egl_context(egl_context)?;
+        }
+        _self.video_encoder = Some(Arc::new(Mutex::new(video_encoder)));
+        for (encoder_type, quality) in additional_encoders {
+            let config = VideoEncoderConfig {
+                quality,
+                rate_control: None,
+                ..video_config.clone()
+            };
+            _self
+                .additional_video_encoders
+                .push(Arc::new(Mutex::new(DynamicEncoder::new(
+                    Some(encoder_type),
+                    resolution.width,
+                    resolution.height,
+                    config,
+                )?)));
+        }
 
-        if include_audio {
-            println!("including audio");
-            let audio_rx =
-                _self.start_pipewire_audio(audio_encoder_type, Arc::clone(&ready_state))?;
-            // Wait until both either threads are ready
-            ready_state.wait_for_both();
-            let audio_loop = audio_encoding_loop(
-                Arc::clone(_self.audio_encoder.as_ref().unwrap()),
-                audio_rx,
-                Arc::clone(&_self.controls),
-            );
+        if include_mic {
+            _self.start_mic(&ready_state, audio_encoder_type, audio_config)?;
+        }
 
-            _self.worker_handles.push(audio_loop);
+        if _self.additional_video_encoders.is_empty() {
+            DynamicEncoder::start_processing(&mut _self, frame_rx)?;
         } else {
-            println!("No audio");
-            ready_state.audio.store(true, Ordering::Release);
-            ready_state.wait_for_both();
+            let mut inputs = Vec::new();
+            let mut senders = Vec::new();
+            for _ in 0..=_self.additional_video_encoders.len() {
+                let (tx, rx) = bounded(video_config.buffer_frames);
+                senders.push(tx);
+                inputs.push(rx);
+            }
+            _self.worker_handles.push(fan_out_frames(frame_rx, senders));
+
+            let mut inputs = inputs.into_iter();
+            DynamicEncoder::start_processing(&mut _self, inputs.next().unwrap())?;
+            for (encoder, input) in _self.additional_video_encoders.iter().zip(inputs) {
+                let handle = spawn_processing_thread(
+                    Arc::clone(encoder),
+                    Arc::clone(&_self.controls),
+                    input,
+                );
+                _self.worker_handles.push(handle);
+            }
+        }
+
+        log::info!("Capture started successfully.");
+        Ok(_self)
+    }
+
+    /// Bring the capture back after [`Self::close`] without prompting for the sources again.
+    ///
+    /// A new portal session is opened with [`Self::restore_token`], so the portal picks the
+    /// same sources without showing its dialog as long as it supports restoring sessions. The
+    /// PipeWire streams, encoders and worker threads are then set up again with the settings
+    /// the capture was built with. A running capture is closed first.
+    ///
+    /// Unlike [`Self::finish`] + [`Self::reset`], which only re-create the encoders while the
+    /// streams keep running, this recovers from a full stop, e.g. after the capture was stopped
+    /// by an error. [`Self::controls`] stay valid and keep their settings, but receivers from
+    /// before the restart disconnect so get new ones afterwards. Like a newly built capture it
+    /// has to be started with [`Self::start`].
+    ///
+    /// Only captures built with the [`CaptureBuilder`](crate::pipeline::builder::CaptureBuilder)
+    /// can be restarted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use waycap_rs::pipeline::builder::CaptureBuilder;
+    /// # use waycap_rs::types::error::Result;
+    /// # fn thing() -> Result<()> {
+    /// let mut capture = CaptureBuilder::new().build()?;
+    /// capture.start()?;
+    /// // ...
+    /// capture.close()?;
+    ///
+    /// capture.restart()?;
+    /// let video_receiver = capture.get_video_receiver();
+    /// capture.start()?;
+    /// # Ok(())}
+    /// ```
+    pub fn restart(&mut self) -> Result<()> {
+        let Some(params) = self.restart_params.clone() else {
+            return Err(WaycapError::Validation(
+                "Only captures built with the built-in encoders can be restarted".to_string(),
+            ));
+        };
+        self.close()?;
+
+        // Dropping the closed capture stops its controls, so it must not keep the ones taken over
+        let controls =
+            std::mem::replace(&mut self.controls, Arc::new(CaptureControls::from_fps(0)));
+        controls.rearm();
+        match Self::open(params, self.restore_token.clone(), Arc::clone(&controls)) {
+            Ok(restarted) => *self = restarted,
+            Err(e) => {
+                self.controls = controls;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Capture moving color bars of `width` x `height` generated at `fps` instead of the
+    /// screen, encoded in software with [`VideoEncoderType::H264Software`].
+    ///
+    /// Neither the portal nor PipeWire are used, so this works on headless machines, e.g. to
+    /// test code consuming the frames in CI. Like any capture it has to be started first.
+    /// Sizes below 2x2 or above 4096x4096 and a framerate of 0 return [`WaycapError::Config`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use waycap_rs::{Capture, types::error::Result};
+    /// # fn thing() -> Result<()> {
+    /// let mut capture = Capture::new_with_test_source(1280, 720, 30)?;
+    /// let frames = capture.get_video_receiver();
+    /// capture.start()?;
+    ///
+    /// let first = frames.recv().unwrap();
+    /// assert!(first.is_keyframe);
+    /// # Ok(())}
+    /// ```
+    pub fn new_with_test_source(width: u32, height: u32, fps: u64) -> Result<Self> {
+        if !(2..=MAX_CAPTURE_SIZE).contains(&width) || !(2..=MAX_CAPTURE_SIZE).contains(&height) {
+            return Err(WaycapError::Config(format!(
+                "Test source size must be between 2x2 and {MAX_CAPTURE_SIZE}x{MAX_CAPTURE_SIZE}, got {width}x{height}"
+            )));
+        }
+        if fps == 0 {
+            return Err(WaycapError::Config(
+                "Test source needs a framerate above 0".to_string(),
+            ));
         }
 
+        let mut _self = Self {
+            controls: Arc::new(CaptureControls::from_fps(fps)),
+            resolution: Resolution {
+                width: width & !1,
+                height: height & !1,
+            },
+            worker_handles: Vec::new(),
+            video_encoder: None,
+            additional_video_encoders: Vec::new(),
+            audio_encoder: None,
+            pw_video_terminate_txs: Vec::new(),
+            pw_audio_terminate_tx: None,
+            mic_encoder: None,
+            pw_mic_terminate_tx: None,
+            restore_token: None,
+            restart_params: None,
+        };
+        _self.controls.set_negotiated_format(VideoFormat::BGRx, 0);
+
+        _self.video_encoder = Some(Arc::new(Mutex::new(DynamicEncoder::new(
+            Some(VideoEncoderType::H264Software),
+            width,
+            height,
+            VideoEncoderConfig::default(),
+        )?)));
+
+        let (frame_tx, frame_rx) = bounded(DEFAULT_BUFFER_FRAMES);
+        _self.worker_handles.push(test_pattern::spawn_test_pattern(
+            width,
+            height,
+            fps,
+            Arc::clone(&_self.controls),
+            frame_tx,
+        ));
         DynamicEncoder::start_processing(&mut _self, frame_rx)?;
 
-        log::info!("Capture started successfully.");
+        log::info!("Test pattern capture created.");
         Ok(_self)
     }
 
@@ -478,6 +1595,37 @@ impl Capture<DynamicEncoder> {
             .unwrap()
     }
 
+    /// Iterate over encoded video frames, blocking until the next one is ready.
+    ///
+    /// Ends once the capture is closed with [`Self::close`] or stopped by an error, after the
+    /// frames encoded before that were yielded. Like [`Self::get_video_receiver`] every call
+    /// creates a new consumer that will receive all future frames, so the iterator can be
+    /// moved to another thread while this one keeps control of the capture.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use waycap_rs::pipeline::builder::CaptureBuilder;
+    /// # use waycap_rs::types::error::Result;
+    /// # fn thing() -> Result<()> {
+    /// let mut capture = CaptureBuilder::new().build()?;
+    /// let frames = capture.video_frames();
+    /// let reader = std::thread::spawn(move || {
+    ///     for frame in frames {
+    ///         println!("{} bytes at {}", frame.data.len(), frame.pts);
+    ///     }
+    /// });
+    ///
+    /// capture.start()?;
+    /// std::thread::sleep(std::time::Duration::from_secs(10));
+    /// capture.close()?;
+    /// reader.join().unwrap();
+    /// # Ok(())}
+    /// ```
+    pub fn video_frames(&mut self) -> impl Iterator<Item = EncodedVideoFrame> {
+        self.get_video_receiver().into_iter()
+    }
+
     /// Get a channel for which to receive encoded audio frames.
     ///
     /// Returns a [`crossbeam::channel::Receiver`] which allows multiple consumers.
@@ -492,6 +1640,98 @@ impl Capture<DynamicEncoder> {
         }
     }
 
+    /// Call `f` with every encoded audio frame.
+    ///
+    /// `f` runs on its own worker thread which is joined on [`Self::close`].
+    /// Returns an error if the capture was built without audio.
+    pub fn on_audio_frame<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnMut(&EncodedAudioFrame) + Send + 'static,
+    {
+        let recv = self.get_audio_receiver()?;
+        let handle = callback_loop(recv, Arc::clone(&self.controls), f);
+        self.worker_handles.push(handle);
+        Ok(())
+    }
+
+    /// Get a channel for which to receive encoded microphone frames.
+    ///
+    /// Only available when the capture was built with
+    /// [`crate::pipeline::builder::CaptureBuilder::with_microphone`].
+    /// Returns a [`crossbeam::channel::Receiver`] which allows multiple consumers.
+    pub fn get_mic_receiver(&mut self) -> Result<Receiver<EncodedAudioFrame>> {
+        if let Some(ref mut mic_enc) = self.mic_encoder {
+            Ok(mic_enc.lock().unwrap().get_encoded_recv().unwrap())
+        } else {
+            Err(WaycapError::Validation(
+                "Microphone encoder does not exist".to_string(),
+            ))
+        }
+    }
+
+    /// Change the video quality preset while recording.
+    ///
+    /// The video encoder is drained and re-created with the new settings, frames still buffered
+    /// in it are sent to the video receiver first. Receivers obtained through
+    /// [`Self::get_video_receiver`] stay valid.
+    pub fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        match self.video_encoder {
+            Some(ref mut enc) => enc.lock().unwrap().set_quality(quality),
+            None => Err(WaycapError::Validation(
+                "Video encoder does not exist".to_string(),
+            )),
+        }
+    }
+
+    /// End the current segment so the recording can be split into several files.
+    ///
+    /// The video encoders are drained and re-created, so the next video frame is a keyframe
+    /// with [`EncodedVideoFrame::starts_segment`] set. Frames before it belong to the old
+    /// segment, audio can be split at that frame's timestamp.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use waycap_rs::pipeline::{builder::CaptureBuilder, muxer::Muxer};
+    /// # use waycap_rs::types::error::Result;
+    /// # fn thing() -> Result<()> {
+    /// let mut capture = CaptureBuilder::new().build()?;
+    /// let video_receiver = capture.get_video_receiver();
+    /// let mut muxer = Muxer::new("segment-0.mp4", &capture)?;
+    /// let mut segment = 0;
+    ///
+    /// capture.start()?;
+    /// for (i, frame) in video_receiver.iter().enumerate().take(1800) {
+    ///     // A new file every 10 seconds at 60 fps
+    ///     if i > 0 && i % 600 == 0 {
+    ///         capture.rotate_segment()?;
+    ///     }
+    ///     if frame.starts_segment {
+    ///         segment += 1;
+    ///         muxer.finish()?;
+    ///         muxer = Muxer::new(format!("segment-{segment}.mp4"), &capture)?;
+    ///     }
+    ///     muxer.push_video(frame)?;
+    /// }
+    /// capture.close()?;
+    /// muxer.finish()?;
+    /// # Ok(())}
+    /// ```
+    pub fn rotate_segment(&self) -> Result<()> {
+        match self.video_encoder {
+            Some(ref enc) => enc.lock().unwrap().rotate_segment()?,
+            None => {
+                return Err(WaycapError::Validation(
+                    "Video encoder does not exist".to_string(),
+                ))
+            }
+        }
+        for enc in &self.additional_video_encoders {
+            enc.lock().unwrap().rotate_segment()?;
+        }
+        Ok(())
+    }
+
     /// Perform an action with the video encoder
     /// # Examples
     ///
@@ -526,7 +1766,31 @@ impl Capture<DynamicEncoder> {
         f(guard.get_encoder())
     }
 
+    /// Codec configuration of the video encoder (`extradata`), for H.264 the SPS and PPS, to
+    /// signal to a decoder out-of-band before the first keyframe, e.g. over RTMP or WebRTC.
+    ///
+    /// `None` when the encoder didn't export any, which some encoders only do when writing
+    /// into a container. Keyframes still carry the parameter sets in-band then. Re-created
+    /// encoders, e.g. after [`Self::set_quality`], can have a new configuration.
+    pub fn video_extradata(&self) -> Option<Vec<u8>> {
+        let guard = self.video_encoder.as_ref()?.lock().unwrap();
+        let encoder = guard.get_encoder().as_ref()?;
+        unsafe {
+            let ctx = encoder.as_ptr();
+            if (*ctx).extradata.is_null() || (*ctx).extradata_size <= 0 {
+                return None;
+            }
+            Some(
+                std::slice::from_raw_parts((*ctx).extradata, (*ctx).extradata_size as usize)
+                    .to_vec(),
+            )
+        }
+    }
+
     /// Perform an action with the audio encoder
+    ///
+    /// The encoder is `None` for [`AudioEncoder::Pcm`](crate::types::config::AudioEncoder::Pcm)
+    /// since raw samples don't go through ffmpeg.
     /// # Examples
     ///
     /// ```
@@ -568,18 +1832,133 @@ impl<V: VideoEncoder> Drop for Capture<V> {
     }
 }
 
+/// Run a [`VideoCapture`] of `stream_node` on a new thread until it's terminated through
+/// `pw_recv`. `active_cast` is kept open until then.
+#[allow(clippy::too_many_arguments)]
+fn spawn_video_capture(
+    fd: RawFd,
+    stream_node: u32,
+    ready_state: Arc<ReadyState>,
+    controls: Arc<CaptureControls>,
+    reso_sender: mpsc::Sender<Resolution>,
+    frame_tx: Sender<RawVideoFrame>,
+    pw_recv: pipewire::channel::Receiver<Terminate>,
+    spa_definition: fn() -> Result<pipewire::spa::pod::Object>,
+    capture_size: Option<Resolution>,
+    target_fps: u64,
+    active_cast: Option<ActiveScreenCast>,
+) -> std::thread::JoinHandle<Result<()>> {
+    spawn_named("waycap-video-pw", move || -> Result<()> {
+        let mut spa_object = spa_definition()?;
+        set_spa_framerate(&mut spa_object, target_fps);
+        if let Some(size) = capture_size {
+            set_spa_size(&mut spa_object, size);
+        }
+        let mut video_cap = match VideoCapture::new(
+            fd,
+            stream_node,
+            ready_state,
+            controls,
+            reso_sender,
+            frame_tx,
+            pw_recv,
+            spa_object,
+        ) {
+            Ok(pw_capture) => pw_capture,
+            Err(e) => {
+                log::error!("Error initializing pipewire struct: {e:}");
+                return Err(e);
+            }
+        };
+
+        video_cap.run()?;
+
+        if let Some(cast) = active_cast {
+            let _ = cast.close(); // Keep this alive until the thread ends
+        }
+        Ok(())
+    })
+}
+
+/// Wait to get back a negotiated resolution from pipewire
+fn wait_for_resolution(reso_recv: &mpsc::Receiver<Resolution>) -> Result<Resolution> {
+    match reso_recv.recv_timeout(Duration::from_secs(5)) {
+        Ok(reso) => Ok(reso),
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            log::error!("Timeout waiting for PipeWire negotiated resolution.");
+            Err(WaycapError::Init(
+                "Timed out waiting for pipewire to negotiate video resolution".into(),
+            ))
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(WaycapError::Init(
+            "The video stream ended before pipewire negotiated a resolution".into(),
+        )),
+    }
+}
+
+/// Open a portal session and prompt for the sources, or restore them from `restore_token`.
+/// Persistence is always requested so the next capture gets a token.
+fn start_screen_cast(
+    cursor_mode: CursorMode,
+    source_type: SourceType,
+    multiple: bool,
+    restore_token: Option<&str>,
+) -> std::result::Result<ActiveScreenCast, PortalError> {
+    let mut screen_cast = ScreenCast::new()?;
+    screen_cast.set_source_types(source_type);
+    screen_cast.set_cursor_mode(cursor_mode);
+    if multiple {
+        screen_cast.enable_multiple();
+    }
+    screen_cast.set_persist_mode(PersistMode::ExplicitlyRevoked);
+    if let Some(token) = restore_token {
+        screen_cast.set_restore_token(token);
+    }
+    screen_cast.start(None)
+}
+
+/// Send a copy of every captured frame to each encoder's input.
+/// Exits once the capture stream ends or every encoder has gone away.
+fn fan_out_frames(
+    input: Receiver<RawVideoFrame>,
+    outputs: Vec<Sender<RawVideoFrame>>,
+) -> std::thread::JoinHandle<Result<()>> {
+    spawn_named("waycap-fan-out", move || -> Result<()> {
+        let mut outputs: Vec<Option<Sender<RawVideoFrame>>> =
+            outputs.into_iter().map(Some).collect();
+        for frame in input.iter() {
+            for slot in outputs.iter_mut() {
+                let Some(tx) = slot else { continue };
+                match tx.try_send(frame.clone()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        log::warn!("Video encoder is falling behind, dropping a frame");
+                    }
+                    Err(TrySendError::Disconnected(_)) => *slot = None,
+                }
+            }
+            if outputs.iter().all(Option::is_none) {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
 #[allow(clippy::too_many_arguments)]
 fn audio_encoding_loop(
     audio_encoder: Arc<Mutex<dyn AudioEncoder + Send>>,
     audio_recv: Receiver<RawAudioFrame>,
     controls: Arc<CaptureControls>,
+    source: AudioSource,
 ) -> std::thread::JoinHandle<Result<()>> {
-    std::thread::spawn(move || -> Result<()> {
+    let thread_name = format!("{}-enc", source.thread_prefix());
+    spawn_named(&thread_name, move || -> Result<()> {
         // CUDA contexts are thread local so set ours to this thread
 
         while !controls.is_stopped() {
-            if controls.is_paused() {
-                std::thread::sleep(Duration::from_millis(100));
+            if !controls.is_started() || controls.is_paused() {
+                controls.wait_until_running();
                 continue;
             }
 
@@ -587,9 +1966,20 @@ fn audio_encoding_loop(
                 recv(audio_recv) -> raw_samples => {
                     match raw_samples {
                         Ok(raw_samples) => {
+                            if source == AudioSource::SystemAudio {
+                                controls.audio_level.update(rms(&raw_samples.samples));
+                            }
                             // If we are getting samples then we know this must be set or we
                             // wouldn't be in here
-                            audio_encoder.as_ref().lock().unwrap().process(raw_samples)?;
+                            match audio_encoder.as_ref().lock().unwrap().process(raw_samples) {
+                                Ok(()) => {}
+                                Err(WaycapError::Disconnected) => {
+                                    log::info!("Audio output receiver dropped, stopping capture");
+                                    controls.stop();
+                                    break;
+                                }
+                                Err(e) => return Err(e),
+                            }
                         }
                         Err(_) => {
                             log::info!("Audio channel disconnected");
@@ -605,3 +1995,84 @@ fn audio_encoding_loop(
         Ok(())
     })
 }
+
+fn callback_loop<T, F>(
+    recv: Receiver<T>,
+    controls: Arc<CaptureControls>,
+    mut f: F,
+) -> std::thread::JoinHandle<Result<()>>
+where
+    T: Send + 'static,
+    F: FnMut(&T) + Send + 'static,
+{
+    spawn_named("waycap-callback", move || -> Result<()> {
+        while !controls.is_stopped() {
+            select! {
+                recv(recv) -> frame => {
+                    match frame {
+                        Ok(frame) => f(&frame),
+                        Err(_) => {
+                            log::info!("Frame channel disconnected, stopping callback");
+                            break;
+                        }
+                    }
+                }
+                default(Duration::from_millis(100)) => {
+                    // Timeout to check the stop flag periodically
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pausing_controls() -> CaptureControls {
+        let controls = CaptureControls::from_fps(0);
+        controls.enable_pause_gap_removal();
+        controls.start();
+        controls
+    }
+
+    #[test]
+    fn without_pauses_takes_out_the_pauses_before_each_timestamp() {
+        let controls = pausing_controls();
+        // Paused for 10ns until 100, then for 20ns more until 200
+        controls.pause_clock.lock().unwrap().resumes = vec![(100, 10), (200, 30)];
+        assert_eq!(controls.without_pauses(50), 50);
+        assert_eq!(controls.without_pauses(100), 90);
+        assert_eq!(controls.without_pauses(199), 189);
+        assert_eq!(controls.without_pauses(250), 220);
+    }
+
+    #[test]
+    fn overlapping_pauses_count_once() {
+        let controls = pausing_controls();
+        controls.pause();
+        let paused_at = controls.pause_clock.lock().unwrap().paused_at.unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        // Already paused, so neither this nor the second resume start or end another pause
+        controls.pause();
+        controls.resume();
+        controls.resume();
+
+        let clock = controls.pause_clock.lock().unwrap();
+        let [(resumed_at, total)] = clock.resumes[..] else {
+            panic!("expected a single pause, got {:?}", clock.resumes);
+        };
+        assert_eq!(total, resumed_at - paused_at);
+        assert!(total >= Duration::from_millis(5).as_nanos() as i64);
+    }
+
+    #[test]
+    fn pauses_before_the_start_are_not_counted() {
+        let controls = CaptureControls::from_fps(0);
+        controls.enable_pause_gap_removal();
+        controls.pause();
+        controls.start();
+        assert!(controls.pause_clock.lock().unwrap().resumes.is_empty());
+    }
+}
@@ -1,18 +1,72 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use ffmpeg_next::ChannelLayout;
+
 use crate::{
-    encoders::dynamic_encoder::DynamicEncoder,
+    capture::audio::find_audio_device,
+    encoders::{
+        audio::OPUS_SAMPLE_RATES,
+        dynamic_encoder::DynamicEncoder,
+        video::{PipewireSPA, StartVideoEncoder, MAX_CAPTURE_SIZE},
+    },
     types::{
-        config::{AudioEncoder, QualityPreset, VideoEncoder},
-        error::Result,
+        config::{
+            AudioEncoder, AudioEncoderConfig, AudioGain, BitDepth, BitstreamFormat, ColorRange,
+            ColorSpace, NvencPreset, NvencTune, OpusApplication, QualityPreset, RateControl,
+            Region, VideoEncoder, VideoEncoderConfig, DEFAULT_BUFFER_FRAMES,
+        },
+        error::{Result, WaycapError},
     },
-    Capture,
+    utils::spawn_named,
+    Capture, CaptureControls, CaptureParams, CursorMode, EglContext, Resolution, SourceType,
 };
 
+/// Most frames the encoders' async depth can be set to, VAAPI's limit
+const MAX_ASYNC_DEPTH: u32 = 64;
+
 pub struct CaptureBuilder {
     video_encoder: Option<VideoEncoder>,
     audio_encoder: Option<AudioEncoder>,
+    audio_bitrate: Option<u32>,
+    audio_sample_rate: Option<u32>,
+    audio_channels: Option<ChannelLayout>,
+    opus_application: OpusApplication,
+    audio_gain: Option<AudioGain>,
     quality_preset: Option<QualityPreset>,
-    include_cursor: bool,
+    rate_control: Option<RateControl>,
+    gop_size: Option<u32>,
+    bit_depth: BitDepth,
+    region: Option<Region>,
+    capture_resolution: Option<Resolution>,
+    color_range: ColorRange,
+    color_space: ColorSpace,
+    bitstream_format: BitstreamFormat,
+    nvenc_tune: NvencTune,
+    nvenc_preset: Option<NvencPreset>,
+    b_frames: Option<u32>,
+    lookahead: Option<u32>,
+    async_depth: Option<u32>,
+    cursor_mode: CursorMode,
     include_audio: bool,
+    include_mic: bool,
+    audio_device: Option<u32>,
+    audio_sink_name: Option<String>,
+    output: Option<String>,
+    combine_outputs: bool,
+    source_type: SourceType,
+    restore_token: Option<String>,
+    software_fallback: bool,
+    additional_encoders: Vec<(VideoEncoder, QualityPreset)>,
+    timing: bool,
+    zero_based_pts: bool,
+    drm_device: Option<PathBuf>,
+    egl_context: Option<Arc<EglContext>>,
+    pause_removes_gap: bool,
+    auto_reconnect: bool,
+    frame_limit: Option<u64>,
+    video_buffer_frames: Option<usize>,
+    audio_buffer_frames: Option<usize>,
+    audio_start_timeout: Option<Duration>,
     target_fps: u64,
 }
 
@@ -27,9 +81,46 @@ impl CaptureBuilder {
         Self {
             video_encoder: None,
             audio_encoder: None,
+            audio_bitrate: None,
+            audio_sample_rate: None,
+            audio_channels: None,
+            opus_application: OpusApplication::Audio,
+            audio_gain: None,
             quality_preset: None,
-            include_cursor: false,
+            rate_control: None,
+            gop_size: None,
+            bit_depth: BitDepth::Eight,
+            region: None,
+            capture_resolution: None,
+            color_range: ColorRange::Limited,
+            color_space: ColorSpace::Bt709,
+            bitstream_format: BitstreamFormat::AnnexB,
+            nvenc_tune: NvencTune::HighQuality,
+            nvenc_preset: None,
+            b_frames: None,
+            lookahead: None,
+            async_depth: None,
+            cursor_mode: CursorMode::HIDDEN,
             include_audio: false,
+            include_mic: false,
+            audio_device: None,
+            audio_sink_name: None,
+            output: None,
+            combine_outputs: false,
+            source_type: SourceType::all(),
+            restore_token: None,
+            software_fallback: false,
+            additional_encoders: Vec::new(),
+            timing: false,
+            zero_based_pts: false,
+            drm_device: None,
+            egl_context: None,
+            pause_removes_gap: false,
+            auto_reconnect: false,
+            frame_limit: None,
+            video_buffer_frames: None,
+            audio_buffer_frames: None,
+            audio_start_timeout: None,
             target_fps: 60,
         }
     }
@@ -48,8 +139,69 @@ impl CaptureBuilder {
         self
     }
 
+    /// Optional: Audio bitrate in bits per second, e.g. lower for voice than for music.
+    /// Ignored by [`AudioEncoder::Flac`] and [`AudioEncoder::Pcm`].
+    /// Default: 70 kbps for Opus, 160 kbps for AAC.
+    pub fn with_audio_bitrate(mut self, bits_per_second: u32) -> Self {
+        self.audio_bitrate = Some(bits_per_second);
+        self
+    }
+
+    /// Optional: Sample rate in Hz to capture and encode audio at. PipeWire converts from the
+    /// rate the sink runs at.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] for rates Opus
+    /// can't encode (8, 12, 16, 24 or 48 kHz) when using [`AudioEncoder::Opus`].
+    /// Default: 48000
+    pub fn with_audio_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.audio_sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Optional: Channels to encode audio with. Audio is captured in stereo,
+    /// [`ChannelLayout::MONO`] averages both channels to halve the size of voice recordings.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] for layouts other
+    /// than mono or stereo.
+    /// Default: [`ChannelLayout::STEREO`]
+    pub fn with_audio_channels(mut self, layout: ChannelLayout) -> Self {
+        self.audio_channels = Some(layout);
+        self
+    }
+
+    /// Optional: Tune the Opus encoder for speech with [`OpusApplication::Voip`], e.g. for
+    /// voice chat recordings. Ignored by the other audio encoders.
+    /// Default: [`OpusApplication::Audio`]
+    pub fn with_opus_application(mut self, application: OpusApplication) -> Self {
+        self.opus_application = application;
+        self
+    }
+
+    /// Optional: How the audio encoders adjust the loudness of the captured audio.
+    /// [`AudioGain::None`] keeps already normalized sources from being boosted into clipping.
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] for negative or
+    /// non-finite fixed gains.
+    /// Default: [`AudioGain::Rms`] for Opus and AAC, [`AudioGain::None`] for the lossless
+    /// FLAC and PCM
+    pub fn with_audio_gain(mut self, gain: AudioGain) -> Self {
+        self.audio_gain = Some(gain);
+        self
+    }
+
     pub fn with_cursor_shown(mut self) -> Self {
-        self.include_cursor = true;
+        self.cursor_mode = CursorMode::EMBEDDED;
+        self
+    }
+
+    /// Optional: Leave the cursor out of the frames and get its position and image through
+    /// [`crate::types::video_frame::RawVideoFrame::cursor`] instead. Cheaper than
+    /// [`Self::with_cursor_shown`] as the compositor doesn't have to draw the cursor into every
+    /// frame. The encoded video does not contain the cursor.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Portal`] if the compositor
+    /// doesn't support cursor metadata.
+    pub fn with_cursor_metadata(mut self) -> Self {
+        self.cursor_mode = CursorMode::METADATA;
         self
     }
 
@@ -58,25 +210,528 @@ impl CaptureBuilder {
         self
     }
 
+    /// Optional: Also capture the default microphone as a separate audio stream.
+    /// It is encoded with the same audio encoder as the system audio and received
+    /// through [`Capture::get_mic_receiver`].
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Device`] if no microphone
+    /// is available.
+    pub fn with_microphone(mut self) -> Self {
+        self.include_mic = true;
+        self
+    }
+
+    /// Optional: Record the system audio from this node instead of the default sink.
+    /// Get the ids from [`crate::pipeline::audio_devices`], sources work too.
+    /// Only used together with [`Self::with_audio`].
+    pub fn with_audio_device(mut self, node_id: u32) -> Self {
+        self.audio_device = Some(node_id);
+        self
+    }
+
+    /// Optional: Record the system audio from the node with this `node.name` or
+    /// `node.description`, e.g. a virtual sink for recording, whether or not it's the default.
+    /// The name is looked up when building, so it keeps working when the node's id changes.
+    /// Takes precedence over [`Self::with_audio_device`] and is only used together with
+    /// [`Self::with_audio`].
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Device`] if no sink or
+    /// source has that name.
+    pub fn with_audio_sink_name(mut self, name: String) -> Self {
+        self.audio_sink_name = Some(name);
+        self
+    }
+
+    /// Optional: Record only the output (monitor) with the given connector name, e.g. `"DP-2"`.
+    /// The portal still decides which outputs are offered, this only filters among the streams
+    /// it returns, so the output has to be picked (or allowed) in the portal dialog.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] if none of the
+    /// streams belong to that output.
+    /// Default: The first stream returned by the portal.
+    pub fn with_output(mut self, name: String) -> Self {
+        self.output = Some(name);
+        self
+    }
+
+    /// Optional: Record every output picked in the portal dialog as one video, laid out side by
+    /// side from left to right and top aligned. The video is as large as all of them together.
+    ///
+    /// The software encoder gets the outputs combined on the CPU, the hardware encoders get them
+    /// combined on the GPU with OpenGL, which needs `EGL_MESA_image_dma_buf_export`.
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] together with
+    /// [`Self::with_output`] or a 10-bit depth.
+    /// Default: Only the first stream returned by the portal is recorded.
+    pub fn with_combined_outputs(mut self) -> Self {
+        self.combine_outputs = true;
+        self
+    }
+
+    /// Optional: Kind of sources the portal offers, e.g. [`SourceType::WINDOW`] to record a
+    /// single application window without monitors showing up in the dialog.
+    /// Default: Both monitors and windows.
+    pub fn with_source_type(mut self, source_type: SourceType) -> Self {
+        self.source_type = source_type;
+        self
+    }
+
+    /// Optional: Restore the sources picked in an earlier capture instead of showing the portal
+    /// dialog again, use the token from [`Capture::restore_token`].
+    /// If the portal rejects the token, e.g. because it was already used, the dialog is shown.
+    /// Default: Always prompt.
+    pub fn with_restore_token(mut self, token: String) -> Self {
+        self.restore_token = Some(token);
+        self
+    }
+
+    /// Optional: Encode on the CPU with [`VideoEncoder::H264Software`] when no supported GPU is
+    /// found, e.g. in VMs or on CI, instead of failing to build. This is much slower than the
+    /// hardware encoders. Has no effect if an encoder is forced with [`Self::with_video_encoder`].
+    /// Default: [`Self::build`] returns [`crate::types::error::WaycapError::Init`] on unknown GPUs.
+    pub fn with_software_fallback(mut self) -> Self {
+        self.software_fallback = true;
+        self
+    }
+
+    /// Optional: Encode the captured frames a second (third, ...) time with `encoder` at
+    /// `quality`, read its output with [`Capture::get_additional_video_receivers`].
+    /// GOP size, region and bit depth are shared with the main encoder, the rate control from
+    /// [`Self::with_rate_control`] only applies to the main one. Software and hardware encoders
+    /// can't be mixed.
+    pub fn with_additional_video_encoder(
+        mut self,
+        encoder: VideoEncoder,
+        quality: QualityPreset,
+    ) -> Self {
+        self.additional_encoders.push((encoder, quality));
+        self
+    }
+
     pub fn with_quality_preset(mut self, quality: QualityPreset) -> Self {
         self.quality_preset = Some(quality);
         self
     }
 
-    /// Optional: Set a target FPS for the recording.
+    /// Optional: Cap the video bitrate, in bits per second. Shorthand for
+    /// [`Self::with_rate_control`] with [`RateControl::VariableBitrate`] where the target
+    /// and maximum are the same.
+    /// Default: Derived from the quality preset.
+    pub fn with_bitrate(mut self, bits_per_second: u64) -> Self {
+        self.rate_control = Some(RateControl::VariableBitrate {
+            target: bits_per_second,
+            max: bits_per_second,
+        });
+        self
+    }
+
+    /// Optional: Pick the video rate control mode yourself. The quality preset then only
+    /// selects the speed preset of encoders which have one.
+    /// Default: [`QualityPreset::rate_control`] of the quality preset.
+    pub fn with_rate_control(mut self, rate_control: RateControl) -> Self {
+        self.rate_control = Some(rate_control);
+        self
+    }
+
+    /// Optional: Number of frames between keyframes. Larger values give smaller files,
+    /// smaller values make seeking and cutting more precise.
+    /// Default: 30
+    pub fn with_gop_size(mut self, frames: u32) -> Self {
+        self.gop_size = Some(frames);
+        self
+    }
+
+    /// Optional: Encode with 10 bits per channel to keep the color of HDR outputs.
+    /// Only [`VideoEncoder::H265Vaapi`] supports this, and it is picked automatically on
+    /// AMD and Intel GPUs when no encoder is forced.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Init`] if the encoder
+    /// can't do 10-bit.
+    /// Default: [`BitDepth::Eight`]
+    pub fn with_bit_depth(mut self, bit_depth: BitDepth) -> Self {
+        self.bit_depth = bit_depth;
+        self
+    }
+
+    /// Optional: Only record a part of the output, e.g. the area of a game window.
+    /// Coordinates are in pixels of the output as PipeWire negotiates it.
+    /// [`Capture::dimensions`] reports the size of the region.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] if the region
+    /// does not lie inside the output.
+    /// Default: The whole output.
+    pub fn with_region(mut self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        self.region = Some(Region {
+            x,
+            y,
+            width,
+            height,
+        });
+        self
+    }
+
+    /// Optional: Ask the compositor for a stream of `width` x `height`, e.g. 1920x1080 to
+    /// downscale a 4K output before the frames reach us. Compositors which don't honor it
+    /// send their own size, which the encoder scales down or up to this one. Either way
+    /// [`Capture::dimensions`] reports the size the video is encoded at.
+    ///
+    /// [`Self::build`] returns [`crate::types::error::WaycapError::Config`] if the size is
+    /// empty or above 4096x4096, if it's combined with [`Self::with_region`] or combined
+    /// outputs, or if NVENC would have to scale since it can't.
+    /// Default: Whatever the compositor picks, usually the output's size.
+    pub fn with_capture_resolution(mut self, width: u32, height: u32) -> Self {
+        self.capture_resolution = Some(Resolution { width, height });
+        self
+    }
+
+    /// Optional: Measure how long frames spend in the video encoder, read it with
+    /// [`Capture::encode_latency`].
+    /// Default: Off, the encoders don't look at the clock.
+    pub fn with_timing(mut self) -> Self {
+        self.timing = true;
+        self
+    }
+
+    /// Optional: Encode with the full 0-255 range instead of the limited 16-235 one.
+    /// Default: [`ColorRange::Limited`]
+    pub fn with_color_range(mut self, range: ColorRange) -> Self {
+        self.color_range = range;
+        self
+    }
+
+    /// Optional: Matrix the captured RGB is converted to YUV with, also written to the
+    /// stream so players convert it back the same way.
+    /// Default: [`ColorSpace::Bt709`]
+    pub fn with_color_space(mut self, space: ColorSpace) -> Self {
+        self.color_space = space;
+        self
+    }
+
+    /// Optional: Layout of H.264 and H.265 frames, [`BitstreamFormat::Avcc`] for length
+    /// prefixed NAL units like MP4 stores them. The encoders write Annex-B and are converted
+    /// when needed. [`crate::pipeline::muxer::Muxer`] expects Annex-B.
+    /// Default: [`BitstreamFormat::AnnexB`] with start codes, as RTP and WebRTC want them.
+    pub fn with_bitstream_format(mut self, format: BitstreamFormat) -> Self {
+        self.bitstream_format = format;
+        self
+    }
+
+    /// Optional: What NVENC tunes for, e.g. [`NvencTune::LowLatency`] for streaming while
+    /// keeping a slow preset. Only used by the NVENC encoders, other backends ignore it.
+    /// Default: [`NvencTune::HighQuality`]
+    pub fn with_nvenc_tuning(mut self, tune: NvencTune) -> Self {
+        self.nvenc_tune = tune;
+        self
+    }
+
+    /// Optional: NVENC speed preset, the bitrate or CQ still come from the quality preset.
+    /// Only used by the NVENC encoders, other backends ignore it.
+    /// Default: Picked by the quality preset, `P2` for [`QualityPreset::Low`] up to `P7` for
+    /// [`QualityPreset::High`] and [`QualityPreset::Ultra`].
+    pub fn with_nvenc_preset(mut self, preset: NvencPreset) -> Self {
+        self.nvenc_preset = Some(preset);
+        self
+    }
+
+    /// Optional: Most B-frames in a row, for better compression in archival recordings.
+    /// Frames then come out in decode order, so their PTS are no longer increasing while their
+    /// DTS still are; mux them by DTS. Only used by NVENC and the H.264 and H.265 VAAPI
+    /// encoders, other backends ignore it.
+    /// Default: The encoder's default.
+    pub fn with_b_frames(mut self, b_frames: u32) -> Self {
+        self.b_frames = Some(b_frames);
+        self
+    }
+
+    /// Optional: Frames the rate control looks ahead to spend bits where they are needed,
+    /// which delays every frame by as many. Only used by NVENC, other backends ignore it.
+    /// Default: The encoder's default.
+    pub fn with_lookahead(mut self, frames: u32) -> Self {
+        self.lookahead = Some(frames);
+        self
+    }
+
+    /// Optional: Frames the encoder works on at once, for captures which are limited by the
+    /// encoder, e.g. 4K at high framerates. More frames in flight raise the throughput, but
+    /// every frame spends longer in the encoder and each one needs its own GPU surface.
+    /// Sets `async_depth` on VAAPI and QSV and `surfaces` on NVENC, and grows their frame
+    /// pools to match. Other backends ignore it. [`Self::build`] returns
+    /// [`crate::types::error::WaycapError::Config`] for depths outside `1..=64`.
+    /// Default: The encoder's default.
+    pub fn with_async_depth(mut self, depth: u32) -> Self {
+        self.async_depth = Some(depth);
+        self
+    }
+
+    /// Optional: Start video PTS at 0 on the first encoded frame instead of the capture
+    /// timestamp, so they can be fed to a muxer as is. DTS is shifted the same way.
+    /// Default: PTS are the capture timestamps in nanoseconds.
+    pub fn with_zero_based_pts(mut self) -> Self {
+        self.zero_based_pts = true;
+        self
+    }
+
+    /// Optional: Run VAAPI and QSV encoders on this DRM render node, e.g.
+    /// `/dev/dri/renderD129` for the second GPU of a hybrid graphics laptop.
+    /// Default: The first `/dev/dri/renderD12*` node the encoder can be created on.
+    pub fn with_drm_device<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.drm_device = Some(path.into());
+        self
+    }
+
+    /// Optional: Import frames into `egl_context`, e.g. one shared with your renderer, instead
+    /// of a context the encoder creates for itself. Each frame is copied into the texture
+    /// given by [`EglContext::get_texture_id`], which is sized for the captured stream.
+    ///
+    /// The context is made current on the encoder's thread, so it must not be current on
+    /// another thread while the capture runs, see [`EglContext::release_current`]. Only the
+    /// NVENC encoders import through EGL, [`Self::build`] returns
+    /// [`crate::types::error::WaycapError::Config`] for others.
+    /// Default: The encoder creates its own context.
+    pub fn with_egl_context(mut self, egl_context: Arc<EglContext>) -> Self {
+        self.egl_context = Some(egl_context);
+        self
+    }
+
+    /// Optional: Take the time spent paused out of the timestamps of video and audio frames,
+    /// so a recording continues right where it was paused instead of freezing for as long as
+    /// the pause lasted. Works across any number of pauses.
+    /// Default: Timestamps keep counting while paused.
+    pub fn with_pause_removes_gap(mut self) -> Self {
+        self.pause_removes_gap = true;
+        self
+    }
+
+    /// Optional: Try to bring the video stream back when the compositor stops it while
+    /// recording, e.g. because the screen got locked, so long unattended recordings carry on
+    /// afterwards. When reconnecting fails the capture stops with
+    /// [`crate::types::error::WaycapError::Stream`] from [`Capture::last_error`].
+    /// Default: The capture stops with that error as soon as the stream stops.
+    pub fn with_auto_reconnect(mut self) -> Self {
+        self.auto_reconnect = true;
+        self
+    }
+
+    /// Optional: Stop the capture on its own once `frames` frames have been encoded, e.g. for
+    /// benchmarks or grabbing an exact number of frames. Frames dropped to keep to the target
+    /// framerate don't count. The encoder is flushed before stopping, so all `frames` reach the
+    /// video receiver. Additional video encoders count their frames separately and may have
+    /// encoded fewer when the main one stops the capture.
+    /// Default: The capture runs until it is stopped or closed.
+    pub fn with_frame_limit(mut self, frames: u64) -> Self {
+        self.frame_limit = Some(frames);
+        self
+    }
+
+    /// Optional: Number of frames the video channels hold, both between the capture and the
+    /// encoder and between the encoder and your receiver. Frames are dropped once one is full,
+    /// so a larger buffer rides out bursts and slow consumers at the cost of memory, a smaller
+    /// one keeps latency down. Must be at least 1.
+    /// Default: 10
+    pub fn with_video_buffer_frames(mut self, frames: usize) -> Self {
+        self.video_buffer_frames = Some(frames);
+        self
+    }
+
+    /// Optional: Number of frames the audio channels hold, both between the capture and the
+    /// encoder and between the encoder and your receiver. Must be at least 1.
+    /// Default: 10
+    pub fn with_audio_buffer_frames(mut self, frames: usize) -> Self {
+        self.audio_buffer_frames = Some(frames);
+        self
+    }
+
+    /// Optional: How long [`Self::build`] waits for the system audio and microphone streams
+    /// to start, e.g. when no sink is available they never do. Building then fails with
+    /// [`crate::types::error::WaycapError::Init`], or
+    /// [`crate::types::error::WaycapError::Device`] for the microphone, instead of hanging.
+    /// Default: 5 seconds
+    pub fn with_audio_start_timeout(mut self, timeout: Duration) -> Self {
+        self.audio_start_timeout = Some(timeout);
+        self
+    }
+
+    /// Optional: Set a target FPS for the recording, 0 encodes every frame the compositor sends.
     /// Default: 60fps
     pub fn with_target_fps(mut self, fps: u64) -> Self {
         self.target_fps = fps;
         self
     }
 
+    /// Same as [`Self::build`] but without blocking the calling thread.
+    ///
+    /// The portal dialog and the PipeWire negotiation run on a background thread, which calls
+    /// `on_ready` with the capture, or the error, once they are done. Useful for UIs since
+    /// building can take up to a few seconds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use waycap_rs::pipeline::builder::CaptureBuilder;
+    /// # fn thing() {
+    /// let (tx, rx) = std::sync::mpsc::channel();
+    /// CaptureBuilder::new().build_async(move |capture| {
+    ///     let _ = tx.send(capture);
+    /// });
+    ///
+    /// // Keep the UI responsive and check back later
+    /// if let Ok(Ok(mut capture)) = rx.try_recv() {
+    ///     capture.start().unwrap();
+    /// }
+    /// # }
+    /// ```
+    pub fn build_async<F>(self, on_ready: F)
+    where
+        F: FnOnce(Result<Capture<DynamicEncoder>>) + Send + 'static,
+    {
+        spawn_named("waycap-build", move || on_ready(self.build()));
+    }
+
     pub fn build(self) -> Result<Capture<DynamicEncoder>> {
+        if self.gop_size == Some(0) {
+            return Err(WaycapError::Config(
+                "GOP size must be at least 1 frame".to_string(),
+            ));
+        }
+        if let Some(size) = self.capture_resolution {
+            if !(2..=MAX_CAPTURE_SIZE).contains(&size.width)
+                || !(2..=MAX_CAPTURE_SIZE).contains(&size.height)
+            {
+                return Err(WaycapError::Config(format!(
+                    "Capture resolution must be between 2x2 and {MAX_CAPTURE_SIZE}x{MAX_CAPTURE_SIZE}, got {}x{}",
+                    size.width, size.height
+                )));
+            }
+            if self.region.is_some() || self.combine_outputs {
+                return Err(WaycapError::Config(
+                    "A capture resolution can't be combined with a region or combined outputs"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(depth) = self.async_depth {
+            if !(1..=MAX_ASYNC_DEPTH).contains(&depth) {
+                return Err(WaycapError::Config(format!(
+                    "Async depth must be between 1 and {MAX_ASYNC_DEPTH}, got {depth}"
+                )));
+            }
+        }
+
+        match self.rate_control {
+            Some(RateControl::ConstantQuality(q)) if q > RateControl::MAX_QUALITY => {
+                return Err(WaycapError::Config(format!(
+                    "Constant quality must be at most {}, got {q}",
+                    RateControl::MAX_QUALITY
+                )));
+            }
+            Some(RateControl::ConstantBitrate(0))
+            | Some(RateControl::VariableBitrate { target: 0, .. }) => {
+                return Err(WaycapError::Config(
+                    "Video bitrate must be above 0".to_string(),
+                ));
+            }
+            Some(RateControl::VariableBitrate { target, max }) if max < target => {
+                return Err(WaycapError::Config(format!(
+                    "Maximum video bitrate {max} is below the target of {target}"
+                )));
+            }
+            _ => {}
+        }
+
         let quality = match self.quality_preset {
             Some(qual) => qual,
             None => QualityPreset::Medium,
         };
 
-        let audio_encoder = if self.include_audio {
+        let (audio_encoder, audio_config) = self.audio_settings()?;
+
+        let defaults = VideoEncoderConfig::default();
+        let video_config = VideoEncoderConfig {
+            quality,
+            rate_control: self.rate_control,
+            gop_size: self.gop_size.unwrap_or(defaults.gop_size),
+            bit_depth: self.bit_depth,
+            region: self.region,
+            capture_resolution: self.capture_resolution,
+            color_range: self.color_range,
+            color_space: self.color_space,
+            bitstream_format: self.bitstream_format,
+            nvenc_tune: self.nvenc_tune,
+            nvenc_preset: self.nvenc_preset,
+            b_frames: self.b_frames,
+            lookahead: self.lookahead,
+            async_depth: self.async_depth,
+            zero_based_pts: self.zero_based_pts,
+            drm_device: self.drm_device.clone(),
+            buffer_frames: self.video_buffer_size()?,
+        };
+
+        let audio_device = self.audio_device()?;
+
+        let params = CaptureParams {
+            video_encoder_type: self.video_encoder,
+            audio_encoder_type: audio_encoder,
+            audio_config,
+            video_config,
+            cursor_mode: self.cursor_mode,
+            include_audio: self.include_audio,
+            include_mic: self.include_mic,
+            audio_device,
+            output: self.output.clone(),
+            combine_outputs: self.combine_outputs,
+            source_type: self.source_type,
+            software_fallback: self.software_fallback,
+            additional_encoders: self.additional_encoders.clone(),
+            egl_context: self.egl_context.clone(),
+        };
+        let mut capture = Capture::new(params, self.restore_token.clone(), self.target_fps)?;
+        self.apply_controls(&capture.controls());
+        Ok(capture)
+    }
+
+    /// Build a capture which encodes with your own `encoder` instead of a built-in one.
+    ///
+    /// Like [`Capture::new_with_encoder`], but with the rest of the builder's settings: audio,
+    /// microphone, cursor, output, source type, restore token, frame rate, buffer sizes, timing,
+    /// pause gaps and reconnecting. Settings of the built-in video encoders like the quality,
+    /// rate control, region or capture resolution are up to `encoder` and ignored here.
+    ///
+    /// Returns [`WaycapError::Config`] if additional encoders or combined outputs were asked
+    /// for, those need the built-in encoders. Like [`Self::build`] the capture has to be
+    /// started with [`Capture::start`].
+    pub fn build_with_encoder<V>(self, encoder: V) -> Result<Capture<V>>
+    where
+        V: PipewireSPA + StartVideoEncoder,
+    {
+        if !self.additional_encoders.is_empty() || self.combine_outputs {
+            return Err(WaycapError::Config(
+                "Additional encoders and combined outputs need the built-in encoders".to_string(),
+            ));
+        }
+        let (audio_encoder, audio_config) = self.audio_settings()?;
+        let audio_device = self.audio_device()?;
+        let buffer_frames = self.video_buffer_size()?;
+
+        let mut capture = Capture::open_with_encoder(
+            encoder,
+            audio_encoder,
+            audio_config,
+            self.cursor_mode,
+            self.include_audio,
+            self.include_mic,
+            audio_device,
+            self.output.clone(),
+            self.source_type,
+            self.restore_token.clone(),
+            self.target_fps,
+            buffer_frames,
+        )?;
+        self.apply_controls(&capture.controls());
+        Ok(capture)
+    }
+
+    /// Audio encoder and its settings, checked for values the encoders can't handle
+    fn audio_settings(&self) -> Result<(AudioEncoder, AudioEncoderConfig)> {
+        let audio_encoder = if self.include_audio || self.include_mic {
             match self.audio_encoder {
                 Some(enc) => enc,
                 None => AudioEncoder::Opus,
@@ -85,13 +740,88 @@ impl CaptureBuilder {
             AudioEncoder::Opus
         };
 
-        Capture::new(
-            self.video_encoder,
-            audio_encoder,
-            quality,
-            self.include_cursor,
-            self.include_audio,
-            self.target_fps,
-        )
+        let audio_defaults = AudioEncoderConfig::default();
+        let audio_config = AudioEncoderConfig {
+            bitrate: self.audio_bitrate,
+            sample_rate: self.audio_sample_rate.unwrap_or(audio_defaults.sample_rate),
+            channel_layout: self.audio_channels.unwrap_or(audio_defaults.channel_layout),
+            opus_application: self.opus_application,
+            gain: self.audio_gain.unwrap_or(match audio_encoder {
+                AudioEncoder::Flac | AudioEncoder::Pcm => AudioGain::None,
+                AudioEncoder::Opus | AudioEncoder::Aac => audio_defaults.gain,
+            }),
+            buffer_frames: self
+                .audio_buffer_frames
+                .unwrap_or(audio_defaults.buffer_frames),
+            start_timeout: self
+                .audio_start_timeout
+                .unwrap_or(audio_defaults.start_timeout),
+        };
+        if let AudioGain::Fixed(factor) = audio_config.gain {
+            if !factor.is_finite() || factor < 0.0 {
+                return Err(WaycapError::Config(format!(
+                    "Audio gain must be a finite, non-negative factor, got {factor}"
+                )));
+            }
+        }
+        if audio_config.buffer_frames == 0 {
+            return Err(WaycapError::Config(
+                "Audio buffer must hold at least one frame".to_string(),
+            ));
+        }
+        if !matches!(audio_config.channel_layout.channels(), 1 | 2) {
+            return Err(WaycapError::Config(format!(
+                "Only mono and stereo audio is supported, got {:?}",
+                audio_config.channel_layout
+            )));
+        }
+        if audio_config.sample_rate == 0 {
+            return Err(WaycapError::Config(
+                "Audio sample rate must be above 0".to_string(),
+            ));
+        }
+        if matches!(audio_encoder, AudioEncoder::Opus)
+            && !OPUS_SAMPLE_RATES.contains(&audio_config.sample_rate)
+        {
+            return Err(WaycapError::Config(format!(
+                "Opus can't encode at {} Hz, use one of {OPUS_SAMPLE_RATES:?}",
+                audio_config.sample_rate
+            )));
+        }
+        Ok((audio_encoder, audio_config))
+    }
+
+    /// Node id of the system audio, looked up by name if one was given
+    fn audio_device(&self) -> Result<Option<u32>> {
+        match self.audio_sink_name {
+            Some(ref name) if self.include_audio => Ok(Some(find_audio_device(name)?)),
+            _ => Ok(self.audio_device),
+        }
+    }
+
+    /// Video buffer size, checked to hold at least one frame
+    fn video_buffer_size(&self) -> Result<usize> {
+        match self.video_buffer_frames {
+            Some(0) => Err(WaycapError::Config(
+                "Video buffer must hold at least one frame".to_string(),
+            )),
+            frames => Ok(frames.unwrap_or(DEFAULT_BUFFER_FRAMES)),
+        }
+    }
+
+    /// Turn on the features of [`CaptureControls`] the builder was asked for
+    fn apply_controls(&self, controls: &CaptureControls) {
+        if self.timing {
+            controls.counters().enable_timing();
+        }
+        if self.pause_removes_gap {
+            controls.enable_pause_gap_removal();
+        }
+        if self.auto_reconnect {
+            controls.enable_auto_reconnect();
+        }
+        if let Some(frames) = self.frame_limit {
+            controls.set_frame_limit(frames);
+        }
     }
 }
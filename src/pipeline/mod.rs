@@ -1 +1,170 @@
+use std::{sync::OnceLock, time::Duration};
+
+use crate::{
+    capture::audio::list_audio_devices,
+    types::{
+        config::{AudioEncoder, AudioEncoderConfig, DEFAULT_BUFFER_FRAMES},
+        error::{BurstError, Result, WaycapError},
+    },
+    waycap_egl::{EglContext, GpuVendor},
+    Capture, CursorMode, RgbaImageEncoder, SourceType,
+};
+
 pub mod builder;
+pub mod muxer;
+pub mod probe;
+pub mod replay;
+
+pub use crate::capture::audio::AudioDevice;
+
+/// Detect the vendor of the GPU, e.g. to pick an encoder for a custom [`Capture`].
+///
+/// The first call loads libEGL and reads the vendor string from a throwaway context, which is
+/// dropped again before returning. The vendor is cached after that, so building several
+/// captures doesn't reconnect to the Wayland display each time. No portal or PipeWire
+/// connection is made.
+///
+/// # Examples
+///
+/// ```
+/// # use waycap_rs::types::error::Result;
+/// # fn thing() -> Result<()> {
+/// use waycap_rs::GpuVendor;
+///
+/// if waycap_rs::pipeline::detect_gpu_vendor()? == GpuVendor::NVIDIA {
+///     println!("Recording with NVENC");
+/// }
+/// # Ok(())}
+/// ```
+pub fn detect_gpu_vendor() -> Result<GpuVendor> {
+    static GPU_VENDOR: OnceLock<GpuVendor> = OnceLock::new();
+
+    if let Some(vendor) = GPU_VENDOR.get() {
+        return Ok(*vendor);
+    }
+    // Dummy dimensions, the context only lives long enough to read the vendor.
+    // Failures aren't cached so a later call can try again
+    let context = EglContext::new(100, 100)?;
+    Ok(*GPU_VENDOR.get_or_init(|| context.get_gpu_vendor()))
+}
+
+/// List the audio sinks and sources PipeWire knows about, e.g. to let users pick one for
+/// [`builder::CaptureBuilder::with_audio_device`].
+///
+/// # Examples
+///
+/// ```
+/// # use waycap_rs::types::error::Result;
+/// # fn thing() -> Result<()> {
+/// for device in waycap_rs::pipeline::audio_devices()? {
+///     let kind = if device.is_sink { "sink" } else { "source" };
+///     println!("{} ({kind}): {}", device.node_id, device.name);
+/// }
+/// # Ok(())}
+/// ```
+pub fn audio_devices() -> Result<Vec<AudioDevice>> {
+    list_audio_devices()
+}
+
+/// Take a single screenshot.
+///
+/// Goes through the portal like a normal capture, waits for the first frame and tears the
+/// capture down again. Returns [`WaycapError::Stream`] if no frame arrives within 5 seconds.
+///
+/// # Examples
+///
+/// ```
+/// # use waycap_rs::types::error::Result;
+/// # fn thing() -> Result<()> {
+/// let image = waycap_rs::pipeline::screenshot(true)?;
+/// image.save("./screenshot.png").unwrap();
+/// # Ok(())}
+/// ```
+pub fn screenshot(include_cursor: bool) -> Result<image::RgbaImage> {
+    let mut capture = Capture::new_with_encoder(RgbaImageEncoder::default(), include_cursor, 60)?;
+    let image = capture
+        .get_output()
+        .recv_timeout(Duration::from_secs(5))
+        .map_err(|_| WaycapError::Stream("Timed out waiting for a screenshot frame".to_string()));
+
+    capture.close()?;
+    image
+}
+
+/// Take `count` screenshots `interval` apart from one capture, e.g. for thumbnails or a
+/// contact sheet, with a single portal prompt.
+///
+/// The cursor is included. Frames in between are dropped by the capture's frame interval
+/// instead of converting every frame. Compositors only send frames when the screen changes,
+/// so screenshots of a still screen can come later than `interval`. If a screenshot doesn't
+/// arrive within `interval` plus 5 seconds, or the capture stops, the [`BurstError`] holds the
+/// screenshots taken up to then and why the capture ended, or [`WaycapError::Stream`] if
+/// that's unknown.
+///
+/// # Examples
+///
+/// ```
+/// # use waycap_rs::types::error::Result;
+/// # fn thing() -> Result<()> {
+/// use std::time::Duration;
+///
+/// let images = waycap_rs::pipeline::screenshot_burst(4, Duration::from_secs(2))?;
+/// for (i, image) in images.iter().enumerate() {
+///     image.save(format!("./thumbnail-{i}.png")).unwrap();
+/// }
+/// # Ok(())}
+/// ```
+pub fn screenshot_burst(
+    count: usize,
+    interval: Duration,
+) -> std::result::Result<Vec<image::RgbaImage>, BurstError> {
+    let mut images = Vec::with_capacity(count);
+    if count == 0 {
+        return Ok(images);
+    }
+    let mut capture = match Capture::open_with_encoder(
+        RgbaImageEncoder::default(),
+        AudioEncoder::Opus,
+        AudioEncoderConfig::default(),
+        CursorMode::EMBEDDED,
+        false,
+        false,
+        None,
+        None,
+        SourceType::all(),
+        None,
+        60,
+        DEFAULT_BUFFER_FRAMES,
+    ) {
+        Ok(capture) => capture,
+        Err(error) => return Err(BurstError { images, error }),
+    };
+    // Set before starting so the frames right after the first one are dropped too
+    let controls = capture.controls();
+    controls.set_frame_interval(interval);
+
+    let output = capture.get_output();
+    let timeout = interval + Duration::from_secs(5);
+    let result = capture.start().and_then(|()| {
+        while images.len() < count {
+            match output.recv_timeout(timeout) {
+                Ok(image) => images.push(image),
+                Err(e) => {
+                    return Err(controls.last_error().unwrap_or_else(|| {
+                        WaycapError::Stream(format!(
+                            "Screenshot {} of {count} did not arrive: {e}",
+                            images.len() + 1
+                        ))
+                    }));
+                }
+            }
+        }
+        Ok(())
+    });
+
+    // Closed either way, an error while closing only counts if the burst went through
+    match result.and(capture.close()) {
+        Ok(()) => Ok(images),
+        Err(error) => Err(BurstError { images, error }),
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    ffi::{c_int, c_void, CString},
+    io::{self, Cursor, Seek, SeekFrom, Write},
+    path::Path,
+    ptr,
+};
+
+use ffmpeg_next::{
+    self as ffmpeg,
+    codec::{packet::Packet, Id},
+    ffi::{
+        av_display_matrix_flip, av_display_rotation_set, av_free, av_freep, av_malloc,
+        av_packet_side_data_new, avformat_alloc_output_context2, avio_alloc_context,
+        avio_context_free, avio_flush, AVIOContext, AVPacketSideDataType, AVERROR,
+        AVFMT_FLAG_CUSTOM_IO, AVSEEK_FORCE, AVSEEK_SIZE,
+    },
+    Rational, Rescale,
+};
+
+use crate::{
+    types::{
+        audio_frame::EncodedAudioFrame,
+        error::{Result, WaycapError},
+        video_frame::{EncodedVideoFrame, Transform},
+    },
+    utils::TIME_UNIT_NS,
+    Capture, DynamicEncoder,
+};
+
+/// Containers the muxer knows which codecs they can hold.
+///
+/// Files with other extensions are still written, but left to ffmpeg to pick the container
+/// and without checking the codecs up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// `.mp4`, H.264, HEVC, AV1 or VP9 with AAC, Opus or FLAC
+    Mp4,
+    /// `.webm`, VP9 or AV1 with Opus
+    WebM,
+    /// `.mkv`, any of the codecs this crate produces
+    Matroska,
+}
+
+impl Container {
+    /// Container for the extension of `path`, if it is one of the known ones
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "mp4" | "m4v" => Some(Container::Mp4),
+            "webm" => Some(Container::WebM),
+            "mkv" => Some(Container::Matroska),
+            _ => None,
+        }
+    }
+
+    /// Name of ffmpeg's muxer for the container
+    fn ffmpeg_name(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::WebM => "webm",
+            Container::Matroska => "matroska",
+        }
+    }
+
+    /// True if the container can hold a stream encoded with `codec`
+    pub fn supports(self, codec: Id) -> bool {
+        match self {
+            Container::Mp4 => matches!(
+                codec,
+                Id::H264 | Id::HEVC | Id::AV1 | Id::VP9 | Id::AAC | Id::OPUS | Id::FLAC
+            ),
+            Container::WebM => matches!(codec, Id::VP8 | Id::VP9 | Id::AV1 | Id::OPUS | Id::VORBIS),
+            Container::Matroska => matches!(
+                codec,
+                Id::H264 | Id::HEVC | Id::AV1 | Id::VP9 | Id::AAC | Id::OPUS | Id::FLAC
+            ),
+        }
+    }
+}
+
+/// Writes the encoded frames of a [`Capture`] into a file.
+///
+/// The container is picked from the file extension, see [`Container`], or given explicitly with
+/// [`Muxer::with_container`], and [`Muxer::in_memory`] writes to a buffer instead. Video is
+/// written to stream 0 and audio (if the capture has it) to stream 1. Raw PCM audio has no
+/// ffmpeg encoder to take the stream parameters from, so no audio stream is added for it.
+/// Video frames before the first keyframe are skipped so the file is decodable, and both
+/// streams are rebased to start with that keyframe. Audio captured before it is dropped, audio
+/// pushed before it is held back until it arrives. A [`Capture::transform`] is stored as the
+/// video's display matrix, so players show portrait captures upright.
+///
+/// Audio is lined up with the video through the capture timestamps, so captures built with
+/// [`CaptureBuilder::with_zero_based_pts`](crate::pipeline::builder::CaptureBuilder::with_zero_based_pts)
+/// can't be muxed with their audio.
+///
+/// # Examples
+///
+/// ```
+/// # use waycap_rs::pipeline::{builder::CaptureBuilder, muxer::Muxer};
+/// # use waycap_rs::types::error::Result;
+/// # fn thing() -> Result<()> {
+/// let mut capture = CaptureBuilder::new().build()?;
+/// let video_receiver = capture.get_video_receiver();
+/// let mut muxer = Muxer::new("out.mp4", &capture)?;
+///
+/// capture.start()?;
+/// for _ in 0..600 {
+///     muxer.push_video(video_receiver.recv().unwrap())?;
+/// }
+/// capture.close()?;
+/// muxer.finish()?;
+/// # Ok(())}
+/// ```
+pub struct Muxer {
+    output: ffmpeg::format::context::Output,
+    video: Option<MuxerStream>,
+    audio: Option<MuxerStream>,
+    /// Set when writing to memory instead of a file
+    memory: Option<MemoryTarget>,
+    /// Capture time in nanoseconds both streams start at, the DTS of the first video keyframe
+    origin: Option<i64>,
+    /// Audio pushed before the first video keyframe, written once it arrives
+    pending_audio: Vec<EncodedAudioFrame>,
+}
+
+/// Size of the buffer ffmpeg collects writes in before handing them to [`MemoryBuffer`]
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Custom ffmpeg IO context writing into a [`MemoryBuffer`]
+struct MemoryTarget {
+    io: *mut AVIOContext,
+    /// Boxed so the pointer ffmpeg's callbacks get stays valid when the target moves
+    buffer: Box<MemoryBuffer>,
+}
+
+struct MemoryBuffer {
+    data: Cursor<Vec<u8>>,
+    max_bytes: usize,
+    /// Set once a write was refused for going over `max_bytes`
+    exceeded: bool,
+}
+
+struct MuxerStream {
+    index: usize,
+    encoder_time_base: Rational,
+    stream_time_base: Rational,
+    /// Subtracted from every timestamp to move the origin to zero, in the encoder time base.
+    /// Set with the first frame written
+    offset: Option<i64>,
+}
+
+impl Muxer {
+    /// Open `path` for writing and add a stream for each of the capture's encoders.
+    ///
+    /// Returns a [`WaycapError::Config`] if the container picked from the extension can't hold
+    /// one of the capture's codecs, or if the capture has audio and zero-based video PTS.
+    pub fn new<P: AsRef<Path>>(path: P, capture: &Capture<DynamicEncoder>) -> Result<Self> {
+        check_timestamps(capture)?;
+        match Container::from_path(&path) {
+            Some(container) => Self::with_container(path, container, capture),
+            None => Self::open(ffmpeg::format::output(&path)?, None, None, capture),
+        }
+    }
+
+    /// Like [`Muxer::new`] but write `container` whatever the extension of `path` is.
+    pub fn with_container<P: AsRef<Path>>(
+        path: P,
+        container: Container,
+        capture: &Capture<DynamicEncoder>,
+    ) -> Result<Self> {
+        // Check the codecs before ffmpeg creates the file
+        check_timestamps(capture)?;
+        capture.with_video_encoder(|enc| check_codec(container, enc.as_ref()))?;
+        if capture.audio_encoder.is_some() {
+            capture.with_audio_encoder(|enc| check_codec(container, enc.as_ref()))?;
+        }
+
+        let output = ffmpeg::format::output_as(&path, container.ffmpeg_name())?;
+        Self::open(output, Some(container), None, capture)
+    }
+
+    /// Mux into memory instead of a file, e.g. to copy a short clip to the clipboard or upload
+    /// it without touching the disk. Take the bytes with [`Muxer::finish_to_vec`].
+    ///
+    /// The output never grows past `max_bytes`, pushing frames or finishing returns a
+    /// [`WaycapError::Io`] instead once it would.
+    pub fn in_memory(
+        container: Container,
+        max_bytes: usize,
+        capture: &Capture<DynamicEncoder>,
+    ) -> Result<Self> {
+        check_timestamps(capture)?;
+        capture.with_video_encoder(|enc| check_codec(container, enc.as_ref()))?;
+        if capture.audio_encoder.is_some() {
+            capture.with_audio_encoder(|enc| check_codec(container, enc.as_ref()))?;
+        }
+
+        let format = CString::new(container.ffmpeg_name()).unwrap();
+        let mut output = unsafe {
+            let mut ctx = ptr::null_mut();
+            match avformat_alloc_output_context2(
+                &mut ctx,
+                ptr::null_mut(),
+                format.as_ptr(),
+                ptr::null(),
+            ) {
+                0 => ffmpeg::format::context::Output::wrap(ctx),
+                e => return Err(ffmpeg::Error::from(e).into()),
+            }
+        };
+        let memory = MemoryTarget::attach(&mut output, max_bytes)?;
+        Self::open(output, Some(container), Some(memory), capture)
+    }
+
+    fn open(
+        output: ffmpeg::format::context::Output,
+        container: Option<Container>,
+        memory: Option<MemoryTarget>,
+        capture: &Capture<DynamicEncoder>,
+    ) -> Result<Self> {
+        // Built right away so the in-memory IO is taken out of the output again on errors
+        let mut muxer = Self {
+            output,
+            video: None,
+            audio: None,
+            memory,
+            origin: None,
+            pending_audio: Vec::new(),
+        };
+
+        let video =
+            capture.with_video_encoder(|enc| add_stream(&mut muxer.output, enc.as_ref()))?;
+        let audio = if capture.audio_encoder.is_some() {
+            capture.with_audio_encoder(|enc| add_stream(&mut muxer.output, enc.as_ref()))?
+        } else {
+            None
+        };
+
+        let transform = capture.transform();
+        if let Some((index, _)) = video.filter(|_| transform != Transform::Normal) {
+            set_display_matrix(&mut muxer.output, index, transform)?;
+        }
+
+        let header = muxer.output.write_header().map_err(WaycapError::from);
+        if let Err(e) = muxer.check_limit(header) {
+            return Err(match (e, container) {
+                (WaycapError::FFmpeg(e), Some(container)) => WaycapError::Config(format!(
+                    "Could not write a {container:?} header for the capture's streams: {e}"
+                )),
+                (e, _) => e,
+            });
+        }
+
+        // Writing the header can change the stream time bases so only read them now
+        let mut video = video.map(|(index, time_base)| MuxerStream::new(index, time_base));
+        let mut audio = audio.map(|(index, time_base)| MuxerStream::new(index, time_base));
+        for stream in video.iter_mut().chain(audio.iter_mut()) {
+            stream.stream_time_base = muxer.output.stream(stream.index).unwrap().time_base();
+        }
+        muxer.video = video;
+        muxer.audio = audio;
+
+        Ok(muxer)
+    }
+
+    /// Write an encoded video frame
+    pub fn push_video(&mut self, frame: EncodedVideoFrame) -> Result<()> {
+        let Some(stream) = self.video.as_mut() else {
+            return Err(WaycapError::Validation(
+                "Muxer does not have a video stream".to_string(),
+            ));
+        };
+
+        let offset = match stream.offset {
+            Some(offset) => offset,
+            // A file has to start on a keyframe to be decodable
+            None if !frame.is_keyframe => return Ok(()),
+            None => {
+                self.origin = Some(frame.dts.rescale(stream.encoder_time_base, nanoseconds()));
+                *stream.offset.insert(frame.dts)
+            }
+        };
+
+        let mut packet = Packet::copy(&frame.data);
+        packet.set_pts(Some(frame.pts - offset));
+        packet.set_dts(Some(frame.dts - offset));
+        packet.set_duration(frame.duration);
+        if frame.is_keyframe {
+            packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+        }
+        let result = stream.write(packet, &mut self.output);
+        self.check_limit(result)?;
+
+        for frame in std::mem::take(&mut self.pending_audio) {
+            self.push_audio(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Write an encoded audio frame
+    pub fn push_audio(&mut self, frame: EncodedAudioFrame) -> Result<()> {
+        let Some(stream) = self.audio.as_mut() else {
+            return Err(WaycapError::Validation(
+                "Muxer does not have an audio stream".to_string(),
+            ));
+        };
+
+        let origin = match self.origin {
+            Some(origin) => origin,
+            None if self.video.is_some() => {
+                self.pending_audio.push(frame);
+                return Ok(());
+            }
+            None => *self.origin.insert(frame.timestamp_ns),
+        };
+        let offset = match stream.offset {
+            Some(offset) => offset,
+            // Audio from before the first video frame has nothing to play along with
+            None => match audio_offset(&frame, origin, stream.encoder_time_base) {
+                Some(offset) => *stream.offset.insert(offset),
+                None => return Ok(()),
+            },
+        };
+
+        let mut packet = Packet::copy(&frame.data);
+        packet.set_pts(Some(frame.pts - offset));
+        packet.set_dts(Some(frame.pts - offset));
+        let result = stream.write(packet, &mut self.output);
+        self.check_limit(result)
+    }
+
+    /// True if the muxer has an audio stream to push audio frames into
+    pub fn has_audio(&self) -> bool {
+        self.audio.is_some()
+    }
+
+    /// Write the trailer and close the file
+    pub fn finish(mut self) -> Result<()> {
+        let trailer = self.output.write_trailer().map_err(WaycapError::from);
+        self.check_limit(trailer)
+    }
+
+    /// Write the trailer and return the whole file of a muxer from [`Muxer::in_memory`]
+    pub fn finish_to_vec(mut self) -> Result<Vec<u8>> {
+        if self.memory.is_none() {
+            return Err(WaycapError::Validation(
+                "Muxer writes to a file, not to memory".to_string(),
+            ));
+        }
+        let trailer = self.output.write_trailer().map_err(WaycapError::from);
+        self.check_limit(trailer)?;
+
+        let memory = self.memory.take().unwrap();
+        Ok(memory.detach(&mut self.output).data.into_inner())
+    }
+
+    /// Report failed writes as going over the limit if the in-memory output refused them
+    fn check_limit(&self, result: Result<()>) -> Result<()> {
+        match &self.memory {
+            Some(memory) if result.is_err() && memory.buffer.exceeded => {
+                Err(WaycapError::Io(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    format!(
+                        "In-memory output is limited to {} bytes",
+                        memory.buffer.max_bytes
+                    ),
+                )))
+            }
+            _ => result,
+        }
+    }
+}
+
+impl Drop for Muxer {
+    fn drop(&mut self) {
+        // The output would otherwise close the custom IO context like a file
+        if let Some(memory) = self.memory.take() {
+            memory.detach(&mut self.output);
+        }
+    }
+}
+
+impl MemoryTarget {
+    /// Make `output` write into a new buffer of at most `max_bytes`
+    fn attach(output: &mut ffmpeg::format::context::Output, max_bytes: usize) -> Result<Self> {
+        let mut buffer = Box::new(MemoryBuffer {
+            data: Cursor::new(Vec::new()),
+            max_bytes,
+            exceeded: false,
+        });
+        unsafe {
+            let io_buffer = av_malloc(IO_BUFFER_SIZE) as *mut u8;
+            if io_buffer.is_null() {
+                return Err(WaycapError::Init(
+                    "Could not allocate the in-memory output buffer".to_string(),
+                ));
+            }
+            let io = avio_alloc_context(
+                io_buffer,
+                IO_BUFFER_SIZE as c_int,
+                1,
+                buffer.as_mut() as *mut MemoryBuffer as *mut c_void,
+                None,
+                Some(write_memory),
+                Some(seek_memory),
+            );
+            if io.is_null() {
+                av_free(io_buffer as *mut c_void);
+                return Err(WaycapError::Init(
+                    "Could not create the in-memory output".to_string(),
+                ));
+            }
+
+            let ctx = output.as_mut_ptr();
+            (*ctx).pb = io;
+            (*ctx).flags |= AVFMT_FLAG_CUSTOM_IO as c_int;
+            Ok(Self { io, buffer })
+        }
+    }
+
+    /// Flush what ffmpeg still buffers, then free the IO context and take it out of `output`
+    fn detach(mut self, output: &mut ffmpeg::format::context::Output) -> MemoryBuffer {
+        unsafe {
+            avio_flush(self.io);
+            (*output.as_mut_ptr()).pb = ptr::null_mut();
+            av_freep(&mut (*self.io).buffer as *mut *mut u8 as *mut c_void);
+            avio_context_free(&mut self.io);
+        }
+        *self.buffer
+    }
+}
+
+/// ffmpeg write callback for [`MemoryTarget`]
+unsafe extern "C" fn write_memory(opaque: *mut c_void, buf: *const u8, buf_size: c_int) -> c_int {
+    let target = &mut *(opaque as *mut MemoryBuffer);
+    let data = std::slice::from_raw_parts(buf, buf_size as usize);
+
+    if target.data.position() as usize + data.len() > target.max_bytes {
+        target.exceeded = true;
+        return AVERROR(libc::ENOSPC);
+    }
+    match target.data.write_all(data) {
+        Ok(()) => buf_size,
+        Err(_) => AVERROR(libc::EIO),
+    }
+}
+
+/// ffmpeg seek callback for [`MemoryTarget`], MP4 seeks back to fill in sizes
+unsafe extern "C" fn seek_memory(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let target = &mut *(opaque as *mut MemoryBuffer);
+    if whence & AVSEEK_SIZE as c_int != 0 {
+        return target.data.get_ref().len() as i64;
+    }
+
+    let position = match whence & !(AVSEEK_FORCE as c_int) {
+        libc::SEEK_SET => SeekFrom::Start(offset as u64),
+        libc::SEEK_CUR => SeekFrom::Current(offset),
+        libc::SEEK_END => SeekFrom::End(offset),
+        _ => return AVERROR(libc::EINVAL) as i64,
+    };
+    match target.data.seek(position) {
+        Ok(position) => position as i64,
+        Err(_) => AVERROR(libc::EINVAL) as i64,
+    }
+}
+
+impl MuxerStream {
+    fn new(index: usize, encoder_time_base: Rational) -> Self {
+        Self {
+            index,
+            encoder_time_base,
+            stream_time_base: encoder_time_base,
+            offset: None,
+        }
+    }
+
+    fn write(
+        &self,
+        mut packet: Packet,
+        output: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        packet.set_stream(self.index);
+        packet.rescale_ts(self.encoder_time_base, self.stream_time_base);
+        packet.write_interleaved(output)?;
+        Ok(())
+    }
+}
+
+/// Size of a display matrix, 3x3 32 bit fixed point numbers
+const DISPLAY_MATRIX_SIZE: usize = 9 * std::mem::size_of::<i32>();
+
+/// Store `transform` as the display matrix of the stream at `index`
+fn set_display_matrix(
+    output: &mut ffmpeg::format::context::Output,
+    index: usize,
+    transform: Transform,
+) -> Result<()> {
+    let Some(mut stream) = output.stream_mut(index) else {
+        return Ok(());
+    };
+    unsafe {
+        let codecpar = (*stream.as_mut_ptr()).codecpar;
+        let side_data = av_packet_side_data_new(
+            &mut (*codecpar).coded_side_data,
+            &mut (*codecpar).nb_coded_side_data,
+            AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            DISPLAY_MATRIX_SIZE,
+            0,
+        );
+        if side_data.is_null() {
+            return Err(WaycapError::Init(
+                "Could not add a display matrix to the video stream".to_string(),
+            ));
+        }
+        let matrix = (*side_data).data as *mut i32;
+        av_display_rotation_set(matrix, transform.rotation() as f64);
+        if transform.is_flipped() {
+            av_display_matrix_flip(matrix, 1, 0);
+        }
+    }
+    Ok(())
+}
+
+/// Offset of the audio stream when `frame` is the first audio frame written, `None` if it was
+/// captured before `origin`.
+///
+/// Audio PTS count samples since the audio encoder started, this lines them up with the video
+/// through the capture timestamp of the first frame written.
+fn audio_offset(frame: &EncodedAudioFrame, origin: i64, time_base: Rational) -> Option<i64> {
+    let since_origin = frame.timestamp_ns - origin;
+    (since_origin >= 0).then(|| frame.pts - since_origin.rescale(nanoseconds(), time_base))
+}
+
+/// Time base of capture timestamps
+fn nanoseconds() -> Rational {
+    Rational::new(1, TIME_UNIT_NS as i32)
+}
+
+/// Make sure the muxer can line up the audio of `capture` with its video
+fn check_timestamps(capture: &Capture<DynamicEncoder>) -> Result<()> {
+    if capture.audio_encoder.is_some() && capture.zero_based_pts() {
+        return Err(WaycapError::Config(
+            "Audio can't be lined up with zero-based video PTS, mux without with_zero_based_pts"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Make sure `container` can hold the stream of the given encoder, if there is one
+fn check_codec<E>(container: Container, encoder: Option<&E>) -> Result<()>
+where
+    E: AsRef<ffmpeg::codec::Context>,
+{
+    let Some(codec) = encoder.and_then(|encoder| encoder.as_ref().codec()) else {
+        return Ok(());
+    };
+    if container.supports(codec.id()) {
+        Ok(())
+    } else {
+        Err(WaycapError::Config(format!(
+            "{container:?} can't hold {} streams, pick another container or encoder",
+            codec.name()
+        )))
+    }
+}
+
+/// Adds a stream to `output` for the given encoder, returns the stream index and
+/// the encoder time base
+fn add_stream<E>(
+    output: &mut ffmpeg::format::context::Output,
+    encoder: Option<&E>,
+) -> Result<Option<(usize, Rational)>>
+where
+    E: AsRef<ffmpeg::codec::Context>,
+{
+    let Some(encoder) = encoder else {
+        return Ok(None);
+    };
+    let context = encoder.as_ref();
+    let codec = context.codec().ok_or(ffmpeg::Error::EncoderNotFound)?;
+    let time_base = context.time_base();
+
+    let mut stream = output.add_stream(codec)?;
+    stream.set_time_base(time_base);
+    stream.set_parameters(encoder);
+    Ok(Some((stream.index(), time_base)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: i64 = 5_000_000_000;
+
+    /// Audio frame starting `pts` samples into the audio stream
+    fn audio(pts: i64, timestamp_ns: i64) -> EncodedAudioFrame {
+        EncodedAudioFrame::new(vec![0; 4], pts, timestamp_ns)
+    }
+
+    #[test]
+    fn audio_before_the_first_keyframe_is_dropped() {
+        let time_base = Rational::new(1, 48_000);
+        assert_eq!(audio_offset(&audio(0, ORIGIN - 1), ORIGIN, time_base), None);
+        assert_eq!(
+            audio_offset(&audio(960, ORIGIN - 20_000_000), ORIGIN, time_base),
+            None
+        );
+    }
+
+    #[test]
+    fn audio_is_rebased_onto_the_first_keyframe() {
+        let time_base = Rational::new(1, 48_000);
+        // Audio held back until the keyframe arrived, the first frame after it starts at 0
+        assert_eq!(
+            audio_offset(&audio(1_920, ORIGIN), ORIGIN, time_base),
+            Some(1_920)
+        );
+        // Captured 20ms after the keyframe, so it starts 960 samples into the file
+        let offset = audio_offset(&audio(2_880, ORIGIN + 20_000_000), ORIGIN, time_base).unwrap();
+        assert_eq!(2_880 - offset, 960);
+    }
+}
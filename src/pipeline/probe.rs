@@ -0,0 +1,149 @@
+//! Check which encoders can be used on this machine before building a capture.
+
+use std::ptr::null_mut;
+
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{av_buffer_unref, av_hwdevice_ctx_create_derived, AVBufferRef, AVHWDeviceType},
+};
+
+use crate::{
+    encoders::{
+        amf_encoder::create_amf_device,
+        video::{create_hw_device, with_drm_device},
+    },
+    types::config::VideoEncoder,
+    GpuVendor,
+};
+
+/// Video encoders which are usable on this machine.
+///
+/// Each backend is checked by looking up its ffmpeg encoder and opening the hardware device it
+/// runs on (CUDA for NVENC, VAAPI for VAAPI and QSV, the AMF runtime for AMF). Every context
+/// opened along the way is released again before returning and no PipeWire connection is made,
+/// so this is cheap enough to call when populating a settings UI.
+/// [`VideoEncoder::H264Software`] is listed whenever ffmpeg was built with libx264.
+///
+/// # Examples
+///
+/// ```
+/// # use waycap_rs::pipeline::probe::available_encoders;
+/// for encoder in available_encoders() {
+///     println!("{encoder:?} is supported");
+/// }
+/// ```
+pub fn available_encoders() -> Vec<VideoEncoder> {
+    if let Err(e) = ffmpeg::init() {
+        log::error!("Could not initialize ffmpeg: {e}");
+        return Vec::new();
+    }
+
+    let vendor = match super::detect_gpu_vendor() {
+        Ok(vendor) => vendor,
+        Err(e) => {
+            log::error!("Could not create an EGL context to detect the GPU: {e}");
+            return Vec::new();
+        }
+    };
+
+    let candidates: &[(VideoEncoder, &str)] = match vendor {
+        GpuVendor::NVIDIA => &[
+            (VideoEncoder::H264Nvenc, "h264_nvenc"),
+            (VideoEncoder::Av1Nvenc, "av1_nvenc"),
+        ],
+        GpuVendor::INTEL => &[
+            (VideoEncoder::H264Qsv, "h264_qsv"),
+            (VideoEncoder::H264Vaapi, "h264_vaapi"),
+            (VideoEncoder::H265Vaapi, "hevc_vaapi"),
+            (VideoEncoder::Vp9Vaapi, "vp9_vaapi"),
+        ],
+        GpuVendor::AMD => &[
+            (VideoEncoder::H264Vaapi, "h264_vaapi"),
+            (VideoEncoder::H265Vaapi, "hevc_vaapi"),
+            (VideoEncoder::Vp9Vaapi, "vp9_vaapi"),
+            (VideoEncoder::H264Amf, "h264_amf"),
+        ],
+        GpuVendor::UNKNOWN => &[],
+    };
+
+    let mut encoders: Vec<VideoEncoder> = candidates
+        .iter()
+        .filter(|(_, name)| ffmpeg::codec::encoder::find_by_name(name).is_some())
+        .filter(|(encoder, _)| device_available(*encoder))
+        .map(|(encoder, _)| *encoder)
+        .collect();
+    // Runs on the CPU, so it only depends on ffmpeg being built with libx264
+    if ffmpeg::codec::encoder::find_by_name("libx264").is_some() {
+        encoders.push(VideoEncoder::H264Software);
+    }
+    encoders
+}
+
+/// Open and release the hardware device `encoder` needs
+fn device_available(encoder: VideoEncoder) -> bool {
+    match encoder {
+        VideoEncoder::H264Software => true,
+        VideoEncoder::H264Nvenc | VideoEncoder::Av1Nvenc => match cust::quick_init() {
+            Ok(_context) => true,
+            Err(e) => {
+                log::info!("CUDA unavailable, skipping {encoder:?}: {e}");
+                false
+            }
+        },
+        VideoEncoder::H264Vaapi | VideoEncoder::H265Vaapi | VideoEncoder::Vp9Vaapi => {
+            match vaapi_device() {
+                Ok(mut device) => {
+                    unsafe { av_buffer_unref(&mut device) };
+                    true
+                }
+                Err(e) => {
+                    log::info!("VAAPI unavailable, skipping {encoder:?}: {e}");
+                    false
+                }
+            }
+        }
+        VideoEncoder::H264Amf => match create_amf_device() {
+            Ok(mut device) => {
+                unsafe { av_buffer_unref(&mut device) };
+                true
+            }
+            Err(e) => {
+                log::info!("Skipping {encoder:?}: {e}");
+                false
+            }
+        },
+        VideoEncoder::H264Qsv => {
+            // QSV is derived from VAAPI like the encoder does it
+            let mut vaapi_device = match vaapi_device() {
+                Ok(device) => device,
+                Err(e) => {
+                    log::info!("VAAPI unavailable, skipping {encoder:?}: {e}");
+                    return false;
+                }
+            };
+            unsafe {
+                let mut qsv_device: *mut AVBufferRef = null_mut();
+                let err = av_hwdevice_ctx_create_derived(
+                    &mut qsv_device,
+                    AVHWDeviceType::AV_HWDEVICE_TYPE_QSV,
+                    vaapi_device,
+                    0,
+                );
+                av_buffer_unref(&mut qsv_device);
+                av_buffer_unref(&mut vaapi_device);
+                if err < 0 {
+                    log::info!("QSV unavailable: error code {err}");
+                }
+                err >= 0
+            }
+        }
+    }
+}
+
+/// Open VAAPI on the first DRM render node that has it
+fn vaapi_device() -> crate::types::error::Result<*mut AVBufferRef> {
+    with_drm_device(None, |device| {
+        create_hw_device(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI, device)
+    })
+    .map(|(device, _)| device)
+}
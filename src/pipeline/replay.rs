@@ -0,0 +1,154 @@
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    pipeline::muxer::Muxer,
+    types::{audio_frame::EncodedAudioFrame, error::Result, video_frame::EncodedVideoFrame},
+    Capture, DynamicEncoder,
+};
+
+/// Keeps the last few seconds of a capture in memory for "instant replay" style clips.
+///
+/// Encoded frames are collected on worker threads of the capture, like
+/// [`Capture::on_video_frame`], so the capture's own receivers should not be read as well.
+/// Old frames are dropped as new ones come in, but only a whole GOP at a time: the video
+/// always starts on a keyframe so a saved clip is decodable. The clip can therefore be up to
+/// one GOP longer than the requested duration.
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Duration;
+/// # use waycap_rs::pipeline::{builder::CaptureBuilder, replay::ReplayBuffer};
+/// # use waycap_rs::types::error::Result;
+/// # fn thing() -> Result<()> {
+/// let mut capture = CaptureBuilder::new().with_audio().build()?;
+/// let replay = ReplayBuffer::new(&mut capture, Duration::from_secs(30))?;
+/// capture.start()?;
+///
+/// // Later, e.g. when a hotkey is pressed
+/// replay.save_clip("clip.mp4", &capture)?;
+/// # Ok(())}
+/// ```
+pub struct ReplayBuffer {
+    frames: Arc<Mutex<ReplayFrames>>,
+}
+
+struct ReplayFrames {
+    video: VecDeque<EncodedVideoFrame>,
+    audio: VecDeque<EncodedAudioFrame>,
+    /// How much to keep, in nanoseconds
+    duration: i64,
+}
+
+impl ReplayBuffer {
+    /// Start collecting the video and, if the capture has it, system audio of `capture`,
+    /// keeping at least the last `duration` of it.
+    pub fn new(capture: &mut Capture<DynamicEncoder>, duration: Duration) -> Result<Self> {
+        let frames = Arc::new(Mutex::new(ReplayFrames {
+            video: VecDeque::new(),
+            audio: VecDeque::new(),
+            duration: duration.as_nanos() as i64,
+        }));
+
+        let video_frames = Arc::clone(&frames);
+        capture.on_video_frame(move |frame| {
+            video_frames.lock().unwrap().push_video(frame.clone());
+        });
+        if capture.audio_encoder.is_some() {
+            let audio_frames = Arc::clone(&frames);
+            capture.on_audio_frame(move |frame| {
+                audio_frames.lock().unwrap().push_audio(frame.clone());
+            })?;
+        }
+
+        Ok(Self { frames })
+    }
+
+    /// Mux everything currently in the buffer into `path`, see [`Muxer`] for how the
+    /// container is picked. `capture` has to be the one this buffer was created for.
+    ///
+    /// The buffer keeps collecting while and after the clip is written.
+    pub fn save_clip<P: AsRef<Path>>(
+        &self,
+        path: P,
+        capture: &Capture<DynamicEncoder>,
+    ) -> Result<()> {
+        // Copy the frames out so the capture isn't held up while writing
+        let (video, audio) = {
+            let frames = self.frames.lock().unwrap();
+            (frames.video.clone(), frames.audio.clone())
+        };
+
+        let mut muxer = Muxer::new(path, capture)?;
+        let audio = if muxer.has_audio() {
+            audio
+        } else {
+            VecDeque::new()
+        };
+        let mut audio = audio.into_iter().peekable();
+        // Interleave by capture time, the muxer drops the audio from before the first keyframe
+        for frame in video {
+            while let Some(audio_frame) = audio.next_if(|a| a.timestamp_ns < frame.dts) {
+                muxer.push_audio(audio_frame)?;
+            }
+            muxer.push_video(frame)?;
+        }
+        for audio_frame in audio {
+            muxer.push_audio(audio_frame)?;
+        }
+        muxer.finish()
+    }
+
+    /// Length of the video currently in the buffer
+    pub fn buffered_duration(&self) -> Duration {
+        let frames = self.frames.lock().unwrap();
+        match (frames.video.front(), frames.video.back()) {
+            (Some(first), Some(last)) => Duration::from_nanos((last.pts - first.pts).max(0) as u64),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Drop everything collected so far
+    pub fn clear(&self) {
+        let mut frames = self.frames.lock().unwrap();
+        frames.video.clear();
+        frames.audio.clear();
+    }
+}
+
+impl ReplayFrames {
+    fn push_video(&mut self, frame: EncodedVideoFrame) {
+        // Anything before the first keyframe can't be decoded
+        if self.video.is_empty() && !frame.is_keyframe {
+            return;
+        }
+        let cutoff = frame.pts - self.duration;
+        self.video.push_back(frame);
+
+        // Start at the newest keyframe which still leaves the full duration buffered
+        if let Some(start) = self
+            .video
+            .iter()
+            .rposition(|f| f.is_keyframe && f.pts <= cutoff)
+        {
+            self.video.drain(..start);
+        }
+    }
+
+    fn push_audio(&mut self, frame: EncodedAudioFrame) {
+        // Keep the audio going back as far as the video, which starts on an earlier keyframe
+        let cutoff = match self.video.front() {
+            Some(first) => first.dts.min(frame.timestamp_ns - self.duration),
+            None => frame.timestamp_ns - self.duration,
+        };
+        self.audio.push_back(frame);
+        while self.audio.front().is_some_and(|f| f.timestamp_ns < cutoff) {
+            self.audio.pop_front();
+        }
+    }
+}
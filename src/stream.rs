@@ -0,0 +1,87 @@
+//! Async [`Stream`] adapters for the encoded frame receivers, enabled with the `tokio` feature.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use futures_core::Stream;
+
+use crate::{
+    types::{audio_frame::EncodedAudioFrame, error::Result, video_frame::EncodedVideoFrame},
+    Capture, CaptureControls, DynamicEncoder,
+};
+
+struct FrameStream<T> {
+    rx: tokio::sync::mpsc::Receiver<T>,
+}
+
+impl<T> Stream for FrameStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Forward frames from `recv` into a stream on a blocking task. The task ends when the
+/// capture is stopped, the encoder goes away or the stream is dropped.
+fn forward<T: Send + 'static>(recv: Receiver<T>, controls: Arc<CaptureControls>) -> FrameStream<T> {
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    tokio::task::spawn_blocking(move || {
+        while !controls.is_stopped() {
+            match recv.recv_timeout(Duration::from_millis(100)) {
+                Ok(frame) => {
+                    if tx.blocking_send(frame).is_err() {
+                        log::debug!("Frame stream dropped, stopping forwarding task");
+                        break;
+                    }
+                }
+                // Timeout to check the stop flag periodically
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+    FrameStream { rx }
+}
+
+impl Capture<DynamicEncoder> {
+    /// Encoded video frames as an async [`Stream`].
+    ///
+    /// Frames are moved from the encoder's channel by a blocking tokio task, so this must be
+    /// called from within a tokio runtime. The stream ends once the capture is closed or
+    /// dropped. Like [`Self::get_video_receiver`], every frame goes to only one consumer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use futures::StreamExt;
+    /// # use waycap_rs::pipeline::builder::CaptureBuilder;
+    /// # use waycap_rs::types::error::Result;
+    /// # async fn thing() -> Result<()> {
+    /// let mut capture = CaptureBuilder::new().build()?;
+    /// let mut frames = Box::pin(capture.video_stream());
+    /// capture.start()?;
+    /// while let Some(frame) = frames.next().await {
+    ///     println!("Got frame with pts {}", frame.pts);
+    /// }
+    /// # Ok(())}
+    /// ```
+    pub fn video_stream(&mut self) -> impl Stream<Item = EncodedVideoFrame> {
+        forward(self.get_video_receiver(), Arc::clone(&self.controls))
+    }
+
+    /// Encoded system audio frames as an async [`Stream`], see [`Self::video_stream`].
+    ///
+    /// Returns an error if the capture was built without audio.
+    pub fn audio_stream(&mut self) -> Result<impl Stream<Item = EncodedAudioFrame>> {
+        Ok(forward(
+            self.get_audio_receiver()?,
+            Arc::clone(&self.controls),
+        ))
+    }
+}
@@ -1,14 +1,50 @@
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EncodedAudioFrame {
     pub data: Vec<u8>,
+    /// Presentation timestamp in samples per channel since the encoder started, the time base
+    /// is one over the sample rate. Not comparable with video PTS, use `timestamp_ns` to
+    /// interleave audio and video.
     pub pts: i64,
-    /// Capture timestamp in micro seconds
+    /// Capture timestamp of the first samples in this frame, in microseconds on the same clock
+    /// as `timestamp_ns`
+    #[deprecated(note = "use `timestamp_ns`, which has the same unit as video timestamps")]
     pub timestamp: i64,
+    /// Capture timestamp of the first samples in this frame, in nanoseconds on the same
+    /// monotonic clock as [`crate::types::video_frame::RawVideoFrame::timestamp`] and
+    /// [`crate::types::video_frame::EncodedVideoFrame::pts`] of the hardware encoders
+    pub timestamp_ns: i64,
+}
+
+impl EncodedAudioFrame {
+    pub(crate) fn new(data: Vec<u8>, pts: i64, timestamp_ns: i64) -> Self {
+        #[allow(deprecated)]
+        Self {
+            data,
+            pts,
+            timestamp: timestamp_ns / 1000,
+            timestamp_ns,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct RawAudioFrame {
     pub samples: Vec<f32>,
-    /// Capture timestamp in micro seconds
+    /// Capture timestamp in microseconds on the same clock as `timestamp_ns`
+    #[deprecated(note = "use `timestamp_ns`, which has the same unit as video timestamps")]
     pub timestamp: i64,
+    /// Capture timestamp in nanoseconds, taken from PipeWire's monotonic stream clock
+    /// (`pw_stream_get_nsec`) like video frames so the two can be compared
+    pub timestamp_ns: i64,
+}
+
+impl RawAudioFrame {
+    pub(crate) fn new(samples: Vec<f32>, timestamp_ns: i64) -> Self {
+        #[allow(deprecated)]
+        Self {
+            samples,
+            timestamp: timestamp_ns / 1000,
+            timestamp_ns,
+        }
+    }
 }
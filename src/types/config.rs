@@ -1,12 +1,134 @@
+use std::{path::PathBuf, time::Duration};
+
+use ffmpeg_next::ChannelLayout;
+
+use crate::{
+    encoders::{audio::DEFAULT_SAMPLE_RATE, video::GOP_SIZE},
+    Resolution,
+};
+
+/// Frames the channels between capture, encoder and consumer hold by default
+pub const DEFAULT_BUFFER_FRAMES: usize = 10;
+
+/// How long opening a capture waits for its audio streams by default
+pub const DEFAULT_AUDIO_START_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Copy)]
 pub enum VideoEncoder {
     H264Nvenc,
+    /// AV1 through NVENC, requires an RTX 40-series GPU or newer
+    Av1Nvenc,
     H264Vaapi,
+    H265Vaapi,
+    /// VP9 through VAAPI, for WebM output and browser playback
+    Vp9Vaapi,
+    /// H.264 through Intel Quick Sync
+    H264Qsv,
+    /// H.264 through AMD's AMF, needs the runtime of AMD's proprietary driver.
+    /// Never picked automatically, AMD GPUs default to [`VideoEncoder::H264Vaapi`]
+    H264Amf,
+    /// H.264 through libx264 on the CPU, for machines without a supported GPU
+    H264Software,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum AudioEncoder {
     Opus,
+    /// AAC-LC, for MP4 workflows which expect it
+    Aac,
+    /// Lossless, samples are not boosted like with the lossy encoders unless a gain is set
+    Flac,
+    /// Raw interleaved `f32` samples, no encoding is done
+    Pcm,
+}
+
+/// What the Opus encoder tunes for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpusApplication {
+    /// Speech, favors intelligibility
+    Voip,
+    /// Music and general audio
+    #[default]
+    Audio,
+    /// Lowest latency at the cost of quality
+    LowDelay,
+}
+
+/// Gain the audio encoders apply to captured samples before encoding
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AudioGain {
+    /// Encode the samples as captured
+    None,
+    /// Boost quiet audio so it stays audible in playback, by up to 5x
+    #[default]
+    Rms,
+    /// Multiply every sample by this factor
+    Fixed(f32),
+}
+
+impl OpusApplication {
+    /// Value of libopus' `application` option
+    pub(crate) fn ffmpeg_name(self) -> &'static str {
+        match self {
+            OpusApplication::Voip => "voip",
+            OpusApplication::Audio => "audio",
+            OpusApplication::LowDelay => "lowdelay",
+        }
+    }
+}
+
+/// What NVENC tunes for, only used by the NVENC encoders
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NvencTune {
+    /// Best quality, for recordings
+    #[default]
+    HighQuality,
+    /// Less delay between a frame going in and coming out, for streaming
+    LowLatency,
+    /// Least delay at the cost of quality
+    UltraLowLatency,
+    /// Lossless, ignores the rate control
+    Lossless,
+}
+
+impl NvencTune {
+    /// Value of NVENC's `tune` option
+    pub(crate) fn ffmpeg_name(self) -> &'static str {
+        match self {
+            NvencTune::HighQuality => "hq",
+            NvencTune::LowLatency => "ll",
+            NvencTune::UltraLowLatency => "ull",
+            NvencTune::Lossless => "lossless",
+        }
+    }
+}
+
+/// NVENC speed preset, from the fastest `P1` to the slowest and best looking `P7`.
+/// Only used by the NVENC encoders
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvencPreset {
+    P1,
+    P2,
+    P3,
+    P4,
+    P5,
+    P6,
+    P7,
+}
+
+impl NvencPreset {
+    /// Value of NVENC's `preset` option
+    pub(crate) fn ffmpeg_name(self) -> &'static str {
+        match self {
+            NvencPreset::P1 => "p1",
+            NvencPreset::P2 => "p2",
+            NvencPreset::P3 => "p3",
+            NvencPreset::P4 => "p4",
+            NvencPreset::P5 => "p5",
+            NvencPreset::P6 => "p6",
+            NvencPreset::P7 => "p7",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -16,3 +138,299 @@ pub enum QualityPreset {
     High,
     Ultra,
 }
+
+impl QualityPreset {
+    /// The rate control this preset stands for, constant quality at a preset QP
+    pub fn rate_control(self) -> RateControl {
+        RateControl::ConstantQuality(match self {
+            QualityPreset::Low => 30,
+            QualityPreset::Medium => 25,
+            QualityPreset::High => 20,
+            QualityPreset::Ultra => 15,
+        })
+    }
+}
+
+/// How the video encoder trades quality against bitrate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateControl {
+    /// Keep the quality constant and let the bitrate vary. Given on the H.264 QP scale
+    /// (0-51, lower is better), encoders with a different scale like VP9 and AV1 map it
+    /// onto theirs.
+    ConstantQuality(u8),
+    /// Always encode at this many bits per second
+    ConstantBitrate(u64),
+    /// Aim for `target` bits per second on average without going above `max`
+    VariableBitrate { target: u64, max: u64 },
+}
+
+impl RateControl {
+    /// Highest value [`RateControl::ConstantQuality`] accepts
+    pub const MAX_QUALITY: u8 = 51;
+
+    /// Average and maximum bitrate to set on the encoder context, `None` for constant quality
+    pub(crate) fn bitrates(self) -> Option<(u64, u64)> {
+        match self {
+            RateControl::ConstantQuality(_) => None,
+            RateControl::ConstantBitrate(bitrate) => Some((bitrate, bitrate)),
+            RateControl::VariableBitrate { target, max } => Some((target, max)),
+        }
+    }
+}
+
+/// Bits per color channel the video is encoded with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    /// 10-bit (P010) for HDR outputs, only supported by [`VideoEncoder::H265Vaapi`]
+    Ten,
+}
+
+/// Range of the encoded pixel values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorRange {
+    /// 16-235, what most players expect from video
+    #[default]
+    Limited,
+    /// 0-255, like the captured RGB frames
+    Full,
+}
+
+impl ColorRange {
+    /// Value of the scale filters' `out_range` option
+    pub(crate) fn filter_name(self) -> &'static str {
+        match self {
+            ColorRange::Limited => "tv",
+            ColorRange::Full => "pc",
+        }
+    }
+
+    pub(crate) fn ffmpeg_range(self) -> ffmpeg_next::color::Range {
+        match self {
+            ColorRange::Limited => ffmpeg_next::color::Range::MPEG,
+            ColorRange::Full => ffmpeg_next::color::Range::JPEG,
+        }
+    }
+}
+
+/// Matrix used to convert the captured RGB frames to YUV
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    #[default]
+    Bt709,
+    /// SD video
+    Bt601,
+    /// Wide gamut, mostly useful with [`BitDepth::Ten`]
+    Bt2020,
+}
+
+/// Layout of the H.264 and H.265 NAL units in
+/// [`EncodedVideoFrame::data`](crate::types::video_frame::EncodedVideoFrame::data).
+/// VP9 and AV1 frames don't consist of NAL units and are left as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BitstreamFormat {
+    /// NAL units separated by start codes, what RTP, WebRTC and raw `.h264` streams use
+    #[default]
+    AnnexB,
+    /// NAL units prefixed with their length as 4 byte big endian, what MP4 and Matroska store
+    Avcc,
+}
+
+impl ColorSpace {
+    /// Value of `scale_vaapi`'s `out_color_matrix` option
+    pub(crate) fn vaapi_name(self) -> &'static str {
+        match self {
+            ColorSpace::Bt709 => "bt709",
+            ColorSpace::Bt601 => "smpte170m",
+            ColorSpace::Bt2020 => "bt2020nc",
+        }
+    }
+
+    /// Value of the software `scale` filter's `out_color_matrix` option
+    pub(crate) fn swscale_name(self) -> &'static str {
+        match self {
+            ColorSpace::Bt709 => "bt709",
+            ColorSpace::Bt601 => "bt601",
+            ColorSpace::Bt2020 => "bt2020",
+        }
+    }
+
+    pub(crate) fn ffmpeg_space(self) -> ffmpeg_next::color::Space {
+        match self {
+            ColorSpace::Bt709 => ffmpeg_next::color::Space::BT709,
+            ColorSpace::Bt601 => ffmpeg_next::color::Space::SMPTE170M,
+            ColorSpace::Bt2020 => ffmpeg_next::color::Space::BT2020NCL,
+        }
+    }
+}
+
+/// Rectangle of the captured output to record, in pixels of the stream resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// True if the region is not empty and lies inside a `width` x `height` frame
+    pub fn fits_in(&self, width: u32, height: u32) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self
+                .x
+                .checked_add(self.width)
+                .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= height)
+    }
+}
+
+/// Settings used to create (and re-create on [`crate::Capture::reset`]) a video encoder
+#[derive(Debug, Clone)]
+pub struct VideoEncoderConfig {
+    pub quality: QualityPreset,
+    /// Overrides the rate control derived from `quality`, which then only picks the speed
+    /// preset of encoders which have one
+    pub rate_control: Option<RateControl>,
+    /// Number of frames between keyframes
+    pub gop_size: u32,
+    pub bit_depth: BitDepth,
+    /// Only encode this part of the stream
+    pub region: Option<Region>,
+    /// Size asked of the compositor. Streams it negotiates at another size are scaled to it
+    /// by the encoder, which NVENC can't do
+    pub capture_resolution: Option<Resolution>,
+    pub color_range: ColorRange,
+    pub color_space: ColorSpace,
+    /// Rebase PTS so the first encoded frame has `pts == 0` instead of its capture timestamp
+    pub zero_based_pts: bool,
+    /// DRM render node VAAPI and QSV encode on, `None` probes `/dev/dri/renderD12*`
+    pub drm_device: Option<PathBuf>,
+    /// Frames the raw and encoded video channels hold before frames are dropped
+    pub buffer_frames: usize,
+    pub bitstream_format: BitstreamFormat,
+    /// Only used by the NVENC encoders
+    pub nvenc_tune: NvencTune,
+    /// Overrides the NVENC preset derived from `quality`, only used by the NVENC encoders
+    pub nvenc_preset: Option<NvencPreset>,
+    /// Most B-frames in a row, `None` keeps the encoder's default. Only used by NVENC and the
+    /// H.264 and H.265 VAAPI encoders
+    pub b_frames: Option<u32>,
+    /// Frames the rate control looks ahead, `None` keeps the encoder's default. Only used by
+    /// NVENC
+    pub lookahead: Option<u32>,
+    /// Frames the encoder keeps in flight, `None` keeps each backend's default. Also sizes
+    /// their GPU frame pools. Only used by VAAPI, NVENC and QSV
+    pub async_depth: Option<u32>,
+}
+
+impl Default for VideoEncoderConfig {
+    fn default() -> Self {
+        Self {
+            quality: QualityPreset::Medium,
+            rate_control: None,
+            gop_size: GOP_SIZE,
+            bit_depth: BitDepth::Eight,
+            region: None,
+            capture_resolution: None,
+            color_range: ColorRange::default(),
+            color_space: ColorSpace::default(),
+            zero_based_pts: false,
+            drm_device: None,
+            buffer_frames: DEFAULT_BUFFER_FRAMES,
+            bitstream_format: BitstreamFormat::AnnexB,
+            nvenc_tune: NvencTune::default(),
+            nvenc_preset: None,
+            b_frames: None,
+            lookahead: None,
+            async_depth: None,
+        }
+    }
+}
+
+/// Settings used to create (and re-create on [`crate::Capture::reset`]) an audio encoder
+#[derive(Debug, Clone, Copy)]
+pub struct AudioEncoderConfig {
+    /// Target bitrate in bits per second. `None` uses the encoder's default,
+    /// lossless and raw output ignore it.
+    pub bitrate: Option<u32>,
+    /// Sample rate in Hz audio is captured and encoded at
+    pub sample_rate: u32,
+    /// Channels to encode, audio is captured in stereo and averaged down for
+    /// [`ChannelLayout::MONO`]
+    pub channel_layout: ChannelLayout,
+    /// Only used by [`AudioEncoder::Opus`]
+    pub opus_application: OpusApplication,
+    /// Applied by every audio encoder. The builder defaults to [`AudioGain::None`] for
+    /// [`AudioEncoder::Flac`] and [`AudioEncoder::Pcm`] so they stay lossless
+    pub gain: AudioGain,
+    /// Frames the raw and encoded audio channels hold before frames are dropped
+    pub buffer_frames: usize,
+    /// How long opening a capture waits for the audio streams to start before failing
+    pub start_timeout: Duration,
+}
+
+impl Default for AudioEncoderConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: None,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channel_layout: ChannelLayout::STEREO,
+            opus_application: OpusApplication::Audio,
+            gain: AudioGain::Rms,
+            buffer_frames: DEFAULT_BUFFER_FRAMES,
+            start_timeout: DEFAULT_AUDIO_START_TIMEOUT,
+        }
+    }
+}
+
+impl VideoEncoderConfig {
+    /// Size of the encoded video for a stream of `width` x `height`, the
+    /// [`Self::capture_resolution`] if there is one, otherwise [`Self::crop_size`].
+    ///
+    /// Rounded down to even numbers since the encoders' 4:2:0 formats can't have odd sizes,
+    /// e.g. window captures of odd sizes lose their last column or row.
+    pub(crate) fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.capture_resolution {
+            Some(size) => (size.width & !1, size.height & !1),
+            None => self.crop_size(width, height),
+        }
+    }
+
+    /// Size of the part of a `width` x `height` stream which is encoded, before scaling it to
+    /// [`Self::output_size`]. Rounded down to even numbers like that.
+    pub(crate) fn crop_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = match self.region {
+            Some(region) => (region.width, region.height),
+            None => (width, height),
+        };
+        (width & !1, height & !1)
+    }
+
+    /// Size of a hardware frame pool which holds `default` frames unless
+    /// [`Self::async_depth`] asks for more in flight
+    pub(crate) fn hw_pool_size(&self, default: u32) -> i32 {
+        self.async_depth.map_or(default, |depth| depth.max(default)) as i32
+    }
+
+    /// Rate control the encoder should use
+    pub(crate) fn rate_control(&self) -> RateControl {
+        self.rate_control
+            .unwrap_or_else(|| self.quality.rate_control())
+    }
+
+    /// PTS for a frame captured at `timestamp`. With `zero_based_pts` this is relative to
+    /// `first_timestamp`, which is set on the first frame.
+    pub(crate) fn frame_pts(&self, first_timestamp: &mut Option<i64>, timestamp: i64) -> i64 {
+        if self.zero_based_pts {
+            timestamp - *first_timestamp.get_or_insert(timestamp)
+        } else {
+            timestamp
+        }
+    }
+}
@@ -8,6 +8,8 @@ pub enum WaycapError {
     FFmpeg(ffmpeg_next::Error),
     /// Egl Errors,
     Egl(khronos_egl::Error),
+    /// OpenGL errors while importing frames, e.g. an incomplete framebuffer
+    Gl(String),
     /// Errors from PipeWire
     PipeWire(String),
     /// Errors from XDG Portal
@@ -26,6 +28,8 @@ pub enum WaycapError {
     Device(String),
     /// Validation errors
     Validation(String),
+    /// The receiver of the output was dropped, the capture shuts down
+    Disconnected,
     /// Other errors
     Other(String),
 }
@@ -43,8 +47,10 @@ impl fmt::Display for WaycapError {
             WaycapError::Encoding(msg) => write!(f, "Encoding error: {msg}"),
             WaycapError::Device(msg) => write!(f, "Device error: {msg}"),
             WaycapError::Validation(msg) => write!(f, "Validation error: {msg}"),
+            WaycapError::Disconnected => write!(f, "Output receiver disconnected"),
             WaycapError::Other(msg) => write!(f, "Error: {msg}"),
             WaycapError::Egl(msg) => write!(f, "Egl Error: {msg}"),
+            WaycapError::Gl(msg) => write!(f, "OpenGL error: {msg}"),
         }
     }
 }
@@ -102,3 +108,30 @@ impl From<khronos_egl::Error> for WaycapError {
 }
 
 pub type Result<T> = std::result::Result<T, WaycapError>;
+
+/// Error of a [`crate::pipeline::screenshot_burst`] which stopped part way, with the
+/// screenshots taken until then
+#[derive(Debug)]
+pub struct BurstError {
+    /// Screenshots taken before the error, in order
+    pub images: Vec<image::RgbaImage>,
+    pub error: WaycapError,
+}
+
+impl fmt::Display for BurstError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after {} screenshots", self.error, self.images.len())
+    }
+}
+
+impl Error for BurstError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<BurstError> for WaycapError {
+    fn from(err: BurstError) -> Self {
+        err.error
+    }
+}
@@ -1,4 +1,5 @@
 pub mod audio_frame;
 pub mod config;
 pub mod error;
+pub mod stats;
 pub mod video_frame;
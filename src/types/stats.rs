@@ -0,0 +1,138 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// RMS below which audio counts as silence, about -80 dBFS
+const SILENCE_RMS: f32 = 0.0001;
+
+/// Video frame counters of a capture, see [`crate::Capture::stats`]
+///
+/// Useful to tell whether the encoder keeps up with the compositor or frames are being lost
+/// because a channel is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CaptureStats {
+    /// Frames received from PipeWire and queued for the encoder
+    pub frames_captured: u64,
+    /// Frames thrown away because a channel was full, either the one between PipeWire and
+    /// the encoder or the one between the encoder and the consumer
+    pub frames_dropped: u64,
+    /// Frames the encoder sent to the consumer
+    ///
+    /// Stays at 0 for [`crate::DmaBufEncoder`], which hands the captured frames over as they are.
+    pub frames_encoded: u64,
+}
+
+/// Live counters behind [`CaptureStats`], shared between the capture and encoder threads
+#[derive(Debug, Default)]
+pub struct FrameCounters {
+    captured: AtomicU64,
+    dropped: AtomicU64,
+    encoded: AtomicU64,
+    timing: AtomicBool,
+    /// Rolling average in nanoseconds, 0 until the first frame is timed
+    encode_latency_ns: AtomicU64,
+}
+
+impl FrameCounters {
+    pub(crate) fn frame_captured(&self) {
+        self.captured.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn frame_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn frame_encoded(&self) {
+        self.encoded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn enable_timing(&self) {
+        self.timing.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether encoders should time their frames, see [`Self::record_encode_latency`]
+    pub(crate) fn timing_enabled(&self) -> bool {
+        self.timing.load(Ordering::Relaxed)
+    }
+
+    /// Add the time a frame took from being sent to the encoder until a packet came out
+    pub(crate) fn record_encode_latency(&self, latency: Duration) {
+        let sample = latency.as_nanos() as u64;
+        let average = self.encode_latency_ns.load(Ordering::Relaxed);
+        // Exponential moving average over roughly the last 16 frames. Only the encoder
+        // thread writes this so there is no lost update to worry about
+        let average = if average == 0 {
+            sample
+        } else {
+            average - average / 16 + sample / 16
+        };
+        self.encode_latency_ns
+            .store(average.max(1), Ordering::Relaxed);
+    }
+
+    /// Average encode latency, `None` if timing is disabled or no frame was encoded yet
+    pub fn encode_latency(&self) -> Option<Duration> {
+        match self.encode_latency_ns.load(Ordering::Relaxed) {
+            0 => None,
+            ns => Some(Duration::from_nanos(ns)),
+        }
+    }
+
+    /// Read the current counter values
+    pub fn snapshot(&self) -> CaptureStats {
+        CaptureStats {
+            frames_captured: self.captured.load(Ordering::Relaxed),
+            frames_dropped: self.dropped.load(Ordering::Relaxed),
+            frames_encoded: self.encoded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Rolling level of the captured system audio, see [`crate::Capture::audio_level`]
+#[derive(Debug)]
+pub struct AudioLevel {
+    /// `f32` bits of the smoothed RMS
+    level: AtomicU32,
+    created: Instant,
+    /// Nanoseconds after `created` the last non-silent samples came in
+    last_sound_ns: AtomicU64,
+}
+
+impl Default for AudioLevel {
+    fn default() -> Self {
+        Self {
+            level: AtomicU32::new(0.0f32.to_bits()),
+            created: Instant::now(),
+            last_sound_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AudioLevel {
+    /// Feed the RMS of a new buffer of samples
+    pub(crate) fn update(&self, rms: f32) {
+        // Smooth over a few buffers so a meter doesn't flicker. Only the audio thread
+        // writes this
+        let level = f32::from_bits(self.level.load(Ordering::Relaxed));
+        let level = level * 0.8 + rms * 0.2;
+        self.level.store(level.to_bits(), Ordering::Relaxed);
+
+        if rms > SILENCE_RMS {
+            self.last_sound_ns
+                .store(self.created.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Current RMS level, from 0.0 for silence to 1.0 for a full scale signal
+    pub fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+
+    /// True if nothing above silence was captured for at least `duration`.
+    /// Also true when no samples arrive at all, e.g. because the sink is idle.
+    pub fn is_silent_for(&self, duration: Duration) -> bool {
+        let last_sound = Duration::from_nanos(self.last_sound_ns.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_sound) >= duration
+    }
+}
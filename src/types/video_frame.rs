@@ -1,33 +1,310 @@
-use std::os::fd::RawFd;
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    sync::Arc,
+};
 
+use drm_fourcc::DrmFourcc;
 use pipewire::spa::{param::video::VideoFormat, utils::Rectangle};
 
-#[derive(Debug)]
+use crate::{
+    types::error::{Result, WaycapError},
+    utils::drm_fourcc,
+};
+
+#[derive(Debug, Clone)]
 pub struct EncodedVideoFrame {
     pub data: Vec<u8>,
     pub is_keyframe: bool,
-    /// Encoder value for when it should be presented (Presentation TimeStamp)
+    /// Encoder value for when it should be presented (Presentation TimeStamp),
+    /// in nanoseconds for the hardware encoders. Not increasing with B-frames, as frames
+    /// arrive in decode order
     pub pts: i64,
-    /// Encoder value for when it should be decoded (Decode TimeStamp)
+    /// Encoder value for when it should be decoded (Decode TimeStamp), always increasing.
+    /// Below `pts` by the B-frame delay with B-frames, equal to it without
     pub dts: i64,
+    /// True for the first frame after [`crate::Capture::rotate_segment`], which is always a
+    /// keyframe. This and every following frame belong in the next segment.
+    pub starts_segment: bool,
+    /// How long the frame is shown, in the same unit as `pts`. Taken from the DTS of the
+    /// frame after it, which gives the PTS difference for encoders without B-frames, so each
+    /// frame is held back until the next one is encoded. The last frame before a drain lasts
+    /// as long as the one before it.
+    pub duration: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RawVideoFrame {
+    /// Pixel data for frames in CPU memory.
+    /// Empty for DMA-BUF frames, use `planes` or [`Self::dmabuf`] for those instead.
     pub data: Vec<u8>,
+    /// Capture timestamp in nanoseconds, taken from PipeWire's monotonic stream clock
+    /// (`pw_stream_get_nsec`) like audio frames so the two can be compared
     pub timestamp: i64,
+    /// Fd of the PipeWire buffer, only valid until the buffer is reused for another frame.
+    /// Use [`Self::dmabuf`] to keep the frame around.
     pub dmabuf_fd: Option<RawFd>,
+    /// Every plane of a DMA-BUF frame, multi-planar formats like NV12 can use a different fd,
+    /// offset and stride per plane. `dmabuf_fd`, `stride` and `offset` describe the first one.
+    /// Empty for frames in CPU memory.
+    pub planes: Vec<DmaBufPlane>,
     pub stride: i32,
     pub offset: u32,
     pub size: u32,
     pub modifier: u64,
     pub format: VideoFormat,
     pub dimensions: Rectangle,
+    /// Cursor position and image sent by the compositor alongside the frame.
+    /// `None` when the stream carries no cursor metadata, see
+    /// [`crate::pipeline::builder::CaptureBuilder::with_cursor_metadata`].
+    pub cursor: Option<CursorMeta>,
+}
+
+impl RawVideoFrame {
+    /// Duplicate the fds of a DMA-BUF frame so it can be held onto after PipeWire reuses the
+    /// buffer. `None` for frames in CPU memory.
+    ///
+    /// The fds are only duplicated when this is called, frames which are dropped or only
+    /// encoded don't pay for it. Call it right after receiving the frame, the fds in `planes`
+    /// belong to PipeWire and are closed when the stream is renegotiated.
+    pub fn dmabuf(&self) -> Result<Option<DmaBufHandle>> {
+        if self.planes.is_empty() {
+            return Ok(None);
+        }
+        let handle = DmaBufHandle::new(&self.planes, self.modifier, self.format, self.dimensions)?;
+        Ok(Some(handle))
+    }
+}
+
+/// Cursor metadata for a single frame, taken from PipeWire's `SPA_META_Cursor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorMeta {
+    /// Position of the cursor on the captured output in pixels
+    pub position: (i32, i32),
+    /// Offset of the cursor's hotspot inside the cursor image
+    pub hotspot: (i32, i32),
+    /// Cursor id from the compositor, this changes when the cursor image changes
+    pub serial: u32,
+    /// The cursor image. Compositors only send it when it changed, so it is `None` on most
+    /// frames and the last one received should be kept around.
+    pub image: Option<CursorImage>,
+}
+
+/// Cursor bitmap from the cursor metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    /// Pixel format of `data`, usually one of the 4 byte RGBA variants
+    pub format: VideoFormat,
+    /// Tightly packed pixels, `width * 4` bytes per row
+    pub data: Vec<u8>,
 }
 
-#[derive(Debug)]
+/// Transform of the captured output, e.g. [`Transform::Rotate90`] for a portrait monitor,
+/// taken from PipeWire's `SPA_META_VideoTransform`. The frames are encoded as the compositor
+/// sends them, so they have to be transformed like this to be shown upright.
+///
+/// Rotations are counter-clockwise, flips mirror the frame horizontally before rotating it,
+/// like Wayland's output transforms.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transform {
+    #[default]
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl Transform {
+    /// From a `spa_meta_videotransform_value`, unknown values are treated as no transform
+    pub(crate) fn from_raw(value: u32) -> Self {
+        match value {
+            1 => Transform::Rotate90,
+            2 => Transform::Rotate180,
+            3 => Transform::Rotate270,
+            4 => Transform::Flipped,
+            5 => Transform::Flipped90,
+            6 => Transform::Flipped180,
+            7 => Transform::Flipped270,
+            _ => Transform::Normal,
+        }
+    }
+
+    /// Counter-clockwise rotation in degrees
+    pub fn rotation(self) -> u32 {
+        match self {
+            Transform::Normal | Transform::Flipped => 0,
+            Transform::Rotate90 | Transform::Flipped90 => 90,
+            Transform::Rotate180 | Transform::Flipped180 => 180,
+            Transform::Rotate270 | Transform::Flipped270 => 270,
+        }
+    }
+
+    /// True if the frame is mirrored horizontally
+    pub fn is_flipped(self) -> bool {
+        matches!(
+            self,
+            Transform::Flipped
+                | Transform::Flipped90
+                | Transform::Flipped180
+                | Transform::Flipped270
+        )
+    }
+
+    /// True if the frames are shown with width and height swapped, e.g. on a portrait monitor
+    pub fn swaps_dimensions(self) -> bool {
+        self.rotation() % 180 == 90
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct DmaBufPlane {
     pub fd: i32,
     pub offset: u32,
     pub stride: u32,
 }
+
+/// DMA-BUF of a captured frame which can be held onto after the frame was handed back to
+/// PipeWire, e.g. across threads or `.await`s before importing it into a GL or Vulkan context.
+///
+/// The handle owns duplicates of the buffer's fds, so the memory stays alive as long as the
+/// handle or one of its clones does. PipeWire does reuse its buffers: holding the handle
+/// doesn't stop a newer frame from being written into the same memory a few frames later.
+#[derive(Debug, Clone)]
+pub struct DmaBufHandle {
+    fds: Arc<Vec<OwnedFd>>,
+    planes: Vec<DmaBufPlane>,
+    modifier: u64,
+    format: VideoFormat,
+    dimensions: Rectangle,
+}
+
+impl DmaBufHandle {
+    /// Duplicate the fds of `planes`, which are only borrowed from PipeWire
+    pub(crate) fn new(
+        planes: &[DmaBufPlane],
+        modifier: u64,
+        format: VideoFormat,
+        dimensions: Rectangle,
+    ) -> io::Result<Self> {
+        let fds = planes
+            .iter()
+            .map(|plane| unsafe { BorrowedFd::borrow_raw(plane.fd) }.try_clone_to_owned())
+            .collect::<io::Result<Vec<_>>>()?;
+        let planes = planes
+            .iter()
+            .zip(&fds)
+            .map(|(plane, fd)| DmaBufPlane {
+                fd: fd.as_raw_fd(),
+                ..*plane
+            })
+            .collect();
+        Ok(Self {
+            fds: Arc::new(fds),
+            planes,
+            modifier,
+            format,
+            dimensions,
+        })
+    }
+
+    /// Planes of the frame. The fds are owned by the handle and closed once it and all of its
+    /// clones are dropped, use [`BorrowedFd::try_clone_to_owned`] to keep one open longer.
+    pub fn planes(&self) -> &[DmaBufPlane] {
+        &self.planes
+    }
+
+    /// Fd of the first plane
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.fds[0].as_fd()
+    }
+
+    /// DRM format modifier of the buffer
+    pub fn modifier(&self) -> u64 {
+        self.modifier
+    }
+
+    pub fn format(&self) -> VideoFormat {
+        self.format
+    }
+
+    pub fn dimensions(&self) -> Rectangle {
+        self.dimensions
+    }
+}
+
+/// Layout of a DMA-BUF frame for importing it as a Vulkan image through
+/// `VK_EXT_external_memory_dma_buf` and `VK_EXT_image_drm_format_modifier`, without going
+/// through GL first.
+///
+/// The fields map onto `VkImageDrmFormatModifierExplicitCreateInfoEXT`: `modifier` is the
+/// `drmFormatModifier` and each plane's `offset`/`row_pitch` is a `VkSubresourceLayout`.
+/// Vulkan takes ownership of imported fds, so pass it one from [`Self::dup_fd`].
+/// Planes with different fds need a disjoint image.
+#[derive(Debug, Clone)]
+pub struct VulkanDmaBufInfo {
+    pub fourcc: DrmFourcc,
+    pub modifier: u64,
+    pub width: u32,
+    pub height: u32,
+    pub planes: Vec<VulkanPlaneLayout>,
+    /// Keeps the fds in `planes` open
+    handle: DmaBufHandle,
+}
+
+/// One memory plane of a [`VulkanDmaBufInfo`]
+#[derive(Debug, Clone, Copy)]
+pub struct VulkanPlaneLayout {
+    /// Owned by the [`VulkanDmaBufInfo`], don't close it or hand it to Vulkan directly
+    pub fd: RawFd,
+    pub offset: u64,
+    pub row_pitch: u64,
+}
+
+impl VulkanDmaBufInfo {
+    /// Describe the DMA-BUF of `frame`.
+    ///
+    /// Returns [`WaycapError::Validation`] for frames in CPU memory and formats without a DRM
+    /// equivalent.
+    pub fn new(frame: &RawVideoFrame) -> Result<Self> {
+        let handle = frame
+            .dmabuf()?
+            .ok_or_else(|| WaycapError::Validation("Frame is not a DMA-BUF".to_string()))?;
+        let fourcc = drm_fourcc(handle.format()).ok_or_else(|| {
+            WaycapError::Validation(format!("No DRM format for {:?}", handle.format()))
+        })?;
+        let planes = handle
+            .planes()
+            .iter()
+            .map(|plane| VulkanPlaneLayout {
+                fd: plane.fd,
+                offset: plane.offset as u64,
+                row_pitch: plane.stride as u64,
+            })
+            .collect();
+
+        Ok(Self {
+            fourcc,
+            modifier: handle.modifier(),
+            width: handle.dimensions().width,
+            height: handle.dimensions().height,
+            planes,
+            handle,
+        })
+    }
+
+    /// New fd for `plane` to pass to `VkImportMemoryFdInfoKHR`, which closes it on success
+    pub fn dup_fd(&self, plane: usize) -> io::Result<OwnedFd> {
+        let fd = self
+            .handle
+            .planes()
+            .get(plane)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No such plane"))?;
+        unsafe { BorrowedFd::borrow_raw(fd.fd) }.try_clone_to_owned()
+    }
+}
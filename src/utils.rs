@@ -1,11 +1,43 @@
+use std::thread::JoinHandle;
+
+use drm_fourcc::DrmFourcc;
+use ffmpeg_next::ffi::AVDRMFrameDescriptor;
+use pipewire::spa::param::video::VideoFormat;
+
 use crate::types::{
-    error::Result,
+    error::{Result, WaycapError},
     video_frame::{DmaBufPlane, RawVideoFrame},
 };
 
 pub const TIME_UNIT_NS: u64 = 1_000_000_000;
 
+/// Current `CLOCK_MONOTONIC` time in nanoseconds, the clock PipeWire timestamps come from
+pub(crate) fn monotonic_ns() -> i64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as i64 * TIME_UNIT_NS as i64 + now.tv_nsec as i64
+}
+
+/// Like [`std::thread::spawn`] but with a name, so workers can be told apart in debuggers and
+/// profilers. Linux only keeps the first 15 bytes of the name.
+pub(crate) fn spawn_named<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .expect("failed to spawn thread")
+}
+
 pub fn extract_dmabuf_planes(raw_frame: &RawVideoFrame) -> Result<Vec<DmaBufPlane>> {
+    if !raw_frame.planes.is_empty() {
+        return Ok(raw_frame.planes.clone());
+    }
     match raw_frame.dmabuf_fd {
         Some(fd) => Ok(vec![DmaBufPlane {
             fd,
@@ -15,3 +47,114 @@ pub fn extract_dmabuf_planes(raw_frame: &RawVideoFrame) -> Result<Vec<DmaBufPlan
         None => Err("No DMA-BUF file descriptor in frame".into()),
     }
 }
+
+/// DRM format of a DMA-BUF with the given PipeWire video format.
+///
+/// PipeWire names formats by their byte order while DRM names them as little endian words,
+/// so the component order is reversed for the packed RGB formats.
+pub(crate) fn drm_fourcc(format: VideoFormat) -> Option<DrmFourcc> {
+    Some(match format {
+        VideoFormat::BGRA => DrmFourcc::Argb8888,
+        VideoFormat::BGRx => DrmFourcc::Xrgb8888,
+        VideoFormat::RGBA => DrmFourcc::Abgr8888,
+        VideoFormat::RGBx => DrmFourcc::Xbgr8888,
+        VideoFormat::ARGB => DrmFourcc::Bgra8888,
+        VideoFormat::xRGB => DrmFourcc::Bgrx8888,
+        VideoFormat::ABGR => DrmFourcc::Rgba8888,
+        VideoFormat::xBGR => DrmFourcc::Rgbx8888,
+        VideoFormat::xRGB_210LE => DrmFourcc::Xrgb2101010,
+        VideoFormat::xBGR_210LE => DrmFourcc::Xbgr2101010,
+        VideoFormat::NV12 => DrmFourcc::Nv12,
+        VideoFormat::I420 => DrmFourcc::Yuv420,
+        VideoFormat::P010_10LE => DrmFourcc::P010,
+        _ => return None,
+    })
+}
+
+/// PipeWire video format of DMA-BUFs with the DRM format `fourcc`, the reverse of
+/// [`drm_fourcc`]
+pub(crate) fn video_format(fourcc: DrmFourcc) -> Option<VideoFormat> {
+    Some(match fourcc {
+        DrmFourcc::Argb8888 => VideoFormat::BGRA,
+        DrmFourcc::Xrgb8888 => VideoFormat::BGRx,
+        DrmFourcc::Abgr8888 => VideoFormat::RGBA,
+        DrmFourcc::Xbgr8888 => VideoFormat::RGBx,
+        DrmFourcc::Bgra8888 => VideoFormat::ARGB,
+        DrmFourcc::Bgrx8888 => VideoFormat::xRGB,
+        DrmFourcc::Rgba8888 => VideoFormat::ABGR,
+        DrmFourcc::Rgbx8888 => VideoFormat::xBGR,
+        DrmFourcc::Xrgb2101010 => VideoFormat::xRGB_210LE,
+        DrmFourcc::Xbgr2101010 => VideoFormat::xBGR_210LE,
+        DrmFourcc::Nv12 => VideoFormat::NV12,
+        DrmFourcc::Yuv420 => VideoFormat::I420,
+        DrmFourcc::P010 => VideoFormat::P010_10LE,
+        _ => return None,
+    })
+}
+
+/// DRM frame descriptor of a DMA-BUF frame with one layer holding all of its planes, for
+/// importing it through ffmpeg's `DRM_PRIME` frames.
+///
+/// Planes sharing an fd share an object. Multi-planar formats sent as a single plane are
+/// assumed to have their chroma planes right after the luma plane in the same buffer.
+pub(crate) fn drm_descriptor(frame: &RawVideoFrame) -> Result<AVDRMFrameDescriptor> {
+    let format = drm_fourcc(frame.format).ok_or_else(|| {
+        WaycapError::Encoding(format!("Can't import {:?} DMA-BUFs", frame.format))
+    })?;
+    let mut planes = extract_dmabuf_planes(frame)?;
+    if planes.len() == 1 {
+        let luma = planes[0];
+        let luma_size = luma.stride * frame.dimensions.height;
+        match format {
+            DrmFourcc::Nv12 | DrmFourcc::P010 => planes.push(DmaBufPlane {
+                offset: luma.offset + luma_size,
+                ..luma
+            }),
+            DrmFourcc::Yuv420 => {
+                let chroma_stride = luma.stride / 2;
+                let chroma_size = chroma_stride * frame.dimensions.height.div_ceil(2);
+                planes.push(DmaBufPlane {
+                    offset: luma.offset + luma_size,
+                    stride: chroma_stride,
+                    ..luma
+                });
+                planes.push(DmaBufPlane {
+                    offset: luma.offset + luma_size + chroma_size,
+                    stride: chroma_stride,
+                    ..luma
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let mut desc: AVDRMFrameDescriptor = unsafe { std::mem::zeroed() };
+    if planes.len() > desc.layers[0].planes.len() {
+        return Err(WaycapError::Encoding(format!(
+            "DMA-BUF frame has {} planes, at most {} are supported",
+            planes.len(),
+            desc.layers[0].planes.len()
+        )));
+    }
+    desc.nb_layers = 1;
+    desc.layers[0].format = format as u32;
+    desc.layers[0].nb_planes = planes.len() as i32;
+    for (i, plane) in planes.iter().enumerate() {
+        let objects = &desc.objects[..desc.nb_objects as usize];
+        let object_index = match objects.iter().position(|object| object.fd == plane.fd) {
+            Some(index) => index,
+            None => {
+                let index = desc.nb_objects as usize;
+                desc.objects[index].fd = plane.fd;
+                desc.objects[index].size = 0;
+                desc.objects[index].format_modifier = 0;
+                desc.nb_objects += 1;
+                index
+            }
+        };
+        desc.layers[0].planes[i].object_index = object_index as i32;
+        desc.layers[0].planes[i].offset = plane.offset as isize;
+        desc.layers[0].planes[i].pitch = plane.stride as isize;
+    }
+    Ok(desc)
+}
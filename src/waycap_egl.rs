@@ -1,24 +1,73 @@
 use std::{
     cell::Cell,
     ffi::{c_void, CStr},
+    os::fd::{FromRawFd, OwnedFd},
 };
 
+use drm_fourcc::DrmFourcc;
 use khronos_egl::{self as egl, ClientBuffer, Dynamic, Instance};
 
-use crate::types::{error::Result, video_frame::DmaBufPlane};
+use crate::types::{
+    error::{Result, WaycapError},
+    video_frame::DmaBufPlane,
+};
 
 type PFNGLEGLIMAGETARGETTEXTURE2DOESPROC =
     unsafe extern "C" fn(target: gl::types::GLenum, image: *const c_void);
+type PFNEGLQUERYDEVICESEXTPROC = unsafe extern "C" fn(
+    max_devices: egl::Int,
+    devices: *mut *mut c_void,
+    num_devices: *mut egl::Int,
+) -> egl::Boolean;
+
+type PFNEGLQUERYDMABUFMODIFIERSEXTPROC = unsafe extern "C" fn(
+    display: *mut c_void,
+    format: egl::Int,
+    max_modifiers: egl::Int,
+    modifiers: *mut u64,
+    external_only: *mut egl::Boolean,
+    num_modifiers: *mut egl::Int,
+) -> egl::Boolean;
+
+type PFNEGLEXPORTDMABUFIMAGEQUERYMESAPROC = unsafe extern "C" fn(
+    display: *mut c_void,
+    image: *mut c_void,
+    fourcc: *mut egl::Int,
+    num_planes: *mut egl::Int,
+    modifiers: *mut u64,
+) -> egl::Boolean;
+
+type PFNEGLEXPORTDMABUFIMAGEMESAPROC = unsafe extern "C" fn(
+    display: *mut c_void,
+    image: *mut c_void,
+    fds: *mut egl::Int,
+    strides: *mut egl::Int,
+    offsets: *mut egl::Int,
+) -> egl::Boolean;
+
+type EglInstance = Instance<Dynamic<libloading::Library, egl::EGL1_5>>;
+
+/// `EGL_PLATFORM_DEVICE_EXT` from `EGL_EXT_platform_device`
+const PLATFORM_DEVICE_EXT: egl::Enum = 0x313F;
+/// Most GPUs looked at when falling back to device displays
+const MAX_EGL_DEVICES: usize = 8;
 
 unsafe impl Sync for EglContext {}
 unsafe impl Send for EglContext {}
 
-#[derive(Clone, Copy)]
+/// Vendor of the GPU the compositor renders on, which decides the encoders that can be used
+///
+/// See [`crate::pipeline::detect_gpu_vendor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum GpuVendor {
+    /// Encodes with NVENC
     NVIDIA,
+    /// Encodes with VAAPI
     AMD,
+    /// Encodes with QSV or VAAPI
     INTEL,
+    /// No hardware encoder is known for this GPU
     UNKNOWN,
 }
 
@@ -46,8 +95,14 @@ impl From<&CStr> for GpuVendor {
     }
 }
 
+/// OpenGL ES context on the compositor's GPU which DMA-BUF frames are imported into.
+///
+/// [`crate::NvencEncoder`] normally creates its own, pass one to
+/// [`CaptureBuilder::with_egl_context`](crate::pipeline::builder::CaptureBuilder::with_egl_context)
+/// to have frames imported into a context shared with your renderer instead. The texture
+/// holding the latest frame is then available through [`Self::get_texture_id`].
 pub struct EglContext {
-    egl_instance: Instance<Dynamic<libloading::Library, egl::EGL1_5>>,
+    egl_instance: EglInstance,
     display: egl::Display,
     context: egl::Context,
     surface: Option<egl::Surface>, // Optional for surfaceless context
@@ -56,26 +111,52 @@ pub struct EglContext {
     dmabuf_modifiers_supported: bool,
     persistent_texture_id: Cell<Option<u32>>,
     gpu_vendor: GpuVendor,
-    width: i32,
-    height: i32,
 
-    // Keep Wayland display alive
-    _wayland_display: wayland_client::Display,
+    // Keep Wayland display alive, `None` when running on a device display
+    _wayland_display: Option<wayland_client::Display>,
+}
+
+/// Texture exported as a DMA-BUF by [`EglContext::export_texture`]. Destroy `image` with
+/// [`EglContext::destroy_image`] once the buffer is no longer used, the fds are closed on drop.
+pub(crate) struct ExportedTexture {
+    pub(crate) image: egl::Image,
+    _fds: Vec<OwnedFd>,
+    pub(crate) planes: Vec<DmaBufPlane>,
+    pub(crate) fourcc: u32,
+    pub(crate) modifier: u64,
 }
 
 impl EglContext {
+    /// Create a context on the Wayland display, with a `width` x `height` pbuffer surface
+    /// where supported or surfaceless otherwise. The context is left current on the calling
+    /// thread.
     pub fn new(width: i32, height: i32) -> Result<Self> {
-        let lib =
-            unsafe { libloading::Library::new("libEGL.so.1") }.expect("unable to find libEGL.so.1");
+        let lib = unsafe { libloading::Library::new("libEGL.so.1") }
+            .map_err(|e| WaycapError::Init(format!("Unable to find libEGL.so.1: {e}")))?;
         let egl_instance = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required_from(lib) }
-            .expect("unable to load libEGL.so.1");
+            .map_err(|e| WaycapError::Init(format!("Unable to load libEGL.so.1: {e}")))?;
 
         egl_instance.bind_api(egl::OPENGL_ES_API)?;
 
-        let wayland_display = wayland_client::Display::connect_to_env().unwrap();
-        let display =
-            unsafe { egl_instance.get_display(wayland_display.c_ptr() as *mut std::ffi::c_void) }
-                .unwrap();
+        let wayland_display = match wayland_client::Display::connect_to_env() {
+            Ok(wayland_display) => Some(wayland_display),
+            Err(e) => {
+                log::warn!("Unable to connect to the Wayland display, trying EGL devices: {e}");
+                None
+            }
+        };
+        let wayland_egl_display = wayland_display.as_ref().and_then(|wayland_display| unsafe {
+            egl_instance.get_display(wayland_display.c_ptr() as *mut c_void)
+        });
+        let display = match wayland_egl_display {
+            Some(display) => display,
+            None => {
+                if wayland_display.is_some() {
+                    log::warn!("No EGL display for the Wayland display, trying EGL devices");
+                }
+                Self::get_device_display(&egl_instance)?
+            }
+        };
 
         egl_instance.initialize(display)?;
 
@@ -103,7 +184,9 @@ impl EglContext {
                     .ok()
                     .flatten()
             })
-            .expect("unable to find an appropriate EGL configuration");
+            .ok_or_else(|| {
+                WaycapError::Init("Unable to find an appropriate EGL configuration".into())
+            })?;
 
         let context_attributes = [egl::CONTEXT_CLIENT_VERSION, 2, egl::NONE];
 
@@ -128,13 +211,19 @@ impl EglContext {
             egl_instance.make_current(display, None, None, Some(context))?;
             None
         } else {
-            return Err("No suitable surface type available".into());
+            return Err(WaycapError::Init(
+                "No suitable surface type available".into(),
+            ));
         };
 
-        gl::load_with(|symbol| egl_instance.get_proc_address(symbol).unwrap() as *const _);
+        gl::load_with(|symbol| {
+            egl_instance
+                .get_proc_address(symbol)
+                .map_or(std::ptr::null(), |proc_addr| proc_addr as *const _)
+        });
 
         let (dmabuf_supported, dmabuf_modifiers_supported) =
-            Self::check_dmabuf_support(&egl_instance, display).unwrap();
+            Self::check_dmabuf_support(&egl_instance, display)?;
 
         let gpu_vendor = get_gpu_vendor();
 
@@ -148,8 +237,6 @@ impl EglContext {
             dmabuf_modifiers_supported,
             persistent_texture_id: Cell::new(None),
             gpu_vendor,
-            width,
-            height,
 
             _wayland_display: wayland_display,
         })
@@ -157,7 +244,19 @@ impl EglContext {
 
     pub fn update_texture_from_image(&self, egl_image: egl::Image) -> Result<()> {
         assert!(self.persistent_texture_id.get().is_some());
+        let texture = self.persistent_texture_id.get().unwrap();
+        self.copy_image_to_texture(egl_image, texture, (0, 0), None)
+    }
 
+    /// Copy `egl_image` into `texture` with its top left corner at `offset`, cut off at
+    /// `max_size` if it is larger
+    pub(crate) fn copy_image_to_texture(
+        &self,
+        egl_image: egl::Image,
+        texture: u32,
+        offset: (i32, i32),
+        max_size: Option<(i32, i32)>,
+    ) -> Result<()> {
         unsafe {
             // Create a temporary texture from the EGL image
             let mut temp_texture = 0;
@@ -171,7 +270,9 @@ impl EglContext {
 
                 if proc_addr.is_none() {
                     gl::DeleteTextures(1, &temp_texture);
-                    return Err("glEGLImageTargetTexture2DOES not available".into());
+                    return Err(WaycapError::Gl(
+                        "glEGLImageTargetTexture2DOES not available".into(),
+                    ));
                 } else {
                     std::mem::transmute::<
                         Option<extern "system" fn()>,
@@ -185,9 +286,9 @@ impl EglContext {
             let gl_error = gl::GetError();
             if gl_error != gl::NO_ERROR {
                 gl::DeleteTextures(1, &temp_texture);
-                return Err(
-                    format!("Failed to bind EGL image to temp texture: 0x{gl_error:x}").into(),
-                );
+                return Err(WaycapError::Gl(format!(
+                    "Failed to bind EGL image to temp texture: 0x{gl_error:x}"
+                )));
             }
 
             // Get dimensions from the EGL image texture
@@ -195,18 +296,38 @@ impl EglContext {
             let mut height = 0;
             gl::GetTexLevelParameteriv(gl::TEXTURE_2D, 0, gl::TEXTURE_WIDTH, &mut width);
             gl::GetTexLevelParameteriv(gl::TEXTURE_2D, 0, gl::TEXTURE_HEIGHT, &mut height);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            let size = match max_size {
+                Some((max_width, max_height)) => (width.min(max_width), height.min(max_height)),
+                None => (width, height),
+            };
+
+            let result = self.copy_texture(temp_texture, texture, offset, size);
+            gl::DeleteTextures(1, &temp_texture);
+            result
+        }
+    }
 
+    /// Copy the top left `size` of `source` into `target` at `offset`
+    pub(crate) fn copy_texture(
+        &self,
+        source: u32,
+        target: u32,
+        offset: (i32, i32),
+        size: (i32, i32),
+    ) -> Result<()> {
+        unsafe {
             // Create framebuffer for copying
             let mut fbo = 0;
             gl::GenFramebuffers(1, &mut fbo);
             gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
 
-            // Attach temporary EGL texture as source
+            // Attach the source texture to read from
             gl::FramebufferTexture2D(
                 gl::FRAMEBUFFER,
                 gl::COLOR_ATTACHMENT0,
                 gl::TEXTURE_2D,
-                temp_texture,
+                source,
                 0,
             );
 
@@ -215,46 +336,54 @@ impl EglContext {
             if status != gl::FRAMEBUFFER_COMPLETE {
                 gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
                 gl::DeleteFramebuffers(1, &fbo);
-                gl::DeleteTextures(1, &temp_texture);
-                return Err(format!("Framebuffer not complete: 0x{status:x}").into());
+                return Err(WaycapError::Gl(format!(
+                    "Framebuffer not complete: 0x{status:x}"
+                )));
             }
 
-            // Bind persistent texture as destination
-            gl::BindTexture(gl::TEXTURE_2D, self.persistent_texture_id.get().unwrap());
+            // Bind target texture as destination
+            gl::BindTexture(gl::TEXTURE_2D, target);
 
             // Use CopyTexSubImage2D instead of CopyTexImage2D
             // This updates existing texture data rather than reallocating
             gl::CopyTexSubImage2D(
                 gl::TEXTURE_2D,
                 0, // mipmap level
-                0,
-                0, // destination x, y offset in texture
+                offset.0,
+                offset.1, // destination x, y offset in texture
                 0,
                 0,      // source x, y offset in framebuffer
-                width,  // width to copy
-                height, // height to copy
+                size.0, // width to copy
+                size.1, // height to copy
             );
 
             let gl_error = gl::GetError();
-            if gl_error != gl::NO_ERROR {
-                gl::BindTexture(gl::TEXTURE_2D, 0);
-                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
-                gl::DeleteFramebuffers(1, &fbo);
-                gl::DeleteTextures(1, &temp_texture);
-                return Err(format!("Failed to copy texture data: 0x{gl_error:x}").into());
-            }
 
             // Cleanup
             gl::BindTexture(gl::TEXTURE_2D, 0);
             gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
             gl::DeleteFramebuffers(1, &fbo);
-            gl::DeleteTextures(1, &temp_texture);
 
+            if gl_error != gl::NO_ERROR {
+                return Err(WaycapError::Gl(format!(
+                    "Failed to copy texture data: 0x{gl_error:x}"
+                )));
+            }
             Ok(())
         }
     }
 
-    pub fn create_persistent_texture(&self) -> Result<()> {
+    /// Create the `width` x `height` texture frames are copied into, see
+    /// [`Self::get_texture_id`]
+    pub fn create_persistent_texture(&self, width: i32, height: i32) -> Result<()> {
+        let texture_id = self.create_texture(width, height)?;
+        log::trace!("✓ Created persistent texture: ID {texture_id} ({width}x{height})");
+        self.persistent_texture_id.set(Some(texture_id));
+        Ok(())
+    }
+
+    /// Create an empty `width` x `height` RGBA texture, delete it with [`Self::delete_texture`]
+    pub(crate) fn create_texture(&self, width: i32, height: i32) -> Result<u32> {
         unsafe {
             let mut texture_id = 0;
             gl::GenTextures(1, &mut texture_id);
@@ -265,8 +394,8 @@ impl EglContext {
                 gl::TEXTURE_2D,
                 0,
                 gl::RGBA8 as i32, // CUDA-compatible format
-                self.width,
-                self.height,
+                width,
+                height,
                 0,
                 gl::RGBA,
                 gl::UNSIGNED_BYTE,
@@ -284,21 +413,199 @@ impl EglContext {
             let gl_error = gl::GetError();
             if gl_error != gl::NO_ERROR {
                 gl::DeleteTextures(1, &texture_id);
-                return Err(format!("Failed to create persistent texture: 0x{gl_error:x}").into());
+                return Err(WaycapError::Gl(format!(
+                    "Failed to create texture: 0x{gl_error:x}"
+                )));
             }
+            Ok(texture_id)
+        }
+    }
 
-            log::trace!(
-                "✓ Created persistent texture: ID {texture_id} ({}x{})",
-                self.width,
-                self.height
+    /// Fill `texture` with opaque black
+    pub(crate) fn clear_texture(&self, texture: u32) -> Result<()> {
+        unsafe {
+            let mut fbo = 0;
+            gl::GenFramebuffers(1, &mut fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                texture,
+                0,
             );
-            self.persistent_texture_id.set(Some(texture_id));
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+
+            let gl_error = gl::GetError();
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::DeleteFramebuffers(1, &fbo);
+            if gl_error != gl::NO_ERROR {
+                return Err(WaycapError::Gl(format!(
+                    "Failed to clear texture: 0x{gl_error:x}"
+                )));
+            }
             Ok(())
         }
     }
 
+    /// Export `texture` as a DMA-BUF through `EGL_MESA_image_dma_buf_export`, e.g. to hand
+    /// frames rendered with GL to a hardware encoder. The buffer shares the texture's memory,
+    /// so later draws into the texture show up in it.
+    pub(crate) fn export_texture(&self, texture: u32) -> Result<ExportedTexture> {
+        let extensions = self
+            .egl_instance
+            .query_string(Some(self.display), egl::EXTENSIONS)?;
+        if !extensions
+            .to_string_lossy()
+            .contains("EGL_MESA_image_dma_buf_export")
+        {
+            return Err(WaycapError::Init(
+                "EGL_MESA_image_dma_buf_export not supported".into(),
+            ));
+        }
+        let query_export = self
+            .egl_instance
+            .get_proc_address("eglExportDMABUFImageQueryMESA")
+            .ok_or_else(|| {
+                WaycapError::Init("eglExportDMABUFImageQueryMESA not available".into())
+            })?;
+        let export = self
+            .egl_instance
+            .get_proc_address("eglExportDMABUFImageMESA")
+            .ok_or_else(|| WaycapError::Init("eglExportDMABUFImageMESA not available".into()))?;
+        let (query_export, export) = unsafe {
+            (
+                std::mem::transmute::<extern "system" fn(), PFNEGLEXPORTDMABUFIMAGEQUERYMESAPROC>(
+                    query_export,
+                ),
+                std::mem::transmute::<extern "system" fn(), PFNEGLEXPORTDMABUFIMAGEMESAPROC>(
+                    export,
+                ),
+            )
+        };
+
+        let image = self.egl_instance.create_image(
+            self.display,
+            self.context,
+            egl::GL_TEXTURE_2D as egl::Enum,
+            unsafe { ClientBuffer::from_ptr(texture as usize as *mut c_void) },
+            &[egl::ATTRIB_NONE],
+        )?;
+
+        let display = self.display.as_ptr();
+        let mut fourcc = 0;
+        let mut num_planes = 0;
+        let mut modifier = 0;
+        if unsafe {
+            query_export(
+                display,
+                image.as_ptr(),
+                &mut fourcc,
+                &mut num_planes,
+                &mut modifier,
+            )
+        } == egl::FALSE
+        {
+            let _ = self.destroy_image(image);
+            return Err(self.last_egl_error("Failed to query the exported texture".into()));
+        }
+
+        // The extension writes at most 4 planes
+        let num_planes = num_planes.clamp(1, 4) as usize;
+        let mut fds = [-1; 4];
+        let mut strides = [0; 4];
+        let mut offsets = [0; 4];
+        if unsafe {
+            export(
+                display,
+                image.as_ptr(),
+                fds.as_mut_ptr(),
+                strides.as_mut_ptr(),
+                offsets.as_mut_ptr(),
+            )
+        } == egl::FALSE
+        {
+            let _ = self.destroy_image(image);
+            return Err(self.last_egl_error("Failed to export the texture".into()));
+        }
+        if fds[0] < 0 {
+            let _ = self.destroy_image(image);
+            return Err(WaycapError::Init(
+                "The exported texture has no DMA-BUF fd".into(),
+            ));
+        }
+
+        // Planes in the first plane's buffer are exported without an fd of their own
+        let planes = (0..num_planes)
+            .map(|i| DmaBufPlane {
+                fd: if fds[i] >= 0 { fds[i] } else { fds[0] },
+                offset: offsets[i] as u32,
+                stride: strides[i] as u32,
+            })
+            .collect();
+        let fds = fds[..num_planes]
+            .iter()
+            .filter(|fd| **fd >= 0)
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(*fd) })
+            .collect();
+        Ok(ExportedTexture {
+            image,
+            _fds: fds,
+            planes,
+            fourcc: fourcc as u32,
+            modifier,
+        })
+    }
+
+    /// Display of the first GPU EGL can enumerate, for when the Wayland display has no EGL
+    /// display, e.g. on NVIDIA setups without the Wayland platform or when running headless
+    fn get_device_display(egl_instance: &EglInstance) -> Result<egl::Display> {
+        let client_extensions = egl_instance.query_string(None, egl::EXTENSIONS)?;
+        let client_extensions = client_extensions.to_string_lossy();
+        if !client_extensions.contains("EGL_EXT_platform_device")
+            || !client_extensions.contains("EGL_EXT_device_enumeration")
+        {
+            log::error!("EGL can't enumerate devices, no display to fall back to");
+            return Err(WaycapError::Egl(egl::Error::BadDisplay));
+        }
+
+        let query_devices = egl_instance
+            .get_proc_address("eglQueryDevicesEXT")
+            .ok_or(WaycapError::Egl(egl::Error::BadDisplay))?;
+        let query_devices = unsafe {
+            std::mem::transmute::<extern "system" fn(), PFNEGLQUERYDEVICESEXTPROC>(query_devices)
+        };
+
+        let mut devices = [std::ptr::null_mut(); MAX_EGL_DEVICES];
+        let mut num_devices = 0;
+        if unsafe {
+            query_devices(
+                devices.len() as egl::Int,
+                devices.as_mut_ptr(),
+                &mut num_devices,
+            )
+        } == egl::FALSE
+        {
+            return Err(WaycapError::Egl(egl::Error::BadDisplay));
+        }
+
+        for device in &devices[..num_devices.max(0) as usize] {
+            match unsafe {
+                egl_instance.get_platform_display(PLATFORM_DEVICE_EXT, *device, &[egl::ATTRIB_NONE])
+            } {
+                Ok(display) => {
+                    log::info!("Using an EGL device display");
+                    return Ok(display);
+                }
+                Err(e) => log::debug!("No EGL display for device {device:?}: {e}"),
+            }
+        }
+        Err(WaycapError::Egl(egl::Error::BadDisplay))
+    }
+
     fn check_dmabuf_support(
-        egl_instance: &Instance<Dynamic<libloading::Library, egl::EGL1_5>>,
+        egl_instance: &EglInstance,
         display: egl::Display,
     ) -> Result<(bool, bool)> {
         let extensions = egl_instance.query_string(Some(display), egl::EXTENSIONS)?;
@@ -308,7 +615,9 @@ impl EglContext {
         let dmabuf_modifiers = ext_str.contains("EGL_EXT_image_dma_buf_import_modifiers");
 
         if !dmabuf_import {
-            return Err("EGL_EXT_image_dma_buf_import not supported".into());
+            return Err(WaycapError::Init(
+                "EGL_EXT_image_dma_buf_import not supported".into(),
+            ));
         }
 
         Ok((dmabuf_import, dmabuf_modifiers))
@@ -323,7 +632,7 @@ impl EglContext {
         modifier: u64,
     ) -> Result<egl::Image> {
         if !self.dmabuf_supported {
-            return Err("DMA-BUF import not supported".into());
+            return Err(WaycapError::Init("DMA-BUF import not supported".into()));
         }
 
         let mut attributes = vec![
@@ -422,15 +731,91 @@ impl EglContext {
                 unsafe { ClientBuffer::from_ptr(std::ptr::null_mut()) },
                 &attributes,
             )
-            .map_err(|e| format!("Failed to create EGL image from DMA-BUF: {e:?}"))?;
+            .map_err(|e| {
+                log::debug!("Failed to create EGL image from DMA-BUF: {e:?}");
+                WaycapError::Egl(e)
+            })?;
 
         Ok(image)
     }
 
+    /// DRM format modifiers DMA-BUFs of `format` can be imported with into a 2D texture,
+    /// as reported by `eglQueryDmaBufModifiersEXT`. Modifiers the driver only supports for
+    /// external textures are left out.
+    pub fn query_dmabuf_modifiers(&self, format: DrmFourcc) -> Result<Vec<u64>> {
+        if !self.dmabuf_modifiers_supported {
+            return Err(WaycapError::Init(
+                "EGL_EXT_image_dma_buf_import_modifiers not supported".into(),
+            ));
+        }
+        let query_modifiers = self
+            .egl_instance
+            .get_proc_address("eglQueryDmaBufModifiersEXT")
+            .ok_or_else(|| WaycapError::Init("eglQueryDmaBufModifiersEXT not available".into()))?;
+        let query_modifiers = unsafe {
+            std::mem::transmute::<extern "system" fn(), PFNEGLQUERYDMABUFMODIFIERSEXTPROC>(
+                query_modifiers,
+            )
+        };
+
+        let display = self.display.as_ptr();
+        let format = format as u32 as egl::Int;
+        let mut count = 0;
+        if unsafe {
+            query_modifiers(
+                display,
+                format,
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                &mut count,
+            )
+        } == egl::FALSE
+        {
+            return Err(
+                self.last_egl_error(format!("Failed to query the modifiers of {format:#x}"))
+            );
+        }
+
+        let mut modifiers = vec![0u64; count.max(0) as usize];
+        let mut external_only = vec![egl::FALSE; modifiers.len()];
+        if unsafe {
+            query_modifiers(
+                display,
+                format,
+                count,
+                modifiers.as_mut_ptr(),
+                external_only.as_mut_ptr(),
+                &mut count,
+            )
+        } == egl::FALSE
+        {
+            return Err(
+                self.last_egl_error(format!("Failed to query the modifiers of {format:#x}"))
+            );
+        }
+        modifiers.truncate(count.max(0) as usize);
+
+        Ok(modifiers
+            .into_iter()
+            .zip(external_only)
+            .filter(|(_, external_only)| *external_only == egl::FALSE)
+            .map(|(modifier, _)| modifier)
+            .collect())
+    }
+
+    /// The error of the last failed EGL call, or an [`WaycapError::Init`] with `context` if
+    /// EGL didn't record one
+    fn last_egl_error(&self, context: String) -> WaycapError {
+        match self.egl_instance.get_error() {
+            Some(e) => WaycapError::Egl(e),
+            None => WaycapError::Init(context),
+        }
+    }
+
     pub fn destroy_image(&self, image: egl::Image) -> Result<()> {
-        self.egl_instance
-            .destroy_image(self.display, image)
-            .map_err(|e| format!("Failed to destroy EGL image: {e:?}").into())
+        self.egl_instance.destroy_image(self.display, image)?;
+        Ok(())
     }
 
     pub fn delete_texture(&self, texture_id: u32) {